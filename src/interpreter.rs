@@ -0,0 +1,1780 @@
+use crate::r7rs::ast::{self, FileId, Source, Sources, Span, AST};
+use crate::r7rs::builtins;
+use crate::r7rs::env::Environment;
+use crate::r7rs::error::Error;
+use crate::r7rs::eval::eval_cdef;
+use crate::r7rs::expand::expand;
+use crate::r7rs::gc;
+use crate::r7rs::library::Libraries;
+use crate::r7rs::value::Value;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// An Interpreter owns a global environment and evaluates Scheme programs in
+/// it. Definitions made by one call to `eval_str` are visible to the next.
+/// The global environment starts out binding every builtin, and the imports
+/// of a program bind what they import in it besides.
+pub struct Interpreter {
+    global: Rc<Environment>,
+    libraries: Libraries,
+    sources: Rc<Sources>,
+    error_span: Option<Span>,
+}
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        let global = gc::alloc(Environment::new());
+        builtins::install(&global);
+        Interpreter {
+            global,
+            libraries: Libraries::new(),
+            sources: Rc::new(Sources::default()),
+            error_span: None,
+        }
+    }
+
+    /// Parse and evaluate a program, returning the value of its last form.
+    pub fn eval_str(&mut self, src: &str) -> Result<Value, Error> {
+        self.eval_source(src, "<string>")
+    }
+
+    /// Read a file and evaluate its contents as a program. Parse errors name
+    /// the file.
+    pub fn eval_file<P: AsRef<Path>>(&mut self, path: P) -> Result<Value, Error> {
+        let src = std::fs::read_to_string(path.as_ref())?;
+        self.eval_source(&src, &path.as_ref().display().to_string())
+    }
+
+    /// The name of the source a span points into: a file path, or
+    /// `<string>` for programs passed to `eval_str`.
+    pub fn source_name(&self, file: FileId) -> Option<String> {
+        self.sources.name(file)
+    }
+
+    /// The directories that imported library names are looked up in as
+    /// `.sld` files, in order. It starts out as the current directory.
+    pub fn library_path(&self) -> &[PathBuf] {
+        self.libraries.path()
+    }
+
+    /// Look library names up in `directory` after the directories of the
+    /// library path.
+    pub fn add_library_path<P: AsRef<Path>>(&mut self, directory: P) {
+        let mut path = self.libraries.path().to_vec();
+        path.push(directory.as_ref().to_path_buf());
+        self.libraries.set_path(path);
+    }
+
+    /// Replace the library path.
+    pub fn set_library_path(&mut self, path: Vec<PathBuf>) {
+        self.libraries.set_path(path);
+    }
+
+    /// Where the last error was raised: the innermost form written in the
+    /// source that the error was raised expanding, or else the top-level
+    /// form whose evaluation failed.
+    pub fn error_span(&self) -> Option<Span> {
+        self.error_span
+    }
+
+    fn eval_source(&mut self, src: &str, name: &str) -> Result<Value, Error> {
+        let sources = Rc::clone(&self.sources);
+        ast::with_sources(&sources, || self.eval_program(src, name))
+    }
+
+    fn eval_program(&mut self, src: &str, name: &str) -> Result<Value, Error> {
+        let text: Rc<str> = Rc::from(src);
+        let source = Source::new(ast::register_source(name), &text);
+        let AST::Program {
+            libraries,
+            imports,
+            forms,
+        } = AST::parse(&source).map_err(|e| Error::Parse(e.with_source(name)))?;
+        for library in libraries {
+            self.libraries.define(library, &text);
+        }
+        for import in imports.iter() {
+            self.libraries.import(import, &self.global)?;
+        }
+        let mut result = Value::Null;
+        for span in forms {
+            let expanded = expand(span, &source, &self.global).map_err(|e| {
+                self.error_span = Some(e.span.unwrap_or(span));
+                Error::from(e)
+            })?;
+            result = Value::Unspecified;
+            for form in expanded.iter() {
+                result = eval_cdef(form, &self.global)
+                    .inspect_err(|_| self.error_span = Some(form.span))?;
+            }
+            gc::collect_if_due();
+        }
+        Ok(result)
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory for one test to write files in, named after the test and
+    // the process so that concurrent runs do not share it, and removed when
+    // the test is done with it, whether or not it passed.
+    struct TempDir(PathBuf);
+    impl TempDir {
+        fn new(test: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("sevenfold-{}-{}", std::process::id(), test));
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_eval_literal() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.eval_str("#t").unwrap().to_string(), "#t");
+        assert_eq!(interpreter.eval_str("#\\a").unwrap().to_string(), "#\\a");
+    }
+
+    #[test]
+    fn test_eval_returns_last_value() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.eval_str("#t\n#f").unwrap().to_string(), "#f");
+    }
+
+    #[test]
+    fn test_eval_numbers() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.eval_str("#x-ff").unwrap().to_string(), "-255");
+        assert_eq!(interpreter.eval_str("#e.5").unwrap().to_string(), "1/2");
+        assert_eq!(
+            interpreter.eval_str("(list 1/3 +2i)").unwrap().to_string(),
+            "(1/3 0.0+2.0i)"
+        );
+        let tests = [
+            ("+inf.0", "+inf.0"),
+            ("(list -inf.0 +i -i)", "(-inf.0 0.0+1.0i 0.0-1.0i)"),
+            ("(symbol? '+inf.0)", "#f"),
+            ("-9223372036854775808", "-9223372036854775808"),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        for input in ["(list 99999999999999999999)", "#e1.2@3"].iter() {
+            assert!(matches!(interpreter.eval_str(input), Err(Error::Parse(_))));
+        }
+    }
+
+    #[test]
+    fn test_eval_strings() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter
+                .eval_str("(string-length \"tab\\there\\x41;\")")
+                .unwrap()
+                .to_string(),
+            "9"
+        );
+        assert!(matches!(
+            interpreter.eval_str("(list \"\\x110000;\")"),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            interpreter.eval_str("(list #\\xD800)"),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_eval_quotation() {
+        let mut interpreter = Interpreter::new();
+        let tests = [
+            ("'foo", "foo"),
+            ("(quote foo)", "foo"),
+            ("'()", "()"),
+            ("'(a b . c)", "(a b . c)"),
+            ("'#(1 \"two\" #\\3)", "#(1 \"two\" #\\3)"),
+            ("''a", "(quote a)"),
+            ("(car '((x) y))", "(x)"),
+            ("(symbol? (quote x))", "#t"),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        assert!(matches!(
+            interpreter.eval_str("'#u8(256)"),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_eval_cyclic_data() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter
+                .eval_str("(define x '#0=(a . #0#)) (car (cdr (cdr x)))")
+                .unwrap()
+                .to_string(),
+            "a"
+        );
+        assert_eq!(
+            interpreter.eval_str("x").unwrap().to_string(),
+            "#0=(a . #0#)"
+        );
+        assert_eq!(
+            interpreter
+                .eval_str("(write-shared x)")
+                .unwrap()
+                .to_string(),
+            "#<unspecified>"
+        );
+        // Cycles through the irritants of an error object and the value of
+        // a parameter are labelled like any other.
+        let tests = [
+            (
+                "(define l (list 1))
+                 (define e (guard (x (#t x)) (error \"boom\" l)))
+                 (set-car! l e)
+                 l",
+                "#0=(#<error \"boom\" #0#>)",
+            ),
+            ("e", "#0=#<error \"boom\" (#0#)>"),
+            (
+                "(define v (vector #f))
+                 (define p (make-parameter v))
+                 (vector-set! v 0 p)
+                 v",
+                "#0=#(#<parameter #0#>)",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        interpreter
+            .eval_str("(set-car! l 1) (vector-set! v 0 #f)")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_eval_vector_literals() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter.eval_str("#(a (b) #t)").unwrap().to_string(),
+            "#(a (b) #t)"
+        );
+        assert_eq!(
+            interpreter
+                .eval_str("(list #u8() #u8(#b11111111 #o377 255 #xff))")
+                .unwrap()
+                .to_string(),
+            "(#u8() #u8(255 255 255 255))"
+        );
+        match interpreter.eval_str("#u8(1 #x100)") {
+            Err(Error::Parse(e)) => {
+                assert_eq!(e.column, 7);
+                assert_eq!(e.expected, "a byte between 0 and 255");
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_eval_unbound_variable() {
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str("foo") {
+            Err(Error::UnboundVariable(name)) => assert_eq!(name, "foo"),
+            _ => panic!("expected an unbound variable error"),
+        }
+    }
+
+    #[test]
+    fn test_eval_procedure_call() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter
+                .eval_str("(car (cons #\\a #f))")
+                .unwrap()
+                .to_string(),
+            "#\\a"
+        );
+        assert_eq!(
+            interpreter
+                .eval_str("(list #t (not #t))")
+                .unwrap()
+                .to_string(),
+            "(#t #f)"
+        );
+    }
+
+    #[test]
+    fn test_eval_call_errors() {
+        let mut interpreter = Interpreter::new();
+        assert!(matches!(
+            interpreter.eval_str("(#t #f)"),
+            Err(Error::NotAProcedure(_))
+        ));
+        assert!(matches!(
+            interpreter.eval_str("(car #t)"),
+            Err(Error::WrongType { .. })
+        ));
+        assert!(matches!(
+            interpreter.eval_str("(cons #t)"),
+            Err(Error::WrongArgumentCount { given: 1, .. })
+        ));
+        assert!(matches!(
+            interpreter.eval_str("(car (undefined))"),
+            Err(Error::UnboundVariable(_))
+        ));
+    }
+
+    #[test]
+    fn test_eval_lambda() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter
+                .eval_str("((lambda (a b) (cons b a)) #t #f)")
+                .unwrap()
+                .to_string(),
+            "(#f . #t)"
+        );
+        assert_eq!(
+            interpreter
+                .eval_str("((lambda (a . rest) rest) #t #f #\\a)")
+                .unwrap()
+                .to_string(),
+            "(#f #\\a)"
+        );
+        assert!(matches!(
+            interpreter.eval_str("((lambda (a) a))"),
+            Err(Error::WrongArgumentCount { given: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_eval_conditionals() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter
+                .eval_str("(if (list) #t #f)")
+                .unwrap()
+                .to_string(),
+            "#t"
+        );
+        assert_eq!(
+            interpreter.eval_str("(if #f #f)").unwrap().to_string(),
+            "#<unspecified>"
+        );
+        assert_eq!(
+            interpreter
+                .eval_str("(cond (#f #\\a) ((car (list #\\b)) => char?) (else #\\c))")
+                .unwrap()
+                .to_string(),
+            "#t"
+        );
+        assert_eq!(
+            interpreter.eval_str("(and #t #\\a)").unwrap().to_string(),
+            "#\\a"
+        );
+        assert_eq!(
+            interpreter.eval_str("(or #f #f)").unwrap().to_string(),
+            "#f"
+        );
+    }
+
+    #[test]
+    fn test_eval_let_forms() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter
+                .eval_str("(let* ((a #t) (b (cons a a))) b)")
+                .unwrap()
+                .to_string(),
+            "(#t . #t)"
+        );
+        assert_eq!(
+            interpreter
+                .eval_str(
+                    "(let loop ((l (list #t #\\a #f)) (acc (list)))
+                       (if (null? l) acc (loop (cdr l) (cons (car l) acc))))"
+                )
+                .unwrap()
+                .to_string(),
+            "(#f #\\a #t)"
+        );
+        assert_eq!(
+            interpreter
+                .eval_str(
+                    "(letrec ((even? (lambda (l) (if (null? l) #t (odd? (cdr l)))))
+                              (odd? (lambda (l) (if (null? l) #f (even? (cdr l))))))
+                       (even? (list #t #t)))"
+                )
+                .unwrap()
+                .to_string(),
+            "#t"
+        );
+        assert_eq!(
+            interpreter
+                .eval_str("(do ((l (list #t #f) (cdr l))) ((null? l) #\\z))")
+                .unwrap()
+                .to_string(),
+            "#\\z"
+        );
+    }
+
+    #[test]
+    fn test_eval_definitions() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str(
+                "(define yes #t)
+                 (define (swap a b) (cons b a))
+                 (define (rest a . more) (define first a) more)
+                 (begin (define no #f) (define pair (swap yes no)))
+                 (define-values (c) #\\c)",
+            )
+            .unwrap();
+        assert_eq!(
+            interpreter.eval_str("pair").unwrap().to_string(),
+            "(#f . #t)"
+        );
+        assert_eq!(
+            interpreter.eval_str("(rest yes no c)").unwrap().to_string(),
+            "(#f #\\c)"
+        );
+        assert!(matches!(
+            interpreter.eval_str("first"),
+            Err(Error::UnboundVariable(_))
+        ));
+        assert_eq!(
+            interpreter
+                .eval_str("(begin (define a #\\a) (car (list a)))")
+                .unwrap()
+                .to_string(),
+            "#\\a"
+        );
+    }
+
+    #[test]
+    fn test_eval_define_record_type() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str(
+                "(define-record-type pare
+                   (kons x y)
+                   pare?
+                   (x kar set-kar!)
+                   (y kdr))
+                 (define p (kons #\\a #\\b))
+                 (set-kar! p #\\c)",
+            )
+            .unwrap();
+        assert_eq!(interpreter.eval_str("(kar p)").unwrap().to_string(), "#\\c");
+        assert_eq!(interpreter.eval_str("(kdr p)").unwrap().to_string(), "#\\b");
+        assert_eq!(interpreter.eval_str("(pare? p)").unwrap().to_string(), "#t");
+        assert_eq!(
+            interpreter.eval_str("(pare? #t)").unwrap().to_string(),
+            "#f"
+        );
+        match interpreter.eval_str("(kar (cons #t #t))") {
+            Err(e @ Error::WrongType { .. }) => assert_eq!(
+                e.to_string(),
+                "kar: expected a record of type pare, given (#t . #t)"
+            ),
+            other => panic!("expected a wrong type error, given {:?}", other),
+        }
+        let errors = [
+            (
+                "(set-kar! 'p #t)",
+                "set-kar!: expected a record of type pare, given p",
+            ),
+            ("(kons #\\a)", "kons: expected 2 argument(s), given 1"),
+            ("(kar p p)", "kar: expected 1 argument(s), given 2"),
+            ("(pare?)", "pare?: expected 1 argument(s), given 0"),
+        ];
+        for (input, message) in errors.iter() {
+            match interpreter.eval_str(input) {
+                Err(e @ (Error::WrongType { .. } | Error::WrongArgumentCount { .. })) => {
+                    assert_eq!(e.to_string(), *message)
+                }
+                other => panic!("expected {} to fail, given {:?}", input, other),
+            }
+        }
+        assert_eq!(
+            interpreter.eval_str("p").unwrap().to_string(),
+            "#<pare x: #\\c y: #\\b>"
+        );
+        let printed = [
+            ("kons", "#<procedure kons>"),
+            (
+                "(list pare? kar set-kar!)",
+                "(#<procedure pare?> #<procedure kar> #<procedure set-kar!>)",
+            ),
+            ("(kons '(1) \"s\")", "#<pare x: (1) y: \"s\">"),
+        ];
+        for (input, output) in printed.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        match interpreter.eval_str("p").unwrap() {
+            Value::Record(record) => {
+                assert_eq!(record.record_type.name, "pare");
+                assert_eq!(record.field("y").unwrap().to_string(), "#\\b");
+                assert!(record.field("z").is_none());
+                let fields: Vec<(String, String)> = record
+                    .field_values()
+                    .into_iter()
+                    .map(|(name, value)| (name, value.to_string()))
+                    .collect();
+                assert_eq!(
+                    fields,
+                    vec![
+                        ("x".to_string(), "#\\c".to_string()),
+                        ("y".to_string(), "#\\b".to_string())
+                    ]
+                );
+                assert_eq!(
+                    record
+                        .set_field("y", Value::Boolean(true))
+                        .unwrap()
+                        .to_string(),
+                    "#\\b"
+                );
+                assert!(record.set_field("z", Value::Null).is_none());
+            }
+            other => panic!("expected a record, given {}", other),
+        }
+        assert_eq!(interpreter.eval_str("(kdr p)").unwrap().to_string(), "#t");
+        assert_eq!(
+            interpreter
+                .eval_str(
+                    "(define-record-type <node> (node next) node? (next next set-next!))
+                     (define n (node '()))
+                     (set-next! n (list n))
+                     n"
+                )
+                .unwrap()
+                .to_string(),
+            "#0=#<node next: (#0#)>"
+        );
+        assert!(matches!(
+            interpreter.eval_str("(define-record-type t (make-t z) t?)"),
+            Err(Error::BadSyntax(_))
+        ));
+        let duplicates = [
+            "(define-record-type u (make-u x) u? (x u-x) (x u-x2))",
+            "(define-record-type u (make-u x x) u? (x u-x))",
+        ];
+        for input in duplicates.iter() {
+            match interpreter.eval_str(input) {
+                Err(Error::BadSyntax(message)) => assert!(message.contains("duplicate field x")),
+                other => panic!("expected {} to be rejected, given {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_mutation() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str(
+                "(define p (list 1 2))
+                 (define q p)
+                 (set-car! q 3)
+                 (set-cdr! (cdr q) (list 4))
+                 (define v (make-vector 2 #f))
+                 (define w v)
+                 (vector-set! w 0 p)
+                 (define s (make-string 3 #\\a))
+                 (define t s)
+                 (string-set! t 1 #\\λ)
+                 (define b (bytevector 1 2))
+                 (bytevector-u8-set! b 1 255)",
+            )
+            .unwrap();
+        let tests = [
+            ("p", "(3 2 4)"),
+            ("v", "#((3 2 4) #f)"),
+            ("s", "\"aλa\""),
+            ("(string-ref s 1)", "#\\λ"),
+            ("b", "#u8(1 255)"),
+            (
+                "(list (eq? p q) (eq? v w) (eq? s t) (eq? car car))",
+                "(#t #t #t #t)",
+            ),
+            (
+                "(list (eq? p (list 3 2 4)) (equal? p (list 3 2 4)))",
+                "(#f #t)",
+            ),
+            ("(let ((f (lambda () #t))) (eq? f f))", "#t"),
+            (
+                "(define (build n tail) (if (= n 0) tail (build (- n 1) (cons n tail))))
+                 (equal? (build 200000 '()) (build 200000 '()))",
+                "#t",
+            ),
+            (
+                "(define c (list 1 2))
+                 (set-cdr! (cdr c) c)
+                 (define d (list 1 2 1 2))
+                 (set-cdr! (cdr (cdr (cdr d))) d)
+                 (list (equal? c d) (equal? c (list 1 2)) (equal? (vector c) (vector d)))",
+                "(#t #f #t)",
+            ),
+            ("(list (equal? \"ab\" \"ab\") (equal? 2 2.0))", "(#t #f)"),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        assert!(matches!(
+            interpreter.eval_str("(vector-ref v 2)"),
+            Err(Error::IndexOutOfRange {
+                index: 2,
+                length: 2,
+                ..
+            })
+        ));
+        assert!(matches!(
+            interpreter.eval_str("(bytevector-u8-set! b 0 256)"),
+            Err(Error::WrongType { .. })
+        ));
+        // Circular lists are not lists, whether their cycle is short or long.
+        let tests = [
+            ("(length c)", "length"),
+            ("(length d)", "length"),
+            ("(apply + 1 c)", "apply"),
+            ("(apply + (cdr d))", "apply"),
+        ];
+        for (input, name) in tests.iter() {
+            match interpreter.eval_str(input) {
+                Err(Error::WrongType { procedure, .. }) => assert_eq!(procedure, *name),
+                _ => panic!("expected {} to be the wrong type", input),
+            }
+        }
+        assert_eq!(
+            interpreter
+                .eval_str("(list (length '()) (length '(1 2 3)) (apply + 1 '(2 3)))")
+                .unwrap()
+                .to_string(),
+            "(0 3 6)"
+        );
+    }
+
+    #[test]
+    fn test_eval_assignment() {
+        let mut interpreter = Interpreter::new();
+        // Both closures share the frame that binds n.
+        interpreter
+            .eval_str(
+                "(define counter
+                   (let ((n \"\"))
+                     (cons (lambda () (set! n (string-append n \"+\")))
+                           (lambda () n))))
+                 ((car counter))
+                 ((car counter))",
+            )
+            .unwrap();
+        let tests = [
+            ("((cdr counter))", "\"++\""),
+            ("(define x #f) (set! x #t) x", "#t"),
+            ("(let ((y 1)) (let ((y 2)) (set! y 3)) y)", "1"),
+            ("(let ((y 1)) (let () (set! y 3)) y)", "3"),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        match interpreter.eval_str("(set! n #t)") {
+            Err(Error::UnboundVariable(name)) => assert_eq!(name, "n"),
+            _ => panic!("expected an unbound variable error"),
+        }
+    }
+
+    #[test]
+    fn test_eval_arithmetic() {
+        let mut interpreter = Interpreter::new();
+        let tests = [
+            (
+                "(list (+) (+ 1 2 3) (- 5) (- 5 1 1) (* 2 3 4))",
+                "(0 6 -5 3 24)",
+            ),
+            ("(+ 1 .5)", "1.5"),
+            (
+                "(list (< 1 2 3) (< 1 3 2) (= 2 2.) (> 3 2 1))",
+                "(#t #f #t #t)",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        assert!(matches!(
+            interpreter.eval_str("(+ 1 #t)"),
+            Err(Error::WrongType { .. })
+        ));
+    }
+
+    #[test]
+    fn test_eval_tail_calls() {
+        let mut interpreter = Interpreter::new();
+        let tests = [
+            (
+                "(let loop ((i 0)) (if (< i 1000000) (loop (+ i 1)) i))",
+                "1000000",
+            ),
+            (
+                "(define (even? n) (or (= n 0) (odd? (- n 1))))
+                 (define (odd? n) (and (> n 0) (even? (- n 1))))
+                 (even? 100001)",
+                "#f",
+            ),
+            (
+                "(define (count n)
+                   (cond ((= n 0) 'done)
+                         (else (when #t (apply count (list (- n 1)))))))
+                 (count 100000)",
+                "done",
+            ),
+            (
+                "(let loop ((i 0))
+                   (let* ((j (+ i 1)))
+                     (letrec ((k j))
+                       (unless (= k 100000) (begin (loop k))))))",
+                "#<unspecified>",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+    }
+
+    #[test]
+    fn test_eval_derived_expressions() {
+        let mut interpreter = Interpreter::new();
+        let tests = [
+            (
+                "(define (classify x)
+                   (case x
+                     ((1 2 3) 'small)
+                     ((a e i o u) 'vowel)
+                     ((#\\x) => (lambda (c) (list c)))
+                     (else => (lambda (x) (list 'other x)))))
+                 (list (classify 2) (classify 'e) (classify #\\x) (classify 9))",
+                "(small vowel (#\\x) (other 9))",
+            ),
+            ("(case 5 ((1) 'one))", "#<unspecified>"),
+            (
+                "(define area
+                   (case-lambda
+                     ((r) (* 3 r r))
+                     ((w h) (* w h))
+                     ((w h . more) (list w h more))))
+                 (list (area 2) (area 2 3) (area 1 2 3 4))",
+                "(12 6 (1 2 (3 4)))",
+            ),
+            // The derived forms call the builtins whatever the program binds
+            // their names to.
+            (
+                "(let ((eqv? #f) (length #f) (apply #f))
+                   (list (case 'a ((a) 1)) ((case-lambda ((x) x)) 2) (or #f 3)))",
+                "(1 2 3)",
+            ),
+            (
+                "(define radix (make-parameter 10))
+                 (define prefix (make-parameter \"x\" (lambda (s) (string-append s \":\"))))
+                 (list (radix) (parameterize ((radix 2) (prefix \"y\")) (list (radix) (prefix))) (radix) (prefix))",
+                "(10 (2 \"y:\") 10 \"x:\")",
+            ),
+            // Escaping from the body restores the parameter, and re-entering
+            // it installs the parameterized value again.
+            (
+                "(define k #f)
+                 (define seen '())
+                 (let ((n 0))
+                   (call/cc (lambda (escape)
+                     (parameterize ((radix 16))
+                       (call/cc (lambda (c) (set! k c)))
+                       (set! seen (cons (radix) seen))
+                       (escape #f))))
+                   (set! seen (cons (radix) seen))
+                   (set! n (+ n 1))
+                   (if (< n 2) (k #f))
+                   seen)",
+                "(10 16 10 16)",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        assert!(matches!(
+            interpreter.eval_str("(area)"),
+            Err(Error::Raised(_))
+        ));
+    }
+
+    #[test]
+    fn test_eval_quasiquote() {
+        let mut interpreter = Interpreter::new();
+        let tests = [
+            ("`(1 2)", "(1 2)"),
+            (
+                "(define x 2) (define l '(3 4)) `(1 ,x ,@l . 5)",
+                "(1 2 3 4 . 5)",
+            ),
+            ("`(,@l ,@'() ,@l)", "(3 4 3 4)"),
+            ("`#(1 ,x ,@l)", "#(1 2 3 4)"),
+            ("`(a . ,x)", "(a . 2)"),
+            ("`(quote ,x)", "(quote 2)"),
+            ("(quasiquote (1 (unquote (+ x 1))))", "(1 3)"),
+            // Only the unquotations at the level of the outermost
+            // quasiquote are evaluated.
+            ("`(1 `(2 ,(3 ,x)))", "(1 (quasiquote (2 (unquote (3 2)))))"),
+            (
+                "`(1 `(2 ,@(,@l)))",
+                "(1 (quasiquote (2 (unquote-splicing (3 4)))))",
+            ),
+            // Quasiquotes nest to any depth.
+            (
+                "`(1 `(2 `(3 `(4 ,(+ 1 2)))))",
+                "(1 (quasiquote (2 (quasiquote (3 (quasiquote (4 (unquote (+ 1 2)))))))))",
+            ),
+            (
+                "`(1 `(2 `(3 `(4 ,,,,x))))",
+                "(1 (quasiquote (2 (quasiquote (3 (quasiquote (4 (unquote (unquote (unquote 2))))))))))",
+            ),
+            // Only the parts that unquote something are built each time.
+            ("(define (f) `(1 ,x)) (eq? (cdr (f)) (cdr (f)))", "#f"),
+            (
+                "(define (g) `(1 (2) ,x)) (eq? (car (cdr (g))) (car (cdr (g))))",
+                "#t",
+            ),
+            ("(let ((cons #f) (append #f)) `(,x ,@l))", "(2 3 4)"),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+    }
+
+    #[test]
+    fn test_eval_promises() {
+        let mut interpreter = Interpreter::new();
+        let tests = [
+            ("(force (delay (+ 1 2)))", "3"),
+            (
+                "(define n 0)
+                 (define p (delay (begin (set! n (+ n 1)) n)))
+                 (list (force p) (force p) n)",
+                "(1 1 1)",
+            ),
+            ("(promise? (force (delay (delay 1))))", "#t"),
+            ("(force (delay-force (delay 1)))", "1"),
+            (
+                "(list (promise? p) (promise? 1) (force 1) (force (make-promise 2)))",
+                "(#t #f 1 2)",
+            ),
+            ("(eq? p (make-promise p))", "#t"),
+            ("p", "#<promise>"),
+            // A promise forced while it is being forced keeps the first value
+            // it is given, R7RS section 4.2.5.
+            (
+                "(define count 0)
+                 (define x 5)
+                 (define q
+                   (delay (begin (set! count (+ count 1))
+                                 (if (> count x) count (force q)))))
+                 (list (force q) (begin (set! x 10) (force q)))",
+                "(6 6)",
+            ),
+            // A chain of delay-forces is forced in constant space.
+            (
+                "(define (loop n)
+                   (delay-force (if (= n 0) (delay 'done) (loop (- n 1)))))
+                 (force (loop 100000))",
+                "done",
+            ),
+            ("(append '(1) '() '(2 3) 4)", "(1 2 3 . 4)"),
+            ("(append)", "()"),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+    }
+
+    #[test]
+    fn test_eval_shadowed_keywords() {
+        let mut interpreter = Interpreter::new();
+        // A keyword bound as a variable is a variable where the binding is
+        // in scope, so a list it heads is a procedure call.
+        let tests = [
+            ("(let ((if list)) (if 1 2 3))", "(1 2 3)"),
+            ("(define (f lambda) (lambda 1)) (f -)", "-1"),
+            (
+                "(let ((else #f)) (cond (else 'keyword) (#t 'variable)))",
+                "variable",
+            ),
+            ("(let ((begin 1) (quote 2)) (+ begin quote))", "3"),
+            ("(if #f 1 2)", "2"),
+            // So is a keyword defined as a global variable.
+            ("(define when list) (when #f 1 2)", "(#f 1 2)"),
+            ("(let ((x 1)) (when x))", "(1)"),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(
+                interpreter.eval_str(input).unwrap().to_string(),
+                *output,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_eval_macros() {
+        let mut interpreter = Interpreter::new();
+        let tests = [
+            (
+                "(define-syntax swap!
+                   (syntax-rules ()
+                     ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+                 (define tmp 1)
+                 (define other 2)
+                 (swap! tmp other)
+                 (list tmp other)",
+                "(2 1)",
+            ),
+            // A binding around the use does not capture what the template
+            // refers to.
+            (
+                "(define-syntax my-or
+                   (syntax-rules ()
+                     ((_) #f)
+                     ((_ e) e)
+                     ((_ e r ...) (let ((t e)) (if t t (my-or r ...))))))
+                 (let ((t 5) (if list)) (my-or #f t))",
+                "5",
+            ),
+            (
+                "(define-syntax first
+                   (syntax-rules () ((_ x) (car x))))
+                 (let ((car cdr)) (first '(1 2)))",
+                "1",
+            ),
+            // Literals match by binding, so a local else is not the keyword.
+            (
+                "(define-syntax test
+                   (syntax-rules (else)
+                     ((_ else) 'keyword)
+                     ((_ x) 'other)))
+                 (list (test else) (let ((else 1)) (test else)))",
+                "(keyword other)",
+            ),
+            (
+                "(define-syntax my-let*
+                   (syntax-rules ()
+                     ((_ () body ...) (let () body ...))
+                     ((_ ((x v) rest ...) body ...) (let ((x v)) (my-let* (rest ...) body ...)))))
+                 (my-let* ((a 1) (b (+ a 1))) (list a b))",
+                "(1 2)",
+            ),
+            (
+                "(define-syntax flatten
+                   (syntax-rules ()
+                     ((_ (a b ...) ...) '(a ... (b ... ...)))))
+                 (flatten (1 2 3) (4 5) (6))",
+                "(1 4 6 (2 3 5))",
+            ),
+            (
+                "(define-syntax vec
+                   (syntax-rules ()
+                     ((_ #(a ...) _ . rest) '(a ... rest))))
+                 (vec #(1 2) ignored 3 4)",
+                "(1 2 (3 4))",
+            ),
+            (
+                "(define-syntax tail
+                   (syntax-rules :::
+                     ()
+                     ((_ x ::: y) '(y x ::: (::: :::) ...))))
+                 (tail 1 2 3)",
+                "(3 1 2 ::: ...)",
+            ),
+            (
+                "(let-syntax ((foo (syntax-rules () ((_ x) (* x 2)))))
+                   (foo 21))",
+                "42",
+            ),
+            (
+                "(letrec-syntax
+                   ((ev? (syntax-rules () ((_) #t) ((_ x . r) (od? . r))))
+                    (od? (syntax-rules () ((_) #f) ((_ x . r) (ev? . r)))))
+                   (list (ev? 1 2) (od? 1 2 3)))",
+                "(#t #t)",
+            ),
+            (
+                "(define (f x)
+                   (define-syntax twice (syntax-rules () ((_ e) (begin e e))))
+                   (define n 0)
+                   (twice (set! n (+ n x)))
+                   n)
+                 (f 3)",
+                "6",
+            ),
+            // A macro that defines a variable and refers to it.
+            (
+                "(define-syntax define-counter
+                   (syntax-rules ()
+                     ((_ name) (begin (define count 0)
+                                      (define (name) (set! count (+ count 1)) count)))))
+                 (define-counter next!)
+                 (next!)
+                 (list (next!) (next!))",
+                "(2 3)",
+            ),
+            (
+                "(define-syntax while
+                   (syntax-rules ()
+                     ((_ test body ...) (let loop () (when test body ... (loop))))))
+                 (define i 0)
+                 (define loop 0)
+                 (while (< i 5) (set! loop (+ loop i)) (set! i (+ i 1)))
+                 (list i loop)",
+                "(5 10)",
+            ),
+            (
+                "(define-syntax one (syntax-rules () ((_) 1.0)))
+                 (list (one) (eqv? (one) 1))",
+                "(1.0 #f)",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        assert!(matches!(
+            interpreter.eval_str("(swap! 1)"),
+            Err(Error::BadSyntax(_))
+        ));
+    }
+
+    #[test]
+    fn test_eval_transformers() {
+        let mut interpreter = Interpreter::new();
+        let tests = [
+            (
+                "(define-syntax swap!
+                   (er-macro-transformer
+                     (lambda (form rename compare)
+                       (let ((a (car (cdr form)))
+                             (b (car (cdr (cdr form))))
+                             (tmp (rename 'tmp)))
+                         (list (rename 'let) (list (list tmp a))
+                               (list (rename 'set!) a b)
+                               (list (rename 'set!) b tmp))))))
+                 (let ((tmp 1) (other 2)) (swap! tmp other) (list tmp other))",
+                "(2 1)",
+            ),
+            (
+                "(define-syntax which
+                   (er-macro-transformer
+                     (lambda (form rename compare)
+                       (if (compare (car (cdr form)) (rename 'else)) ''else ''other))))
+                 (list (which else) (let ((else 1)) (which else)))",
+                "(else other)",
+            ),
+            // Only the injected it is visible to the body; the list the
+            // transformer inserts is the global one.
+            (
+                "(define-syntax aif
+                   (ir-macro-transformer
+                     (lambda (form inject compare)
+                       (let ((test (car (cdr form)))
+                             (then (car (cdr (cdr form))))
+                             (else (car (cdr (cdr (cdr form))))))
+                         (list 'let (list (list (inject 'it) test))
+                               (list 'if (inject 'it) (list 'list ''found then) else))))))
+                 (let ((list vector)) (aif (+ 1 2) (list it) #f))",
+                "(found #(3))",
+            ),
+            (
+                "(define-syntax my-or
+                   (lambda (x)
+                     (syntax-case x ()
+                       ((_) (syntax #f))
+                       ((_ e) (syntax e))
+                       ((_ e r ...) (syntax (let ((t e)) (if t t (my-or r ...))))))))
+                 (let ((t 5)) (my-or #f t))",
+                "5",
+            ),
+            (
+                "(define-syntax kind
+                   (lambda (x)
+                     (syntax-case x (else)
+                       ((_ else) (syntax 'else))
+                       ((_ n) (symbol? (syntax->datum (syntax n))) (syntax 'symbol))
+                       ((_ n) (syntax 'other)))))
+                 (list (kind else) (kind a) (kind 1) (let ((else 1)) (kind else)))",
+                "(else symbol other symbol)",
+            ),
+            (
+                "(define-syntax aif
+                   (lambda (x)
+                     (syntax-case x ()
+                       ((k test then else)
+                        (with-syntax ((it (datum->syntax (syntax k) 'it)))
+                          (syntax (let ((it test)) (if it then else))))))))
+                 (aif (car '((a . 1))) (cdr it) 0)",
+                "1",
+            ),
+            (
+                "(define-syntax my-let
+                   (lambda (x)
+                     (syntax-case x ()
+                       ((_ ((n v) ...) body ...)
+                        (with-syntax (((t ...) (generate-temporaries (syntax (n ...)))))
+                          (syntax (let ((t v) ...) (let ((n t) ...) body ...))))))))
+                 (my-let ((a 1) (b 2)) (list b a))",
+                "(2 1)",
+            ),
+            (
+                "(let-syntax ((twice (lambda (x)
+                                       (syntax-case x () ((_ e) (syntax (* 2 e)))))))
+                   (twice 21))",
+                "42",
+            ),
+            // What a transformer inserts is used as it is: a closure with the
+            // variables it closes over, a builtin, a record, data that is
+            // shared or circular.
+            (
+                "(define-syntax counter
+                   (er-macro-transformer
+                     (let ((n 0))
+                       (lambda (form rename compare)
+                         (list (lambda () (set! n (+ n 1)) n))))))
+                 (list (counter) (counter))",
+                "(1 2)",
+            ),
+            (
+                "(define-record-type point (make-point x y) point? (x point-x) (y point-y))
+                 (define origin (make-point 0 0))
+                 (define data (list 1 2))
+                 (set-cdr! (cdr data) data)
+                 (define-syntax inserted
+                   (er-macro-transformer
+                     (lambda (form rename compare)
+                       (list list car origin (list (rename 'quote) data)))))
+                 (let ((values (inserted)))
+                   (list ((car values) '(1 2))
+                         (eq? (car (cdr values)) origin)
+                         (eq? (car (cdr (cdr values))) data)))",
+                "(1 #t #t)",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        assert!(matches!(
+            interpreter.eval_str("(my-let)"),
+            Err(Error::BadSyntax(_))
+        ));
+    }
+
+    #[test]
+    fn test_eval_continuations() {
+        let mut interpreter = Interpreter::new();
+        let tests = [
+            ("(+ 1 (call/cc (lambda (k) (+ 10 (k 2)))))", "3"),
+            (
+                "(call-with-current-continuation (lambda (k) (apply k '(4))))",
+                "4",
+            ),
+            // Re-entering a continuation runs the rest of the let again.
+            (
+                "(define n 0)
+                 (define k #f)
+                 (let ((i (call/cc (lambda (c) (set! k c) 0))))
+                   (set! n (+ n 1))
+                   (if (< i 3) (k (+ i 1)) (list i n)))",
+                "(3 4)",
+            ),
+            // A continuation captured by an earlier command returns to it.
+            (
+                "(define r '()) (set! r (cons (call/cc (lambda (c) (set! k c) 1)) r)) r",
+                "(1)",
+            ),
+            ("(k 2)", "#<unspecified>"),
+            ("r", "(2 1)"),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+    }
+
+    #[test]
+    fn test_eval_dynamic_wind() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str(
+                "(define trace '())
+                 (define (note x) (set! trace (cons x trace)))
+                 (define (wind name thunk)
+                   (dynamic-wind (lambda () (note (list 'in name)))
+                                 thunk
+                                 (lambda () (note (list 'out name)))))",
+            )
+            .unwrap();
+        let tests = [
+            ("(wind 'a (lambda () 'value))", "value"),
+            ("trace", "((out a) (in a))"),
+            // Escaping runs the after thunks from the innermost out.
+            (
+                "(set! trace '())
+                 (call/cc (lambda (k) (wind 'a (lambda () (wind 'b (lambda () (k 'escaped)))))))",
+                "escaped",
+            ),
+            ("trace", "((out a) (out b) (in b) (in a))"),
+            // Re-entering runs the before thunks from the outermost in.
+            (
+                "(define k #f)
+                 (set! trace '())
+                 (let ((count 0))
+                   (wind 'a (lambda () (wind 'b (lambda () (call/cc (lambda (c) (set! k c)))))))
+                   (set! count (+ count 1))
+                   (if (< count 2) (k 'again))
+                   trace)",
+                "((out a) (out b) (in b) (in a) (out a) (out b) (in b) (in a))",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+    }
+
+    #[test]
+    fn test_eval_exceptions() {
+        let mut interpreter = Interpreter::new();
+        let tests = [
+            (
+                "(with-exception-handler
+                   (lambda (e) 42)
+                   (lambda () (+ (raise-continuable 'oops) 1)))",
+                "43",
+            ),
+            (
+                "(guard (e ((symbol? e) (list 'caught e)))
+                   (raise 'boom))",
+                "(caught boom)",
+            ),
+            (
+                "(guard (e ((string? e) 'string) (else 'other))
+                   (raise 1))",
+                "other",
+            ),
+            (
+                "(guard (e ((error-object? e)
+                            (cons (error-object-message e) (error-object-irritants e))))
+                   (error \"bad thing:\" 1 2))",
+                "(\"bad thing:\" 1 2)",
+            ),
+            (
+                "(guard (e ((and (pair? e) (cdr e)) => (lambda (x) (* x 2))))
+                   (raise '(a . 21)))",
+                "42",
+            ),
+            ("(guard (e (#t 'ignored)) 'no-raise)", "no-raise"),
+            // Errors the interpreter detects are raised as error objects.
+            (
+                "(guard (e ((error-object? e) (error-object-message e))) (car 1))",
+                "\"car: expected pair, given 1\"",
+            ),
+            (
+                "(guard (e ((file-error? e) 'missing)) (include \"no/such/file.scm\"))",
+                "missing",
+            ),
+            // With no clause applying, the guard raises the object again in
+            // the dynamic environment of the original raise.
+            (
+                "(with-exception-handler
+                   (lambda (e) (* e 2))
+                   (lambda ()
+                     (+ 1 (guard (e ((string? e) 'string)) (raise-continuable 20)))))",
+                "41",
+            ),
+            (
+                "(define trace '())
+                 (guard (e (#t (set! trace (cons 'handled trace))))
+                   (dynamic-wind
+                     (lambda () (set! trace (cons 'in trace)))
+                     (lambda () (raise 'x))
+                     (lambda () (set! trace (cons 'out trace)))))
+                 trace",
+                "(handled out in)",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+
+        match interpreter.eval_str("(error \"bad thing:\" 'x)") {
+            Err(e @ Error::Raised(_)) => assert_eq!(e.to_string(), "error: bad thing: x"),
+            _ => panic!("expected a raised error"),
+        }
+        match interpreter.eval_str("(raise 'boom)") {
+            Err(e @ Error::Raised(_)) => assert_eq!(e.to_string(), "uncaught exception: boom"),
+            _ => panic!("expected a raised error"),
+        }
+        // A handler returning from raise is itself an error.
+        match interpreter.eval_str("(with-exception-handler (lambda (e) 0) (lambda () (raise 'x)))")
+        {
+            Err(Error::Raised(Value::ErrorObject(e))) => {
+                assert_eq!(e.message, "handler returned from non-continuable raise")
+            }
+            _ => panic!("expected a raised error"),
+        }
+        // An error the interpreter detects that no handler handles is
+        // returned as itself.
+        match interpreter.eval_str("(guard (e ((string? e) e)) (car 1))") {
+            Err(Error::WrongType { procedure, .. }) => assert_eq!(procedure, "car"),
+            _ => panic!("expected a wrong type error"),
+        }
+    }
+
+    #[test]
+    fn test_eval_multiple_values() {
+        let mut interpreter = Interpreter::new();
+        let tests = [
+            (
+                "(call-with-values (lambda () (values 1 2)) cons)",
+                "(1 . 2)",
+            ),
+            ("(call-with-values (lambda () (values)) list)", "()"),
+            ("(call-with-values (lambda () 5) list)", "(5)"),
+            ("(+ (values 1) 2)", "3"),
+            ("(values 1 2 3)", "1 2 3"),
+            (
+                "(let-values (((a b) (values 1 2)) ((c . d) (values 3 4 5)) (all (values 6 7)))
+                   (list a b c d all))",
+                "(1 2 3 (4 5) (6 7))",
+            ),
+            // The inits of let-values do not see each other's formals.
+            (
+                "(define a 'outer)
+                 (let-values (((a) (values 'inner)) ((b) (values a))) (list a b))",
+                "(inner outer)",
+            ),
+            (
+                "(let*-values (((a b) (values 1 2)) ((c) (values (+ a b)))) (list a b c))",
+                "(1 2 3)",
+            ),
+            (
+                "(define-values (x y . z) (values 1 2 3 4)) (list x y z)",
+                "(1 2 (3 4))",
+            ),
+            ("(define-values all (values)) all", "()"),
+            (
+                "(call-with-values (lambda () (call/cc (lambda (k) (k 1 2)))) list)",
+                "(1 2)",
+            ),
+            (
+                "(call-with-values (lambda () (guard (e (#t 0)) (values 1 2))) list)",
+                "(1 2)",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        // Too few or too many values are reported against the form that
+        // binds them, not the procedures it is compiled into.
+        let errors = [
+            (
+                "(define-values (p q) (values 1))",
+                "(define-values (p q) ...): expected 2 argument(s), given 1",
+            ),
+            (
+                "(let-values (((a b) (values 1))) a)",
+                "(let-values (((a b) ...)) ...): expected 2 argument(s), given 1",
+            ),
+            (
+                "(let-values ((a (values)) ((b c) (values 1 2 3))) a)",
+                "(let-values (((b c) ...)) ...): expected 2 argument(s), given 3",
+            ),
+            (
+                "(let*-values (((a) (values 1 2))) a)",
+                "(let*-values (((a) ...)) ...): expected 1 argument(s), given 2",
+            ),
+        ];
+        for (input, message) in errors.iter() {
+            match interpreter.eval_str(input) {
+                Err(e @ Error::WrongArgumentCount { .. }) => assert_eq!(e.to_string(), *message),
+                other => panic!("expected an arity error from {}, given {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_deep_recursion() {
+        let mut interpreter = Interpreter::new();
+        // Frames are on the heap, so recursion that is not a tail call does
+        // not run out of Rust stack.
+        assert_eq!(
+            interpreter
+                .eval_str(
+                    "(define (build n) (if (= n 0) '() (cons n (build (- n 1)))))
+                     (define (sum l) (if (null? l) 0 (+ (car l) (sum (cdr l)))))
+                     (sum (build 100000))"
+                )
+                .unwrap()
+                .to_string(),
+            "5000050000"
+        );
+    }
+
+    #[test]
+    fn test_eval_gc() {
+        let mut interpreter = Interpreter::new();
+        // Each call leaves a frame and a closure that refer to each other.
+        interpreter
+            .eval_str(
+                "(define (make) (define (self) self) self)
+                 (define kept (make))",
+            )
+            .unwrap();
+        let made = match interpreter.eval_str("(make)").unwrap() {
+            Value::Procedure(procedure) => Rc::downgrade(&procedure),
+            _ => panic!("expected a procedure"),
+        };
+        assert!(made.upgrade().is_some());
+        let live = gc::stats().live;
+        assert!(gc::collect() > 0);
+        assert!(made.upgrade().is_none());
+        assert!(gc::stats().live < live);
+        assert_eq!(
+            interpreter
+                .eval_str("(eq? (kept) kept)")
+                .unwrap()
+                .to_string(),
+            "#t"
+        );
+
+        // The global frame and the procedures defined in it.
+        drop(interpreter);
+        assert!(gc::collect() >= 3);
+        assert!(gc::stats().live < live);
+    }
+
+    #[test]
+    fn test_eval_init_file() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_file("src/core/init.scm").unwrap();
+        assert_eq!(
+            interpreter.eval_str("(string? hello)").unwrap().to_string(),
+            "#t"
+        );
+    }
+
+    #[test]
+    fn test_eval_parse_error() {
+        let mut interpreter = Interpreter::new();
+        assert!(matches!(interpreter.eval_str("(#t"), Err(Error::Parse(_))));
+        assert!(matches!(interpreter.eval_str("#t )"), Err(Error::Parse(_))));
+        match interpreter.eval_str("(car #t)\n  #(if ])") {
+            Err(Error::Parse(e)) => {
+                assert_eq!(e.source, "<string>");
+                assert_eq!((e.line, e.column), (2, 8));
+                assert_eq!(e.snippet, "  #(if ])");
+                assert_eq!(e.expected, "a datum");
+                assert_eq!(
+                    e.to_string(),
+                    "<string>:2:8: expected a datum\n  #(if ])\n       ^"
+                );
+            }
+            _ => panic!("expected a parse error"),
+        }
+        // A malformed form is read as any other, and the expander rejects
+        // it where it was written.
+        match interpreter.eval_str("(list #t)\n  (if)") {
+            Err(Error::BadSyntax(_)) => {
+                let span = interpreter.error_span().unwrap();
+                assert_eq!((span.line, span.column), (2, 3));
+            }
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+        match interpreter.eval_str("(list #t\n 99999999999999999999)") {
+            Err(Error::Parse(e)) => {
+                assert_eq!((e.line, e.column), (2, 2));
+                assert_eq!(e.expected, "a number that fits in 64 bits");
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_eval_file_parse_error() {
+        let directory = TempDir::new("file-parse-error");
+        let path = directory.path().join("parse-error.scm");
+        std::fs::write(&path, "(define x\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_file(&path) {
+            Err(Error::Parse(e)) => assert_eq!(e.source, path.display().to_string()),
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn test_eval_error_span() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("#t").unwrap();
+        assert!(interpreter.eval_str("(define a #t)\n  (car a)").is_err());
+        let span = interpreter.error_span().unwrap();
+        assert_eq!(interpreter.source_name(span.file).unwrap(), "<string>");
+        assert_eq!((span.start, span.end), (16, 23));
+        assert_eq!((span.line, span.column), (2, 3));
+        // An error in what a macro use expands into is where the use is.
+        assert!(interpreter
+            .eval_str("(define-syntax first (syntax-rules () ((_ x) (car x))))\n  (first a)")
+            .is_err());
+        let span = interpreter.error_span().unwrap();
+        assert_eq!((span.start, span.end), (58, 67));
+        assert_eq!((span.line, span.column), (2, 3));
+        // An error in an operand of a macro use is where the operand is,
+        // whether the expander or the lowering finds it.
+        let tests = [
+            (
+                "(define-syntax twice (syntax-rules () ((_ e) (begin e e))))\n(twice\n  (list 1\n    (lambda)))",
+                "(lambda)",
+            ),
+            ("(twice\n  (list 1\n    (if)))", "(if)"),
+            ("(twice\n  (let ()\n    (let ((x)) x)))", "(let ((x)) x)"),
+        ];
+        for (input, operand) in tests.iter() {
+            assert!(matches!(
+                interpreter.eval_str(input),
+                Err(Error::BadSyntax(_))
+            ));
+            let span = interpreter.error_span().unwrap();
+            assert_eq!(&input[span.start..span.end], *operand);
+            assert_eq!((span.line, span.column), (input.lines().count(), 5));
+        }
+        // Each interpreter numbers its own sources, once per name.
+        for _ in 0..10 {
+            interpreter.eval_str("#t").unwrap();
+        }
+        assert_eq!(span.file, 0);
+        assert_eq!(interpreter.source_name(1), None);
+        assert_eq!(Interpreter::new().source_name(0), None);
+    }
+
+    #[test]
+    fn test_eval_fold_case() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(
+            interpreter
+                .eval_str("#!fold-case (DEFINE (Swap A B) (CONS B A)) (SWAP #\\a #\\SPACE)")
+                .unwrap()
+                .to_string(),
+            "(#\\space . #\\a)"
+        );
+        assert!(matches!(
+            interpreter.eval_str("(SWAP #t #f)"),
+            Err(Error::UnboundVariable(_))
+        ));
+        // Identifiers are folded as Unicode folds them, not as lowercased.
+        assert_eq!(
+            interpreter
+                .eval_str(
+                    "#!fold-case (define ΣΑΣ 1) (define ſ 2) (define \u{212A} 3) (list σασ s k)"
+                )
+                .unwrap()
+                .to_string(),
+            "(1 2 3)"
+        );
+        // Folding İ lengthens it, but spans are still where the text is.
+        let input = "#!fold-case (define İ 1)\n  (car İ)";
+        assert!(interpreter.eval_str(input).is_err());
+        let span = interpreter.error_span().unwrap();
+        assert_eq!(&input[span.start..span.end], "(car İ)");
+        assert_eq!((span.line, span.column), (2, 3));
+        let input = "#!fold-case (define İ #t) (car İ)";
+        assert!(interpreter.eval_str(input).is_err());
+        let span = interpreter.error_span().unwrap();
+        assert_eq!(&input[span.start..span.end], "(car İ)");
+        assert_eq!((span.line, span.column), (1, 27));
+    }
+
+    #[test]
+    fn test_eval_include() {
+        let temp = TempDir::new("include");
+        let directory = temp.path();
+        std::fs::write(
+            directory.join("main.scm"),
+            "(include \"defs.scm\")\n(include-ci \"ci.scm\")",
+        )
+        .unwrap();
+        std::fs::write(directory.join("defs.scm"), "(define big #t)").unwrap();
+        std::fs::write(directory.join("ci.scm"), "(DEFINE SMALL (LIST BIG #\\X))").unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_file(directory.join("main.scm")).unwrap();
+        assert_eq!(
+            interpreter
+                .eval_str("(cons big small)")
+                .unwrap()
+                .to_string(),
+            "(#t #t #\\X)"
+        );
+        assert!(matches!(
+            interpreter.eval_str("(include \"does/not/exist.scm\")"),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_eval_libraries() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str(
+                "(define-library (example counter)
+                   (export next! (rename peek current) count-twice)
+                   (import (scheme base))
+                   (begin
+                     (define count 0)
+                     (define (bump n) (set! count (+ count n)) count)
+                     (define (next!) (bump 1))
+                     (define (peek) count)
+                     (define-syntax count-twice
+                       (syntax-rules () ((_) (begin (bump 1) (bump 1)))))))
+                 (import (prefix (example counter) c:))",
+            )
+            .unwrap();
+        let tests = [
+            ("(c:next!)", "1"),
+            ("(c:count-twice)", "3"),
+            ("(let ((bump #f) (count #f)) (c:count-twice))", "5"),
+            ("(c:current)", "5"),
+            (
+                "(import (rename (only (scheme base) car cdr) (car first)))
+                 (first (cdr '(1 2)))",
+                "2",
+            ),
+            (
+                "(define-library (example private)
+                   (export visible)
+                   (import (only (scheme base) cons))
+                   (begin (define (visible x) (cons x hidden)) (define hidden 'h)))
+                 (import (example private))
+                 (visible 1)",
+                "(1 . h)",
+            ),
+            (
+                "(define-library (example swap)
+                   (export swap!)
+                   (import (only (scheme base) car))
+                   (begin
+                     (define-syntax swap!
+                       (lambda (form)
+                         (syntax-case form ()
+                           ((_ a b) (syntax (let ((tmp a)) (set! a b) (set! b tmp)))))))))
+                 (import (example swap))
+                 (define tmp 1)
+                 (define other 2)
+                 (swap! tmp other)
+                 (list tmp other)",
+                "(2 1)",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(interpreter.eval_str(input).unwrap().to_string(), *output);
+        }
+        let errors = [
+            "(c:peek)",
+            "hidden",
+            "(set! c:current 0)",
+            "(import (only (example counter) peek))",
+            "(import (nowhere at all))",
+            "(define-library (example broken) (export missing)) (import (example broken))",
+            "(define-library (example narrow)
+               (export f)
+               (import (only (scheme base) car))
+               (begin (define (f) (cdr '(1)))))
+             (import (example narrow))
+             (f)",
+        ];
+        for input in errors.iter() {
+            assert!(interpreter.eval_str(input).is_err(), "{}", input);
+        }
+        assert!(matches!(
+            interpreter.eval_str("(import (nowhere at all))"),
+            Err(Error::UnknownLibrary(_))
+        ));
+    }
+
+    #[test]
+    fn test_eval_library_path() {
+        let temp = TempDir::new("library-path");
+        let directory = temp.path();
+        std::fs::create_dir_all(directory.join("example")).unwrap();
+        std::fs::write(
+            directory.join("example").join("shapes.sld"),
+            "(define-library (example shapes)
+               (export square area)
+               (import (scheme base) (example units))
+               (include-library-declarations \"shapes-exports.scm\")
+               (cond-expand
+                 ((and r7rs (library (scheme base))) (include \"shapes.scm\"))
+                 (else (begin (define (area s) 'unsupported)))))",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("example").join("shapes-exports.scm"),
+            "(export (rename shape-kind kind))",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("example").join("shapes.scm"),
+            "(define (square side) (list 'square side))
+             (define (area shape) (unit (* (cadr shape) (cadr shape))))
+             (define shape-kind car)",
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("example").join("units.sld"),
+            "(define-library (example units)
+               (export unit cadr)
+               (import (scheme base))
+               (begin
+                 (define (cadr x) (car (cdr x)))
+                 (define (unit n) (list n 'm2))))",
+        )
+        .unwrap();
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.library_path(), [PathBuf::from(".")]);
+        assert!(matches!(
+            interpreter.eval_str("(import (example shapes))"),
+            Err(Error::UnknownLibrary(_))
+        ));
+        interpreter.add_library_path(directory);
+        assert_eq!(
+            interpreter
+                .eval_str("(import (example shapes)) (list (area (square 3)) (kind (square 3)))")
+                .unwrap()
+                .to_string(),
+            "((9 m2) square)"
+        );
+    }
+
+    #[test]
+    fn test_eval_file_missing() {
+        let mut interpreter = Interpreter::new();
+        assert!(matches!(
+            interpreter.eval_file("does/not/exist.scm"),
+            Err(Error::Io(_))
+        ));
+    }
+}
@@ -3,5 +3,13 @@
 extern crate pest;
 extern crate pest_derive;
 
+mod interpreter;
 mod parser;
 mod r7rs;
+
+pub use interpreter::Interpreter;
+pub use r7rs::ast::{FileId, Span};
+pub use r7rs::error::{Error, ParseError};
+pub use r7rs::gc::{collect as collect_garbage, stats as heap_stats, HeapStats};
+pub use r7rs::reader::{read_all, read_datum};
+pub use r7rs::value::{Number, Record, RecordType, Value};
@@ -1,9 +1,29 @@
+use crate::r7rs::error::ParseError;
+use pest::iterators::{Pair, Pairs};
+use pest::Span;
 use pest_derive::Parser;
 
 #[derive(Parser)]
 #[grammar = "parser/r7rs.pest"]
 pub struct R7RSParser;
 
+/// Take the next child of a pair whose children are `inner`. The grammar
+/// guarantees the children that lowering asks for, but should the two ever
+/// disagree the result is an error pointing at the parent, not a panic.
+pub fn child<'i>(
+    inner: &mut Pairs<'i, Rule>,
+    parent: Span<'i>,
+) -> Result<Pair<'i, Rule>, ParseError> {
+    inner
+        .next()
+        .ok_or_else(|| ParseError::new(parent, "a complete form"))
+}
+
+/// The error for a pair whose rule lowering does not handle at this point.
+pub fn unexpected(pair: &Pair<Rule>, expected: &str) -> ParseError {
+    ParseError::new(pair.as_span(), expected)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -32,10 +52,7 @@ mod tests {
                 let form = parsing.next().unwrap();
                 assert_eq!("(+ 2 2)", form.as_str());
             }
-            Err(e) => {
-                println!("{}", e);
-                assert!(false);
-            }
+            Err(e) => panic!("{}", e),
         }
     }
     #[test]
@@ -47,10 +64,7 @@ mod tests {
                 let form = parsing.next().unwrap();
                 assert_eq!("(+ 2 2)\n(* 3 3)", form.as_str());
             }
-            Err(e) => {
-                println!("{}", e);
-                assert!(false);
-            }
+            Err(e) => panic!("{}", e),
         }
     }
 }
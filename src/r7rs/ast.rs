@@ -1,238 +1,1915 @@
-use crate::parser::Rule;
+use crate::parser::{child, unexpected, R7RSParser, Rule};
+use crate::r7rs::error::{Error, Located, ParseError};
+use crate::r7rs::gc;
+use crate::r7rs::reader;
+use crate::r7rs::value::{escape, Number, Pair, Value};
+use pest::Parser;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
 
+/// Identifies the source text a node was lowered from.
+pub type FileId = usize;
+
+/// The names of the sources an Interpreter has read, indexed by FileId. A
+/// name is registered once however often it is read, so the table grows
+/// with the files an interpreter reads and not with the programs it
+/// evaluates, and it is dropped with the interpreter.
+#[derive(Default)]
+pub struct Sources {
+    names: RefCell<Vec<String>>,
+}
+impl Sources {
+    /// The name a source was registered with.
+    pub fn name(&self, file: FileId) -> Option<String> {
+        self.names.borrow().get(file).cloned()
+    }
+
+    fn register(&self, name: &str) -> FileId {
+        let mut names = self.names.borrow_mut();
+        match names.iter().position(|known| known == name) {
+            Some(file) => file,
+            None => {
+                names.push(name.to_string());
+                names.len() - 1
+            }
+        }
+    }
+}
+
+thread_local! {
+    // The sources of the interpreter evaluating on this thread, if any.
+    static SOURCES: RefCell<Option<Rc<Sources>>> = const { RefCell::new(None) };
+}
+
+/// Run `f` with sources registered in `sources`, as an Interpreter does
+/// while it evaluates. The sources in use before are restored after.
+pub fn with_sources<T>(sources: &Rc<Sources>, f: impl FnOnce() -> T) -> T {
+    struct Restore(Option<Rc<Sources>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            SOURCES.with(|current| *current.borrow_mut() = self.0.take());
+        }
+    }
+    let _restore = Restore(SOURCES.with(|current| current.replace(Some(Rc::clone(sources)))));
+    f()
+}
+
+/// Number a source text by its file path or `<string>`, in the sources of
+/// the interpreter evaluating. Outside one every source is numbered 0.
+pub fn register_source(name: &str) -> FileId {
+    SOURCES.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .map_or(0, |sources| sources.register(name))
+    })
+}
+
+/// The name a source text was registered with.
+pub fn source_name(file: FileId) -> Option<String> {
+    SOURCES.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .and_then(|sources| sources.name(file))
+    })
+}
+
+/// Where a node was written: the source it came from, its byte range within
+/// that source and the line and column (both counted from 1) it starts at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub file: FileId,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A source text being lowered. It records where each line starts, so that
+/// the span of a node can be found without rescanning the text, and where
+/// each `#!fold-case` or `#!no-fold-case` directive is, so that identifiers
+/// and character names are folded where the directives say.
+pub struct Source<'i> {
+    file: FileId,
+    text: &'i str,
+    lines: Vec<usize>,
+    directives: Vec<(usize, bool)>,
+    folding: bool,
+}
+impl<'i> Source<'i> {
+    pub fn new(file: FileId, text: &'i str) -> Source<'i> {
+        Source::with_folding(file, text, false)
+    }
+
+    /// A source text that starts out folded when `folding` is set, as the
+    /// files read by include-ci do.
+    pub fn with_folding(file: FileId, text: &'i str, folding: bool) -> Source<'i> {
+        let lines = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Source {
+            file,
+            text,
+            lines,
+            directives: directives(text, folding),
+            folding,
+        }
+    }
+
+    pub(crate) fn text(&self) -> &'i str {
+        self.text
+    }
+
+    fn span(&self, span: pest::Span) -> Span {
+        self.locate(span.start(), span.end())
+    }
+
+    /// Whether case is folded in the text at a byte offset.
+    pub(crate) fn folds(&self, offset: usize) -> bool {
+        let before = self.directives.partition_point(|&(at, _)| at < offset);
+        match before {
+            0 => self.folding,
+            _ => self.directives[before - 1].1,
+        }
+    }
+
+    /// The name an identifier pair of this source denotes: folded where
+    /// case is, unless written between vertical lines.
+    pub(crate) fn identifier(&self, pair: &pest::iterators::Pair<Rule>) -> String {
+        let text = pair.as_str();
+        if self.folds(pair.as_span().start()) && !text.starts_with('|') {
+            caseless::default_case_fold_str(text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    /// The span of the text between two byte offsets.
+    pub(crate) fn locate(&self, start: usize, end: usize) -> Span {
+        let line = self.lines.partition_point(|&line| line <= start);
+        let column = self.text[self.lines[line - 1]..start].chars().count() + 1;
+        Span {
+            file: self.file,
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+}
+
+// Where each fold-case directive of a text is, and whether it turns folding
+// on. The text is only lexed for them if it may fold.
+fn directives(text: &str, folding: bool) -> Vec<(usize, bool)> {
+    if !folding && !text.contains("#!fold-case") {
+        return Vec::new();
+    }
+    R7RSParser::parse(Rule::directives, text)
+        .into_iter()
+        .flatten()
+        .flat_map(|pair| pair.into_inner())
+        .filter_map(|pair| match pair.as_rule() {
+            Rule::fold_case => Some((pair.as_span().start(), true)),
+            Rule::no_fold_case => Some((pair.as_span().start(), false)),
+            _ => None,
+        })
+        .collect()
+}
+
+thread_local! {
+    // Where the pairs of forms read from source text were written, by the
+    // identity of the pair.
+    static LOCATIONS: RefCell<Locations> = RefCell::new(Locations {
+        pairs: HashMap::new(),
+        prune_at: 1024,
+    });
+}
+
+struct Locations {
+    pairs: HashMap<usize, Location>,
+    // The number of entries there may be before those of freed pairs are
+    // dropped.
+    prune_at: usize,
+}
+
+// Where a pair was written: the list it starts, if it starts one, and the
+// element it holds. The pair is held weakly, so that an entry left by a
+// freed pair is not taken for one of a pair since allocated in its place.
+struct Location {
+    pair: Weak<Pair>,
+    form: Option<Span>,
+    car: Option<Span>,
+}
+impl Location {
+    fn of(pair: &Rc<Pair>) -> Location {
+        Location {
+            pair: Rc::downgrade(pair),
+            form: None,
+            car: None,
+        }
+    }
+
+    fn is_of(&self, pair: &Rc<Pair>) -> bool {
+        self.pair
+            .upgrade()
+            .is_some_and(|located| Rc::ptr_eq(&located, pair))
+    }
+}
+
+/// Note where a pair read from source text was written: the list it
+/// starts, or the element it holds.
+pub(crate) fn locate(pair: &Rc<Pair>, form: Option<Span>, car: Option<Span>) {
+    LOCATIONS.with(|locations| {
+        let mut locations = locations.borrow_mut();
+        if locations.pairs.len() >= locations.prune_at {
+            locations
+                .pairs
+                .retain(|_, location| location.pair.strong_count() > 0);
+            locations.prune_at = (locations.pairs.len() * 2).max(1024);
+        }
+        let location = locations
+            .pairs
+            .entry(gc::identity(pair))
+            .or_insert_with(|| Location::of(pair));
+        if !location.is_of(pair) {
+            *location = Location::of(pair);
+        }
+        location.form = form.or(location.form);
+        location.car = car.or(location.car);
+    })
+}
+
+// Where a pair was written, as far as is known: the list it starts and the
+// element it holds.
+fn located(pair: &Rc<Pair>) -> (Option<Span>, Option<Span>) {
+    LOCATIONS.with(
+        |locations| match locations.borrow().pairs.get(&gc::identity(pair)) {
+            Some(location) if location.is_of(pair) => (location.form, location.car),
+            _ => (None, None),
+        },
+    )
+}
+
+/// Where the list a datum is was written, if it was read from source text
+/// or made from a list that was.
+pub(crate) fn location(datum: &Value) -> Option<Span> {
+    match datum {
+        Value::Pair(pair) => located(pair).0,
+        _ => None,
+    }
+}
+
+/// Give a form made from another the location of the other, unless it has
+/// a location of its own.
+pub(crate) fn relocate(from: &Value, to: &Value) {
+    if let (Some(span), Value::Pair(to)) = (location(from), to) {
+        if location(&Value::Pair(Rc::clone(to))).is_none() {
+            locate(to, Some(span), None);
+        }
+    }
+}
+
+/// Give a list made from another element by element the location of the
+/// other, and each of its elements the location of the one in the same
+/// place, unless they have locations of their own.
+pub(crate) fn relocate_elements(from: &Value, to: &Value) {
+    let (mut from, mut to) = match (from, to) {
+        (Value::Pair(from), Value::Pair(to)) => (Rc::clone(from), Rc::clone(to)),
+        _ => return,
+    };
+    while !Rc::ptr_eq(&from, &to) {
+        relocate_pair(&from, &to);
+        match (from.cdr(), to.cdr()) {
+            (Value::Pair(next), Value::Pair(next_to)) => {
+                from = next;
+                to = next_to;
+            }
+            _ => return,
+        }
+    }
+}
+
+/// Give a pair copied from another what is known of where the other was
+/// written, unless it is known where it was written itself.
+pub(crate) fn relocate_pair(from: &Rc<Pair>, to: &Rc<Pair>) {
+    let (form, car) = located(from);
+    let (own_form, own_car) = located(to);
+    if (form.is_some() && own_form.is_none()) || (car.is_some() && own_car.is_none()) {
+        locate(to, own_form.or(form), own_car.or(car));
+    }
+}
+
+/// A parsed program. Its commands and definitions are kept as where they
+/// were written: each is read and expanded when the program reaches it, as
+/// the macros and variables it may use are only defined by then.
+#[allow(clippy::upper_case_acronyms)]
 pub enum AST {
     Program {
+        libraries: Vec<Library>,
         imports: Vec<Import>,
-        cdefs: Vec<CDef>,
+        forms: Vec<Span>,
+    },
+}
+impl AST {
+    /// Parse and lower a whole program.
+    pub fn parse(source: &Source) -> Result<AST, ParseError> {
+        let text = source.text;
+        let mut pairs = R7RSParser::parse(Rule::program, text)?;
+        AST::from(
+            child(&mut pairs, pest::Span::new(text, 0, text.len()).unwrap())?,
+            source,
+        )
+    }
+
+    pub fn from(program: pest::iterators::Pair<Rule>, source: &Source) -> Result<AST, ParseError> {
+        let mut libraries = Vec::new();
+        let mut imports = Vec::new();
+        let mut forms = Vec::new();
+        for pair in program.into_inner() {
+            match pair.as_rule() {
+                Rule::library => libraries.push(Library::from(pair, source)?),
+                Rule::import_declaration => imports.extend(Import::declaration(pair, source)?),
+                Rule::datum => forms.push(source.span(pair.as_span())),
+                Rule::EOI => (),
+                _ => {
+                    return Err(unexpected(
+                        &pair,
+                        "a library, import, command or definition",
+                    ))
+                }
+            }
+        }
+        Ok(AST::Program {
+            libraries,
+            imports,
+            forms,
+        })
+    }
+}
+
+/// A command or definition of a program, with the span of its source text.
+/// Two CDefs are equal when their forms are, wherever they were written.
+#[derive(Debug, Clone)]
+pub struct CDef {
+    pub kind: CDefKind,
+    pub span: Span,
+}
+impl PartialEq for CDef {
+    fn eq(&self, other: &CDef) -> bool {
+        self.kind == other.kind
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CDefKind {
+    Command(Expression),
+    Definition(Definition),
+    /// A top-level begin whose forms are spliced into the enclosing program.
+    Begin(Vec<CDef>),
+}
+
+/// A definition binds one or more identifiers in the environment it is
+/// evaluated in. The procedure shorthand `(define (f . formals) body)` is
+/// lowered into a variable definition of a lambda.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Definition {
+    Variable {
+        identifier: String,
+        expression: Box<Expression>,
+    },
+    Values {
+        formals: Formals,
+        body: Body,
+    },
+    RecordType {
+        name: String,
+        constructor: Constructor,
+        predicate: String,
+        fields: Vec<FieldSpec>,
+    },
+    Begin(Vec<Definition>),
+}
+
+/// The constructor clause of define-record-type: the constructor name and
+/// the fields it initialises, in argument order.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Constructor {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+/// A field of a record type with its accessor and optional mutator.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FieldSpec {
+    pub name: String,
+    pub accessor: String,
+    pub mutator: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Literal {
+    Number(Number),
+    String(String),
+    Bool(bool),
+    Char(char),
+    Vector(Rc<RefCell<Vec<Value>>>),
+    Bytevector(Vec<u8>),
+    /// A quoted datum, read into the value it denotes when it is lowered.
+    Quotation(Value),
+}
+
+/// An expression with the span of its source text. Two expressions are
+/// equal when they have the same structure, wherever they were written.
+#[derive(Debug, Clone)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+impl PartialEq for Expression {
+    fn eq(&self, other: &Expression) -> bool {
+        self.kind == other.kind
+    }
+}
+// An expression made by the implementation rather than read from source has
+// an empty span.
+impl From<ExpressionKind> for Expression {
+    fn from(kind: ExpressionKind) -> Expression {
+        Expression {
+            kind,
+            span: Span::default(),
+        }
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExpressionKind {
+    Identifier(String),
+    Literal(Literal),
+    ProcedureCall(Operator, Vec<Operand>),
+    Lambda {
+        formals: Formals,
+        body: Rc<Body>,
+    },
+    Conditional {
+        test: Box<Expression>,
+        consequent: Box<Expression>,
+        alternate: Option<Box<Expression>>,
+    },
+    Assignment {
+        identifier: String,
+        expression: Box<Expression>,
+    },
+    DerivedExpression(Derived),
+    Includer {
+        fold_case: bool,
+        files: Vec<String>,
+    },
+}
+
+/// The parameters of a lambda: required identifiers and an optional rest
+/// identifier bound to a list of any remaining arguments.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Formals {
+    pub required: Vec<String>,
+    pub rest: Option<String>,
+}
+
+/// A body is any number of definitions followed by a non-empty sequence of
+/// expressions.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Body {
+    pub definitions: Vec<Definition>,
+    pub sequence: Vec<Expression>,
+}
+
+/// The derived expression types of R7RS section 4.2.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Derived {
+    Cond {
+        clauses: Vec<CondClause>,
+        else_clause: Option<Vec<Expression>>,
+    },
+    Case {
+        key: Box<Expression>,
+        clauses: Vec<CaseClause>,
+        else_clause: Option<ClauseBody>,
+    },
+    And(Vec<Expression>),
+    Or(Vec<Expression>),
+    When {
+        test: Box<Expression>,
+        sequence: Vec<Expression>,
+    },
+    Unless {
+        test: Box<Expression>,
+        sequence: Vec<Expression>,
+    },
+    Let {
+        bindings: Vec<Binding>,
+        body: Body,
+    },
+    NamedLet {
+        name: String,
+        bindings: Vec<Binding>,
+        body: Rc<Body>,
+    },
+    LetStar {
+        bindings: Vec<Binding>,
+        body: Body,
+    },
+    Letrec {
+        bindings: Vec<Binding>,
+        body: Body,
+    },
+    LetrecStar {
+        bindings: Vec<Binding>,
+        body: Body,
+    },
+    LetValues {
+        bindings: Vec<MvBinding>,
+        body: Body,
+    },
+    LetStarValues {
+        bindings: Vec<MvBinding>,
+        body: Body,
     },
+    Begin(Vec<Expression>),
+    Do {
+        specs: Vec<IterationSpec>,
+        test: Box<Expression>,
+        result: Vec<Expression>,
+        commands: Vec<Expression>,
+    },
+    Delay(Box<Expression>),
+    DelayForce(Box<Expression>),
+    Parameterize {
+        bindings: Vec<(Expression, Expression)>,
+        body: Body,
+    },
+    Guard {
+        variable: String,
+        clauses: Vec<CondClause>,
+        else_clause: Option<Vec<Expression>>,
+        body: Body,
+    },
+    Quasiquote(Template),
+    CaseLambda(Vec<CaseLambdaClause>),
+}
+
+/// What a cond or case clause does once it is selected: evaluate a sequence
+/// or pass the tested value to a recipient procedure (`=>`).
+#[derive(Debug, PartialEq, Clone)]
+pub enum ClauseBody {
+    Sequence(Vec<Expression>),
+    Recipient(Box<Expression>),
+}
+
+/// A cond clause; a clause without a body yields the value of its test.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CondClause {
+    pub test: Expression,
+    pub body: Option<ClauseBody>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CaseClause {
+    pub data: Vec<Value>,
+    pub body: ClauseBody,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Binding {
+    pub identifier: String,
+    pub init: Expression,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MvBinding {
+    pub formals: Formals,
+    pub init: Expression,
+}
+
+/// A do loop variable with its initial value and optional step.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IterationSpec {
+    pub identifier: String,
+    pub init: Expression,
+    pub step: Option<Expression>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CaseLambdaClause {
+    pub formals: Formals,
+    pub body: Rc<Body>,
+}
+
+/// A quasiquote template. The parts of it that unquote nothing at the level
+/// of the quasiquote are data; the rest are built when it is evaluated.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Template {
+    Datum(Value),
+    Unquote(Box<Expression>),
+    /// A list of elements and the template of its tail.
+    List(Vec<TemplateElement>, Box<Template>),
+    Vector(Vec<TemplateElement>),
+}
+
+/// An element of a list or vector template: a template, or an expression
+/// whose value, a list, is spliced in.
+#[derive(Debug, PartialEq, Clone)]
+pub enum TemplateElement {
+    Template(Template),
+    Splice(Expression),
+}
+
+impl Template {
+    // A list template, which is a datum if none of its parts unquotes
+    // anything.
+    fn list(elements: Vec<TemplateElement>, tail: Template) -> Template {
+        match (TemplateElement::data(&elements), tail) {
+            (Some(data), Template::Datum(tail)) => Template::Datum(
+                data.into_iter()
+                    .rev()
+                    .fold(tail, |cdr, car| Value::Pair(gc::alloc(Pair::new(car, cdr)))),
+            ),
+            (_, tail) => Template::List(elements, Box::new(tail)),
+        }
+    }
+
+    fn vector(elements: Vec<TemplateElement>) -> Template {
+        match TemplateElement::data(&elements) {
+            Some(data) => Template::Datum(Value::vector(data)),
+            None => Template::Vector(elements),
+        }
+    }
+
+    // `(keyword template)`, as a quote, or a quasiquote or unquotation of a
+    // deeper level, is in a template.
+    fn form(keyword: &str, template: Template) -> Template {
+        Template::list(
+            vec![
+                TemplateElement::Template(Template::Datum(Value::Symbol(keyword.to_string()))),
+                TemplateElement::Template(template),
+            ],
+            Template::Datum(Value::Null),
+        )
+    }
+}
+
+impl TemplateElement {
+    // The data the elements are, if they are all data.
+    fn data(elements: &[TemplateElement]) -> Option<Vec<Value>> {
+        elements
+            .iter()
+            .map(|element| match element {
+                TemplateElement::Template(Template::Datum(datum)) => Some(datum.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// Every command or definition is read as data and expanded, and the forms
+// it expands into are lowered below. A list read from source text, or that the expander
+// made from one, has the span it was written at; any other form takes the
+// span of the nearest form around it that has one, at worst that of the
+// command or definition the forms were expanded from. An error lowering a
+// form is placed at the same span. Values a macro inserts that no source
+// text can write, such as procedures and records, are constants.
+
+impl CDef {
+    /// Lower a form that a command or definition written at `span`
+    /// expanded into.
+    pub fn from_datum(datum: &Value, span: Span) -> Result<CDef, Located> {
+        let span = location(datum).unwrap_or(span);
+        CDef::lower(datum, span).map_err(|e| e.at(span))
+    }
+
+    fn lower(datum: &Value, span: Span) -> Result<CDef, Located> {
+        let kind = match keyword(datum) {
+            Some("begin") => CDefKind::Begin(
+                items(datum)?[1..]
+                    .iter()
+                    .map(|form| CDef::from_datum(form, span))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Some("define" | "define-values" | "define-record-type") => {
+                CDefKind::Definition(Definition::from_datum(datum, span)?)
+            }
+            _ => CDefKind::Command(Expression::from_datum(datum, span)?),
+        };
+        Ok(CDef { kind, span })
+    }
+}
+
+impl Definition {
+    fn from_datum(datum: &Value, span: Span) -> Result<Definition, Located> {
+        let span = location(datum).unwrap_or(span);
+        Definition::lower(datum, span).map_err(|e| e.at(span))
+    }
+
+    fn lower(datum: &Value, span: Span) -> Result<Definition, Located> {
+        match (keyword(datum), items(datum)?.as_slice()) {
+            (Some("define"), [_, name @ Value::Symbol(_), expression]) => {
+                Ok(Definition::Variable {
+                    identifier: identifier(name)?,
+                    expression: Box::new(Expression::from_datum(expression, span)?),
+                })
+            }
+            (Some("define"), [_, Value::Pair(signature), body @ ..]) => Ok(Definition::Variable {
+                identifier: identifier(&signature.car())?,
+                expression: Box::new(Expression {
+                    kind: ExpressionKind::Lambda {
+                        formals: Formals::from_datum(&signature.cdr())?,
+                        body: Rc::new(Body::from_data(body, span)?),
+                    },
+                    span,
+                }),
+            }),
+            (Some("define-values"), [_, formals, expression]) => Ok(Definition::Values {
+                formals: Formals::from_datum(formals)?,
+                body: Body::from_data(std::slice::from_ref(expression), span)?,
+            }),
+            (Some("define-record-type"), [_, name, constructor, predicate, fields @ ..]) => {
+                let constructor = match items(constructor)?.split_first() {
+                    Some((name, fields)) => Constructor {
+                        name: identifier(name)?,
+                        fields: fields.iter().map(identifier).collect::<Result<_, _>>()?,
+                    },
+                    None => return Err(malformed(datum).into()),
+                };
+                Ok(Definition::RecordType {
+                    name: identifier(name)?,
+                    constructor,
+                    predicate: identifier(predicate)?,
+                    fields: fields
+                        .iter()
+                        .map(FieldSpec::from_datum)
+                        .collect::<Result<_, _>>()?,
+                })
+            }
+            (Some("begin"), [_, definitions @ ..]) => Ok(Definition::Begin(
+                definitions
+                    .iter()
+                    .map(|definition| Definition::from_datum(definition, span))
+                    .collect::<Result<_, _>>()?,
+            )),
+            _ => Err(malformed(datum).into()),
+        }
+    }
+}
+
+impl FieldSpec {
+    fn from_datum(datum: &Value) -> Result<FieldSpec, Error> {
+        match items(datum)?.as_slice() {
+            [name, accessor, mutator @ ..] if mutator.len() <= 1 => Ok(FieldSpec {
+                name: identifier(name)?,
+                accessor: identifier(accessor)?,
+                mutator: mutator.first().map(identifier).transpose()?,
+            }),
+            _ => Err(malformed(datum)),
+        }
+    }
+}
+
+impl Expression {
+    fn from_datum(datum: &Value, span: Span) -> Result<Expression, Located> {
+        let span = location(datum).unwrap_or(span);
+        Expression::lower(datum, span).map_err(|e| e.at(span))
+    }
+
+    fn lower(datum: &Value, span: Span) -> Result<Expression, Located> {
+        let kind = match datum {
+            Value::Symbol(_) => ExpressionKind::Identifier(identifier(datum)?),
+            Value::Pair(_) => match keyword(datum) {
+                Some(keyword) => ExpressionKind::from_datum(keyword, datum, span)?,
+                None => {
+                    let elements = elements(datum, span)?;
+                    let (operator, operands) = &elements.split_first().expect("a pair");
+                    ExpressionKind::ProcedureCall(
+                        Operator(Box::new(Expression::from_datum(&operator.0, operator.1)?)),
+                        operands
+                            .iter()
+                            .map(|(operand, span)| {
+                                Ok(Operand(Box::new(Expression::from_datum(operand, *span)?)))
+                            })
+                            .collect::<Result<_, Located>>()?,
+                    )
+                }
+            },
+            Value::Null => return Err(malformed(datum).into()),
+            Value::Number(n) => ExpressionKind::Literal(Literal::Number(n.clone())),
+            Value::String(s) => ExpressionKind::Literal(Literal::String(s.borrow().clone())),
+            Value::Boolean(b) => ExpressionKind::Literal(Literal::Bool(*b)),
+            Value::Character(c) => ExpressionKind::Literal(Literal::Char(*c)),
+            Value::Vector(elements) => {
+                ExpressionKind::Literal(Literal::Vector(Rc::clone(elements)))
+            }
+            Value::Bytevector(bytes) => {
+                ExpressionKind::Literal(Literal::Bytevector(bytes.borrow().clone()))
+            }
+            other => ExpressionKind::Literal(Literal::Quotation(other.clone())),
+        };
+        Ok(Expression { kind, span })
+    }
+
+    fn from_data(data: &[Value], span: Span) -> Result<Vec<Expression>, Located> {
+        data.iter()
+            .map(|datum| Expression::from_datum(datum, span))
+            .collect()
+    }
+}
+
+// The keywords of the forms an expansion may hold. They always mean these
+// forms: the expander writes no variable under one of these names at the
+// head of a list.
+const KEYWORDS: &[&str] = &[
+    "define",
+    "define-values",
+    "define-record-type",
+    "quote",
+    "quasiquote",
+    "lambda",
+    "set!",
+    "if",
+    "let",
+    "let*",
+    "letrec",
+    "letrec*",
+    "let-values",
+    "let*-values",
+    "do",
+    "case-lambda",
+    "guard",
+    "parameterize",
+    "cond",
+    "case",
+    "and",
+    "or",
+    "when",
+    "unless",
+    "begin",
+    "delay",
+    "delay-force",
+    "include",
+    "include-ci",
+];
+
+impl ExpressionKind {
+    fn from_datum(keyword: &str, datum: &Value, span: Span) -> Result<ExpressionKind, Located> {
+        let expression = |datum: &Value| Expression::from_datum(datum, span).map(Box::new);
+        let derived = match (keyword, items(datum)?.as_slice()) {
+            ("quote", [_, datum]) => {
+                return Ok(ExpressionKind::Literal(Literal::Quotation(datum.clone())))
+            }
+            ("quasiquote", [_, template]) => {
+                Derived::Quasiquote(Template::from_datum(template, 1, span)?)
+            }
+            ("lambda", [_, formals, body @ ..]) => {
+                return Ok(ExpressionKind::Lambda {
+                    formals: Formals::from_datum(formals)?,
+                    body: Rc::new(Body::from_data(body, span)?),
+                })
+            }
+            ("set!", [_, variable, value]) => {
+                return Ok(ExpressionKind::Assignment {
+                    identifier: identifier(variable)?,
+                    expression: expression(value)?,
+                })
+            }
+            ("if", [_, test, consequent, alternate @ ..]) if alternate.len() <= 1 => {
+                return Ok(ExpressionKind::Conditional {
+                    test: expression(test)?,
+                    consequent: expression(consequent)?,
+                    alternate: alternate.first().map(expression).transpose()?,
+                })
+            }
+            ("let", [_, name @ Value::Symbol(_), bindings, body @ ..]) => Derived::NamedLet {
+                name: identifier(name)?,
+                bindings: Binding::from_data(bindings, span)?,
+                body: Rc::new(Body::from_data(body, span)?),
+            },
+            ("let" | "let*" | "letrec" | "letrec*", [_, bindings, body @ ..]) => {
+                let bindings = Binding::from_data(bindings, span)?;
+                let body = Body::from_data(body, span)?;
+                match keyword {
+                    "let" => Derived::Let { bindings, body },
+                    "let*" => Derived::LetStar { bindings, body },
+                    "letrec" => Derived::Letrec { bindings, body },
+                    _ => Derived::LetrecStar { bindings, body },
+                }
+            }
+            ("let-values" | "let*-values", [_, bindings, body @ ..]) => {
+                let bindings = items(bindings)?
+                    .iter()
+                    .map(|binding| match items(binding)?.as_slice() {
+                        [formals, init] => Ok(MvBinding {
+                            formals: Formals::from_datum(formals)?,
+                            init: Expression::from_datum(init, span)?,
+                        }),
+                        _ => Err(malformed(binding).into()),
+                    })
+                    .collect::<Result<_, Located>>()?;
+                let body = Body::from_data(body, span)?;
+                match keyword {
+                    "let-values" => Derived::LetValues { bindings, body },
+                    _ => Derived::LetStarValues { bindings, body },
+                }
+            }
+            ("do", [_, specs, exit, commands @ ..]) => {
+                let specs = items(specs)?
+                    .iter()
+                    .map(|spec| match items(spec)?.as_slice() {
+                        [variable, init, step @ ..] if step.len() <= 1 => Ok(IterationSpec {
+                            identifier: identifier(variable)?,
+                            init: Expression::from_datum(init, span)?,
+                            step: step
+                                .first()
+                                .map(|step| Expression::from_datum(step, span))
+                                .transpose()?,
+                        }),
+                        _ => Err(malformed(spec).into()),
+                    })
+                    .collect::<Result<_, Located>>()?;
+                match items(exit)?.split_first() {
+                    Some((test, result)) => Derived::Do {
+                        specs,
+                        test: expression(test)?,
+                        result: Expression::from_data(result, span)?,
+                        commands: Expression::from_data(commands, span)?,
+                    },
+                    None => return Err(malformed(datum).into()),
+                }
+            }
+            ("case-lambda", [_, clauses @ ..]) => Derived::CaseLambda(
+                clauses
+                    .iter()
+                    .map(|clause| match items(clause)?.split_first() {
+                        Some((formals, body)) => Ok(CaseLambdaClause {
+                            formals: Formals::from_datum(formals)?,
+                            body: Rc::new(Body::from_data(body, span)?),
+                        }),
+                        None => Err(malformed(clause).into()),
+                    })
+                    .collect::<Result<_, Located>>()?,
+            ),
+            ("guard", [_, Value::Pair(spec), body @ ..]) => {
+                let (clauses, else_clause) = CondClause::from_data(&items(&spec.cdr())?, span)?;
+                Derived::Guard {
+                    variable: identifier(&spec.car())?,
+                    clauses,
+                    else_clause,
+                    body: Body::from_data(body, span)?,
+                }
+            }
+            ("parameterize", [_, bindings, body @ ..]) => Derived::Parameterize {
+                bindings: items(bindings)?
+                    .iter()
+                    .map(|binding| match items(binding)?.as_slice() {
+                        [parameter, value] => Ok((
+                            Expression::from_datum(parameter, span)?,
+                            Expression::from_datum(value, span)?,
+                        )),
+                        _ => Err(malformed(binding).into()),
+                    })
+                    .collect::<Result<_, Located>>()?,
+                body: Body::from_data(body, span)?,
+            },
+            ("cond", [_, clauses @ ..]) => {
+                let (clauses, else_clause) = CondClause::from_data(clauses, span)?;
+                Derived::Cond {
+                    clauses,
+                    else_clause,
+                }
+            }
+            ("case", [_, key, clauses @ ..]) => {
+                let mut cases = Vec::new();
+                let mut else_clause = None;
+                for clause in clauses {
+                    match items(clause)?.split_first() {
+                        Some((Value::Symbol(symbol), rest)) if symbol == "else" => {
+                            else_clause = Some(ClauseBody::from_data(rest, span)?)
+                        }
+                        Some((data, rest)) => cases.push(CaseClause {
+                            data: items(data)?,
+                            body: ClauseBody::from_data(rest, span)?,
+                        }),
+                        None => return Err(malformed(clause).into()),
+                    }
+                }
+                Derived::Case {
+                    key: expression(key)?,
+                    clauses: cases,
+                    else_clause,
+                }
+            }
+            ("and", [_, tests @ ..]) => Derived::And(Expression::from_data(tests, span)?),
+            ("or", [_, tests @ ..]) => Derived::Or(Expression::from_data(tests, span)?),
+            ("when" | "unless", [_, test, sequence @ ..]) => {
+                let test = expression(test)?;
+                let sequence = Expression::from_data(sequence, span)?;
+                match keyword {
+                    "when" => Derived::When { test, sequence },
+                    _ => Derived::Unless { test, sequence },
+                }
+            }
+            ("begin", [_, sequence @ ..]) => Derived::Begin(Expression::from_data(sequence, span)?),
+            ("delay", [_, promised]) => Derived::Delay(expression(promised)?),
+            ("delay-force", [_, promised]) => Derived::DelayForce(expression(promised)?),
+            ("include" | "include-ci", [_, files @ ..]) => {
+                return Ok(ExpressionKind::Includer {
+                    fold_case: keyword == "include-ci",
+                    files: files
+                        .iter()
+                        .map(|file| match file {
+                            Value::String(file) => Ok(file.borrow().clone()),
+                            other => Err(malformed(other)),
+                        })
+                        .collect::<Result<_, _>>()?,
+                })
+            }
+            _ => return Err(malformed(datum).into()),
+        };
+        Ok(ExpressionKind::DerivedExpression(derived))
+    }
+}
+
+impl Formals {
+    fn from_datum(datum: &Value) -> Result<Formals, Error> {
+        let mut required = Vec::new();
+        let mut tail = datum.clone();
+        while let Value::Pair(pair) = tail {
+            required.push(identifier(&pair.car())?);
+            tail = pair.cdr();
+        }
+        let rest = match tail {
+            Value::Null => None,
+            rest => Some(identifier(&rest)?),
+        };
+        Ok(Formals { required, rest })
+    }
+}
+
+impl Body {
+    fn from_data(data: &[Value], span: Span) -> Result<Body, Located> {
+        let definitions = data
+            .iter()
+            .take_while(|datum| is_definition(datum))
+            .map(|datum| Definition::from_datum(datum, span))
+            .collect::<Result<Vec<_>, _>>()?;
+        let sequence = &data[definitions.len()..];
+        if let Some(definition) = sequence.iter().find(|datum| is_definition(datum)) {
+            return Err(Error::BadSyntax(format!(
+                "definition after the expressions of a body: {}",
+                definition
+            ))
+            .into());
+        }
+        if sequence.is_empty() {
+            return Err(Error::BadSyntax("a body needs an expression".to_string()).into());
+        }
+        Ok(Body {
+            definitions,
+            sequence: Expression::from_data(sequence, span)?,
+        })
+    }
+}
+
+impl CondClause {
+    // The clauses of cond or guard, and the else clause if there is one.
+    #[allow(clippy::type_complexity)]
+    fn from_data(
+        clauses: &[Value],
+        span: Span,
+    ) -> Result<(Vec<CondClause>, Option<Vec<Expression>>), Located> {
+        let mut conditions = Vec::new();
+        let mut else_clause = None;
+        for clause in clauses {
+            match items(clause)?.split_first() {
+                Some((Value::Symbol(symbol), rest)) if symbol == "else" => {
+                    else_clause = Some(Expression::from_data(rest, span)?)
+                }
+                Some((test, [])) => conditions.push(CondClause {
+                    test: Expression::from_datum(test, span)?,
+                    body: None,
+                }),
+                Some((test, rest)) => conditions.push(CondClause {
+                    test: Expression::from_datum(test, span)?,
+                    body: Some(ClauseBody::from_data(rest, span)?),
+                }),
+                None => return Err(malformed(clause).into()),
+            }
+        }
+        Ok((conditions, else_clause))
+    }
+}
+
+impl ClauseBody {
+    fn from_data(data: &[Value], span: Span) -> Result<ClauseBody, Located> {
+        match data {
+            [Value::Symbol(arrow), recipient] if arrow == "=>" => Ok(ClauseBody::Recipient(
+                Box::new(Expression::from_datum(recipient, span)?),
+            )),
+            _ => Ok(ClauseBody::Sequence(Expression::from_data(data, span)?)),
+        }
+    }
+}
+
+impl Binding {
+    fn from_data(bindings: &Value, span: Span) -> Result<Vec<Binding>, Located> {
+        items(bindings)?
+            .iter()
+            .map(|binding| match items(binding)?.as_slice() {
+                [variable, init] => Ok(Binding {
+                    identifier: identifier(variable)?,
+                    init: Expression::from_datum(init, span)?,
+                }),
+                _ => Err(malformed(binding).into()),
+            })
+            .collect()
+    }
+}
+
+impl Template {
+    // A template of a quasiquote nested `depth` deep: unquotations of depth
+    // 1 are evaluated, and those deeper are data.
+    fn from_datum(datum: &Value, depth: usize, span: Span) -> Result<Template, Located> {
+        match (unquotation(datum), datum) {
+            (Some(("unquote", operand)), _) if depth == 1 => Ok(Template::Unquote(Box::new(
+                Expression::from_datum(&operand, span)?,
+            ))),
+            (Some(("unquote-splicing", _)), _) if depth == 1 => Err(malformed(datum).into()),
+            (Some(("quasiquote", operand)), _) => Ok(Template::form(
+                "quasiquote",
+                Template::from_datum(&operand, depth + 1, span)?,
+            )),
+            (Some((keyword, operand)), _) => Ok(Template::form(
+                keyword,
+                Template::from_datum(&operand, depth - 1, span)?,
+            )),
+            (None, Value::Pair(_)) => {
+                // `(a unquote b)` is `(a . ,b)`.
+                let mut elements = Vec::new();
+                let mut tail = datum.clone();
+                while let Value::Pair(pair) = &tail {
+                    if !elements.is_empty() && unquotation(&tail).is_some() {
+                        break;
+                    }
+                    elements.push(TemplateElement::from_datum(&pair.car(), depth, span)?);
+                    tail = pair.cdr();
+                }
+                Ok(Template::list(
+                    elements,
+                    Template::from_datum(&tail, depth, span)?,
+                ))
+            }
+            (None, Value::Vector(vector)) => Ok(Template::vector(
+                vector
+                    .borrow()
+                    .iter()
+                    .map(|item| TemplateElement::from_datum(item, depth, span))
+                    .collect::<Result<_, _>>()?,
+            )),
+            (None, other) => Ok(Template::Datum(other.clone())),
+        }
+    }
+}
+
+impl TemplateElement {
+    fn from_datum(datum: &Value, depth: usize, span: Span) -> Result<TemplateElement, Located> {
+        match unquotation(datum) {
+            Some(("unquote-splicing", operand)) if depth == 1 => Ok(TemplateElement::Splice(
+                Expression::from_datum(&operand, span)?,
+            )),
+            _ => Ok(TemplateElement::Template(Template::from_datum(
+                datum, depth, span,
+            )?)),
+        }
+    }
+}
+
+// The keyword of a form, if it is one of the forms above.
+fn keyword(datum: &Value) -> Option<&'static str> {
+    match datum {
+        Value::Pair(pair) => match pair.car() {
+            Value::Symbol(symbol) => KEYWORDS.iter().find(|keyword| **keyword == symbol).copied(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The elements of a proper list, which a form that is circular is not.
+fn items(datum: &Value) -> Result<Vec<Value>, Error> {
+    let mut items = Vec::new();
+    let mut visited = HashSet::new();
+    let mut tail = datum.clone();
+    while let Value::Pair(pair) = tail {
+        if !visited.insert(gc::identity(&pair)) {
+            return Err(malformed(datum));
+        }
+        items.push(pair.car());
+        tail = pair.cdr();
+    }
+    match tail {
+        Value::Null => Ok(items),
+        _ => Err(malformed(datum)),
+    }
+}
+
+// The elements of a proper list, each with the span it was written at if
+// that is known and `span` otherwise.
+fn elements(datum: &Value, span: Span) -> Result<Vec<(Value, Span)>, Error> {
+    let mut elements = Vec::new();
+    let mut visited = HashSet::new();
+    let mut tail = datum.clone();
+    while let Value::Pair(pair) = tail {
+        if !visited.insert(gc::identity(&pair)) {
+            return Err(malformed(datum));
+        }
+        elements.push((pair.car(), located(&pair).1.unwrap_or(span)));
+        tail = pair.cdr();
+    }
+    match tail {
+        Value::Null => Ok(elements),
+        _ => Err(malformed(datum)),
+    }
+}
+
+// The quasiquote, unquote or unquote-splicing form `datum` is, with its
+// operand.
+fn unquotation(datum: &Value) -> Option<(&'static str, Value)> {
+    let keyword = ["quasiquote", "unquote", "unquote-splicing"]
+        .into_iter()
+        .find(|keyword| matches!(datum, Value::Pair(pair) if pair.car() == Value::Symbol(keyword.to_string())))?;
+    match items(datum).ok()?.as_slice() {
+        [_, operand] => Some((keyword, operand.clone())),
+        _ => None,
+    }
+}
+
+fn is_definition(datum: &Value) -> bool {
+    matches!(
+        keyword(datum),
+        Some("define" | "define-values" | "define-record-type")
+    )
+}
+
+// An identifier as the AST holds it: as it is written.
+fn identifier(datum: &Value) -> Result<String, Error> {
+    match datum {
+        Value::Symbol(_) => Ok(datum.to_string()),
+        other => Err(Error::BadSyntax(format!(
+            "expected an identifier, given {}",
+            other
+        ))),
+    }
 }
-impl AST {
-    fn from(program: pest::iterators::Pair<Rule>) -> AST {
-        let mut imports = Vec::new();
-        let mut cdefs = Vec::new();
-        for pair in program.into_inner() {
-            match pair.as_rule() {
-                // Rule::import_declaration => {
-                //     imports.push(Import::from(pair));
-                // }
-                Rule::command_or_definition => {
-                    cdefs.push(CDef::from(pair));
+
+fn malformed(datum: &Value) -> Error {
+    Error::BadSyntax(format!("malformed form {}", datum))
+}
+
+// The Display impls below write the AST back out as Scheme source. They are
+// used when printing procedures and in error messages.
+
+fn join<T: std::fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl std::fmt::Display for CDef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl std::fmt::Display for CDefKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CDefKind::Command(expression) => write!(f, "{}", expression),
+            CDefKind::Definition(definition) => write!(f, "{}", definition),
+            CDefKind::Begin(cdefs) => write!(f, "(begin {})", join(cdefs)),
+        }
+    }
+}
+
+impl std::fmt::Display for Definition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Definition::Variable {
+                identifier,
+                expression,
+            } => write!(f, "(define {} {})", identifier, expression),
+            Definition::Values { formals, body } => {
+                write!(f, "(define-values {} {})", formals, body)
+            }
+            Definition::RecordType {
+                name,
+                constructor,
+                predicate,
+                fields,
+            } => {
+                write!(
+                    f,
+                    "(define-record-type {} {} {}",
+                    name, constructor, predicate
+                )?;
+                for field in fields.iter() {
+                    write!(f, " {}", field)?;
                 }
-                _ => unreachable!(),
+                write!(f, ")")
             }
+            Definition::Begin(definitions) => write!(f, "(begin {})", join(definitions)),
         }
-        AST::Program { imports, cdefs }
     }
 }
 
-pub enum CDef {
-    Command(Expression),
-    Definition,
+impl std::fmt::Display for Constructor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "({}", self.name)?;
+        for field in self.fields.iter() {
+            write!(f, " {}", field)?;
+        }
+        write!(f, ")")
+    }
 }
-impl CDef {
-    fn from(pair: pest::iterators::Pair<Rule>) -> CDef {
-        match pair.as_rule() {
-            Rule::command_or_definition => {
-                let mut inner = pair.into_inner();
-                let command_or_definition = inner.next().unwrap();
-                match command_or_definition.as_rule() {
-                    Rule::command => {
-                        let mut inner = command_or_definition.into_inner();
-                        let expression = inner.next().unwrap();
-                        CDef::Command(Expression::from(expression))
-                    }
-                    Rule::definition => CDef::Definition,
-                    _ => unreachable!(),
+
+impl std::fmt::Display for FieldSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.mutator {
+            Some(mutator) => write!(f, "({} {} {})", self.name, self.accessor, mutator),
+            None => write!(f, "({} {})", self.name, self.accessor),
+        }
+    }
+}
+
+impl std::fmt::Display for Literal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Literal::Number(n) => write!(f, "{}", n),
+            Literal::String(s) => write!(f, "\"{}\"", escape(s)),
+            Literal::Bool(b) => write!(f, "{}", if *b { "#t" } else { "#f" }),
+            Literal::Char(c) => write!(f, "#\\{}", c),
+            Literal::Vector(elements) => write!(f, "{}", Value::Vector(Rc::clone(elements))),
+            Literal::Bytevector(bytes) => write!(f, "{}", Value::bytevector(bytes.clone())),
+            Literal::Quotation(datum) => write!(f, "(quote {})", datum),
+        }
+    }
+}
+
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl std::fmt::Display for ExpressionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExpressionKind::Identifier(name) => write!(f, "{}", name),
+            ExpressionKind::Literal(literal) => write!(f, "{}", literal),
+            ExpressionKind::ProcedureCall(operator, operands) => {
+                write!(f, "({}", operator.0)?;
+                for operand in operands.iter() {
+                    write!(f, " {}", operand.0)?;
                 }
+                write!(f, ")")
+            }
+            ExpressionKind::Lambda { formals, body } => write!(f, "(lambda {} {})", formals, body),
+            ExpressionKind::Conditional {
+                test,
+                consequent,
+                alternate,
+            } => match alternate {
+                Some(alternate) => write!(f, "(if {} {} {})", test, consequent, alternate),
+                None => write!(f, "(if {} {})", test, consequent),
+            },
+            ExpressionKind::Assignment {
+                identifier,
+                expression,
+            } => write!(f, "(set! {} {})", identifier, expression),
+            ExpressionKind::DerivedExpression(derived) => write!(f, "{}", derived),
+            ExpressionKind::Includer { fold_case, files } => {
+                write!(f, "({}", if *fold_case { "include-ci" } else { "include" })?;
+                for file in files.iter() {
+                    write!(f, " {:?}", file)?;
+                }
+                write!(f, ")")
             }
-            _ => unreachable!(),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum Literal {
-    String(String),
-    Bool(bool),
-    Char(char),
+impl std::fmt::Display for Formals {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.rest, self.required.is_empty()) {
+            (Some(rest), true) => write!(f, "{}", rest),
+            (Some(rest), false) => write!(f, "({} . {})", self.required.join(" "), rest),
+            (None, _) => write!(f, "({})", self.required.join(" ")),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum Expression {
-    Identifier(String),
-    Literal(Literal),
-    ProcedureCall(Operator, Vec<Operand>),
-    Lambda,
-    Conditional,
-    Assignment,
-    DerivedExpression,
-    MacroUse,
-    MacroBlock,
-    Includer,
+impl std::fmt::Display for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for definition in self.definitions.iter() {
+            write!(f, "{} ", definition)?;
+        }
+        write!(f, "{}", join(&self.sequence))
+    }
 }
-impl Expression {
-    // Given a pest Pair, return an Expression or Error.
-    fn from(pair: pest::iterators::Pair<Rule>) -> Expression {
-        match pair.as_rule() {
-            Rule::expression => {
-                let mut inner = pair.into_inner();
-                let expression = inner.next().unwrap();
-                dbg!(&expression);
-                match expression.as_rule() {
-                    Rule::identifier => {
-                        Expression::Identifier(expression.as_span().as_str().to_string())
-                    }
-                    Rule::literal => {
-                        // match the types of literals
-                        let literal = expression.into_inner().next().unwrap();
-                        match literal.as_rule() {
-                            Rule::string => Expression::Literal(Literal::String(
-                                literal.as_span().as_str().to_string(),
-                            )),
-                            Rule::boolean => Expression::Literal(Literal::Bool(
-                                literal.as_span().as_str().to_string() == "#t",
-                            )),
-                            // handle character, which has three cases:
-                            // any_character, named_character, hex_character
-                            Rule::character => {
-                                let mut inner = literal.into_inner();
-                                let character = inner.next().unwrap();
-                                // Each character literal has several styles
-                                // any_character prefixed with a #\, e.g. #\a; a named_character, e.g. #\newline; a hex_character code, e.g. #\x0A; a character name, e.g. #\NUL.
-                                match character.as_rule() {
-                                    // strip the #\ prefix from the any_character
-                                    Rule::any_character => {
-                                        dbg!(&character.as_span().as_str().chars().nth(2).unwrap());
-                                        Expression::Literal(Literal::Char(
-                                            character.as_span().as_str().chars().nth(2).unwrap(),
-                                        ))
-                                    }
-                                    Rule::named_character => {
-                                        let mut inner = character.into_inner();
-                                        let named_character = inner.next().unwrap();
-                                        // named characters are defined in the R7RS standard as follows:
-                                        // "alarm" | "backspace" | "delete" | "escape" | "newline" | "null" | "return" | "space" | "tab"
-                                        // So the names have to be translated from #\escape to #\x1B etc, they're not pest Rules but strings
-                                        // to be translated.
-                                        match named_character.as_str() {
-                                            "alarm" => Expression::Literal(Literal::Char('\x07')),
-                                            "backspace" => {
-                                                Expression::Literal(Literal::Char('\x08'))
-                                            }
-                                            "delete" => Expression::Literal(Literal::Char('\x7F')),
-                                            "escape" => Expression::Literal(Literal::Char('\x1B')),
-                                            "newline" => Expression::Literal(Literal::Char('\x0A')),
-                                            "null" => Expression::Literal(Literal::Char('\x00')),
-                                            "return" => Expression::Literal(Literal::Char('\x0D')),
-                                            "space" => Expression::Literal(Literal::Char('\x20')),
-                                            "tab" => Expression::Literal(Literal::Char('\x09')),
-                                            _ => unreachable!(),
-                                        }
-                                    }
-                                    Rule::hex_character => {
-                                        let mut inner = character.into_inner();
-                                        let hex_character = inner.next().unwrap();
-                                        Expression::Literal(Literal::Char(
-                                            char::from_u32(
-                                                u32::from_str_radix(hex_character.as_str(), 16)
-                                                    .unwrap(),
-                                            )
-                                            .unwrap(),
-                                        ))
-                                    }
-                                    _ => unreachable!(),
-                                }
-                            }
-                            /*
-                            Rule::character => {
-                                let mut inner = literal.into_inner();
-                                let character = inner.next().unwrap();
-                                // Each character literal has several styles
-                                // any_character prefixed with a #\, e.g. #\a; a named_character, e.g. #\newline; a hex_character code, e.g. #\x0A; a character name, e.g. #\NUL.
-                                match character.as_rule() {
-                                    Rule::any_character => {
-                                        Expression::Literal(Literal::Char(
-                                            character.as_span().as_str().chars().next().unwrap(),
-                                        ))
-                                    }
-                                    Rule::named_character => {
-                                        let mut inner = character.into_inner();
-                                        let named_character = inner.next().unwrap();
-                                        // named characters are defined in the R7RS standard as follows:
-                                        // "alarm" | "backspace" | "delete" | "escape" | "newline" | "null" | "return" | "space" | "tab"
-                                        // So the names have to be translated from #\escape to #\x1B etc, they're not pest Rules but strings
-                                        // to be translated.
-                                        match named_character.as_str() {
-                                            "alarm" => Expression::Literal(Literal::Char('\x07')),
-                                            "backspace" => Expression::Literal(Literal::Char('\x08')),
-                                            "delete" => Expression::Literal(Literal::Char('\x7F')),
-                                            "escape" => Expression::Literal(Literal::Char('\x1B')),
-                                            "newline" => Expression::Literal(Literal::Char('\x0A')),
-                                            "null" => Expression::Literal(Literal::Char('\x00')),
-                                            "return" => Expression::Literal(Literal::Char('\x0D')),
-                                            "space" => Expression::Literal(Literal::Char('\x20')),
-                                            "tab" => Expression::Literal(Literal::Char('\x09')),
-                                            _ => unreachable!(),
-                                        }
-
-                            }
-                            */
-                            _ => unreachable!(),
-                        }
-                    }
-                    Rule::procedure_call => {
-                        let mut inner = expression.into_inner();
-                        let operator = inner.next().unwrap();
-                        let operands = inner.collect::<Vec<_>>();
-                        Expression::ProcedureCall(
-                            Operator(Box::new(Expression::from(operator))),
-                            operands
-                                .iter()
-                                .map(|operand| Operand(Box::new(Expression::from(operand.clone()))))
-                                .collect(),
-                        )
+
+impl std::fmt::Display for ClauseBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClauseBody::Sequence(sequence) => write!(f, "{}", join(sequence)),
+            ClauseBody::Recipient(recipient) => write!(f, "=> {}", recipient),
+        }
+    }
+}
+
+impl std::fmt::Display for CondClause {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.body {
+            Some(body) => write!(f, "({} {})", self.test, body),
+            None => write!(f, "({})", self.test),
+        }
+    }
+}
+
+impl std::fmt::Display for CaseClause {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "(({}) {})", join(&self.data), self.body)
+    }
+}
+
+impl std::fmt::Display for Binding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "({} {})", self.identifier, self.init)
+    }
+}
+
+impl std::fmt::Display for MvBinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "({} {})", self.formals, self.init)
+    }
+}
+
+impl std::fmt::Display for IterationSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.step {
+            Some(step) => write!(f, "({} {} {})", self.identifier, self.init, step),
+            None => write!(f, "({} {})", self.identifier, self.init),
+        }
+    }
+}
+
+impl std::fmt::Display for CaseLambdaClause {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "({} {})", self.formals, self.body)
+    }
+}
+
+impl std::fmt::Display for Template {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Template::Datum(datum) => write!(f, "{}", datum),
+            Template::Unquote(expression) => write!(f, "(unquote {})", expression),
+            Template::List(elements, tail) => {
+                write!(f, "({}", join(elements))?;
+                if **tail != Template::Datum(Value::Null) {
+                    write!(f, " . {}", tail)?;
+                }
+                write!(f, ")")
+            }
+            Template::Vector(elements) => write!(f, "#({})", join(elements)),
+        }
+    }
+}
+
+impl std::fmt::Display for TemplateElement {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TemplateElement::Template(template) => write!(f, "{}", template),
+            TemplateElement::Splice(expression) => write!(f, "(unquote-splicing {})", expression),
+        }
+    }
+}
+
+impl std::fmt::Display for Derived {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Derived::Cond {
+                clauses,
+                else_clause,
+            } => {
+                write!(f, "(cond {}", join(clauses))?;
+                if let Some(sequence) = else_clause {
+                    write!(f, " (else {})", join(sequence))?;
+                }
+                write!(f, ")")
+            }
+            Derived::Case {
+                key,
+                clauses,
+                else_clause,
+            } => {
+                write!(f, "(case {} {}", key, join(clauses))?;
+                if let Some(body) = else_clause {
+                    write!(f, " (else {})", body)?;
+                }
+                write!(f, ")")
+            }
+            Derived::And(tests) => write!(f, "(and {})", join(tests)),
+            Derived::Or(tests) => write!(f, "(or {})", join(tests)),
+            Derived::When { test, sequence } => write!(f, "(when {} {})", test, join(sequence)),
+            Derived::Unless { test, sequence } => {
+                write!(f, "(unless {} {})", test, join(sequence))
+            }
+            Derived::Let { bindings, body } => write!(f, "(let ({}) {})", join(bindings), body),
+            Derived::NamedLet {
+                name,
+                bindings,
+                body,
+            } => write!(f, "(let {} ({}) {})", name, join(bindings), body),
+            Derived::LetStar { bindings, body } => {
+                write!(f, "(let* ({}) {})", join(bindings), body)
+            }
+            Derived::Letrec { bindings, body } => {
+                write!(f, "(letrec ({}) {})", join(bindings), body)
+            }
+            Derived::LetrecStar { bindings, body } => {
+                write!(f, "(letrec* ({}) {})", join(bindings), body)
+            }
+            Derived::LetValues { bindings, body } => {
+                write!(f, "(let-values ({}) {})", join(bindings), body)
+            }
+            Derived::LetStarValues { bindings, body } => {
+                write!(f, "(let*-values ({}) {})", join(bindings), body)
+            }
+            Derived::Begin(sequence) => write!(f, "(begin {})", join(sequence)),
+            Derived::Do {
+                specs,
+                test,
+                result,
+                commands,
+            } => {
+                write!(f, "(do ({}) ({}", join(specs), test)?;
+                for expression in result.iter() {
+                    write!(f, " {}", expression)?;
+                }
+                write!(f, ")")?;
+                for command in commands.iter() {
+                    write!(f, " {}", command)?;
+                }
+                write!(f, ")")
+            }
+            Derived::Delay(expression) => write!(f, "(delay {})", expression),
+            Derived::DelayForce(expression) => write!(f, "(delay-force {})", expression),
+            Derived::Parameterize { bindings, body } => {
+                write!(f, "(parameterize (")?;
+                for (i, (parameter, value)) in bindings.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
                     }
-                    Rule::lambda_expression => Expression::Lambda,
-                    Rule::conditional => Expression::Conditional,
-                    Rule::assignment => Expression::Assignment,
-                    Rule::derived_expression => Expression::DerivedExpression,
-                    Rule::macro_use => Expression::MacroUse,
-                    Rule::macro_block => Expression::MacroBlock,
-                    Rule::includer => Expression::Includer,
-                    _ => unreachable!(),
+                    write!(f, "({} {})", parameter, value)?;
                 }
+                write!(f, ") {})", body)
             }
-            _ => unreachable!(),
+            Derived::Guard {
+                variable,
+                clauses,
+                else_clause,
+                body,
+            } => {
+                write!(f, "(guard ({} {}", variable, join(clauses))?;
+                if let Some(sequence) = else_clause {
+                    write!(f, " (else {})", join(sequence))?;
+                }
+                write!(f, ") {})", body)
+            }
+            Derived::Quasiquote(template) => write!(f, "(quasiquote {})", template),
+            Derived::CaseLambda(clauses) => write!(f, "(case-lambda {})", join(clauses)),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Expression;
-    use crate::parser::R7RSParser as parser;
-    use crate::parser::Rule;
-    use pest::Parser;
+    use super::{CDef, CDefKind, Expression, ExpressionKind, Source};
+    use crate::r7rs::builtins;
+    use crate::r7rs::error::{Error, Located};
+    use crate::r7rs::gc;
+    use crate::r7rs::reader;
+    use crate::r7rs::value::{Pair, Value};
+
+    // The command or definition `input` lowers into, read as the forms of a
+    // program are.
+    fn lowered(input: &str) -> CDef {
+        let source = Source::new(0, input);
+        let datum = reader::read_form(&source, source.locate(0, input.len())).unwrap();
+        CDef::from_datum(&datum, super::Span::default()).unwrap()
+    }
+
+    fn expression(input: &str) -> Expression {
+        match lowered(input).kind {
+            CDefKind::Command(expression) => expression,
+            other => panic!("expected a command, got {}", other),
+        }
+    }
+
     #[test]
     fn test_expression() {
-        let input = "foo";
-        let mut pairs = parser::parse(Rule::expression, input).unwrap();
-        let pair = pairs.next().unwrap();
-        let expression = Expression::from(pair);
-        assert_eq!(expression, Expression::Identifier("foo".to_string()));
+        let expression = expression("foo");
+        assert_eq!(
+            expression,
+            ExpressionKind::Identifier("foo".to_string()).into()
+        );
+    }
+
+    #[test]
+    fn test_procedure_call() {
+        assert_eq!(
+            expression("(f a #t)"),
+            ExpressionKind::ProcedureCall(
+                super::Operator(Box::new(ExpressionKind::Identifier("f".to_string()).into())),
+                vec![
+                    super::Operand(Box::new(ExpressionKind::Identifier("a".to_string()).into())),
+                    super::Operand(Box::new(
+                        ExpressionKind::Literal(super::Literal::Bool(true)).into()
+                    )),
+                ]
+            )
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_import_sets() {
+        use super::{Import, LibraryName, AST};
+        let input = "(import (rename (prefix (only (srfi 1) x y) p:) (p:x z)) (scheme base))";
+        let AST::Program { imports, .. } = AST::parse(&Source::new(0, input)).unwrap();
+        let srfi = LibraryName(vec!["srfi".to_string(), "1".to_string()]);
+        assert_eq!(srfi.to_string(), "(srfi 1)");
+        assert_eq!(
+            imports,
+            vec![
+                Import::Rename {
+                    set: Box::new(Import::Prefix {
+                        set: Box::new(Import::Only {
+                            set: Box::new(Import::Library(srfi)),
+                            identifiers: vec!["x".to_string(), "y".to_string()],
+                        }),
+                        prefix: "p:".to_string(),
+                    }),
+                    renames: vec![("p:x".to_string(), "z".to_string())],
+                },
+                Import::Library(LibraryName(vec!["scheme".to_string(), "base".to_string()])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let input = "(f \"λ\"\n   (g a))";
+        let source = Source::new(3, input);
+        let datum = reader::read_form(&source, source.locate(0, input.len())).unwrap();
+        let expression = match CDef::from_datum(&datum, super::Span::default()) {
+            Ok(CDef {
+                kind: CDefKind::Command(expression),
+                ..
+            }) => expression,
+            other => panic!("expected a command, got {:?}", other),
+        };
+        assert_eq!(
+            expression.span,
+            super::Span {
+                file: 3,
+                start: 0,
+                end: input.len(),
+                line: 1,
+                column: 1
+            }
+        );
+        let spans = call_spans(&expression);
+        assert_eq!((spans[1].start, spans[1].end), (1, 2));
+        assert_eq!((spans[2].line, spans[2].column), (1, 4));
+        assert_eq!(&input[spans[3].start..spans[3].end], "(g a)");
+        assert_eq!((spans[3].line, spans[3].column), (2, 4));
+        // An error lowering a form is placed at the form.
+        let input = "(f 1\n  (if))";
+        let source = Source::new(3, input);
+        let datum = reader::read_form(&source, source.locate(0, input.len())).unwrap();
+        match CDef::from_datum(&datum, super::Span::default()) {
+            Err(Located {
+                error,
+                span: Some(span),
+            }) if matches!(*error, Error::BadSyntax(_)) => {
+                assert_eq!((span.line, span.column, span.end - span.start), (2, 3, 4))
+            }
+            other => panic!("expected a located error, got {:?}", other),
+        }
+    }
+
+    // The spans of a procedure call, its operator and its operands.
+    fn call_spans(expression: &Expression) -> Vec<super::Span> {
+        match &expression.kind {
+            ExpressionKind::ProcedureCall(operator, operands) => [expression.span, operator.span()]
+                .into_iter()
+                .chain(operands.iter().map(|operand| operand.span()))
+                .collect(),
+            other => panic!("expected a procedure call, got {}", other),
+        }
+    }
+
+    #[test]
+    fn test_lambda() {
+        match expression("(lambda (a . rest) a)").kind {
+            ExpressionKind::Lambda { formals, body } => {
+                assert_eq!(formals.required, vec!["a".to_string()]);
+                assert_eq!(formals.rest, Some("rest".to_string()));
+                assert_eq!(
+                    body.sequence,
+                    vec![ExpressionKind::Identifier("a".to_string()).into()]
+                );
+            }
+            other => panic!("expected a lambda, got {}", other),
+        }
+        match expression("(lambda args args)").kind {
+            ExpressionKind::Lambda { formals, .. } => {
+                assert!(formals.required.is_empty());
+                assert_eq!(formals.rest, Some("args".to_string()));
+            }
+            other => panic!("expected a lambda, got {}", other),
+        }
+    }
+
+    #[test]
+    fn test_conditional() {
+        assert_eq!(
+            expression("(if a b)"),
+            ExpressionKind::Conditional {
+                test: Box::new(ExpressionKind::Identifier("a".to_string()).into()),
+                consequent: Box::new(ExpressionKind::Identifier("b".to_string()).into()),
+                alternate: None,
+            }
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_derived_expressions() {
+        let tests = [
+            "(cond (a => f) (b c) (else d))",
+            "(let ((x a) (y b)) (f x y))",
+            "(let loop ((x a)) (loop x))",
+            "(and a (or b c))",
+            "(do ((i a (f i))) ((g i) i) (h i))",
+        ];
+        for input in tests.iter() {
+            let expression = expression(input);
+            assert!(matches!(
+                expression.kind,
+                ExpressionKind::DerivedExpression(_)
+            ));
+            assert_eq!(expression.to_string(), *input);
+        }
+    }
+
+    #[test]
+    fn test_definitions() {
+        let tests = [
+            ("(define x a)", "(define x a)"),
+            ("(define (f a . b) b)", "(define f (lambda (a . b) b))"),
+            ("(define-values (a b) (f))", "(define-values (a b) (f))"),
+            (
+                "(define-record-type point (make-point x) point? (x point-x) (y point-y set-y!))",
+                "(define-record-type point (make-point x) point? (x point-x) (y point-y set-y!))",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            let cdef = lowered(input);
+            assert!(matches!(cdef.kind, CDefKind::Definition(_)));
+            assert_eq!(cdef.to_string(), *output);
+        }
+        // A begin at top level is spliced into the program, whatever forms
+        // it holds.
+        for input in [
+            "(begin (define x a) (f x))",
+            "(begin (define x a) (define y b))",
+        ] {
+            assert!(matches!(lowered(input).kind, CDefKind::Begin(_)));
+        }
+    }
+
+    #[test]
+    fn test_from_datum() {
+        // A lowered form is written as one that lowers into it again, and
+        // where no list has a span of its own, takes the span it is given.
+        let tests = [
+            "(define (f a . b) (define c a) (set! c 1) (if c (f c) b))",
+            "(define-values (a . b) (values 1 2))",
+            "(define-record-type point (make-point x) point? (x point-x) (y point-y set-y!))",
+            "(begin (define x 'a) (f x \"s\" #\\c 1.5 #(1 2) #u8(3)))",
+            "(let loop ((i 0)) (let* ((j i)) (letrec ((k j)) (letrec* ((l k)) (loop l)))))",
+            "(let-values (((a b) (f)) (c (g))) (let*-values ((d (h))) (list a b c d)))",
+            "(do ((i 0 (+ i 1)) (j 1)) ((= i 3) j) (f i))",
+            "(case-lambda ((x) x) ((x . y) y))",
+            "(guard (e ((symbol? e) => f) ((string? e)) (else 1)) (raise 'x))",
+            "(cond ((f) 1) ((g) => h) (else 2))",
+            "(case (f) ((a b) 1) ((c) => g) (else => h))",
+            "(parameterize ((p 1)) (and (or a b) (when c d) (unless e f) (begin g)))",
+            "(list (delay (f)) (delay-force (g)) (lambda x x))",
+            "`(1 ,a ,@b (c . ,d) #(e ,@f) `(g ,(h ,i)) . j)",
+            "(include \"a.scm\" \"b.scm\")",
+        ];
+        let span = super::Span {
+            file: 1,
+            start: 2,
+            end: 3,
+            line: 4,
+            column: 5,
+        };
+        for input in tests.iter() {
+            let datum = reader::read_datum(input).unwrap();
+            let lowered = CDef::from_datum(&datum, span).unwrap();
+            let written = reader::read_datum(&lowered.to_string()).unwrap();
+            assert_eq!(
+                CDef::from_datum(&written, span).unwrap(),
+                lowered,
+                "{}",
+                input
+            );
+            assert_eq!(lowered.span, span);
+        }
+        // Values no text can write are constants.
+        let datum = Value::Pair(gc::alloc(Pair::new(
+            builtins::builtin("car"),
+            Value::Pair(gc::alloc(Pair::new(
+                Value::Symbol("x".to_string()),
+                Value::Null,
+            ))),
+        )));
+        assert_eq!(
+            CDef::from_datum(&datum, span).unwrap().to_string(),
+            "((quote #<procedure car>) x)"
+        );
+        for input in ["()", "(lambda (x) x (define y 1))", "(lambda (x))", "(if)"] {
+            let datum = reader::read_datum(input).unwrap();
+            match CDef::from_datum(&datum, span) {
+                Err(Located {
+                    error,
+                    span: Some(at),
+                }) => assert!(matches!(*error, Error::BadSyntax(_)) && at == span),
+                other => panic!("expected an error lowering {}, got {:?}", input, other),
+            }
+        }
     }
 
     #[test]
     fn test_literal_string() {
-        let input = "\"foo\"";
-        let mut pairs = parser::parse(Rule::expression, input).unwrap();
-        let pair = pairs.next().unwrap();
-        dbg!(&pair);
-        let expression = Expression::from(pair);
+        assert_eq!(
+            expression("\"foo\""),
+            ExpressionKind::Literal(super::Literal::String("foo".to_string())).into()
+        );
+    }
+
+    #[test]
+    fn test_literal_string_escapes() {
+        let input = r#""a\n\x3bb;\"\\\|c \
+                       d""#;
+        let expression = expression(input);
         assert_eq!(
             expression,
-            Expression::Literal(super::Literal::String("\"foo\"".to_string()))
+            ExpressionKind::Literal(super::Literal::String("a\n\u{3bb}\"\\|c d".to_string()))
+                .into()
         );
+        assert_eq!(expression.to_string(), r#""a\nλ\"\\|c d""#);
+    }
+
+    #[test]
+    fn test_quotation() {
+        for input in ["'(a . b)", "(quote (a . b))"].iter() {
+            let expression = expression(input);
+            assert_eq!(expression.to_string(), "(quote (a . b))");
+            match expression.kind {
+                ExpressionKind::Literal(super::Literal::Quotation(datum)) => assert_eq!(
+                    datum,
+                    Value::Pair(gc::alloc(Pair::new(
+                        Value::Symbol("a".to_string()),
+                        Value::Symbol("b".to_string())
+                    )))
+                ),
+                other => panic!("expected a quotation, got {}", other),
+            }
+        }
     }
 
     #[test]
@@ -244,12 +1921,9 @@ mod tests {
             ("#false", false),
         ];
         for (input, expected) in tests.iter() {
-            let mut pairs = parser::parse(Rule::expression, input).unwrap();
-            let pair = pairs.next().unwrap();
-            let expression = Expression::from(pair);
             assert_eq!(
-                expression,
-                Expression::Literal(super::Literal::Bool(*expected))
+                expression(input),
+                ExpressionKind::Literal(super::Literal::Bool(*expected)).into()
             );
         }
     }
@@ -308,141 +1982,304 @@ mod tests {
             // Wow copilot that was a lot of emoji
         ];
         for (input, expected) in tests.iter() {
-            let mut pairs = parser::parse(Rule::expression, input).unwrap();
-            let pair = pairs.next().unwrap();
-            let expression = Expression::from(pair);
             assert_eq!(
-                expression,
-                Expression::Literal(super::Literal::Char(*expected))
+                expression(input),
+                ExpressionKind::Literal(super::Literal::Char(*expected)).into()
             );
         }
     }
 }
-#[derive(Debug, PartialEq, Eq)]
-pub struct Operator(Box<Expression>);
+#[derive(Debug, PartialEq, Clone)]
+pub struct Operator(pub Box<Expression>);
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct Operand(Box<Expression>);
-pub enum Import {
-    Library {
-        name: String,
+#[derive(Debug, PartialEq, Clone)]
+pub struct Operand(pub Box<Expression>);
+impl Operator {
+    pub fn span(&self) -> Span {
+        self.0.span
+    }
+}
+impl Operand {
+    pub fn span(&self) -> Span {
+        self.0.span
+    }
+}
+
+/// The name of a library, such as `(scheme base)`: identifiers and exact
+/// integers.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct LibraryName(pub Vec<String>);
+impl LibraryName {
+    fn from(pair: pest::iterators::Pair<Rule>, source: &Source) -> Result<LibraryName, ParseError> {
+        match pair.as_rule() {
+            Rule::library_name => Ok(LibraryName(
+                pair.into_inner()
+                    .map(|part| source.identifier(&part))
+                    .collect(),
+            )),
+            _ => Err(unexpected(&pair, "a library name")),
+        }
+    }
+}
+impl std::fmt::Display for LibraryName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "({})", self.0.join(" "))
+    }
+}
+
+/// A define-library form: the name of the library and its declarations, in
+/// the order they are written.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Library {
+    pub name: LibraryName,
+    pub declarations: Vec<LibraryDeclaration>,
+    pub span: Span,
+}
+impl Library {
+    fn from(pair: pest::iterators::Pair<Rule>, source: &Source) -> Result<Library, ParseError> {
+        match pair.as_rule() {
+            Rule::library => {
+                let span = pair.as_span();
+                let mut inner = pair.into_inner();
+                let name = LibraryName::from(child(&mut inner, span)?, source)?;
+                Ok(Library {
+                    name,
+                    declarations: inner
+                        .map(|pair| LibraryDeclaration::from(pair, source))
+                        .collect::<Result<_, _>>()?,
+                    span: source.span(span),
+                })
+            }
+            _ => Err(unexpected(&pair, "a library definition")),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum LibraryDeclaration {
+    Export(Vec<ExportSpec>),
+    Import(Vec<Import>),
+    /// A begin, or an include whose forms are evaluated as a begin's are,
+    /// with where each of its forms was written.
+    Begin(Vec<Span>),
+    IncludeLibraryDeclarations {
+        files: Vec<String>,
+        span: Span,
     },
+    /// The clauses of a cond-expand. An else clause requires the feature
+    /// `else`, which every implementation has.
+    CondExpand(Vec<(FeatureRequirement, Vec<LibraryDeclaration>)>),
+}
+impl LibraryDeclaration {
+    /// Parse and lower the declarations of a file that a library includes
+    /// with include-library-declarations.
+    pub fn parse(source: &Source) -> Result<Vec<LibraryDeclaration>, ParseError> {
+        let text = source.text;
+        let mut pairs = R7RSParser::parse(Rule::library_declarations, text)?;
+        child(&mut pairs, pest::Span::new(text, 0, text.len()).unwrap())?
+            .into_inner()
+            .filter(|pair| pair.as_rule() != Rule::EOI)
+            .map(|pair| LibraryDeclaration::from(pair, source))
+            .collect()
+    }
+
+    // The kind of a declaration is told by its children, as the keywords
+    // are lookaheads. An export or begin without any declares nothing.
+    fn from(
+        pair: pest::iterators::Pair<Rule>,
+        source: &Source,
+    ) -> Result<LibraryDeclaration, ParseError> {
+        if pair.as_rule() != Rule::library_declaration {
+            return Err(unexpected(&pair, "a library declaration"));
+        }
+        let span = pair.as_span();
+        let mut inner = pair.into_inner();
+        let rule = match inner.peek() {
+            Some(first) => first.as_rule(),
+            None => return Ok(LibraryDeclaration::Begin(Vec::new())),
+        };
+        match rule {
+            Rule::export_spec => Ok(LibraryDeclaration::Export(
+                inner
+                    .map(|pair| ExportSpec::from(pair, source))
+                    .collect::<Result<_, _>>()?,
+            )),
+            Rule::import_declaration => Ok(LibraryDeclaration::Import(Import::declaration(
+                child(&mut inner, span)?,
+                source,
+            )?)),
+            Rule::datum => Ok(LibraryDeclaration::Begin(
+                inner.map(|pair| source.span(pair.as_span())).collect(),
+            )),
+            Rule::includer => Ok(LibraryDeclaration::Begin(vec![
+                source.span(child(&mut inner, span)?.as_span())
+            ])),
+            Rule::string => Ok(LibraryDeclaration::IncludeLibraryDeclarations {
+                files: inner
+                    .map(|pair| reader::string(&pair))
+                    .collect::<Result<_, _>>()?,
+                span: source.span(span),
+            }),
+            Rule::cond_expand_clause => {
+                let mut clauses = Vec::new();
+                let mut otherwise = Vec::new();
+                for pair in inner {
+                    match pair.as_rule() {
+                        Rule::cond_expand_clause => {
+                            let span = pair.as_span();
+                            let mut inner = pair.into_inner();
+                            let requirement =
+                                FeatureRequirement::from(child(&mut inner, span)?, source)?;
+                            let declarations = inner
+                                .map(|pair| LibraryDeclaration::from(pair, source))
+                                .collect::<Result<_, _>>()?;
+                            clauses.push((requirement, declarations));
+                        }
+                        _ => otherwise.push(LibraryDeclaration::from(pair, source)?),
+                    }
+                }
+                if !otherwise.is_empty() {
+                    clauses.push((FeatureRequirement::Feature("else".to_string()), otherwise));
+                }
+                Ok(LibraryDeclaration::CondExpand(clauses))
+            }
+            _ => Err(ParseError::new(span, "a library declaration")),
+        }
+    }
+}
+
+/// An identifier a library exports, under the name importers know it by.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExportSpec {
+    pub internal: String,
+    pub external: String,
+}
+impl ExportSpec {
+    fn from(pair: pest::iterators::Pair<Rule>, source: &Source) -> Result<ExportSpec, ParseError> {
+        let span = pair.as_span();
+        let mut inner = pair.into_inner();
+        let internal = source.identifier(&child(&mut inner, span)?);
+        let external = match inner.next() {
+            Some(pair) => source.identifier(&pair),
+            None => internal.clone(),
+        };
+        Ok(ExportSpec { internal, external })
+    }
+}
+
+/// The requirement of a cond-expand clause.
+#[derive(Debug, PartialEq, Clone)]
+pub enum FeatureRequirement {
+    Feature(String),
+    Library(LibraryName),
+    And(Vec<FeatureRequirement>),
+    Or(Vec<FeatureRequirement>),
+    Not(Box<FeatureRequirement>),
+}
+impl FeatureRequirement {
+    fn from(
+        pair: pest::iterators::Pair<Rule>,
+        source: &Source,
+    ) -> Result<FeatureRequirement, ParseError> {
+        let span = pair.as_span();
+        let mut inner = pair.into_inner();
+        let first = child(&mut inner, span)?;
+        match first.as_rule() {
+            Rule::identifier => Ok(FeatureRequirement::Feature(source.identifier(&first))),
+            Rule::library_name => Ok(FeatureRequirement::Library(LibraryName::from(
+                first, source,
+            )?)),
+            Rule::feature_connective => {
+                let mut requirements = inner
+                    .map(|pair| FeatureRequirement::from(pair, source))
+                    .collect::<Result<Vec<_>, _>>()?;
+                match first.as_str() {
+                    "and" => Ok(FeatureRequirement::And(requirements)),
+                    "or" => Ok(FeatureRequirement::Or(requirements)),
+                    _ if requirements.len() == 1 => {
+                        Ok(FeatureRequirement::Not(Box::new(requirements.remove(0))))
+                    }
+                    _ => Err(ParseError::new(span, "one requirement after not")),
+                }
+            }
+            _ => Err(unexpected(&first, "a feature requirement")),
+        }
+    }
+}
+
+/// An import set: the identifiers a library exports, as an import
+/// declaration narrows, prefixes or renames them.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Import {
+    Library(LibraryName),
     Only {
-        imports: Vec<Box<Import>>,
+        set: Box<Import>,
         identifiers: Vec<String>,
     },
     Except {
-        imports: Vec<Box<Import>>,
+        set: Box<Import>,
         identifiers: Vec<String>,
     },
     Prefix {
-        imports: Vec<Box<Import>>,
+        set: Box<Import>,
         prefix: String,
     },
     Rename {
-        imports: Vec<Box<Import>>,
-        identifiers: Vec<(String, String)>,
+        set: Box<Import>,
+        renames: Vec<(String, String)>,
     },
 }
-/*
 impl Import {
-    /// Given a pest pair, return an Import or Error. The import_set rule gives either:
-    /// - library_name
-    /// - import_only
-    /// - import_except
-    /// - import_prefix
-    /// - import_rename
-    ///
-
-    fn from(pair: pest::iterators::Pair<Rule>) -> Result<Import, String> {
+    // The import sets of an import declaration.
+    fn declaration(
+        pair: pest::iterators::Pair<Rule>,
+        source: &Source,
+    ) -> Result<Vec<Import>, ParseError> {
         match pair.as_rule() {
-            Rule::import_set => {
-                let mut inner = pair.into_inner();
-                let import = inner.next().unwrap();
-                match import.as_rule() {
-                    Rule::library_name => {
-                        let mut inner = import.into_inner();
-                        let name = inner.next().unwrap().as_str().to_string();
-                        Ok(Import::Library { name })
-                    }
-                    Rule::import_only => {
-                        let mut imports = Vec::new();
-                        let mut identifiers = Vec::new();
-                        for pair in import.into_inner() {
-                            match pair.as_rule() {
-                                Rule::import_set => {
-                                    imports.push(Box::new(Import::from(pair).unwrap()));
-                                }
-                                Rule::identifier => {
-                                    identifiers.push(pair.as_str().to_string());
-                                }
-                                _ => unreachable!(),
-                            }
-                        }
-                        Ok(Import::Only {
-                            imports,
-                            identifiers,
-                        })
-                    }
-                    Rule::import_except => {
-                        let mut imports = Vec::new();
-                        let mut identifiers = Vec::new();
-                        for pair in import.into_inner() {
-                            match pair.as_rule() {
-                                Rule::import_set => {
-                                    imports.push(Box::new(Import::from(pair).unwrap()));
-                                }
-                                Rule::identifier => {
-                                    identifiers.push(pair.as_str().to_string());
-                                }
-                                _ => unreachable!(),
-                            }
-                        }
-                        Ok(Import::Except {
-                            imports,
-                            identifiers,
-                        })
-                    }
-                    Rule::import_prefix => {
-                        let mut imports = Vec::new();
-                        let mut prefix = String::new();
-                        for pair in import.into_inner() {
-                            match pair.as_rule() {
-                                Rule::import_set => {
-                                    imports.push(Import::from(pair).unwrap());
-                                }
-                                Rule::identifier => {
-                                    prefix = pair.as_str().to_string();
-                                }
-                                _ => unreachable!(),
-                            }
-                        }
-                        Ok(Import::Prefix { imports, prefix })
-                    }
-                    Rule::import_rename => {
-                        let mut imports = Vec::new();
-                        let mut identifiers = Vec::new();
-                        for pair in import.into_inner() {
-                            match pair.as_rule() {
-                                Rule::import_set => {
-                                    imports.push(Import::from(pair).unwrap());
-                                }
-                                Rule::identifier => {
-                                    identifiers.push(pair.as_str().to_string());
-                                }
-                                _ => unreachable!(),
-                            }
-                        }
-                        Ok(Import::Rename {
-                            imports,
-                            identifiers,
-                        })
-                    }
-                    _ => unreachable!(),
+            Rule::import_declaration => pair
+                .into_inner()
+                .map(|pair| Import::from(pair, source))
+                .collect(),
+            _ => Err(unexpected(&pair, "an import declaration")),
+        }
+    }
+
+    // Given an import_set pair, whose child is a library name or one of
+    // only, except, prefix and rename around another import set.
+    fn from(pair: pest::iterators::Pair<Rule>, source: &Source) -> Result<Import, ParseError> {
+        if pair.as_rule() != Rule::import_set {
+            return Err(unexpected(&pair, "an import set"));
+        }
+        let span = pair.as_span();
+        let import = child(&mut pair.into_inner(), span)?;
+        let rule = import.as_rule();
+        if rule == Rule::library_name {
+            return Ok(Import::Library(LibraryName::from(import, source)?));
+        }
+        let mut inner = import.into_inner();
+        let set = Box::new(Import::from(child(&mut inner, span)?, source)?);
+        let mut identifiers = inner.map(|pair| source.identifier(&pair));
+        match rule {
+            Rule::import_only => Ok(Import::Only {
+                set,
+                identifiers: identifiers.collect(),
+            }),
+            Rule::import_except => Ok(Import::Except {
+                set,
+                identifiers: identifiers.collect(),
+            }),
+            Rule::import_prefix => Ok(Import::Prefix {
+                set,
+                prefix: identifiers.next().unwrap_or_default(),
+            }),
+            Rule::import_rename => {
+                let mut renames = Vec::new();
+                while let (Some(from), Some(to)) = (identifiers.next(), identifiers.next()) {
+                    renames.push((from, to));
                 }
+                Ok(Import::Rename { set, renames })
             }
-            _ => unreachable!(),
+            _ => Err(ParseError::new(span, "an import set")),
         }
     }
 }
- */
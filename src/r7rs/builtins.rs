@@ -0,0 +1,1021 @@
+//! Primitive procedures implemented in Rust and installed into the global
+//! environment of every Interpreter.
+
+use crate::r7rs::env::Environment;
+use crate::r7rs::error::Error;
+use crate::r7rs::eval::Tail;
+use crate::r7rs::expand;
+use crate::r7rs::gc;
+use crate::r7rs::value::{
+    Arity, Control, ErrorKind, ErrorObject, Number, Pair, Parameter, Primitive, Promise, Value,
+};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const PRIMITIVES: &[Primitive] = &[
+    Primitive {
+        name: "not",
+        arity: Arity::Exactly(1),
+        func: not,
+    },
+    Primitive {
+        name: "boolean?",
+        arity: Arity::Exactly(1),
+        func: is_boolean,
+    },
+    Primitive {
+        name: "char?",
+        arity: Arity::Exactly(1),
+        func: is_char,
+    },
+    Primitive {
+        name: "string?",
+        arity: Arity::Exactly(1),
+        func: is_string,
+    },
+    Primitive {
+        name: "symbol?",
+        arity: Arity::Exactly(1),
+        func: is_symbol,
+    },
+    Primitive {
+        name: "procedure?",
+        arity: Arity::Exactly(1),
+        func: is_procedure,
+    },
+    Primitive {
+        name: "null?",
+        arity: Arity::Exactly(1),
+        func: is_null,
+    },
+    Primitive {
+        name: "pair?",
+        arity: Arity::Exactly(1),
+        func: is_pair,
+    },
+    Primitive {
+        name: "length",
+        arity: Arity::Exactly(1),
+        func: list_length,
+    },
+    Primitive {
+        name: "cons",
+        arity: Arity::Exactly(2),
+        func: cons,
+    },
+    Primitive {
+        name: "car",
+        arity: Arity::Exactly(1),
+        func: car,
+    },
+    Primitive {
+        name: "cdr",
+        arity: Arity::Exactly(1),
+        func: cdr,
+    },
+    Primitive {
+        name: "list",
+        arity: Arity::AtLeast(0),
+        func: list,
+    },
+    Primitive {
+        name: "append",
+        arity: Arity::AtLeast(0),
+        func: append,
+    },
+    Primitive {
+        name: "string-length",
+        arity: Arity::Exactly(1),
+        func: string_length,
+    },
+    Primitive {
+        name: "string-append",
+        arity: Arity::AtLeast(0),
+        func: string_append,
+    },
+    Primitive {
+        name: "char->integer",
+        arity: Arity::Exactly(1),
+        func: char_to_integer,
+    },
+    Primitive {
+        name: "+",
+        arity: Arity::AtLeast(0),
+        func: add,
+    },
+    Primitive {
+        name: "-",
+        arity: Arity::AtLeast(1),
+        func: subtract,
+    },
+    Primitive {
+        name: "*",
+        arity: Arity::AtLeast(0),
+        func: multiply,
+    },
+    Primitive {
+        name: "=",
+        arity: Arity::AtLeast(1),
+        func: equal_numbers,
+    },
+    Primitive {
+        name: "<",
+        arity: Arity::AtLeast(1),
+        func: less,
+    },
+    Primitive {
+        name: ">",
+        arity: Arity::AtLeast(1),
+        func: greater,
+    },
+    Primitive {
+        name: "eq?",
+        arity: Arity::Exactly(2),
+        func: eqv,
+    },
+    Primitive {
+        name: "eqv?",
+        arity: Arity::Exactly(2),
+        func: eqv,
+    },
+    Primitive {
+        name: "equal?",
+        arity: Arity::Exactly(2),
+        func: equal,
+    },
+    Primitive {
+        name: "set-car!",
+        arity: Arity::Exactly(2),
+        func: set_car,
+    },
+    Primitive {
+        name: "set-cdr!",
+        arity: Arity::Exactly(2),
+        func: set_cdr,
+    },
+    Primitive {
+        name: "vector?",
+        arity: Arity::Exactly(1),
+        func: is_vector,
+    },
+    Primitive {
+        name: "vector",
+        arity: Arity::AtLeast(0),
+        func: vector,
+    },
+    Primitive {
+        name: "make-vector",
+        arity: Arity::Between(1, 2),
+        func: make_vector,
+    },
+    Primitive {
+        name: "vector-length",
+        arity: Arity::Exactly(1),
+        func: vector_length,
+    },
+    Primitive {
+        name: "vector-ref",
+        arity: Arity::Exactly(2),
+        func: vector_ref,
+    },
+    Primitive {
+        name: "vector-set!",
+        arity: Arity::Exactly(3),
+        func: vector_set,
+    },
+    Primitive {
+        name: "make-string",
+        arity: Arity::Between(1, 2),
+        func: make_string,
+    },
+    Primitive {
+        name: "string-ref",
+        arity: Arity::Exactly(2),
+        func: string_ref,
+    },
+    Primitive {
+        name: "string-set!",
+        arity: Arity::Exactly(3),
+        func: string_set,
+    },
+    Primitive {
+        name: "bytevector?",
+        arity: Arity::Exactly(1),
+        func: is_bytevector,
+    },
+    Primitive {
+        name: "bytevector",
+        arity: Arity::AtLeast(0),
+        func: bytevector,
+    },
+    Primitive {
+        name: "make-bytevector",
+        arity: Arity::Between(1, 2),
+        func: make_bytevector,
+    },
+    Primitive {
+        name: "bytevector-length",
+        arity: Arity::Exactly(1),
+        func: bytevector_length,
+    },
+    Primitive {
+        name: "bytevector-u8-ref",
+        arity: Arity::Exactly(2),
+        func: bytevector_u8_ref,
+    },
+    Primitive {
+        name: "bytevector-u8-set!",
+        arity: Arity::Exactly(3),
+        func: bytevector_u8_set,
+    },
+    Primitive {
+        name: "write",
+        arity: Arity::Exactly(1),
+        func: write,
+    },
+    Primitive {
+        name: "write-shared",
+        arity: Arity::Exactly(1),
+        func: write_shared,
+    },
+    Primitive {
+        name: "write-simple",
+        arity: Arity::Exactly(1),
+        func: write_simple,
+    },
+    Primitive {
+        name: "values",
+        arity: Arity::AtLeast(0),
+        func: values,
+    },
+    Primitive {
+        name: "error-object?",
+        arity: Arity::Exactly(1),
+        func: is_error_object,
+    },
+    Primitive {
+        name: "error-object-message",
+        arity: Arity::Exactly(1),
+        func: error_object_message,
+    },
+    Primitive {
+        name: "error-object-irritants",
+        arity: Arity::Exactly(1),
+        func: error_object_irritants,
+    },
+    Primitive {
+        name: "read-error?",
+        arity: Arity::Exactly(1),
+        func: is_read_error,
+    },
+    Primitive {
+        name: "file-error?",
+        arity: Arity::Exactly(1),
+        func: is_file_error,
+    },
+    Primitive {
+        name: "make-promise",
+        arity: Arity::Exactly(1),
+        func: make_promise,
+    },
+    Primitive {
+        name: "promise?",
+        arity: Arity::Exactly(1),
+        func: is_promise,
+    },
+];
+
+const CONTROLS: &[Control] = &[
+    Control {
+        name: "apply",
+        arity: Arity::AtLeast(2),
+        func: apply,
+    },
+    Control {
+        name: "call-with-current-continuation",
+        arity: Arity::Exactly(1),
+        func: call_cc,
+    },
+    Control {
+        name: "call/cc",
+        arity: Arity::Exactly(1),
+        func: call_cc,
+    },
+    Control {
+        name: "call-with-values",
+        arity: Arity::Exactly(2),
+        func: call_with_values,
+    },
+    Control {
+        name: "dynamic-wind",
+        arity: Arity::Exactly(3),
+        func: dynamic_wind,
+    },
+    Control {
+        name: "make-parameter",
+        arity: Arity::Between(1, 2),
+        func: make_parameter,
+    },
+    Control {
+        name: "with-exception-handler",
+        arity: Arity::Exactly(2),
+        func: with_exception_handler,
+    },
+    Control {
+        name: "raise",
+        arity: Arity::Exactly(1),
+        func: raise,
+    },
+    Control {
+        name: "raise-continuable",
+        arity: Arity::Exactly(1),
+        func: raise_continuable,
+    },
+    Control {
+        name: "force",
+        arity: Arity::Exactly(1),
+        func: force,
+    },
+    Control {
+        name: "error",
+        arity: Arity::AtLeast(1),
+        func: error,
+    },
+];
+
+/// Bind every primitive procedure in `env`.
+pub fn install(env: &Environment) {
+    for primitive in PRIMITIVES.iter().chain(expand::PRIMITIVES.iter()) {
+        env.define(primitive.name, Value::Primitive(primitive.clone()));
+    }
+    for control in CONTROLS.iter().chain(expand::CONTROLS.iter()) {
+        env.define(control.name, Value::Control(control.clone()));
+    }
+}
+
+thread_local! {
+    // The builtins in an environment of their own, which no program
+    // defines into.
+    static ENVIRONMENT: Rc<Environment> = {
+        let env = Rc::new(Environment::new());
+        install(&env);
+        env
+    };
+}
+
+/// The environment binding every primitive procedure and nothing else. The
+/// standard libraries export its variables, and identifiers the expander
+/// inserts refer to them, whatever a program defines.
+pub(crate) fn environment() -> Rc<Environment> {
+    ENVIRONMENT.with(Rc::clone)
+}
+
+// Procedures the code the compiler generates calls that are not bound in
+// the global environment.
+const INTERNAL_PRIMITIVES: &[Primitive] = &[
+    Primitive {
+        name: "parameter-set!",
+        arity: Arity::Exactly(2),
+        func: parameter_set,
+    },
+    Primitive {
+        name: "make-lazy-promise",
+        arity: Arity::Exactly(1),
+        func: make_lazy_promise,
+    },
+    Primitive {
+        name: "make-eager-promise",
+        arity: Arity::Exactly(1),
+        func: make_eager_promise,
+    },
+];
+
+const INTERNAL_CONTROLS: &[Control] = &[Control {
+    name: "parameter-convert",
+    arity: Arity::Exactly(2),
+    func: parameter_convert,
+}];
+
+/// The builtin procedure named `name`, for code the compiler generates to
+/// call however the program has rebound the name.
+pub(crate) fn builtin(name: &str) -> Value {
+    let mut primitives = PRIMITIVES.iter().chain(INTERNAL_PRIMITIVES.iter());
+    if let Some(primitive) = primitives.find(|primitive| primitive.name == name) {
+        return Value::Primitive(primitive.clone());
+    }
+    let mut controls = CONTROLS.iter().chain(INTERNAL_CONTROLS.iter());
+    match controls.find(|control| control.name == name) {
+        Some(control) => Value::Control(control.clone()),
+        None => unreachable!("no builtin procedure named {}", name),
+    }
+}
+
+fn wrong_type(procedure: &str, expected: &'static str, given: &Value) -> Error {
+    Error::WrongType {
+        procedure: procedure.to_string(),
+        expected: expected.to_string(),
+        given: given.to_string(),
+    }
+}
+
+fn not(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::Boolean(false))))
+}
+
+fn is_boolean(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::Boolean(_))))
+}
+
+fn is_char(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::Character(_))))
+}
+
+fn is_string(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::String(_))))
+}
+
+fn is_symbol(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::Symbol(_))))
+}
+
+fn is_procedure(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(
+        args[0],
+        Value::Procedure(_)
+            | Value::Primitive(_)
+            | Value::Control(_)
+            | Value::Continuation(_)
+            | Value::Parameter(_)
+            | Value::RecordProcedure(_)
+    )))
+}
+
+fn is_null(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::Null)))
+}
+
+fn is_pair(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::Pair(_))))
+}
+
+fn cons(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Pair(gc::alloc(Pair::new(
+        args[0].clone(),
+        args[1].clone(),
+    ))))
+}
+
+fn car(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::Pair(p) => Ok(p.car().clone()),
+        other => Err(wrong_type("car", "pair", other)),
+    }
+}
+
+fn cdr(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::Pair(p) => Ok(p.cdr().clone()),
+        other => Err(wrong_type("cdr", "pair", other)),
+    }
+}
+
+fn list(args: &[Value]) -> Result<Value, Error> {
+    Ok(args.iter().rev().fold(Value::Null, |cdr, car| {
+        Value::Pair(gc::alloc(Pair::new(car.clone(), cdr)))
+    }))
+}
+
+fn list_length(args: &[Value]) -> Result<Value, Error> {
+    let items = list_items("length", &args[0])?;
+    Ok(Value::Number(Number::Integer(items.len() as i64)))
+}
+
+// The items of a proper list. A list that is improper or circular is the
+// wrong type; a circular one is told by a second pointer, moving two pairs
+// for each one the first moves, coming round to meet it.
+fn list_items(procedure: &str, list: &Value) -> Result<Vec<Value>, Error> {
+    let mut items = Vec::new();
+    let mut slow = list.clone();
+    let mut tail = list.clone();
+    loop {
+        tail = match &tail {
+            Value::Null => return Ok(items),
+            Value::Pair(pair) => {
+                items.push(pair.car());
+                pair.cdr()
+            }
+            _ => return Err(wrong_type(procedure, "list", list)),
+        };
+        if items.len() % 2 == 0 {
+            if let Value::Pair(pair) = &slow {
+                slow = pair.cdr();
+            }
+            if matches!((&tail, &slow), (Value::Pair(a), Value::Pair(b)) if Rc::ptr_eq(a, b)) {
+                return Err(wrong_type(procedure, "list", list));
+            }
+        }
+    }
+}
+
+// Every list but the last is copied; the last is shared, and need not be a
+// list.
+fn append(args: &[Value]) -> Result<Value, Error> {
+    let (last, init) = match args.split_last() {
+        Some(split) => split,
+        None => return Ok(Value::Null),
+    };
+    let mut items = Vec::new();
+    for list in init.iter() {
+        items.extend(list_items("append", list)?);
+    }
+    Ok(items.into_iter().rev().fold(last.clone(), |cdr, car| {
+        Value::Pair(gc::alloc(Pair::new(car, cdr)))
+    }))
+}
+
+fn string_length(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::String(s) => Ok(Value::Number(Number::Integer(
+            s.borrow().chars().count() as i64
+        ))),
+        other => Err(wrong_type("string-length", "string", other)),
+    }
+}
+
+fn string_append(args: &[Value]) -> Result<Value, Error> {
+    let mut result = String::new();
+    for arg in args.iter() {
+        match arg {
+            Value::String(s) => result.push_str(&s.borrow()),
+            other => return Err(wrong_type("string-append", "string", other)),
+        }
+    }
+    Ok(Value::string(result))
+}
+
+fn char_to_integer(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::Character(c) => Ok(Value::Number(Number::Integer(*c as i64))),
+        other => Err(wrong_type("char->integer", "char", other)),
+    }
+}
+
+// Arithmetic is on exact integers and reals. An exact result that does not
+// fit in 64 bits is an error rather than a silently inexact one.
+
+fn number(procedure: &str, value: &Value) -> Result<Number, Error> {
+    match value {
+        Value::Number(n @ (Number::Integer(_) | Number::Real(_))) => Ok(n.clone()),
+        other => Err(wrong_type(procedure, "integer or real", other)),
+    }
+}
+
+fn to_f64(n: &Number) -> f64 {
+    match n {
+        Number::Integer(i) => *i as f64,
+        Number::Real(r) => *r,
+        _ => unreachable!("arithmetic is on integers and reals"),
+    }
+}
+
+fn fold(
+    procedure: &str,
+    initial: Number,
+    args: &[Value],
+    exact: fn(i64, i64) -> Option<i64>,
+    inexact: fn(f64, f64) -> f64,
+) -> Result<Value, Error> {
+    let mut result = initial;
+    for arg in args.iter() {
+        result = match (result, number(procedure, arg)?) {
+            (Number::Integer(a), Number::Integer(b)) => match exact(a, b) {
+                Some(n) => Number::Integer(n),
+                None => {
+                    return Err(Error::Unsupported(format!(
+                        "{}: exact integer overflow",
+                        procedure
+                    )))
+                }
+            },
+            (a, b) => Number::Real(inexact(to_f64(&a), to_f64(&b))),
+        };
+    }
+    Ok(Value::Number(result))
+}
+
+fn add(args: &[Value]) -> Result<Value, Error> {
+    fold("+", Number::Integer(0), args, i64::checked_add, |a, b| {
+        a + b
+    })
+}
+
+fn subtract(args: &[Value]) -> Result<Value, Error> {
+    let (initial, rest) = match args {
+        [arg] => (Number::Integer(0), std::slice::from_ref(arg)),
+        _ => (number("-", &args[0])?, &args[1..]),
+    };
+    fold("-", initial, rest, i64::checked_sub, |a, b| a - b)
+}
+
+fn multiply(args: &[Value]) -> Result<Value, Error> {
+    fold("*", Number::Integer(1), args, i64::checked_mul, |a, b| {
+        a * b
+    })
+}
+
+// Whether each argument is in the given order with the next.
+fn compare(
+    procedure: &str,
+    args: &[Value],
+    test: fn(std::cmp::Ordering) -> bool,
+) -> Result<Value, Error> {
+    let numbers = args
+        .iter()
+        .map(|arg| number(procedure, arg))
+        .collect::<Result<Vec<Number>, Error>>()?;
+    Ok(Value::Boolean(numbers.windows(2).all(|pair| {
+        let ordering = match (&pair[0], &pair[1]) {
+            (Number::Integer(a), Number::Integer(b)) => Some(a.cmp(b)),
+            (a, b) => to_f64(a).partial_cmp(&to_f64(b)),
+        };
+        ordering.is_some_and(test)
+    })))
+}
+
+fn equal_numbers(args: &[Value]) -> Result<Value, Error> {
+    compare("=", args, std::cmp::Ordering::is_eq)
+}
+
+fn less(args: &[Value]) -> Result<Value, Error> {
+    compare("<", args, std::cmp::Ordering::is_lt)
+}
+
+fn greater(args: &[Value]) -> Result<Value, Error> {
+    compare(">", args, std::cmp::Ordering::is_gt)
+}
+
+// apply spreads its last argument, a list, after the others.
+fn apply(args: &[Value]) -> Result<Tail, Error> {
+    let (list, init) = args.split_last().unwrap();
+    let mut args = init[1..].to_vec();
+    args.extend(list_items("apply", list)?);
+    Ok(Tail::Call(init[0].clone(), args))
+}
+
+fn call_cc(args: &[Value]) -> Result<Tail, Error> {
+    Ok(Tail::CallWithContinuation(args[0].clone()))
+}
+
+fn values(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::values(args.to_vec()))
+}
+
+fn call_with_values(args: &[Value]) -> Result<Tail, Error> {
+    Ok(Tail::CallWithValues(args[0].clone(), args[1].clone()))
+}
+
+fn make_parameter(args: &[Value]) -> Result<Tail, Error> {
+    match args.get(1) {
+        Some(converter) => Ok(Tail::MakeParameter(args[0].clone(), converter.clone())),
+        None => Ok(Tail::Value(Value::Parameter(gc::alloc(Parameter {
+            value: RefCell::new(args[0].clone()),
+            converter: None,
+        })))),
+    }
+}
+
+// A promise that is already done, unless it is given one.
+fn make_promise(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        promise @ Value::Promise(_) => Ok(promise.clone()),
+        _ => make_eager_promise(args),
+    }
+}
+
+fn is_promise(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::Promise(_))))
+}
+
+// Anything but a promise forces to itself.
+fn force(args: &[Value]) -> Result<Tail, Error> {
+    match &args[0] {
+        Value::Promise(promise) if !promise.is_done() => Ok(Tail::Force(Rc::clone(promise))),
+        Value::Promise(promise) => Ok(Tail::Value(promise.value())),
+        other => Ok(Tail::Value(other.clone())),
+    }
+}
+
+// What delay-force makes of its thunk: a promise that is not done.
+fn make_lazy_promise(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Promise(gc::alloc(Promise::new(
+        false,
+        args[0].clone(),
+    ))))
+}
+
+// What the thunk of a delay returns its value in: a promise that is done,
+// even if the value is a promise itself.
+fn make_eager_promise(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Promise(gc::alloc(Promise::new(
+        true,
+        args[0].clone(),
+    ))))
+}
+
+fn parameter(procedure: &str, value: &Value) -> Result<Rc<Parameter>, Error> {
+    match value {
+        Value::Parameter(parameter) => Ok(Rc::clone(parameter)),
+        other => Err(wrong_type(procedure, "parameter", other)),
+    }
+}
+
+// Convert a value the way the parameter converts the values it is given.
+fn parameter_convert(args: &[Value]) -> Result<Tail, Error> {
+    match &parameter("parameterize", &args[0])?.converter {
+        Some(converter) => Ok(Tail::Call(converter.clone(), vec![args[1].clone()])),
+        None => Ok(Tail::Value(args[1].clone())),
+    }
+}
+
+fn parameter_set(args: &[Value]) -> Result<Value, Error> {
+    parameter("parameterize", &args[0])?
+        .value
+        .replace(args[1].clone());
+    Ok(Value::Unspecified)
+}
+
+fn with_exception_handler(args: &[Value]) -> Result<Tail, Error> {
+    Ok(Tail::WithExceptionHandler(args[0].clone(), args[1].clone()))
+}
+
+fn raise(args: &[Value]) -> Result<Tail, Error> {
+    Ok(Tail::Raise(args[0].clone()))
+}
+
+fn raise_continuable(args: &[Value]) -> Result<Tail, Error> {
+    Ok(Tail::RaiseContinuable(args[0].clone()))
+}
+
+fn error(args: &[Value]) -> Result<Tail, Error> {
+    let message = match &args[0] {
+        Value::String(s) => s.borrow().clone(),
+        other => return Err(wrong_type("error", "string", other)),
+    };
+    let object = ErrorObject::new(message, args[1..].to_vec());
+    Ok(Tail::Raise(Value::ErrorObject(gc::alloc(object))))
+}
+
+fn is_error_object(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::ErrorObject(_))))
+}
+
+fn error_object(procedure: &str, value: &Value) -> Result<Rc<ErrorObject>, Error> {
+    match value {
+        Value::ErrorObject(object) => Ok(Rc::clone(object)),
+        other => Err(wrong_type(procedure, "error object", other)),
+    }
+}
+
+fn error_object_message(args: &[Value]) -> Result<Value, Error> {
+    let object = error_object("error-object-message", &args[0])?;
+    Ok(Value::string(object.message.as_str()))
+}
+
+fn error_object_irritants(args: &[Value]) -> Result<Value, Error> {
+    let object = error_object("error-object-irritants", &args[0])?;
+    let irritants = object.irritants.borrow();
+    Ok(irritants.iter().rev().fold(Value::Null, |cdr, car| {
+        Value::Pair(gc::alloc(Pair::new(car.clone(), cdr)))
+    }))
+}
+
+fn is_read_error(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(
+        &args[0],
+        Value::ErrorObject(object) if object.kind == ErrorKind::Read
+    )))
+}
+
+fn is_file_error(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(
+        &args[0],
+        Value::ErrorObject(object) if object.kind == ErrorKind::File
+    )))
+}
+
+fn dynamic_wind(args: &[Value]) -> Result<Tail, Error> {
+    Ok(Tail::DynamicWind(
+        args[0].clone(),
+        args[1].clone(),
+        args[2].clone(),
+    ))
+}
+
+// eq? is eqv?: the only objects eqv? tells apart that eq? may not are
+// numbers and characters, which are never shared.
+fn eqv(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(args[0].eqv(&args[1])))
+}
+
+fn equal(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(args[0].equal(&args[1])))
+}
+
+fn set_car(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::Pair(p) => p.set_car(args[1].clone()),
+        other => return Err(wrong_type("set-car!", "pair", other)),
+    }
+    Ok(Value::Unspecified)
+}
+
+fn set_cdr(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::Pair(p) => p.set_cdr(args[1].clone()),
+        other => return Err(wrong_type("set-cdr!", "pair", other)),
+    }
+    Ok(Value::Unspecified)
+}
+
+// A length argument, an exact non-negative integer.
+fn length(procedure: &str, value: &Value) -> Result<usize, Error> {
+    match value {
+        Value::Number(Number::Integer(k)) if *k >= 0 => Ok(*k as usize),
+        other => Err(wrong_type(procedure, "exact non-negative integer", other)),
+    }
+}
+
+// An index argument, which must be less than the length of the object it
+// indexes.
+fn index(procedure: &str, value: &Value, length: usize) -> Result<usize, Error> {
+    match value {
+        Value::Number(Number::Integer(k)) if *k >= 0 && (*k as usize) < length => Ok(*k as usize),
+        Value::Number(Number::Integer(k)) => Err(Error::IndexOutOfRange {
+            procedure: procedure.to_string(),
+            index: *k,
+            length,
+        }),
+        other => Err(wrong_type(procedure, "exact integer", other)),
+    }
+}
+
+fn is_vector(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::Vector(_))))
+}
+
+fn vector(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::vector(args.to_vec()))
+}
+
+fn make_vector(args: &[Value]) -> Result<Value, Error> {
+    let k = length("make-vector", &args[0])?;
+    let fill = args.get(1).cloned().unwrap_or(Value::Unspecified);
+    Ok(Value::vector(vec![fill; k]))
+}
+
+fn vector_length(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::Vector(v) => Ok(Value::Number(Number::Integer(v.borrow().len() as i64))),
+        other => Err(wrong_type("vector-length", "vector", other)),
+    }
+}
+
+fn vector_ref(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::Vector(v) => {
+            let v = v.borrow();
+            Ok(v[index("vector-ref", &args[1], v.len())?].clone())
+        }
+        other => Err(wrong_type("vector-ref", "vector", other)),
+    }
+}
+
+fn vector_set(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::Vector(v) => {
+            let k = index("vector-set!", &args[1], v.borrow().len())?;
+            v.borrow_mut()[k] = args[2].clone();
+            Ok(Value::Unspecified)
+        }
+        other => Err(wrong_type("vector-set!", "vector", other)),
+    }
+}
+
+fn make_string(args: &[Value]) -> Result<Value, Error> {
+    let k = length("make-string", &args[0])?;
+    let fill = match args.get(1) {
+        None => ' ',
+        Some(Value::Character(c)) => *c,
+        Some(other) => return Err(wrong_type("make-string", "char", other)),
+    };
+    Ok(Value::string(
+        std::iter::repeat_n(fill, k).collect::<String>(),
+    ))
+}
+
+fn string_ref(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::String(s) => {
+            let s = s.borrow();
+            let k = index("string-ref", &args[1], s.chars().count())?;
+            Ok(Value::Character(s.chars().nth(k).unwrap()))
+        }
+        other => Err(wrong_type("string-ref", "string", other)),
+    }
+}
+
+fn string_set(args: &[Value]) -> Result<Value, Error> {
+    let c = match &args[2] {
+        Value::Character(c) => *c,
+        other => return Err(wrong_type("string-set!", "char", other)),
+    };
+    match &args[0] {
+        Value::String(s) => {
+            let mut s = s.borrow_mut();
+            let k = index("string-set!", &args[1], s.chars().count())?;
+            // Strings are stored as UTF-8, so the character is replaced by
+            // its byte range, which may change length.
+            let (start, old) = s.char_indices().nth(k).unwrap();
+            s.replace_range(start..start + old.len_utf8(), c.encode_utf8(&mut [0; 4]));
+            Ok(Value::Unspecified)
+        }
+        other => Err(wrong_type("string-set!", "string", other)),
+    }
+}
+
+fn is_bytevector(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::Bytevector(_))))
+}
+
+// A byte argument, an exact integer from 0 to 255.
+fn byte(procedure: &str, value: &Value) -> Result<u8, Error> {
+    match value {
+        Value::Number(Number::Integer(b)) if (0..=255).contains(b) => Ok(*b as u8),
+        other => Err(wrong_type(procedure, "byte", other)),
+    }
+}
+
+fn bytevector(args: &[Value]) -> Result<Value, Error> {
+    let bytes = args
+        .iter()
+        .map(|arg| byte("bytevector", arg))
+        .collect::<Result<Vec<u8>, Error>>()?;
+    Ok(Value::bytevector(bytes))
+}
+
+fn make_bytevector(args: &[Value]) -> Result<Value, Error> {
+    let k = length("make-bytevector", &args[0])?;
+    let fill = match args.get(1) {
+        None => 0,
+        Some(value) => byte("make-bytevector", value)?,
+    };
+    Ok(Value::bytevector(vec![fill; k]))
+}
+
+fn bytevector_length(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::Bytevector(b) => Ok(Value::Number(Number::Integer(b.borrow().len() as i64))),
+        other => Err(wrong_type("bytevector-length", "bytevector", other)),
+    }
+}
+
+fn bytevector_u8_ref(args: &[Value]) -> Result<Value, Error> {
+    match &args[0] {
+        Value::Bytevector(b) => {
+            let b = b.borrow();
+            let k = index("bytevector-u8-ref", &args[1], b.len())?;
+            Ok(Value::Number(Number::Integer(b[k] as i64)))
+        }
+        other => Err(wrong_type("bytevector-u8-ref", "bytevector", other)),
+    }
+}
+
+fn bytevector_u8_set(args: &[Value]) -> Result<Value, Error> {
+    let value = byte("bytevector-u8-set!", &args[2])?;
+    match &args[0] {
+        Value::Bytevector(b) => {
+            let mut b = b.borrow_mut();
+            let k = index("bytevector-u8-set!", &args[1], b.len())?;
+            b[k] = value;
+            Ok(Value::Unspecified)
+        }
+        other => Err(wrong_type("bytevector-u8-set!", "bytevector", other)),
+    }
+}
+
+// The write procedures write to standard output, the current output port.
+
+fn write(args: &[Value]) -> Result<Value, Error> {
+    print!("{}", args[0]);
+    Ok(Value::Unspecified)
+}
+
+fn write_shared(args: &[Value]) -> Result<Value, Error> {
+    print!("{}", args[0].write_shared());
+    Ok(Value::Unspecified)
+}
+
+fn write_simple(args: &[Value]) -> Result<Value, Error> {
+    print!("{}", args[0].write_simple());
+    Ok(Value::Unspecified)
+}
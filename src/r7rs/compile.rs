@@ -0,0 +1,1077 @@
+//! Compilation of the AST into Code, the handful of core forms the evaluator
+//! runs. Derived expressions are rewritten into core forms here, so that the
+//! evaluator only has to know about variables, constants, assignment,
+//! conditionals, lambda and procedure calls. The rewrites follow the
+//! definitions of the derived forms in R7RS section 7.3, calling the
+//! procedures they need as constants so that rebinding their names in the
+//! program does not change what the forms mean.
+//!
+//! Code is shared through `Rc`, so that a continuation can hold on to the
+//! code it has still to run for as long as the continuation lives.
+
+use crate::r7rs::ast::{
+    Body, CDef, CDefKind, CaseClause, CaseLambdaClause, ClauseBody, CondClause, Definition,
+    Derived, Expression, ExpressionKind, Formals, Literal, MvBinding, Span, Template,
+    TemplateElement,
+};
+use crate::r7rs::builtins;
+use crate::r7rs::value::{Number, Value};
+use std::fmt;
+use std::rc::Rc;
+
+pub type Code = Rc<Node>;
+
+pub enum Node {
+    Constant(Value),
+    Variable(String),
+    Set(String, Code),
+    /// Bind an identifier in the innermost frame.
+    Define(String, Code),
+    If(Code, Code, Code),
+    Lambda(Rc<Lambda>),
+    /// A procedure call: the operator and then the operands.
+    Call(Code, Rc<[Code]>),
+    /// A non-empty sequence, evaluated in order for the value of the last.
+    Sequence(Rc<[Code]>),
+    DefineValues(Formals, Code),
+    /// A define-record-type definition, which is always a
+    /// `Definition::RecordType`.
+    DefineRecordType(Rc<Definition>),
+    Include {
+        files: Vec<String>,
+        fold_case: bool,
+        span: Span,
+    },
+}
+
+// Code writes as the Scheme it stands for. Identifiers the compiler
+// introduces are written between bars, as symbols holding a space are.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Constant(value @ (Value::Symbol(_) | Value::Pair(_) | Value::Null)) => {
+                write!(f, "'{}", value)
+            }
+            Node::Constant(value) => write!(f, "{}", value),
+            Node::Variable(name) => write!(f, "{}", identifier(name)),
+            Node::Set(name, code) => write!(f, "(set! {} {})", identifier(name), code),
+            Node::Define(name, code) => write!(f, "(define {} {})", identifier(name), code),
+            Node::If(test, consequent, alternate) => {
+                write!(f, "(if {} {} {})", test, consequent, alternate)
+            }
+            Node::Lambda(lambda) => {
+                write!(f, "(lambda {} {})", Written(&lambda.formals), lambda.code)
+            }
+            Node::Call(operator, operands) => {
+                write!(f, "({}", operator)?;
+                for operand in operands.iter() {
+                    write!(f, " {}", operand)?;
+                }
+                write!(f, ")")
+            }
+            Node::Sequence(forms) => {
+                write!(f, "(begin")?;
+                for form in forms.iter() {
+                    write!(f, " {}", form)?;
+                }
+                write!(f, ")")
+            }
+            Node::DefineValues(formals, code) => {
+                write!(f, "(define-values {} {})", Written(formals), code)
+            }
+            Node::DefineRecordType(definition) => write!(f, "{}", definition),
+            Node::Include {
+                files, fold_case, ..
+            } => {
+                write!(f, "({}", if *fold_case { "include-ci" } else { "include" })?;
+                for file in files.iter() {
+                    write!(f, " {}", Value::string(file.as_str()))?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+fn identifier(name: &str) -> Value {
+    Value::Symbol(name.to_string())
+}
+
+// Formals written with their identifiers written as symbols.
+struct Written<'a>(&'a Formals);
+impl fmt::Display for Written<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let required: Vec<String> = self
+            .0
+            .required
+            .iter()
+            .map(|name| identifier(name).to_string())
+            .collect();
+        match (&self.0.rest, required.is_empty()) {
+            (Some(rest), true) => write!(f, "{}", identifier(rest)),
+            (Some(rest), false) => write!(f, "({} . {})", required.join(" "), identifier(rest)),
+            (None, _) => write!(f, "({})", required.join(" ")),
+        }
+    }
+}
+
+/// A compiled lambda. The body it was compiled from is kept to write the
+/// procedures made from it. A lambda the compiler makes for a derived form
+/// that binds what the program passes it is named after that form.
+pub struct Lambda {
+    pub formals: Formals,
+    pub body: Rc<Body>,
+    pub code: Code,
+    pub form: Option<String>,
+}
+impl Lambda {
+    pub fn new(formals: Formals, body: Rc<Body>) -> Lambda {
+        let code = compile_body(&body);
+        Lambda {
+            formals,
+            body,
+            code,
+            form: None,
+        }
+    }
+
+    /// What errors in calls of the lambda call it: the form it was made
+    /// for, or the lambda itself.
+    pub fn name(&self) -> String {
+        match &self.form {
+            Some(form) => form.clone(),
+            None => format!("(lambda {} ...)", self.formals),
+        }
+    }
+}
+
+// Identifiers the compiler introduces hold a space. An identifier only holds
+// one if it is written between bars or made with string->symbol, so the
+// program's own identifiers do not capture or shadow them unless they are
+// written to.
+const TEMPORARY: &str = " t";
+const LOOP: &str = " loop";
+const CONDITION: &str = " condition";
+const KEY: &str = " key";
+const ARGUMENTS: &str = " arguments";
+const SWAP: &str = " swap";
+const GUARD_K: &str = " guard-k";
+const HANDLER_K: &str = " handler-k";
+
+/// Compile a top-level command or definition.
+pub fn compile_cdef(cdef: &CDef) -> Code {
+    match &cdef.kind {
+        CDefKind::Command(expression) => compile(expression),
+        CDefKind::Definition(definition) => compile_definition(definition),
+        CDefKind::Begin(cdefs) => sequence(cdefs.iter().map(compile_cdef).collect()),
+    }
+}
+
+pub fn compile(expression: &Expression) -> Code {
+    match &expression.kind {
+        ExpressionKind::Identifier(name) => variable(name),
+        ExpressionKind::Literal(literal) => constant(match literal {
+            Literal::Number(n) => Value::Number(n.clone()),
+            Literal::String(s) => Value::string(s.as_str()),
+            Literal::Bool(b) => Value::Boolean(*b),
+            Literal::Char(c) => Value::Character(*c),
+            Literal::Vector(elements) => Value::Vector(Rc::clone(elements)),
+            Literal::Bytevector(bytes) => Value::bytevector(bytes.clone()),
+            Literal::Quotation(datum) => datum.clone(),
+        }),
+        ExpressionKind::ProcedureCall(operator, operands) => call(
+            compile(&operator.0),
+            operands.iter().map(|operand| compile(&operand.0)).collect(),
+        ),
+        ExpressionKind::Lambda { formals, body } => lambda(formals.clone(), Rc::clone(body)),
+        ExpressionKind::Conditional {
+            test,
+            consequent,
+            alternate,
+        } => Rc::new(Node::If(
+            compile(test),
+            compile(consequent),
+            match alternate {
+                Some(alternate) => compile(alternate),
+                None => unspecified(),
+            },
+        )),
+        ExpressionKind::Assignment {
+            identifier,
+            expression,
+        } => Rc::new(Node::Set(identifier.clone(), compile(expression))),
+        ExpressionKind::DerivedExpression(derived) => compile_derived(derived),
+        ExpressionKind::Includer { fold_case, files } => Rc::new(Node::Include {
+            files: files.clone(),
+            fold_case: *fold_case,
+            span: expression.span,
+        }),
+    }
+}
+
+fn compile_definition(definition: &Definition) -> Code {
+    match definition {
+        Definition::Variable {
+            identifier,
+            expression,
+        } => Rc::new(Node::Define(identifier.clone(), compile(expression))),
+        Definition::Values {
+            formals: identifiers,
+            body,
+        } => Rc::new(Node::DefineValues(
+            identifiers.clone(),
+            call(lambda(formals(&[]), Rc::new(body.clone())), Vec::new()),
+        )),
+        Definition::RecordType { .. } => {
+            Rc::new(Node::DefineRecordType(Rc::new(definition.clone())))
+        }
+        Definition::Begin(definitions) => {
+            sequence(definitions.iter().map(compile_definition).collect())
+        }
+    }
+}
+
+// A body's definitions become defines in the frame of the procedure call or
+// let it is the body of, followed by its expressions.
+fn compile_body(body: &Body) -> Code {
+    sequence(
+        body.definitions
+            .iter()
+            .map(compile_definition)
+            .chain(body.sequence.iter().map(compile))
+            .collect(),
+    )
+}
+
+fn compile_sequence(expressions: &[Expression]) -> Code {
+    sequence(expressions.iter().map(compile).collect())
+}
+
+fn compile_derived(derived: &Derived) -> Code {
+    match derived {
+        Derived::Cond {
+            clauses,
+            else_clause,
+        } => compile_cond(
+            clauses,
+            match else_clause {
+                Some(sequence) => compile_sequence(sequence),
+                None => unspecified(),
+            },
+        ),
+        // (if test (and rest ...) #f)
+        Derived::And(tests) => {
+            let mut tests = tests.iter().rev().map(compile);
+            let mut code = match tests.next() {
+                Some(last) => last,
+                None => constant(Value::Boolean(true)),
+            };
+            for test in tests {
+                code = Rc::new(Node::If(test, code, constant(Value::Boolean(false))));
+            }
+            code
+        }
+        Derived::Or(tests) => {
+            let mut tests = tests.iter().rev().map(compile);
+            let mut code = match tests.next() {
+                Some(last) => last,
+                None => constant(Value::Boolean(false)),
+            };
+            for test in tests {
+                code = or(test, code);
+            }
+            code
+        }
+        Derived::When { test, sequence } => Rc::new(Node::If(
+            compile(test),
+            compile_sequence(sequence),
+            unspecified(),
+        )),
+        Derived::Unless { test, sequence } => Rc::new(Node::If(
+            compile(test),
+            unspecified(),
+            compile_sequence(sequence),
+        )),
+        // ((lambda (identifier ...) body) init ...)
+        Derived::Let { bindings, body } => call(
+            lambda(
+                formals(
+                    &bindings
+                        .iter()
+                        .map(|b| b.identifier.as_str())
+                        .collect::<Vec<_>>(),
+                ),
+                Rc::new(body.clone()),
+            ),
+            bindings.iter().map(|b| compile(&b.init)).collect(),
+        ),
+        // ((letrec ((name (lambda (identifier ...) body))) name) init ...)
+        Derived::NamedLet {
+            name,
+            bindings,
+            body,
+        } => {
+            let procedure = lambda(
+                formals(
+                    &bindings
+                        .iter()
+                        .map(|b| b.identifier.as_str())
+                        .collect::<Vec<_>>(),
+                ),
+                Rc::clone(body),
+            );
+            call(
+                letrec(vec![(name.clone(), procedure)], variable(name)),
+                bindings.iter().map(|b| compile(&b.init)).collect(),
+            )
+        }
+        // (let ((first init)) (let* (rest ...) body))
+        Derived::LetStar { bindings, body } => {
+            let mut code = scope(body);
+            for binding in bindings.iter().rev() {
+                code = call(
+                    closure(formals(&[binding.identifier.as_str()]), code),
+                    vec![compile(&binding.init)],
+                );
+            }
+            code
+        }
+        // Both bind every identifier before evaluating any init, then
+        // assign the inits in order, which is what letrec* requires and
+        // letrec allows.
+        Derived::Letrec { bindings, body } | Derived::LetrecStar { bindings, body } => letrec(
+            bindings
+                .iter()
+                .map(|b| (b.identifier.clone(), compile(&b.init)))
+                .collect(),
+            compile_body(body),
+        ),
+        Derived::Begin(sequence) => compile_sequence(sequence),
+        // (let loop ((identifier init) ...)
+        //   (if test
+        //       (begin result ...)
+        //       (begin command ... (loop step ...))))
+        Derived::Do {
+            specs,
+            test,
+            result,
+            commands,
+        } => {
+            let identifiers: Vec<&str> = specs.iter().map(|s| s.identifier.as_str()).collect();
+            let steps = specs
+                .iter()
+                .map(|spec| match &spec.step {
+                    Some(step) => compile(step),
+                    None => variable(&spec.identifier),
+                })
+                .collect();
+            let iteration = commands
+                .iter()
+                .map(compile)
+                .chain(std::iter::once(call(variable(LOOP), steps)))
+                .collect();
+            let result = match result.len() {
+                0 => unspecified(),
+                _ => compile_sequence(result),
+            };
+            let procedure = closure(
+                formals(&identifiers),
+                Rc::new(Node::If(compile(test), result, sequence(iteration))),
+            );
+            call(
+                letrec(vec![(LOOP.to_string(), procedure)], variable(LOOP)),
+                specs.iter().map(|s| compile(&s.init)).collect(),
+            )
+        }
+        Derived::Case {
+            key,
+            clauses,
+            else_clause,
+        } => compile_case(key, clauses, else_clause.as_ref()),
+        Derived::LetValues { bindings, body } => compile_let_values(bindings, body),
+        // (call-with-values (lambda () init)
+        //   (lambda formals (let*-values (rest ...) body)))
+        Derived::LetStarValues { bindings, body } => {
+            let mut code = scope(body);
+            for binding in bindings.iter().rev() {
+                code = call(
+                    builtin("call-with-values"),
+                    vec![
+                        thunk(compile(&binding.init)),
+                        named(
+                            binding.formals.clone(),
+                            code,
+                            Some(values_form("let*-values", &binding.formals)),
+                        ),
+                    ],
+                );
+            }
+            code
+        }
+        // (make-lazy-promise (lambda () (make-eager-promise expression)))
+        Derived::Delay(expression) => call(
+            builtin("make-lazy-promise"),
+            vec![thunk(call(
+                builtin("make-eager-promise"),
+                vec![compile(expression)],
+            ))],
+        ),
+        // (make-lazy-promise (lambda () expression))
+        Derived::DelayForce(expression) => call(
+            builtin("make-lazy-promise"),
+            vec![thunk(compile(expression))],
+        ),
+        Derived::Parameterize { bindings, body } => compile_parameterize(bindings, body),
+        Derived::Guard {
+            variable: identifier,
+            clauses,
+            else_clause,
+            body,
+        } => compile_guard(identifier, clauses, else_clause.as_deref(), body),
+        Derived::Quasiquote(template) => compile_template(template),
+        Derived::CaseLambda(clauses) => compile_case_lambda(clauses),
+    }
+}
+
+// A template is built with cons and append where it unquotes something, and
+// is a constant everywhere else:
+//
+// `(a ,b ,@c . d) => (cons 'a (cons b (append c 'd)))
+// `#(a ,b) => (apply vector (cons 'a (cons b '())))
+fn compile_template(template: &Template) -> Code {
+    match template {
+        Template::Datum(datum) => constant(datum.clone()),
+        Template::Unquote(expression) => compile(expression),
+        Template::List(elements, tail) => compile_elements(elements, compile_template(tail)),
+        Template::Vector(elements) => call(
+            builtin("apply"),
+            vec![
+                builtin("vector"),
+                compile_elements(elements, constant(Value::Null)),
+            ],
+        ),
+    }
+}
+
+fn compile_elements(elements: &[TemplateElement], tail: Code) -> Code {
+    elements
+        .iter()
+        .rev()
+        .fold(tail, |rest, element| match element {
+            TemplateElement::Template(template) => {
+                call(builtin("cons"), vec![compile_template(template), rest])
+            }
+            TemplateElement::Splice(expression) => {
+                call(builtin("append"), vec![compile(expression), rest])
+            }
+        })
+}
+
+// Every init is evaluated outside the scope of all the formals, so their
+// values are bound to temporaries first, and to the formals only once all
+// have been evaluated.
+//
+// (call-with-values (lambda () init)
+//   (lambda temporaries
+//     ...
+//     ((lambda (identifier ...) body) temporary ...)))
+fn compile_let_values(bindings: &[MvBinding], body: &Body) -> Code {
+    let mut identifiers: Vec<String> = Vec::new();
+    let mut bound = Vec::new();
+    for binding in bindings.iter() {
+        let first = identifiers.len();
+        identifiers.extend(binding.formals.required.iter().cloned());
+        let required = (first..identifiers.len()).map(temporary).collect();
+        let rest = binding.formals.rest.as_ref().map(|rest| {
+            identifiers.push(rest.clone());
+            temporary(identifiers.len() - 1)
+        });
+        bound.push(Formals { required, rest });
+    }
+    let temporaries = (0..identifiers.len())
+        .map(|i| variable(&temporary(i)))
+        .collect();
+    let mut code = call(
+        lambda(
+            Formals {
+                required: identifiers,
+                rest: None,
+            },
+            Rc::new(body.clone()),
+        ),
+        temporaries,
+    );
+    for (binding, formals) in bindings.iter().zip(bound).rev() {
+        let form = values_form("let-values", &binding.formals);
+        code = call(
+            builtin("call-with-values"),
+            vec![
+                thunk(compile(&binding.init)),
+                named(formals, code, Some(form)),
+            ],
+        );
+    }
+    code
+}
+
+// How errors name the binding of `formals` by a let-values or let*-values.
+fn values_form(keyword: &str, formals: &Formals) -> String {
+    format!("({} (({} ...)) ...)", keyword, formals)
+}
+
+// The name of the temporary the `i`th identifier a form binds is bound to
+// while it evaluates the rest of its inits.
+fn temporary(i: usize) -> String {
+    format!("{} {}", TEMPORARY, i)
+}
+
+// (let ((key key))
+//   (if (or (eqv? key 'datum) ...)
+//       body
+//       ...))
+fn compile_case(
+    key: &Expression,
+    clauses: &[CaseClause],
+    else_clause: Option<&ClauseBody>,
+) -> Code {
+    let body = |body: &ClauseBody| match body {
+        ClauseBody::Sequence(sequence) => compile_sequence(sequence),
+        ClauseBody::Recipient(recipient) => call(compile(recipient), vec![variable(KEY)]),
+    };
+    let mut code = match else_clause {
+        Some(else_clause) => body(else_clause),
+        None => unspecified(),
+    };
+    for clause in clauses.iter().rev() {
+        // eqv? returns a boolean, so (or a b) can be (if a #t b).
+        let mut tests = clause.data.iter().rev().map(|datum| {
+            call(
+                builtin("eqv?"),
+                vec![variable(KEY), constant(datum.clone())],
+            )
+        });
+        let mut test = tests
+            .next()
+            .unwrap_or_else(|| constant(Value::Boolean(false)));
+        for eqv in tests {
+            test = Rc::new(Node::If(eqv, constant(Value::Boolean(true)), test));
+        }
+        code = Rc::new(Node::If(test, body(&clause.body), code));
+    }
+    call(closure(formals(&[KEY]), code), vec![compile(key)])
+}
+
+// Each clause becomes a procedure, and the procedure case-lambda evaluates
+// to applies the first that accepts as many arguments as it is given.
+//
+// ((lambda (clause ...)
+//    (lambda arguments
+//      ((lambda (t)
+//         (if (= t required) (apply clause arguments)
+//             (if (< t required) ... (apply clause arguments))))
+//       (length arguments))))
+//  (lambda formals body) ...)
+fn compile_case_lambda(clauses: &[CaseLambdaClause]) -> Code {
+    let mut dispatch = call(
+        builtin("error"),
+        vec![
+            constant(Value::string(
+                "case-lambda: no clause accepts the arguments",
+            )),
+            variable(ARGUMENTS),
+        ],
+    );
+    for (i, clause) in clauses.iter().enumerate().rev() {
+        let apply = call(
+            builtin("apply"),
+            vec![variable(&temporary(i)), variable(ARGUMENTS)],
+        );
+        let required = constant(Value::Number(Number::Integer(
+            clause.formals.required.len() as i64,
+        )));
+        dispatch = match clause.formals.rest {
+            None => Rc::new(Node::If(
+                call(builtin("="), vec![variable(TEMPORARY), required]),
+                apply,
+                dispatch,
+            )),
+            Some(_) => Rc::new(Node::If(
+                call(builtin("<"), vec![variable(TEMPORARY), required]),
+                dispatch,
+                apply,
+            )),
+        };
+    }
+    let procedure = closure(
+        Formals {
+            required: Vec::new(),
+            rest: Some(ARGUMENTS.to_string()),
+        },
+        call(
+            closure(formals(&[TEMPORARY]), dispatch),
+            vec![call(builtin("length"), vec![variable(ARGUMENTS)])],
+        ),
+    );
+    call(
+        closure(
+            Formals {
+                required: (0..clauses.len()).map(temporary).collect(),
+                rest: None,
+            },
+            procedure,
+        ),
+        clauses
+            .iter()
+            .map(|clause| lambda(clause.formals.clone(), Rc::clone(&clause.body)))
+            .collect(),
+    )
+}
+
+// The parameters take their new values on entering the body and get their
+// old ones back on leaving it, by swapping each value with the parameter's.
+// The values are converted by the parameters' converters first.
+//
+// ((lambda (parameter value ...)
+//    (set! value (parameter-convert parameter value)) ...
+//    ((lambda (swap)
+//       (dynamic-wind swap (lambda () body) swap))
+//     (lambda ()
+//       ((lambda (t) (parameter-set! parameter value) (set! value t))
+//        (parameter))
+//       ...)))
+//  parameter value ...)
+fn compile_parameterize(bindings: &[(Expression, Expression)], body: &Body) -> Code {
+    let parameter = |i: usize| temporary(2 * i);
+    let value = |i: usize| temporary(2 * i + 1);
+    let mut forms = Vec::new();
+    let mut swaps = Vec::new();
+    for i in 0..bindings.len() {
+        forms.push(Rc::new(Node::Set(
+            value(i),
+            call(
+                builtin("parameter-convert"),
+                vec![variable(&parameter(i)), variable(&value(i))],
+            ),
+        )));
+        let swap = sequence(vec![
+            call(
+                builtin("parameter-set!"),
+                vec![variable(&parameter(i)), variable(&value(i))],
+            ),
+            Rc::new(Node::Set(value(i), variable(TEMPORARY))),
+        ]);
+        swaps.push(call(
+            closure(formals(&[TEMPORARY]), swap),
+            vec![call(variable(&parameter(i)), Vec::new())],
+        ));
+    }
+    forms.push(call(
+        closure(
+            formals(&[SWAP]),
+            call(
+                builtin("dynamic-wind"),
+                vec![
+                    variable(SWAP),
+                    lambda(formals(&[]), Rc::new(body.clone())),
+                    variable(SWAP),
+                ],
+            ),
+        ),
+        vec![thunk(sequence(swaps))],
+    ));
+    call(
+        closure(
+            Formals {
+                required: (0..2 * bindings.len()).map(temporary).collect(),
+                rest: None,
+            },
+            sequence(forms),
+        ),
+        bindings
+            .iter()
+            .flat_map(|(parameter, value)| [compile(parameter), compile(value)])
+            .collect(),
+    )
+}
+
+// The clauses of a cond, falling through to `otherwise` if no test is true.
+fn compile_cond(clauses: &[CondClause], otherwise: Code) -> Code {
+    let mut code = otherwise;
+    for clause in clauses.iter().rev() {
+        let test = compile(&clause.test);
+        code = match &clause.body {
+            None => or(test, code),
+            Some(ClauseBody::Sequence(sequence)) => {
+                Rc::new(Node::If(test, compile_sequence(sequence), code))
+            }
+            // (let ((t test)) (if t (recipient t) code))
+            Some(ClauseBody::Recipient(recipient)) => {
+                let body = Rc::new(Node::If(
+                    variable(TEMPORARY),
+                    call(compile(recipient), vec![variable(TEMPORARY)]),
+                    code,
+                ));
+                call(closure(formals(&[TEMPORARY]), body), vec![test])
+            }
+        };
+    }
+    code
+}
+
+// The expansion R7RS gives for guard. The clauses are evaluated in the
+// dynamic environment of the guard, and if none applies the condition is
+// raised again in the dynamic environment of the original raise.
+//
+// ((call/cc
+//    (lambda (guard-k)
+//      (with-exception-handler
+//        (lambda (condition)
+//          ((call/cc
+//             (lambda (handler-k)
+//               (guard-k
+//                 (lambda ()
+//                   (let ((identifier condition))
+//                     (cond clause ...
+//                           (else (handler-k
+//                                   (lambda ()
+//                                     (raise-continuable condition))))))))))))
+//        (lambda ()
+//          (let ((t (let () body)))
+//            (guard-k (lambda () t))))))))
+fn compile_guard(
+    identifier: &str,
+    clauses: &[CondClause],
+    else_clause: Option<&[Expression]>,
+    body: &Body,
+) -> Code {
+    let otherwise = match else_clause {
+        Some(sequence) => compile_sequence(sequence),
+        None => call(
+            variable(HANDLER_K),
+            vec![thunk(call(
+                builtin("raise-continuable"),
+                vec![variable(CONDITION)],
+            ))],
+        ),
+    };
+    let handling = call(
+        closure(formals(&[identifier]), compile_cond(clauses, otherwise)),
+        vec![variable(CONDITION)],
+    );
+    let handler = closure(
+        formals(&[CONDITION]),
+        call(
+            call(
+                builtin("call/cc"),
+                vec![closure(
+                    formals(&[HANDLER_K]),
+                    call(variable(GUARD_K), vec![thunk(handling)]),
+                )],
+            ),
+            Vec::new(),
+        ),
+    );
+    let body = thunk(call(
+        closure(
+            formals(&[TEMPORARY]),
+            call(variable(GUARD_K), vec![thunk(variable(TEMPORARY))]),
+        ),
+        vec![call(
+            lambda(formals(&[]), Rc::new(body.clone())),
+            Vec::new(),
+        )],
+    ));
+    call(
+        call(
+            builtin("call/cc"),
+            vec![closure(
+                formals(&[GUARD_K]),
+                call(builtin("with-exception-handler"), vec![handler, body]),
+            )],
+        ),
+        Vec::new(),
+    )
+}
+
+// (let ((t test)) (if t t otherwise))
+fn or(test: Code, otherwise: Code) -> Code {
+    let code = Rc::new(Node::If(
+        variable(TEMPORARY),
+        variable(TEMPORARY),
+        otherwise,
+    ));
+    call(closure(formals(&[TEMPORARY]), code), vec![test])
+}
+
+// ((lambda () (define identifier) ... (set! identifier init) ... body))
+fn letrec(bindings: Vec<(String, Code)>, body: Code) -> Code {
+    let mut forms: Vec<Code> = bindings
+        .iter()
+        .map(|(identifier, _)| Rc::new(Node::Define(identifier.clone(), unspecified())))
+        .collect();
+    for (identifier, init) in bindings {
+        forms.push(Rc::new(Node::Set(identifier, init)));
+    }
+    forms.push(body);
+    call(closure(formals(&[]), sequence(forms)), Vec::new())
+}
+
+// A body with a scope of its own: ((lambda () body)), or just the body if
+// it has no definitions to make in that scope.
+fn scope(body: &Body) -> Code {
+    match body.definitions.len() {
+        0 => compile_body(body),
+        _ => call(lambda(formals(&[]), Rc::new(body.clone())), Vec::new()),
+    }
+}
+
+fn lambda(formals: Formals, body: Rc<Body>) -> Code {
+    Rc::new(Node::Lambda(Rc::new(Lambda::new(formals, body))))
+}
+
+// A lambda the compiler makes, which has no source body.
+fn closure(formals: Formals, code: Code) -> Code {
+    named(formals, code, None)
+}
+
+// A lambda the compiler makes to bind the values of part of `form`, such as
+// the values of one init of let-values, which is the name errors give it.
+fn named(formals: Formals, code: Code, form: Option<String>) -> Code {
+    Rc::new(Node::Lambda(Rc::new(Lambda {
+        formals,
+        body: Rc::new(Body {
+            definitions: Vec::new(),
+            sequence: Vec::new(),
+        }),
+        code,
+        form,
+    })))
+}
+
+fn thunk(code: Code) -> Code {
+    closure(formals(&[]), code)
+}
+
+fn formals(identifiers: &[&str]) -> Formals {
+    Formals {
+        required: identifiers.iter().map(|i| i.to_string()).collect(),
+        rest: None,
+    }
+}
+
+fn call(operator: Code, operands: Vec<Code>) -> Code {
+    Rc::new(Node::Call(operator, Rc::from(operands)))
+}
+
+fn sequence(mut forms: Vec<Code>) -> Code {
+    match forms.len() {
+        0 => unspecified(),
+        1 => forms.pop().unwrap(),
+        _ => Rc::new(Node::Sequence(Rc::from(forms))),
+    }
+}
+
+fn variable(name: &str) -> Code {
+    Rc::new(Node::Variable(name.to_string()))
+}
+
+fn constant(value: Value) -> Code {
+    Rc::new(Node::Constant(value))
+}
+
+// A builtin procedure, called as itself whatever the program binds its name
+// to.
+fn builtin(name: &str) -> Code {
+    constant(builtins::builtin(name))
+}
+
+fn unspecified() -> Code {
+    constant(Value::Unspecified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r7rs::ast::Span;
+    use crate::r7rs::reader;
+    use crate::Interpreter;
+
+    fn compiled(input: &str) -> String {
+        let datum = reader::read_datum(input).unwrap();
+        compile_cdef(&CDef::from_datum(&datum, Span::default()).unwrap()).to_string()
+    }
+
+    fn evaluated(interpreter: &mut Interpreter, input: &str) -> String {
+        match interpreter.eval_str(input) {
+            Ok(value) => value.to_string(),
+            Err(e) => panic!("{}: {}", input, e),
+        }
+    }
+
+    fn check(tests: &[(&str, &str)]) {
+        let mut interpreter = Interpreter::new();
+        for (input, output) in tests.iter() {
+            assert_eq!(evaluated(&mut interpreter, input), *output, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_lambda_application() {
+        // The one structural check: a let is the application of a lambda.
+        assert_eq!(
+            compiled("(let ((x 1) (y 2)) (f x y))"),
+            "((lambda (x y) (f x y)) 1 2)"
+        );
+    }
+
+    #[test]
+    fn test_conditionals() {
+        check(&[
+            (
+                "(define (f x) (if (pair? x) (car x) #f))
+                 (list (cond ((f '(2)) => (lambda (t) (* t 10))) (else 'no))
+                       (cond ((f 1) 1) ((cdr '(1 3 5))) (else 'no))
+                       (cond ((f 1) 1) ((f '()) 2) (else 'no 'else)))",
+                "(20 (3 5) else)",
+            ),
+            // The key of a case is evaluated once.
+            (
+                "(define n 0)
+                 (define (key) (set! n (+ n 1)) 'c)
+                 (list (case (key) ((a b) 1) ((c) => (lambda (k) (list k n))) (else 'no))
+                       (case 5 ((1) 'one) (else => (lambda (k) (* k 2))))
+                       (case 2.0 ((2) 'exact) (else 'inexact))
+                       n)",
+                "((c 1) 10 inexact 1)",
+            ),
+            (
+                "(list (and) (and 1 2 3) (and 1 #f 3) (or) (or #f 2) (or #f #f))",
+                "(#t 3 #f #f 2 #f)",
+            ),
+            // Each operand of an or is evaluated once.
+            (
+                "(define m 0)
+                 (list (or (begin (set! m (+ m 1)) m) 'no) m)",
+                "(1 1)",
+            ),
+            (
+                "(list (when (= 1 1) 'a 'b) (unless (= 1 2) 'c 'd))",
+                "(b d)",
+            ),
+            // The temporaries do not capture the program's identifiers.
+            (
+                "(define t 'mine)
+                 (define key '(mine too))
+                 (list (or #f t) (cond (1 => (lambda (x) t))) (case 1 ((1) key)))",
+                "(mine mine (mine too))",
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_bindings() {
+        check(&[
+            ("(let ((x 1) (y 2)) (list x y))", "(1 2)"),
+            (
+                "(let loop ((i 0) (acc '())) (if (= i 3) acc (loop (+ i 1) (cons i acc))))",
+                "(2 1 0)",
+            ),
+            ("(let* ((a 1) (b (+ a 1))) (list a b))", "(1 2)"),
+            (
+                "(letrec ((even? (lambda (n) (if (= n 0) #t (odd? (- n 1)))))
+                          (odd? (lambda (n) (if (= n 0) #f (even? (- n 1))))))
+                   (list (even? 10) (odd? 10)))",
+                "(#t #f)",
+            ),
+            ("(letrec* ((a 1) (b (+ a 1))) (list a b))", "(1 2)"),
+            ("(begin 1 2 3)", "3"),
+            (
+                "(do ((i 0 (+ i 1)) (acc '() (cons i acc)) (k 'fixed)) ((= i 3) (list acc k)))",
+                "((2 1 0) fixed)",
+            ),
+            // The body of a do is run for its effects on each iteration.
+            (
+                "(define v (make-vector 3 0))
+                 (do ((i 0 (+ i 1))) ((= i 3) v) (vector-set! v i (* i i)))",
+                "#(0 1 4)",
+            ),
+            // A named let's loop variable does not leak into the inits.
+            (
+                "(define (loop x) 'outer)
+                 (let loop ((x (loop 1))) x)",
+                "outer",
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_case_lambda() {
+        check(&[
+            (
+                "(define f (case-lambda ((x) (list 'one x)) ((x y . z) (list 'many x y z))))
+                 (list (f 1) (f 1 2) (f 1 2 3 4))",
+                "((one 1) (many 1 2 ()) (many 1 2 (3 4)))",
+            ),
+            (
+                "(define g (case-lambda ((x y) 'two) (all all)))
+                 (list (g 1 2) (g) (g 1 2 3))",
+                "(two () (1 2 3))",
+            ),
+        ]);
+        // An application no clause accepts is an error.
+        let mut interpreter = Interpreter::new();
+        assert!(interpreter
+            .eval_str("((case-lambda ((x) x) ((x y z) x)) 1 2)")
+            .is_err());
+    }
+
+    #[test]
+    fn test_quasiquote() {
+        check(&[
+            ("`(1 (2))", "(1 (2))"),
+            (
+                "(define x 2) (define l '(3 4)) `(1 ,x ,@l . t)",
+                "(1 2 3 4 . t)",
+            ),
+            ("`#(1 ,x ,@l)", "#(1 2 3 4)"),
+            ("`(1 `,(2 ,x))", "(1 (quasiquote (unquote (2 2))))"),
+            // The program's bindings of list procedures do not change how a
+            // template is built.
+            ("(let ((cons list) (append list)) `(1 ,x ,@l))", "(1 2 3 4)"),
+        ]);
+    }
+
+    #[test]
+    fn test_delay() {
+        check(&[
+            (
+                "(define n 0)
+                 (define p (delay (begin (set! n (+ n 1)) n)))
+                 (list (force p) (force p) n)",
+                "(1 1 1)",
+            ),
+            (
+                "(define (stream-from k) (delay-force (if (= k 1000) (delay k) (stream-from (+ k 1)))))
+                 (force (stream-from 0))",
+                "1000",
+            ),
+            ("(list (promise? (delay 1)) (force (make-promise 2)))", "(#t 2)"),
+        ]);
+    }
+
+    #[test]
+    fn test_parameterize() {
+        check(&[
+            (
+                "(define p (make-parameter 1 (lambda (x) (* x 10))))
+                 (list (p) (parameterize ((p 2)) (p)) (p))",
+                "(10 20 10)",
+            ),
+            // Escaping from the body restores the parameter.
+            (
+                "(list (call/cc (lambda (k) (parameterize ((p 3)) (k (p))))) (p))",
+                "(30 10)",
+            ),
+        ]);
+    }
+}
@@ -1,23 +1,66 @@
+use crate::r7rs::error::Error;
+use crate::r7rs::gc::{self, trace_value, Trace};
+use crate::r7rs::value::Value;
 use crate::r7rs::vars::Variable;
+use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::rc::Rc;
 
+/// An Environment is one frame of bindings. Frames are shared through `Rc`
+/// so that a closure keeps the frame it was created in alive, and are
+/// allocated with `gc::alloc` since a closure bound in its own frame makes a
+/// cycle.
 pub struct Environment {
-    vars: BTreeMap<String, Variable>,
-    parent: Option<Box<Environment>>,
+    vars: RefCell<BTreeMap<String, Variable>>,
+    parent: Option<Rc<Environment>>,
 }
 impl Environment {
     pub fn new() -> Environment {
         Environment {
-            vars: BTreeMap::new(),
+            vars: RefCell::new(BTreeMap::new()),
             parent: None,
         }
     }
+    /// Create an empty frame whose lookups fall through to `parent`.
+    pub fn extend(parent: &Rc<Environment>) -> Environment {
+        Environment {
+            vars: RefCell::new(BTreeMap::new()),
+            parent: Some(Rc::clone(parent)),
+        }
+    }
     pub fn keys(&self) -> Vec<String> {
-        self.vars.keys().map(|x| x.to_string()).collect()
+        self.vars.borrow().keys().map(|x| x.to_string()).collect()
+    }
+    /// Bind `key` in this frame, replacing any existing binding.
+    pub fn define(&self, key: &str, value: Value) {
+        self.vars
+            .borrow_mut()
+            .insert(key.to_string(), Variable::new(key, value));
+    }
+    /// Bind `key` in this frame to the variable `name` of `env`, as an
+    /// import does. The two frames share the variable, so that the frame
+    /// sees what `env` later defines `name` to.
+    pub fn import(&self, key: &str, env: &Rc<Environment>, name: &str) {
+        self.vars
+            .borrow_mut()
+            .insert(key.to_string(), Variable::imported(key, env, name));
+    }
+    /// Rebind `key` in the nearest frame that binds it, as set! does.
+    pub fn set(&self, key: &str, value: Value) -> Result<(), Error> {
+        let mut frame = self;
+        loop {
+            if let Some(var) = frame.vars.borrow_mut().get_mut(key) {
+                return var.set(value);
+            }
+            frame = match &frame.parent {
+                Some(parent) => parent,
+                None => return Err(Error::UnboundVariable(key.to_string())),
+            };
+        }
     }
-    pub fn get(&self, key: &str) -> Option<&Variable> {
-        match self.vars.get(key) {
-            Some(v) => Some(v.clone().to_owned()),
+    pub fn get(&self, key: &str) -> Option<Value> {
+        match self.vars.borrow().get(key) {
+            Some(v) => v.value(),
             None => match &self.parent {
                 Some(p) => p.get(key),
                 None => None,
@@ -25,3 +68,31 @@ impl Environment {
         }
     }
 }
+impl Trace for Environment {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        for var in self.vars.borrow().values() {
+            match var {
+                Variable::Own { value, .. } => trace_value(value, visit),
+                Variable::Imported { env, .. } => visit(gc::identity(env)),
+            }
+        }
+        if let Some(parent) = &self.parent {
+            visit(gc::identity(parent));
+        }
+    }
+    fn clear(&self, trash: &mut Vec<Value>) {
+        let vars = std::mem::take(&mut *self.vars.borrow_mut());
+        // An imported variable's environment is a heap object, not a value,
+        // but dropping the reference is safe: the collector holds its own
+        // until clearing is done.
+        trash.extend(vars.into_values().filter_map(|var| match var {
+            Variable::Own { value, .. } => Some(value),
+            Variable::Imported { .. } => None,
+        }));
+    }
+}
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::new()
+    }
+}
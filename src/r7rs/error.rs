@@ -0,0 +1,235 @@
+use crate::parser::Rule;
+use crate::r7rs::ast::Span;
+use crate::r7rs::value::{Arity, Value};
+
+/// The Error enum represents everything that can go wrong between reading
+/// Scheme source text and producing a Value from it.
+#[derive(Debug)]
+pub enum Error {
+    /// The source text does not match the R7RS grammar or holds a malformed
+    /// literal.
+    Parse(ParseError),
+    /// A form matches the grammar but is malformed, for example a record
+    /// constructor naming a field the record type does not have.
+    BadSyntax(String),
+    /// A source file could not be read.
+    Io(std::io::Error),
+    /// An identifier was referenced that has no binding in scope.
+    UnboundVariable(String),
+    /// A value that is not a procedure was called.
+    NotAProcedure(String),
+    /// A procedure was called with the wrong number of arguments.
+    WrongArgumentCount {
+        procedure: String,
+        expected: Arity,
+        given: usize,
+    },
+    /// A procedure was called with an argument of the wrong type.
+    WrongType {
+        procedure: String,
+        expected: String,
+        given: String,
+    },
+    /// An index passed to a procedure is outside the object it indexes.
+    IndexOutOfRange {
+        procedure: String,
+        index: i64,
+        length: usize,
+    },
+    /// The program uses a form the evaluator does not handle yet.
+    Unsupported(String),
+    /// A library is imported that is not standard, not defined by a program
+    /// and not found on the library path.
+    UnknownLibrary(String),
+    /// The program raised an object, with raise or error, and no handler
+    /// handled it.
+    Raised(Value),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "parse error: {}", e),
+            Error::BadSyntax(message) => write!(f, "bad syntax: {}", message),
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::UnboundVariable(name) => write!(f, "unbound variable: {}", name),
+            Error::NotAProcedure(value) => write!(f, "not a procedure: {}", value),
+            Error::WrongArgumentCount {
+                procedure,
+                expected,
+                given,
+            } => write!(
+                f,
+                "{}: expected {} argument(s), given {}",
+                procedure, expected, given
+            ),
+            Error::WrongType {
+                procedure,
+                expected,
+                given,
+            } => write!(f, "{}: expected {}, given {}", procedure, expected, given),
+            Error::IndexOutOfRange {
+                procedure,
+                index,
+                length,
+            } => write!(
+                f,
+                "{}: index {} out of range for length {}",
+                procedure, index, length
+            ),
+            Error::Unsupported(what) => write!(f, "unsupported: {}", what),
+            Error::UnknownLibrary(name) => write!(f, "unknown library: {}", name),
+            Error::Raised(Value::ErrorObject(e)) => write!(f, "error: {}", e),
+            Error::Raised(value) => write!(f, "uncaught exception: {}", value),
+        }
+    }
+}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Error {
+        Error::Parse(e)
+    }
+}
+
+/// An error raised expanding a form or lowering what it expands into, with
+/// where the innermost form it was raised in was written, if that form was
+/// read from source text.
+#[derive(Debug)]
+pub struct Located {
+    pub error: Box<Error>,
+    pub span: Option<Span>,
+}
+impl Located {
+    /// Place the error at `span` unless it is placed already.
+    pub fn at(mut self, span: Span) -> Located {
+        self.span.get_or_insert(span);
+        self
+    }
+}
+impl From<Error> for Located {
+    fn from(error: Error) -> Located {
+        Located {
+            error: Box::new(error),
+            span: None,
+        }
+    }
+}
+impl From<ParseError> for Located {
+    fn from(e: ParseError) -> Located {
+        Located::from(Error::Parse(e))
+    }
+}
+impl From<Located> for Error {
+    fn from(e: Located) -> Error {
+        *e.error
+    }
+}
+
+/// A ParseError points at the place in the source text where reading
+/// failed and says what was expected there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// The name of the source, a file path or `<string>`.
+    pub source: String,
+    /// The 1-based line of the error.
+    pub line: usize,
+    /// The 1-based column of the error, counted in characters.
+    pub column: usize,
+    /// The source line the error is on.
+    pub snippet: String,
+    /// What was expected at the error position.
+    pub expected: String,
+}
+impl ParseError {
+    /// An error at the start of `span`. The source is named `<string>` until
+    /// `with_source` gives it a name.
+    pub fn new(span: pest::Span, expected: impl Into<String>) -> ParseError {
+        let position = span.start_pos();
+        let (line, column) = position.line_col();
+        ParseError {
+            source: "<string>".to_string(),
+            line,
+            column,
+            snippet: position
+                .line_of()
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+            expected: expected.into(),
+        }
+    }
+    pub fn with_source(mut self, source: &str) -> ParseError {
+        self.source = source.to_string();
+        self
+    }
+}
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: expected {}\n{}\n{:>width$}",
+            self.source,
+            self.line,
+            self.column,
+            self.expected,
+            self.snippet,
+            "^",
+            width = self.column
+        )
+    }
+}
+impl std::error::Error for ParseError {}
+impl From<pest::error::Error<Rule>> for ParseError {
+    fn from(e: pest::error::Error<Rule>) -> ParseError {
+        let (line, column) = match e.line_col {
+            pest::error::LineColLocation::Pos(position) => position,
+            pest::error::LineColLocation::Span(start, _) => start,
+        };
+        let expected = match &e.variant {
+            pest::error::ErrorVariant::ParsingError { positives, .. } if !positives.is_empty() => {
+                let mut rules: Vec<&str> = Vec::new();
+                for name in positives.iter().map(rule_name) {
+                    if !rules.contains(&name) {
+                        rules.push(name);
+                    }
+                }
+                match rules.len() {
+                    1 => rules[0].to_string(),
+                    _ => format!("one of {}", rules.join(", ")),
+                }
+            }
+            pest::error::ErrorVariant::ParsingError { .. } => "valid syntax".to_string(),
+            pest::error::ErrorVariant::CustomError { message } => message.clone(),
+        };
+        ParseError {
+            source: "<string>".to_string(),
+            line,
+            column,
+            snippet: e.line().to_string(),
+            expected,
+        }
+    }
+}
+
+// A readable name for a grammar rule in an "expected ..." message.
+fn rule_name(rule: &Rule) -> &'static str {
+    match rule {
+        Rule::EOI => "end of input",
+        Rule::datum => "a datum",
+        Rule::identifier => "an identifier",
+        Rule::string => "a string",
+        Rule::number => "a number",
+        Rule::byte => "a byte",
+        _ => "valid syntax",
+    }
+}
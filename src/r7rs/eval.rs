@@ -0,0 +1,826 @@
+//! The evaluator runs compiled Code on a machine whose continuation is a
+//! stack of frames on the heap rather than on the Rust stack. Deep recursion
+//! therefore cannot overflow the Rust stack, tail calls push no frame, and
+//! call/cc captures the continuation by sharing its frames.
+//!
+//! Errors the machine detects are raised as error objects, so that a handler
+//! installed by the program can catch them. An error object no handler
+//! handles becomes the `Error` it was made from.
+
+use crate::r7rs::ast::{self, CDef, Definition, Formals, Source, Span, AST};
+use crate::r7rs::compile::{compile_cdef, Code, Node};
+use crate::r7rs::env::Environment;
+use crate::r7rs::error::Error;
+use crate::r7rs::expand::expand;
+use crate::r7rs::gc::{self, trace_value, Trace};
+use crate::r7rs::value::{
+    Arity, ErrorObject, Pair, Parameter, Procedure, Promise, RecordProcedure, RecordProcedureKind,
+    RecordType, Value,
+};
+use std::cell::{Cell, RefCell};
+use std::path::Path;
+use std::rc::Rc;
+
+/// What a control primitive asks the evaluator to do in its place.
+pub enum Tail {
+    /// Return a value.
+    Value(Value),
+    /// Call a procedure, as a tail call.
+    Call(Value, Vec<Value>),
+    /// Call a procedure with the current continuation, as call/cc does.
+    CallWithContinuation(Value),
+    /// Call the second thunk, calling the first whenever control enters its
+    /// dynamic extent and the third whenever control leaves it.
+    DynamicWind(Value, Value, Value),
+    /// Call the first procedure and then the second with the values it
+    /// returns, as call-with-values does.
+    CallWithValues(Value, Value),
+    /// Make a parameter with the value the converter converts the first
+    /// value to.
+    MakeParameter(Value, Value),
+    /// Call the thunk with the handler installed for exceptions it raises.
+    WithExceptionHandler(Value, Value),
+    /// Raise an object, which the handler may not return from.
+    Raise(Value),
+    /// Raise an object, returning what the handler returns.
+    RaiseContinuable(Value),
+    /// Force a promise that is not done yet by calling its thunk.
+    Force(Rc<Promise>),
+}
+
+/// Evaluate a top-level command or definition. Definitions evaluate to the
+/// unspecified value.
+pub fn eval_cdef(cdef: &CDef, env: &Rc<Environment>) -> Result<Value, Error> {
+    Machine::new().run(compile_cdef(cdef), Rc::clone(env))
+}
+
+/// Call a procedure with arguments on a machine of its own, as the
+/// expander calls the procedures of low-level transformers.
+pub(crate) fn apply(
+    procedure: &Value,
+    args: Vec<Value>,
+    env: &Rc<Environment>,
+) -> Result<Value, Error> {
+    let constant = |value| Rc::new(Node::Constant(value));
+    let code = Rc::new(Node::Call(
+        constant(procedure.clone()),
+        args.into_iter().map(constant).collect(),
+    ));
+    Machine::new().run(code, Rc::clone(env))
+}
+
+/// A continuation captured by call/cc. Calling it returns to the frames it
+/// holds, running the before and after thunks of dynamic-wind on the way.
+pub struct Continuation {
+    stack: Stack,
+    winders: Winders,
+    handlers: Handlers,
+}
+impl Trace for Continuation {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        if let Some(link) = &self.stack {
+            visit(gc::identity(link));
+        }
+        if let Some(wind) = &self.winders {
+            visit(gc::identity(wind));
+        }
+        if let Some(handler) = &self.handlers {
+            visit(gc::identity(handler));
+        }
+    }
+    fn clear(&self, _: &mut Vec<Value>) {}
+}
+
+// The frames of a continuation, innermost first. Frames are shared by the
+// continuations captured while they are on the stack, and by the machine.
+type Stack = Option<Rc<Link>>;
+
+struct Link {
+    frame: Frame,
+    next: Stack,
+    // Frames are registered with the collector when a continuation captures
+    // them, rather than on every push.
+    registered: Cell<bool>,
+}
+impl Trace for Link {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        self.frame.trace(visit);
+        if let Some(next) = &self.next {
+            visit(gc::identity(next));
+        }
+    }
+    fn clear(&self, _: &mut Vec<Value>) {}
+}
+// Dropping a deep stack would otherwise recurse once per frame.
+impl Drop for Link {
+    fn drop(&mut self) {
+        let mut next = self.next.take();
+        while let Some(link) = next {
+            match Rc::try_unwrap(link) {
+                Ok(mut link) => next = link.next.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+// The dynamic-wind calls whose dynamic extent control is in, innermost
+// first.
+type Winders = Option<Rc<Wind>>;
+
+pub struct Wind {
+    before: Value,
+    after: Value,
+    next: Winders,
+    depth: usize,
+}
+impl Trace for Wind {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        trace_value(&self.before, visit);
+        trace_value(&self.after, visit);
+        if let Some(next) = &self.next {
+            visit(gc::identity(next));
+        }
+    }
+    fn clear(&self, _: &mut Vec<Value>) {}
+}
+
+// The installed exception handlers, innermost first.
+type Handlers = Option<Rc<Handler>>;
+
+pub struct Handler {
+    handler: Value,
+    next: Handlers,
+}
+impl Trace for Handler {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        trace_value(&self.handler, visit);
+        if let Some(next) = &self.next {
+            visit(gc::identity(next));
+        }
+    }
+    fn clear(&self, _: &mut Vec<Value>) {}
+}
+
+fn depth(winders: &Winders) -> usize {
+    winders.as_ref().map_or(0, |wind| wind.depth)
+}
+
+// What the machine does with the value returned to a frame. Frames for a
+// form keep the form's node to find its parts again.
+#[derive(Clone)]
+enum Frame {
+    // The test of an If has been evaluated.
+    If(Code, Rc<Environment>),
+    // The value of a Set, Define or DefineValues has been evaluated.
+    Set(Code, Rc<Environment>),
+    Define(Code, Rc<Environment>),
+    DefineValues(Code, Rc<Environment>),
+    // Form `index` of a Sequence has been evaluated.
+    Sequence(Code, usize, Rc<Environment>),
+    // The operator and the first operands of a Call have been evaluated.
+    Call(Code, Vec<Value>, Rc<Environment>),
+    // The before thunk of a dynamic-wind has returned.
+    WindBefore {
+        before: Value,
+        thunk: Value,
+        after: Value,
+    },
+    // The thunk of a dynamic-wind has returned.
+    WindThunk {
+        after: Value,
+    },
+    // An after thunk has returned; return the value it was called after.
+    Discard(Value),
+    // The thunk of a with-exception-handler, or a handler, has returned;
+    // reinstall the handlers it was called with.
+    Handlers(Handlers),
+    // A handler has returned from a raise that is not continuable.
+    Raised(Value),
+    // The producer of a call-with-values has returned; call the consumer.
+    Values(Value),
+    // The converter of a new parameter has returned its value.
+    Parameter(Value),
+    // The thunk of a promise being forced has returned.
+    Force(Rc<Promise>),
+    // Control is passing to a continuation. Step `index` of `steps`, a
+    // before or after thunk to call with the winders given, has returned.
+    Reroot {
+        steps: Rc<[(Value, Winders)]>,
+        index: usize,
+        winders: Winders,
+        value: Value,
+    },
+}
+impl Frame {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        match self {
+            Frame::If(_, env)
+            | Frame::Set(_, env)
+            | Frame::Define(_, env)
+            | Frame::DefineValues(_, env)
+            | Frame::Sequence(_, _, env) => visit(gc::identity(env)),
+            Frame::Call(_, values, env) => {
+                for value in values.iter() {
+                    trace_value(value, visit);
+                }
+                visit(gc::identity(env));
+            }
+            Frame::WindBefore {
+                before,
+                thunk,
+                after,
+            } => {
+                trace_value(before, visit);
+                trace_value(thunk, visit);
+                trace_value(after, visit);
+            }
+            Frame::WindThunk { after } => trace_value(after, visit),
+            Frame::Discard(value)
+            | Frame::Raised(value)
+            | Frame::Values(value)
+            | Frame::Parameter(value) => trace_value(value, visit),
+            Frame::Handlers(handlers) => {
+                if let Some(handler) = handlers {
+                    visit(gc::identity(handler));
+                }
+            }
+            Frame::Force(promise) => visit(gc::identity(promise)),
+            // The steps are not a heap object, so the collector counts what
+            // they hold as held from outside the heap.
+            Frame::Reroot { winders, value, .. } => {
+                if let Some(wind) = winders {
+                    visit(gc::identity(wind));
+                }
+                trace_value(value, visit);
+            }
+        }
+    }
+}
+
+enum State {
+    Eval(Code, Rc<Environment>),
+    Return(Value),
+    Apply(Value, Vec<Value>),
+}
+
+struct Machine {
+    stack: Stack,
+    winders: Winders,
+    handlers: Handlers,
+}
+impl Machine {
+    fn new() -> Machine {
+        Machine {
+            stack: None,
+            winders: None,
+            handlers: None,
+        }
+    }
+
+    fn push(&mut self, frame: Frame) {
+        self.stack = Some(Rc::new(Link {
+            frame,
+            next: self.stack.take(),
+            registered: Cell::new(false),
+        }));
+    }
+
+    // Take the innermost frame, copying it if a continuation shares it.
+    fn pop(&mut self) -> Option<Frame> {
+        let link = self.stack.take()?;
+        match Rc::try_unwrap(link) {
+            Ok(mut link) => {
+                self.stack = link.next.take();
+                // The frame is moved out and the link dropped empty.
+                Some(std::mem::replace(
+                    &mut link.frame,
+                    Frame::Discard(Value::Null),
+                ))
+            }
+            Err(link) => {
+                self.stack = link.next.clone();
+                Some(link.frame.clone())
+            }
+        }
+    }
+
+    fn run(&mut self, code: Code, env: Rc<Environment>) -> Result<Value, Error> {
+        let mut state = State::Eval(code, env);
+        loop {
+            let step = match state {
+                State::Eval(code, env) => self.eval(code, env),
+                State::Apply(procedure, args) => self.apply(procedure, args),
+                State::Return(value) => match self.pop() {
+                    Some(frame) => self.resume(frame, value),
+                    None => return Ok(value),
+                },
+            };
+            state = match step {
+                Ok(state) => state,
+                Err(error) if self.handlers.is_none() => return Err(error),
+                Err(error) => {
+                    let object = Value::ErrorObject(gc::alloc(ErrorObject::from(error)));
+                    self.raise(object, false)?
+                }
+            }
+        }
+    }
+
+    // Call the innermost handler with a raised object, with the handlers
+    // outside it installed. Returning from the handler returns to the raise
+    // if it is continuable, and is an error if not.
+    fn raise(&mut self, object: Value, continuable: bool) -> Result<State, Error> {
+        let handler = match self.handlers.take() {
+            Some(handler) => handler,
+            None => return Err(uncaught(object)),
+        };
+        self.push(Frame::Handlers(Some(Rc::clone(&handler))));
+        if !continuable {
+            self.push(Frame::Raised(object.clone()));
+        }
+        self.handlers = handler.next.clone();
+        Ok(State::Apply(handler.handler.clone(), vec![object]))
+    }
+
+    fn eval(&mut self, code: Code, env: Rc<Environment>) -> Result<State, Error> {
+        Ok(match &*code {
+            Node::Constant(value) => State::Return(value.clone()),
+            Node::Variable(name) => match env.get(name) {
+                Some(value) => State::Return(value),
+                None => return Err(Error::UnboundVariable(name.to_string())),
+            },
+            Node::Set(_, value) => {
+                let value = Rc::clone(value);
+                self.push(Frame::Set(code, Rc::clone(&env)));
+                State::Eval(value, env)
+            }
+            Node::Define(_, value) => {
+                let value = Rc::clone(value);
+                self.push(Frame::Define(code, Rc::clone(&env)));
+                State::Eval(value, env)
+            }
+            Node::DefineValues(_, value) => {
+                let value = Rc::clone(value);
+                self.push(Frame::DefineValues(code, Rc::clone(&env)));
+                State::Eval(value, env)
+            }
+            Node::If(test, _, _) => {
+                let test = Rc::clone(test);
+                self.push(Frame::If(code, Rc::clone(&env)));
+                State::Eval(test, env)
+            }
+            Node::Lambda(lambda) => State::Return(Value::Procedure(gc::alloc(Procedure {
+                env,
+                lambda: Rc::clone(lambda),
+            }))),
+            Node::Call(operator, _) => {
+                let operator = Rc::clone(operator);
+                self.push(Frame::Call(code, Vec::new(), Rc::clone(&env)));
+                State::Eval(operator, env)
+            }
+            Node::Sequence(forms) => {
+                let first = Rc::clone(&forms[0]);
+                if forms.len() > 1 {
+                    self.push(Frame::Sequence(code, 0, Rc::clone(&env)));
+                }
+                State::Eval(first, env)
+            }
+            Node::DefineRecordType(definition) => {
+                define_record_type(definition, &env)?;
+                State::Return(Value::Unspecified)
+            }
+            Node::Include {
+                files,
+                fold_case,
+                span,
+            } => State::Eval(include(files, *fold_case, *span, &env)?, env),
+        })
+    }
+
+    fn resume(&mut self, frame: Frame, value: Value) -> Result<State, Error> {
+        Ok(match frame {
+            Frame::If(code, env) => match &*code {
+                Node::If(_, consequent, alternate) => match is_true(&value) {
+                    true => State::Eval(Rc::clone(consequent), env),
+                    false => State::Eval(Rc::clone(alternate), env),
+                },
+                _ => unreachable!("an If frame holds an If node"),
+            },
+            Frame::Set(code, env) => match &*code {
+                Node::Set(name, _) => {
+                    env.set(name, value)?;
+                    State::Return(Value::Unspecified)
+                }
+                _ => unreachable!("a Set frame holds a Set node"),
+            },
+            Frame::Define(code, env) => match &*code {
+                Node::Define(name, _) => {
+                    env.define(name, value);
+                    State::Return(Value::Unspecified)
+                }
+                _ => unreachable!("a Define frame holds a Define node"),
+            },
+            Frame::DefineValues(code, env) => match &*code {
+                Node::DefineValues(formals, _) => {
+                    bind_formals(formals, &value.into_values(), &env, || {
+                        format!("(define-values {} ...)", formals)
+                    })?;
+                    State::Return(Value::Unspecified)
+                }
+                _ => unreachable!("a DefineValues frame holds a DefineValues node"),
+            },
+            Frame::Sequence(code, index, env) => {
+                let forms = match &*code {
+                    Node::Sequence(forms) => forms,
+                    _ => unreachable!("a Sequence frame holds a Sequence node"),
+                };
+                let next = Rc::clone(&forms[index + 1]);
+                // The last form is evaluated in tail position.
+                if index + 2 < forms.len() {
+                    self.push(Frame::Sequence(
+                        Rc::clone(&code),
+                        index + 1,
+                        Rc::clone(&env),
+                    ));
+                }
+                State::Eval(next, env)
+            }
+            Frame::Call(code, mut values, env) => {
+                let operands = match &*code {
+                    Node::Call(_, operands) => operands,
+                    _ => unreachable!("a Call frame holds a Call node"),
+                };
+                values.push(value);
+                match operands.get(values.len() - 1) {
+                    Some(operand) => {
+                        let operand = Rc::clone(operand);
+                        self.push(Frame::Call(Rc::clone(&code), values, Rc::clone(&env)));
+                        State::Eval(operand, env)
+                    }
+                    None => {
+                        let procedure = values.remove(0);
+                        State::Apply(procedure, values)
+                    }
+                }
+            }
+            Frame::WindBefore {
+                before,
+                thunk,
+                after,
+            } => {
+                self.winders = Some(gc::alloc(Wind {
+                    before,
+                    after: after.clone(),
+                    depth: depth(&self.winders) + 1,
+                    next: self.winders.take(),
+                }));
+                self.push(Frame::WindThunk { after });
+                State::Apply(thunk, Vec::new())
+            }
+            Frame::WindThunk { after } => {
+                self.winders = self.winders.as_ref().and_then(|wind| wind.next.clone());
+                self.push(Frame::Discard(value));
+                State::Apply(after, Vec::new())
+            }
+            Frame::Discard(value) => State::Return(value),
+            Frame::Values(consumer) => State::Apply(consumer, value.into_values()),
+            Frame::Parameter(converter) => State::Return(Value::Parameter(gc::alloc(Parameter {
+                value: RefCell::new(value),
+                converter: Some(converter),
+            }))),
+            Frame::Handlers(handlers) => {
+                self.handlers = handlers;
+                State::Return(value)
+            }
+            // The thunk returns the promise the forced one stands for,
+            // unless forcing it again from within the thunk has already
+            // made it done.
+            Frame::Force(promise) => {
+                if !promise.is_done() {
+                    match &value {
+                        Value::Promise(other) => promise.update(other),
+                        other => {
+                            return Err(Error::WrongType {
+                                procedure: "force".to_string(),
+                                expected: "promise".to_string(),
+                                given: other.to_string(),
+                            })
+                        }
+                    }
+                }
+                self.force(promise)
+            }
+            Frame::Raised(object) => {
+                let error =
+                    ErrorObject::new("handler returned from non-continuable raise", vec![object]);
+                self.raise(Value::ErrorObject(gc::alloc(error)), false)?
+            }
+            Frame::Reroot {
+                steps,
+                index,
+                winders,
+                value,
+            } => match steps.get(index) {
+                Some((thunk, during)) => {
+                    let thunk = thunk.clone();
+                    self.winders = during.clone();
+                    self.push(Frame::Reroot {
+                        steps: Rc::clone(&steps),
+                        index: index + 1,
+                        winders,
+                        value,
+                    });
+                    State::Apply(thunk, Vec::new())
+                }
+                None => {
+                    self.winders = winders;
+                    State::Return(value)
+                }
+            },
+        })
+    }
+
+    fn apply(&mut self, procedure: Value, args: Vec<Value>) -> Result<State, Error> {
+        let arity = match &procedure {
+            Value::Primitive(primitive) => Some((primitive.name.to_string(), primitive.arity)),
+            Value::Control(control) => Some((control.name.to_string(), control.arity)),
+            Value::RecordProcedure(procedure) => Some((procedure.name.clone(), procedure.arity())),
+            Value::Parameter(_) => Some(("parameter".to_string(), Arity::Exactly(0))),
+            _ => None,
+        };
+        if let Some((name, arity)) = arity {
+            if !arity.accepts(args.len()) {
+                return Err(Error::WrongArgumentCount {
+                    procedure: name,
+                    expected: arity,
+                    given: args.len(),
+                });
+            }
+        }
+        Ok(match procedure {
+            Value::Primitive(primitive) => State::Return((primitive.func)(&args)?),
+            Value::Control(control) => match (control.func)(&args)? {
+                Tail::Value(value) => State::Return(value),
+                Tail::Call(procedure, args) => State::Apply(procedure, args),
+                Tail::CallWithContinuation(procedure) => {
+                    let continuation = self.capture();
+                    State::Apply(procedure, vec![continuation])
+                }
+                Tail::DynamicWind(before, thunk, after) => {
+                    self.push(Frame::WindBefore {
+                        before: before.clone(),
+                        thunk,
+                        after,
+                    });
+                    State::Apply(before, Vec::new())
+                }
+                Tail::CallWithValues(producer, consumer) => {
+                    self.push(Frame::Values(consumer));
+                    State::Apply(producer, Vec::new())
+                }
+                Tail::MakeParameter(value, converter) => {
+                    self.push(Frame::Parameter(converter.clone()));
+                    State::Apply(converter, vec![value])
+                }
+                Tail::WithExceptionHandler(handler, thunk) => {
+                    self.push(Frame::Handlers(self.handlers.clone()));
+                    self.handlers = Some(gc::alloc(Handler {
+                        handler,
+                        next: self.handlers.take(),
+                    }));
+                    State::Apply(thunk, Vec::new())
+                }
+                Tail::Raise(object) => self.raise(object, false)?,
+                Tail::RaiseContinuable(object) => self.raise(object, true)?,
+                Tail::Force(promise) => self.force(promise),
+            },
+            Value::Procedure(procedure) => {
+                gc::collect_if_due();
+                let frame = gc::alloc(Environment::extend(&procedure.env));
+                bind_formals(&procedure.lambda.formals, &args, &frame, || {
+                    procedure.lambda.name()
+                })?;
+                State::Eval(Rc::clone(&procedure.lambda.code), frame)
+            }
+            Value::RecordProcedure(procedure) => State::Return(procedure.call(&args)?),
+            Value::Parameter(parameter) => State::Return(parameter.value.borrow().clone()),
+            Value::Continuation(continuation) => {
+                let value = Value::values(args);
+                self.stack = continuation.stack.clone();
+                self.handlers = continuation.handlers.clone();
+                self.push(Frame::Reroot {
+                    steps: Rc::from(reroot(&self.winders, &continuation.winders)),
+                    index: 0,
+                    winders: continuation.winders.clone(),
+                    value,
+                });
+                State::Return(Value::Unspecified)
+            }
+            other => return Err(Error::NotAProcedure(other.to_string())),
+        })
+    }
+
+    // Return the value of a promise that is done, or call its thunk.
+    fn force(&mut self, promise: Rc<Promise>) -> State {
+        match promise.is_done() {
+            true => State::Return(promise.value()),
+            false => {
+                let thunk = promise.value();
+                self.push(Frame::Force(promise));
+                State::Apply(thunk, Vec::new())
+            }
+        }
+    }
+
+    // Capture the current continuation, registering its frames with the
+    // collector now that they may outlive the machine's use of them.
+    fn capture(&self) -> Value {
+        let mut link = self.stack.clone();
+        while let Some(current) = link {
+            if current.registered.replace(true) {
+                break;
+            }
+            gc::register(&current);
+            link = current.next.clone();
+        }
+        Value::Continuation(gc::alloc(Continuation {
+            stack: self.stack.clone(),
+            winders: self.winders.clone(),
+            handlers: self.handlers.clone(),
+        }))
+    }
+}
+
+// The error to return for an object no handler handled: the error an error
+// object was made from, if it was, or else the object itself.
+fn uncaught(object: Value) -> Error {
+    if let Value::ErrorObject(error) = &object {
+        if let Some(cause) = error.cause.borrow_mut().take() {
+            return cause;
+        }
+    }
+    Error::Raised(object)
+}
+
+// The thunks to call to pass from the dynamic extent of `from` into that of
+// `to`: the after thunks of the winds being left, innermost first, then the
+// before thunks of those being entered, outermost first. Each is called with
+// the winders in force around its dynamic-wind.
+fn reroot(from: &Winders, to: &Winders) -> Vec<(Value, Winders)> {
+    let mut leaving = Vec::new();
+    let mut entering = Vec::new();
+    let (mut from, mut to) = (from.clone(), to.clone());
+    loop {
+        let same = match (&from, &to) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        if same {
+            break;
+        }
+        if depth(&from) >= depth(&to) {
+            let wind = from.unwrap();
+            leaving.push((wind.after.clone(), wind.next.clone()));
+            from = wind.next.clone();
+        } else {
+            let wind = to.unwrap();
+            entering.push((wind.before.clone(), wind.next.clone()));
+            to = wind.next.clone();
+        }
+    }
+    leaving.extend(entering.into_iter().rev());
+    leaving
+}
+
+/// Only #f counts as false in a conditional test.
+fn is_true(value: &Value) -> bool {
+    !matches!(value, Value::Boolean(false))
+}
+
+// Bind the procedures define-record-type generates for a record type.
+fn define_record_type(definition: &Definition, env: &Rc<Environment>) -> Result<(), Error> {
+    let (name, constructor, predicate, fields) = match definition {
+        Definition::RecordType {
+            name,
+            constructor,
+            predicate,
+            fields,
+        } => (name, constructor, predicate, fields),
+        _ => unreachable!("a DefineRecordType node holds a record type definition"),
+    };
+    for (i, field) in fields.iter().enumerate() {
+        if fields[..i].iter().any(|earlier| earlier.name == field.name) {
+            return Err(Error::BadSyntax(format!(
+                "{}: duplicate field {}",
+                name, field.name
+            )));
+        }
+    }
+    for (i, field) in constructor.fields.iter().enumerate() {
+        if constructor.fields[..i].contains(field) {
+            return Err(Error::BadSyntax(format!(
+                "{}: duplicate field {}",
+                constructor.name, field
+            )));
+        }
+    }
+    let record_type = Rc::new(RecordType {
+        name: name.to_string(),
+        fields: fields.iter().map(|field| field.name.clone()).collect(),
+    });
+    let index = |field: &str| {
+        record_type.field_index(field).ok_or_else(|| {
+            Error::BadSyntax(format!("{}: no field named {}", constructor.name, field))
+        })
+    };
+    let indices = constructor
+        .fields
+        .iter()
+        .map(|field| index(field))
+        .collect::<Result<Vec<usize>, Error>>()?;
+    let mut procedures = vec![
+        (&constructor.name, RecordProcedureKind::Constructor(indices)),
+        (predicate, RecordProcedureKind::Predicate),
+    ];
+    for (i, field) in fields.iter().enumerate() {
+        procedures.push((&field.accessor, RecordProcedureKind::Accessor(i)));
+        if let Some(mutator) = &field.mutator {
+            procedures.push((mutator, RecordProcedureKind::Mutator(i)));
+        }
+    }
+    env.define(name, Value::RecordType(Rc::clone(&record_type)));
+    for (name, kind) in procedures {
+        let procedure = RecordProcedure {
+            name: name.to_string(),
+            record_type: Rc::clone(&record_type),
+            kind,
+        };
+        env.define(name, Value::RecordProcedure(Rc::new(procedure)));
+    }
+    Ok(())
+}
+
+/// Bind the arguments of a call in `frame`, collecting any arguments beyond
+/// the required ones into a list for the rest formal. Arity errors call
+/// what is called `procedure`.
+fn bind_formals(
+    formals: &Formals,
+    args: &[Value],
+    frame: &Environment,
+    procedure: impl FnOnce() -> String,
+) -> Result<(), Error> {
+    let required = formals.required.len();
+    let expected = match formals.rest {
+        Some(_) => Arity::AtLeast(required),
+        None => Arity::Exactly(required),
+    };
+    if !expected.accepts(args.len()) {
+        return Err(Error::WrongArgumentCount {
+            procedure: procedure(),
+            expected,
+            given: args.len(),
+        });
+    }
+    for (name, value) in formals.required.iter().zip(args.iter()) {
+        frame.define(name, value.clone());
+    }
+    if let Some(rest) = &formals.rest {
+        let list = args[required..].iter().rev().fold(Value::Null, |cdr, car| {
+            Value::Pair(gc::alloc(Pair::new(car.clone(), cdr)))
+        });
+        frame.define(rest, list);
+    }
+    Ok(())
+}
+
+// Read and compile the forms of each included file, to be evaluated in turn
+// as if they were written in place of the include. Relative paths are
+// resolved against the directory of the file holding the include.
+fn include(
+    files: &[String],
+    fold_case: bool,
+    span: Span,
+    env: &Rc<Environment>,
+) -> Result<Code, Error> {
+    let directory = ast::source_name(span.file)
+        .and_then(|name| Path::new(&name).parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+    let mut forms = Vec::new();
+    for file in files.iter() {
+        let path = directory.join(file);
+        let name = path.display().to_string();
+        let text = std::fs::read_to_string(&path)?;
+        let source = Source::with_folding(ast::register_source(&name), &text, fold_case);
+        let AST::Program { forms: spans, .. } =
+            AST::parse(&source).map_err(|e| Error::Parse(e.with_source(&name)))?;
+        for span in spans {
+            forms.extend(expand(span, &source, env)?.iter().map(compile_cdef));
+        }
+    }
+    Ok(match forms.len() {
+        0 => Rc::new(Node::Constant(Value::Unspecified)),
+        _ => Rc::new(Node::Sequence(Rc::from(forms))),
+    })
+}
@@ -0,0 +1,2550 @@
+//! The macro expander. Each command or definition is read as a datum and
+//! expanded into forms that only use the core syntax, and those are lowered
+//! into the AST as they are, data and values the macros insert and all. An
+//! identifier is taken for a keyword only where no variable of that name is
+//! in scope. A variable named like a keyword is written under its fresh
+//! name, and a global one is referred to in a begin, so that lowering never
+//! mistakes a call of it for the keyword's form.
+//!
+//! Expansion is hygienic by renaming. Every identifier a template inserts
+//! is replaced by a fresh alias that remembers the identifier and the scope
+//! the macro was defined in. An alias bound by the expansion is written
+//! under a fresh name, so it captures nothing the macro use wrote. A free
+//! alias means what its identifier meant where the macro was defined, and
+//! a binding around the macro use that would capture it is renamed instead.
+//!
+//! Besides syntax-rules, a transformer may be a procedure, evaluated in the
+//! global environment when its keyword is defined: an explicit-renaming or
+//! implicit-renaming transformer made by `er-macro-transformer` or
+//! `ir-macro-transformer`, or a procedure of one form as `syntax-case`
+//! takes apart. Syntax objects are the forms themselves, with the
+//! identifiers a transformer inserts as aliases.
+//!
+//! The forms are read noting where each list was written, and the lists
+//! the expander makes from them, the expansion of a macro use among them,
+//! are noted as written where the forms they were made from were. So the
+//! operands of a macro use keep their spans through its expansion, and an
+//! error raised expanding a form is placed at the innermost form around it
+//! that has a span. Errors raised evaluating what a command or definition
+//! expands into are placed at the top-level form they were raised in.
+
+use crate::r7rs::ast::{self, CDef, Source, Span};
+use crate::r7rs::builtins;
+use crate::r7rs::env::Environment;
+use crate::r7rs::error::{Error, Located};
+use crate::r7rs::eval::{self, Tail};
+use crate::r7rs::gc;
+use crate::r7rs::reader;
+use crate::r7rs::value::{Arity, Control, Number, Pair, Primitive, Value};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::{Rc, Weak};
+
+// The keywords of the forms the expander knows and the auxiliary syntax
+// they use.
+const SPECIAL_FORMS: &[&str] = &[
+    "quote",
+    "quasiquote",
+    "unquote",
+    "unquote-splicing",
+    "lambda",
+    "define",
+    "set!",
+    "if",
+    "let",
+    "let*",
+    "letrec",
+    "letrec*",
+    "let-values",
+    "let*-values",
+    "define-values",
+    "define-record-type",
+    "do",
+    "case-lambda",
+    "guard",
+    "parameterize",
+    "cond",
+    "case",
+    "and",
+    "or",
+    "when",
+    "unless",
+    "begin",
+    "delay",
+    "delay-force",
+    "include",
+    "include-ci",
+    "define-syntax",
+    "let-syntax",
+    "letrec-syntax",
+    "syntax-rules",
+    "syntax-case",
+    "syntax",
+    "with-syntax",
+    "else",
+    "=>",
+    "...",
+    "_",
+];
+
+thread_local! {
+    // The aliases made on this thread that may still be used, by the name
+    // they are written under. Those an expansion makes are forgotten when it
+    // is over unless what it leaves behind refers to them; those made
+    // outside one, as by a syntax template run after expansion, are kept.
+    static ALIASES: RefCell<HashMap<String, Rc<Alias>>> = RefCell::new(HashMap::new());
+    // What the expansion in progress has made so far.
+    static EXPANSION: RefCell<Expansion> = RefCell::new(Expansion::default());
+    // The number of the next fresh name.
+    static FRESH: Cell<usize> = const { Cell::new(0) };
+    // The calls of transformer procedures in progress, innermost last.
+    static TRANSCRIPTIONS: RefCell<Vec<Transcription>> = const { RefCell::new(Vec::new()) };
+}
+
+// The aliases and scopes the outermost expansion in progress has made, and
+// the forms and macros it leaves behind, which may refer to them. The
+// expansions of forms evaluated while it runs, such as transformer
+// expressions, are part of it.
+#[derive(Default)]
+struct Expansion {
+    depth: usize,
+    aliases: Vec<String>,
+    scopes: Vec<Weak<Scope>>,
+    kept: Vec<Value>,
+}
+impl Expansion {
+    // Forget the aliases nothing kept refers to, and empty the scopes
+    // nothing kept reaches, breaking the cycles between the scopes and the
+    // macros they bind.
+    fn sweep(self) {
+        let mut symbols = HashSet::new();
+        let mut reached = HashSet::new();
+        let mut stack = self.kept;
+        let mut scopes: Vec<Rc<Scope>> = Vec::new();
+        loop {
+            while let Some(value) = stack.pop() {
+                match value {
+                    Value::Symbol(symbol) => {
+                        if let Some(alias) = alias(&symbol) {
+                            stack.push(identifier(&alias.symbol));
+                            scopes.push(Rc::clone(&alias.scope));
+                        }
+                        symbols.insert(symbol);
+                    }
+                    Value::Pair(pair) if reached.insert(gc::identity(&pair)) => {
+                        stack.push(pair.car());
+                        stack.push(pair.cdr());
+                    }
+                    Value::Vector(vector) if reached.insert(gc::identity(&vector)) => {
+                        stack.extend(vector.borrow().iter().cloned());
+                    }
+                    Value::Macro(m) if reached.insert(Rc::as_ptr(&m) as usize) => {
+                        stack.push(identifier(&m.keyword));
+                        stack.extend(m.ellipsis.iter().map(|ellipsis| identifier(ellipsis)));
+                        stack.extend(m.literals.iter().map(|literal| identifier(literal)));
+                        for rule in m.rules.iter() {
+                            stack.push(rule.pattern.clone());
+                            stack.push(rule.template.clone());
+                        }
+                        scopes.push(Rc::clone(&m.scope));
+                    }
+                    _ => (),
+                }
+            }
+            let scope = match scopes.pop() {
+                Some(scope) => scope,
+                None => break,
+            };
+            if !reached.insert(Rc::as_ptr(&scope) as usize) {
+                continue;
+            }
+            for (symbol, denotation) in scope.bindings.borrow().iter() {
+                stack.push(identifier(symbol));
+                match denotation {
+                    Denotation::Macro(m) => stack.push(Value::Macro(Rc::clone(m))),
+                    Denotation::Global(name) => stack.push(identifier(name)),
+                    _ => (),
+                }
+            }
+            scopes.extend(scope.parent.iter().cloned());
+        }
+        let forgotten = ALIASES.with(|aliases| {
+            let mut aliases = aliases.borrow_mut();
+            self.aliases
+                .iter()
+                .filter(|name| !symbols.contains(*name))
+                .filter_map(|name| aliases.remove(name))
+                .collect::<Vec<Rc<Alias>>>()
+        });
+        for scope in self.scopes.iter().filter_map(Weak::upgrade) {
+            if !reached.contains(&(Rc::as_ptr(&scope) as usize)) {
+                scope.bindings.borrow_mut().clear();
+            }
+        }
+        drop(forgotten);
+    }
+}
+
+// Note that an expansion leaves `values` behind, as forms to evaluate or
+// macros bound in a global environment.
+fn keep(values: &[Value]) {
+    EXPANSION.with(|expansion| {
+        let mut expansion = expansion.borrow_mut();
+        if expansion.depth > 0 {
+            expansion.kept.extend(values.iter().cloned());
+        }
+    })
+}
+
+/// A macro: its keyword, its transformer and the scope it was defined in.
+/// The transformer is either syntax-rules or a procedure.
+pub struct Macro {
+    pub keyword: String,
+    ellipsis: Option<String>,
+    literals: Vec<String>,
+    rules: Vec<Rule>,
+    procedure: Option<(Convention, Value)>,
+    scope: Rc<Scope>,
+}
+
+// How a transformer procedure is called.
+#[derive(Clone, Copy)]
+enum Convention {
+    // With the form, a procedure renaming identifiers into the scope of the
+    // macro, and a procedure comparing identifiers of the form.
+    Explicit,
+    // With the form, a procedure injecting identifiers into the scope of the
+    // macro use, and the comparison. Every other identifier is renamed.
+    Implicit,
+    // With the form alone, as syntax-case transformers are.
+    Syntax,
+}
+
+// A call of a transformer procedure: the macro, the scope of its use and
+// the aliases made so far, so that the procedures the transformer calls
+// can rename, inject and compare identifiers.
+struct Transcription {
+    m: Rc<Macro>,
+    env: Rc<Environment>,
+    scope: Rc<Scope>,
+    renames: HashMap<String, String>,
+    // The identifiers of the form an implicit-renaming transformer is
+    // given, by the names they are hidden under.
+    injected: HashMap<String, String>,
+    hidden: HashMap<String, String>,
+}
+impl Transcription {
+    // The name an identifier of the macro use is hidden under while an
+    // implicit-renaming transformer runs.
+    fn inject(&mut self, symbol: &str) -> String {
+        if let Some(hidden) = self.hidden.get(symbol) {
+            return hidden.clone();
+        }
+        let hidden = fresh(&strip_symbol(symbol));
+        self.hidden.insert(symbol.to_string(), hidden.clone());
+        self.injected.insert(hidden.clone(), symbol.to_string());
+        hidden
+    }
+
+    // What an identifier the transformer was given or made means: one of
+    // the macro use where it is used, and one the transformer inserts where
+    // the macro was defined.
+    fn denotation(&self, symbol: &str) -> Denotation {
+        match (self.injected.get(symbol), self.m.procedure.as_ref()) {
+            (Some(injected), _) => resolve(&self.env, &self.scope, injected),
+            (None, Some((Convention::Implicit, _))) => resolve(&self.env, &self.m.scope, symbol),
+            (None, _) => resolve(&self.env, &self.scope, symbol),
+        }
+    }
+}
+
+// A syntax rule, with the depth of ellipses each pattern variable is under.
+struct Rule {
+    pattern: Value,
+    template: Value,
+    variables: HashMap<String, usize>,
+}
+
+// What a pattern variable matched: one form, or a sequence of matches for
+// each ellipsis it is under.
+#[derive(Clone)]
+enum Binding {
+    One(Value),
+    Many(Vec<Binding>),
+}
+
+impl Binding {
+    // The binding of a pattern variable under `depth` ellipses to what it
+    // holds at run time.
+    fn of(value: &Value, depth: usize) -> Result<Binding, Error> {
+        match depth {
+            0 => Ok(Binding::One(value.clone())),
+            _ => Ok(Binding::Many(
+                list(value)?
+                    .iter()
+                    .map(|value| Binding::of(value, depth - 1))
+                    .collect::<Result<_, _>>()?,
+            )),
+        }
+    }
+
+    // What a pattern variable of syntax-case holds at run time: the form it
+    // matched, or a list of what it matched for each repetition.
+    fn syntax(self) -> Value {
+        match self {
+            Binding::One(form) => form,
+            Binding::Many(bindings) => {
+                from_list(bindings.into_iter().map(Binding::syntax).collect())
+            }
+        }
+    }
+}
+
+// The pattern variables of a matched rule, with their depths.
+type Bindings = HashMap<String, (usize, Binding)>;
+
+// An identifier inserted by a template and the scope of the macro that
+// inserted it.
+struct Alias {
+    symbol: String,
+    scope: Rc<Scope>,
+}
+
+// A variable bound within the form being expanded. It is written under its
+// own name unless it is bound by an alias, would capture a free alias, or
+// is named like a keyword, which lowering would take it for.
+struct Variable {
+    name: String,
+    fresh: String,
+    renamed: Cell<bool>,
+}
+
+/// What an identifier means where it is used.
+#[derive(Clone)]
+enum Denotation {
+    Variable(Rc<Variable>),
+    Global(String),
+    Macro(Rc<Macro>),
+    Special(&'static str),
+    // A pattern variable of syntax-case, with the depth of ellipses it is
+    // under. At run time it holds what it matched.
+    Pattern(Rc<Variable>, usize),
+}
+impl Denotation {
+    fn same(&self, other: &Denotation) -> bool {
+        match (self, other) {
+            (Denotation::Variable(a), Denotation::Variable(b)) => Rc::ptr_eq(a, b),
+            (Denotation::Pattern(a, _), Denotation::Pattern(b, _)) => Rc::ptr_eq(a, b),
+            (Denotation::Global(a), Denotation::Global(b)) => a == b,
+            (Denotation::Macro(a), Denotation::Macro(b)) => Rc::ptr_eq(a, b),
+            (Denotation::Special(a), Denotation::Special(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// One scope of the syntactic environment: the identifiers a binding form
+/// binds. The outermost scope of a top-level form falls through to the
+/// global environment it is expanded in, which a macro defined there keeps
+/// referring to wherever it is used, as the macros a library exports do.
+/// The reference is weak, as the environment binds the macro.
+pub(crate) struct Scope {
+    bindings: RefCell<HashMap<String, Denotation>>,
+    parent: Option<Rc<Scope>>,
+    env: Option<Weak<Environment>>,
+}
+impl Scope {
+    fn extend(parent: Option<&Rc<Scope>>) -> Rc<Scope> {
+        Scope::made(Scope {
+            bindings: RefCell::new(HashMap::new()),
+            parent: parent.cloned(),
+            env: None,
+        })
+    }
+
+    fn root(env: &Rc<Environment>) -> Rc<Scope> {
+        Scope::made(Scope {
+            bindings: RefCell::new(HashMap::new()),
+            parent: None,
+            env: Some(Rc::downgrade(env)),
+        })
+    }
+
+    // A scope, noted as made by the expansion in progress, if any.
+    fn made(scope: Scope) -> Rc<Scope> {
+        let scope = Rc::new(scope);
+        EXPANSION.with(|expansion| {
+            let mut expansion = expansion.borrow_mut();
+            if expansion.depth > 0 {
+                expansion.scopes.push(Rc::downgrade(&scope));
+            }
+        });
+        scope
+    }
+
+    fn bind(&self, symbol: &str, denotation: Denotation) {
+        self.bindings
+            .borrow_mut()
+            .insert(symbol.to_string(), denotation);
+    }
+}
+
+/// Expand the command or definition of `source` written at `span` into the
+/// forms it stands for, which may be none.
+pub fn expand(span: Span, source: &Source, env: &Rc<Environment>) -> Result<Vec<CDef>, Located> {
+    let form = reader::read_form(source, span)?;
+    expand_datum(form, env, span)?
+        .iter()
+        .map(|form| CDef::from_datum(form, span))
+        .collect()
+}
+
+// Expand a top-level form written at `span` into the forms it stands for,
+// with the names variables end up with. Once the outermost expansion is
+// over, what it made and left nothing referring to is forgotten.
+fn expand_datum(form: Value, env: &Rc<Environment>, span: Span) -> Result<Vec<Value>, Located> {
+    EXPANSION.with(|expansion| expansion.borrow_mut().depth += 1);
+    let forms = expand_form(form, env, span);
+    if let Ok(forms) = &forms {
+        keep(forms);
+    }
+    let finished = EXPANSION.with(|expansion| {
+        let mut expansion = expansion.borrow_mut();
+        expansion.depth -= 1;
+        (expansion.depth == 0).then(|| std::mem::take(&mut *expansion))
+    });
+    if let Some(expansion) = finished {
+        expansion.sweep();
+    }
+    forms
+}
+
+fn expand_form(form: Value, env: &Rc<Environment>, span: Span) -> Result<Vec<Value>, Located> {
+    let mut expander = Expander {
+        env,
+        span,
+        variables: HashMap::new(),
+        inserted: HashMap::new(),
+        failed: None,
+    };
+    let scope = Scope::root(env);
+    let forms = expander
+        .scan(vec![form], &scope, true)
+        .and_then(|forms| expander.finish(forms, &scope));
+    // What the top-level scope binds is in the global environment once the
+    // forms run, and macros defined here refer to it.
+    scope.bindings.borrow_mut().clear();
+    let forms = forms.map_err(|error| Located {
+        error: Box::new(error),
+        span: expander.failed,
+    })?;
+    Ok(forms
+        .into_iter()
+        .map(|form| expander.rename(form))
+        .collect())
+}
+
+// A form of a body or program, once its macro uses are expanded far enough
+// to tell definitions from expressions. Definitions have bound their names,
+// and keep the form they were written as.
+enum Scanned {
+    Define(Value, Value, Value),
+    DefineValues(Value, Value, Value),
+    RecordType(Value),
+    Expression(Value),
+}
+
+struct Expander<'a> {
+    env: &'a Rc<Environment>,
+    // Where the form was written, which is where the forms it expands into,
+    // transformer expressions among them, are taken to be written.
+    span: Span,
+    // The variables bound so far, by their fresh names.
+    variables: HashMap<String, Rc<Variable>>,
+    // The aliases syntax templates insert, by scope and identifier.
+    inserted: HashMap<(usize, String), String>,
+    // Where the innermost form an error was raised in was written.
+    failed: Option<Span>,
+}
+impl Expander<'_> {
+    // Note that an error was raised in `form`, unless one was noted in a
+    // form inside it.
+    fn fail(&mut self, form: &Value) {
+        if self.failed.is_none() {
+            self.failed = ast::location(form);
+        }
+    }
+
+    fn resolve(&self, scope: &Rc<Scope>, symbol: &str) -> Denotation {
+        resolve(self.env, scope, symbol)
+    }
+
+    // The keyword of a special form, if `form` is one.
+    fn special(&self, form: &Value, scope: &Rc<Scope>) -> Option<&'static str> {
+        match form {
+            Value::Pair(pair) => match pair.car() {
+                Value::Symbol(symbol) => match self.resolve(scope, &symbol) {
+                    Denotation::Special(keyword) => Some(keyword),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Expand a form as long as it is a macro use.
+    fn head(&mut self, mut form: Value, scope: &Rc<Scope>) -> Result<Value, Error> {
+        loop {
+            let m = match &form {
+                Value::Pair(pair) => match pair.car() {
+                    Value::Symbol(symbol) => match self.resolve(scope, &symbol) {
+                        Denotation::Macro(m) => m,
+                        _ => return Ok(form),
+                    },
+                    _ => return Ok(form),
+                },
+                _ => return Ok(form),
+            };
+            form = self.transcribe(&m, &form, scope)?;
+        }
+    }
+
+    // Bind an identifier as a variable of `scope`, or as a global variable
+    // at top level, and return the name it is written under.
+    fn bind(&mut self, scope: &Rc<Scope>, identifier: &Value, top: bool) -> Result<Value, Error> {
+        let symbol = identifier_of(identifier)?;
+        if top {
+            scope.bind(symbol, Denotation::Global(symbol.to_string()));
+            return Ok(identifier.clone());
+        }
+        let name = strip_symbol(symbol);
+        let renamed = alias(symbol).is_some() || SPECIAL_FORMS.contains(&name.as_str());
+        let variable = Rc::new(Variable {
+            fresh: fresh(&name),
+            name,
+            renamed: Cell::new(renamed),
+        });
+        self.variables
+            .insert(variable.fresh.clone(), Rc::clone(&variable));
+        let fresh = Value::Symbol(variable.fresh.clone());
+        scope.bind(symbol, Denotation::Variable(variable));
+        Ok(fresh)
+    }
+
+    // Bind the identifiers of a formals list, such as a lambda has.
+    fn bind_formals(
+        &mut self,
+        scope: &Rc<Scope>,
+        formals: &Value,
+        top: bool,
+    ) -> Result<Value, Error> {
+        let (identifiers, rest) = split(formals);
+        let identifiers = identifiers
+            .iter()
+            .map(|identifier| self.bind(scope, identifier, top))
+            .collect::<Result<Vec<Value>, Error>>()?;
+        let rest = match rest {
+            Value::Null => Value::Null,
+            rest => self.bind(scope, &rest, top)?,
+        };
+        Ok(list_with_tail(identifiers, rest))
+    }
+
+    // Expand the forms of a body or, when `top` is set, of a program up to
+    // telling definitions from expressions, binding what they define.
+    fn scan(
+        &mut self,
+        forms: Vec<Value>,
+        scope: &Rc<Scope>,
+        top: bool,
+    ) -> Result<Vec<Scanned>, Error> {
+        let mut pending: VecDeque<Value> = forms.into();
+        let mut scanned = Vec::new();
+        while let Some(form) = pending.pop_front() {
+            self.scan_form(form.clone(), scope, top, &mut pending, &mut scanned)
+                .inspect_err(|_| self.fail(&form))?;
+        }
+        Ok(scanned)
+    }
+
+    // Scan one form, putting the forms of a begin before those pending.
+    fn scan_form(
+        &mut self,
+        form: Value,
+        scope: &Rc<Scope>,
+        top: bool,
+        pending: &mut VecDeque<Value>,
+        scanned: &mut Vec<Scanned>,
+    ) -> Result<(), Error> {
+        let form = self.head(form, scope)?;
+        match self.special(&form, scope) {
+            Some("begin") => {
+                for item in list(&form)?.into_iter().skip(1).rev() {
+                    pending.push_front(item);
+                }
+            }
+            Some("define") => {
+                let (name, expression) = match list(&form)?.as_slice() {
+                    [_, name @ Value::Symbol(_), expression] => (name.clone(), expression.clone()),
+                    [_, Value::Pair(signature), body @ ..] if !body.is_empty() => (
+                        signature.car(),
+                        located(
+                            &form,
+                            list_with_tail(
+                                vec![identifier("lambda"), signature.cdr()],
+                                from_list(body.to_vec()),
+                            ),
+                        ),
+                    ),
+                    _ => return Err(malformed(&form)),
+                };
+                let name = self.bind(scope, &name, top)?;
+                scanned.push(Scanned::Define(form.clone(), name, expression));
+            }
+            Some("define-values") => match list(&form)?.as_slice() {
+                [_, formals, expression] => {
+                    let formals = self.bind_formals(scope, formals, top)?;
+                    scanned.push(Scanned::DefineValues(
+                        form.clone(),
+                        formals,
+                        expression.clone(),
+                    ));
+                }
+                _ => return Err(malformed(&form)),
+            },
+            Some("define-record-type") => {
+                scanned.push(Scanned::RecordType(self.record_type(&form, scope, top)?));
+            }
+            Some("define-syntax") => match list(&form)?.as_slice() {
+                [_, keyword, spec] => {
+                    let symbol = identifier_of(keyword)?;
+                    let m = self.transformer(symbol, spec, scope)?;
+                    if top {
+                        self.env
+                            .define(&written(symbol), Value::Macro(Rc::clone(&m)));
+                        keep(&[Value::Macro(Rc::clone(&m))]);
+                    }
+                    scope.bind(symbol, Denotation::Macro(m));
+                }
+                _ => return Err(malformed(&form)),
+            },
+            _ => scanned.push(Scanned::Expression(form)),
+        }
+        Ok(())
+    }
+
+    // Expand the expressions of scanned forms.
+    fn finish(&mut self, forms: Vec<Scanned>, scope: &Rc<Scope>) -> Result<Vec<Value>, Error> {
+        forms
+            .into_iter()
+            .map(|form| {
+                Ok(match form {
+                    Scanned::Define(form, name, expression) => {
+                        let expression = self.expand(&expression, scope)?;
+                        located(
+                            &form,
+                            from_list(vec![identifier("define"), name, expression]),
+                        )
+                    }
+                    Scanned::DefineValues(form, formals, expression) => {
+                        let expression = self.expand(&expression, scope)?;
+                        let expanded =
+                            from_list(vec![identifier("define-values"), formals, expression]);
+                        located(&form, expanded)
+                    }
+                    Scanned::RecordType(form) => form,
+                    Scanned::Expression(form) => self.expand(&form, scope)?,
+                })
+            })
+            .collect()
+    }
+
+    // Expand a body in a scope of its own.
+    fn body(&mut self, forms: &[Value], scope: &Rc<Scope>) -> Result<Vec<Value>, Error> {
+        let scope = Scope::extend(Some(scope));
+        let scanned = self.scan(forms.to_vec(), &scope, false)?;
+        self.finish(scanned, &scope)
+    }
+
+    // Expand a form into one written where it was.
+    fn expand(&mut self, form: &Value, scope: &Rc<Scope>) -> Result<Value, Error> {
+        let expanded = self
+            .expand_form(form, scope)
+            .inspect_err(|_| self.fail(form))?;
+        Ok(located(form, expanded))
+    }
+
+    fn expand_form(&mut self, form: &Value, scope: &Rc<Scope>) -> Result<Value, Error> {
+        match form {
+            // A global variable named like a keyword is referred to in a
+            // begin, so that lowering does not take a list it heads for the
+            // keyword's form.
+            Value::Symbol(symbol) => match self.reference(symbol, scope)? {
+                Value::Symbol(name) if SPECIAL_FORMS.contains(&name.as_str()) => {
+                    Ok(from_list(vec![identifier("begin"), Value::Symbol(name)]))
+                }
+                reference => Ok(reference),
+            },
+            Value::Pair(pair) => {
+                if let Value::Symbol(symbol) = pair.car() {
+                    match self.resolve(scope, &symbol) {
+                        Denotation::Macro(m) => {
+                            let expansion = self.transcribe(&m, form, scope)?;
+                            return self.expand(&expansion, scope);
+                        }
+                        Denotation::Special(keyword) => {
+                            return self.expand_special(keyword, form, scope)
+                        }
+                        _ => (),
+                    }
+                }
+                let items = list(form)?
+                    .iter()
+                    .map(|item| self.expand(item, scope))
+                    .collect::<Result<_, _>>()?;
+                let call = from_list(items);
+                ast::relocate_elements(form, &call);
+                Ok(call)
+            }
+            Value::Vector(_) => Ok(strip(form)),
+            other => Ok(other.clone()),
+        }
+    }
+
+    // A reference to a variable, written under the name of the binding it
+    // denotes.
+    fn reference(&mut self, symbol: &str, scope: &Rc<Scope>) -> Result<Value, Error> {
+        let denotation = self.resolve(scope, symbol);
+        if alias(symbol).is_some() {
+            protect(scope, &denotation);
+        }
+        match denotation {
+            Denotation::Variable(variable) | Denotation::Pattern(variable, _) => {
+                Ok(Value::Symbol(variable.fresh.clone()))
+            }
+            Denotation::Global(name) => Ok(Value::Symbol(name)),
+            Denotation::Macro(_) | Denotation::Special(_) => Err(Error::BadSyntax(format!(
+                "{} is a syntactic keyword, not a variable",
+                strip_symbol(symbol)
+            ))),
+        }
+    }
+
+    fn expand_all(&mut self, forms: &[Value], scope: &Rc<Scope>) -> Result<Vec<Value>, Error> {
+        forms.iter().map(|form| self.expand(form, scope)).collect()
+    }
+
+    fn expand_special(
+        &mut self,
+        keyword: &'static str,
+        form: &Value,
+        scope: &Rc<Scope>,
+    ) -> Result<Value, Error> {
+        let items = list(form)?;
+        let head = || identifier(keyword);
+        match (keyword, items.as_slice()) {
+            ("quote", [_, datum]) => Ok(from_list(vec![head(), strip(datum)])),
+            ("quasiquote", [_, template]) => Ok(from_list(vec![
+                head(),
+                self.quasiquote(template, 1, scope)?,
+            ])),
+            ("lambda", [_, formals, body @ ..]) => {
+                let inner = Scope::extend(Some(scope));
+                let formals = self.bind_formals(&inner, formals, false)?;
+                let body = self.body(body, &inner)?;
+                Ok(list_with_tail(vec![head(), formals], from_list(body)))
+            }
+            ("set!", [_, Value::Symbol(symbol), expression]) => Ok(from_list(vec![
+                head(),
+                self.reference(symbol, scope)?,
+                self.expand(expression, scope)?,
+            ])),
+            ("let", [_, name @ Value::Symbol(_), bindings, body @ ..]) => {
+                let (variables, inits) = self.bindings(bindings, scope)?;
+                let outer = Scope::extend(Some(scope));
+                let name = self.bind(&outer, name, false)?;
+                let inner = Scope::extend(Some(&outer));
+                let bindings = self.bound(&inner, variables, inits)?;
+                let body = self.body(body, &inner)?;
+                Ok(list_with_tail(
+                    vec![head(), name, bindings],
+                    from_list(body),
+                ))
+            }
+            ("let", [_, bindings, body @ ..]) => {
+                let (variables, inits) = self.bindings(bindings, scope)?;
+                let inner = Scope::extend(Some(scope));
+                let bindings = self.bound(&inner, variables, inits)?;
+                let body = self.body(body, &inner)?;
+                Ok(list_with_tail(vec![head(), bindings], from_list(body)))
+            }
+            ("let*", [_, bindings, body @ ..]) => {
+                let mut inner = Rc::clone(scope);
+                let mut bound = Vec::new();
+                for binding in list(bindings)? {
+                    let (variable, init) = match list(&binding)?.as_slice() {
+                        [variable, init] => (variable.clone(), self.expand(init, &inner)?),
+                        _ => return Err(malformed(&binding)),
+                    };
+                    inner = Scope::extend(Some(&inner));
+                    bound.push(from_list(vec![self.bind(&inner, &variable, false)?, init]));
+                }
+                let body = self.body(body, &inner)?;
+                Ok(list_with_tail(
+                    vec![head(), from_list(bound)],
+                    from_list(body),
+                ))
+            }
+            ("letrec" | "letrec*", [_, bindings, body @ ..]) => {
+                let inner = Scope::extend(Some(scope));
+                let mut variables = Vec::new();
+                let mut inits = Vec::new();
+                for binding in list(bindings)? {
+                    match list(&binding)?.as_slice() {
+                        [variable, init] => {
+                            variables.push(self.bind(&inner, variable, false)?);
+                            inits.push(init.clone());
+                        }
+                        _ => return Err(malformed(&binding)),
+                    }
+                }
+                let bound = variables
+                    .into_iter()
+                    .zip(inits.iter())
+                    .map(|(variable, init)| {
+                        Ok(from_list(vec![variable, self.expand(init, &inner)?]))
+                    })
+                    .collect::<Result<_, Error>>()?;
+                let body = self.body(body, &inner)?;
+                Ok(list_with_tail(
+                    vec![head(), from_list(bound)],
+                    from_list(body),
+                ))
+            }
+            ("let-values" | "let*-values", [_, bindings, body @ ..]) => {
+                let sequential = keyword == "let*-values";
+                let mut inner = Scope::extend(Some(scope));
+                let mut bound = Vec::new();
+                for binding in list(bindings)? {
+                    let (formals, init) = match list(&binding)?.as_slice() {
+                        [formals, init] if sequential => {
+                            (formals.clone(), self.expand(init, &inner)?)
+                        }
+                        [formals, init] => (formals.clone(), self.expand(init, scope)?),
+                        _ => return Err(malformed(&binding)),
+                    };
+                    if sequential {
+                        inner = Scope::extend(Some(&inner));
+                    }
+                    bound.push(from_list(vec![
+                        self.bind_formals(&inner, &formals, false)?,
+                        init,
+                    ]));
+                }
+                let body = self.body(body, &inner)?;
+                Ok(list_with_tail(
+                    vec![head(), from_list(bound)],
+                    from_list(body),
+                ))
+            }
+            ("do", [_, specs, exit, commands @ ..]) => {
+                let inner = Scope::extend(Some(scope));
+                let mut variables = Vec::new();
+                for spec in list(specs)? {
+                    match list(&spec)?.as_slice() {
+                        [variable, init, step @ ..] if step.len() <= 1 => variables.push((
+                            variable.clone(),
+                            self.expand(init, scope)?,
+                            step.to_vec(),
+                        )),
+                        _ => return Err(malformed(&spec)),
+                    }
+                }
+                let mut bound = Vec::new();
+                for (variable, init, _) in variables.iter() {
+                    bound.push(vec![self.bind(&inner, variable, false)?, init.clone()]);
+                }
+                for (spec, (_, _, step)) in bound.iter_mut().zip(variables.iter()) {
+                    spec.extend(self.expand_all(step, &inner)?);
+                }
+                let exit = from_list(self.expand_all(&list(exit)?, &inner)?);
+                let commands = self.expand_all(commands, &inner)?;
+                Ok(list_with_tail(
+                    vec![
+                        head(),
+                        from_list(bound.into_iter().map(from_list).collect()),
+                        exit,
+                    ],
+                    from_list(commands),
+                ))
+            }
+            ("case-lambda", [_, clauses @ ..]) => {
+                let mut expanded = vec![head()];
+                for clause in clauses {
+                    match list(clause)?.as_slice() {
+                        [formals, body @ ..] => {
+                            let inner = Scope::extend(Some(scope));
+                            let formals = self.bind_formals(&inner, formals, false)?;
+                            let body = self.body(body, &inner)?;
+                            expanded.push(list_with_tail(vec![formals], from_list(body)));
+                        }
+                        _ => return Err(malformed(clause)),
+                    }
+                }
+                Ok(from_list(expanded))
+            }
+            ("guard", [_, Value::Pair(spec), body @ ..]) => {
+                let inner = Scope::extend(Some(scope));
+                let variable = self.bind(&inner, &spec.car(), false)?;
+                let clauses = list(&spec.cdr())?
+                    .iter()
+                    .map(|clause| self.cond_clause(clause, &inner))
+                    .collect::<Result<Vec<Value>, Error>>()?;
+                let body = self.body(body, scope)?;
+                Ok(list_with_tail(
+                    vec![head(), list_with_tail(vec![variable], from_list(clauses))],
+                    from_list(body),
+                ))
+            }
+            ("cond", [_, clauses @ ..]) => {
+                let mut expanded = vec![head()];
+                for clause in clauses {
+                    expanded.push(self.cond_clause(clause, scope)?);
+                }
+                Ok(from_list(expanded))
+            }
+            ("case", [_, key, clauses @ ..]) => {
+                let mut expanded = vec![head(), self.expand(key, scope)?];
+                for clause in clauses {
+                    let (data, rest) = match list(clause)?.as_slice() {
+                        [Value::Symbol(symbol), rest @ ..] if self.is(scope, symbol, "else") => {
+                            (identifier("else"), rest.to_vec())
+                        }
+                        [data, rest @ ..] => (strip(data), rest.to_vec()),
+                        _ => return Err(malformed(clause)),
+                    };
+                    let rest = self.clause_rest(&rest, scope)?;
+                    expanded.push(list_with_tail(vec![data], rest));
+                }
+                Ok(from_list(expanded))
+            }
+            ("parameterize", [_, bindings, body @ ..]) => {
+                let mut bound = Vec::new();
+                for binding in list(bindings)? {
+                    bound.push(from_list(self.expand_all(&list(&binding)?, scope)?));
+                }
+                let body = self.body(body, scope)?;
+                Ok(list_with_tail(
+                    vec![head(), from_list(bound)],
+                    from_list(body),
+                ))
+            }
+            (
+                "if" | "and" | "or" | "when" | "unless" | "begin" | "delay" | "delay-force",
+                [_, forms @ ..],
+            ) => Ok(list_with_tail(
+                vec![head()],
+                from_list(self.expand_all(forms, scope)?),
+            )),
+            ("include" | "include-ci", _) => Ok(strip(form)),
+            ("let-syntax" | "letrec-syntax", [_, specs, body @ ..]) => {
+                let inner = Scope::extend(Some(scope));
+                for spec in list(specs)? {
+                    match list(&spec)?.as_slice() {
+                        [name, transformer] => {
+                            let symbol = identifier_of(name)?;
+                            // A letrec-syntax transformer is defined in the
+                            // scope it binds its keyword in.
+                            let defined_in = match keyword {
+                                "letrec-syntax" => &inner,
+                                _ => scope,
+                            };
+                            let m = self.transformer(symbol, transformer, defined_in)?;
+                            inner.bind(symbol, Denotation::Macro(m));
+                        }
+                        _ => return Err(malformed(&spec)),
+                    }
+                }
+                let body = self.body(body, &inner)?;
+                Ok(list_with_tail(
+                    vec![identifier("let"), Value::Null],
+                    from_list(body),
+                ))
+            }
+            ("syntax-case", _) => self.syntax_case(form, scope),
+            ("syntax", [_, template]) => self.syntax(template, scope),
+            ("with-syntax", [_, bindings, body @ ..]) => {
+                let mut patterns = Vec::new();
+                let mut expressions = vec![identifier(&core("list"))];
+                for binding in list(bindings)? {
+                    match list(&binding)?.as_slice() {
+                        [pattern, expression] => {
+                            patterns.push(pattern.clone());
+                            expressions.push(expression.clone());
+                        }
+                        _ => return Err(malformed(&binding)),
+                    }
+                }
+                let clause = from_list(vec![
+                    from_list(patterns),
+                    list_with_tail(
+                        vec![identifier(&core("let")), Value::Null],
+                        from_list(body.to_vec()),
+                    ),
+                ]);
+                let form = from_list(vec![
+                    identifier(&core("syntax-case")),
+                    from_list(expressions),
+                    Value::Null,
+                    clause,
+                ]);
+                self.expand(&form, scope)
+            }
+            ("define" | "define-values" | "define-record-type" | "define-syntax", _) => {
+                Err(Error::BadSyntax(format!(
+                    "{} is only allowed at top level or in a body",
+                    keyword
+                )))
+            }
+            _ => Err(malformed(form)),
+        }
+    }
+
+    // Each clause of syntax-case becomes a call of $syntax-dispatch, which
+    // calls the clause with what the pattern variables matched, or else the
+    // clauses after it.
+    fn syntax_case(&mut self, form: &Value, scope: &Rc<Scope>) -> Result<Value, Error> {
+        let items = list(form)?;
+        let (input, literals, clauses) = match items.as_slice() {
+            [_, input, literals, clauses @ ..] => (input, literals, clauses),
+            _ => return Err(malformed(form)),
+        };
+        let input = self.expand(input, scope)?;
+        let literals = list(literals)?
+            .iter()
+            .map(|literal| identifier_of(literal).map(str::to_string))
+            .collect::<Result<Vec<String>, _>>()?;
+        let matcher = Macro::syntax(literals.clone());
+        // The literals are matched as the identifiers they are here.
+        let aliases = literals
+            .iter()
+            .map(|literal| (literal.clone(), self.insert(literal, scope)))
+            .collect::<HashMap<String, String>>();
+        let outer = Scope::extend(Some(scope));
+        let subject = self.bind(&outer, &identifier(&core("form")), false)?;
+        let mut rest = from_list(vec![
+            self.reference(&core("syntax-violation"), &outer)?,
+            from_list(vec![identifier("quote"), identifier("syntax-case")]),
+            Value::string("no clause matches"),
+            subject.clone(),
+        ]);
+        for clause in clauses.iter().rev() {
+            let (pattern, fender, output) = match list(clause)?.as_slice() {
+                [pattern, output] => (pattern.clone(), None, output.clone()),
+                [pattern, fender, output] => {
+                    (pattern.clone(), Some(fender.clone()), output.clone())
+                }
+                _ => return Err(malformed(clause)),
+            };
+            let mut depths = HashMap::new();
+            matcher.pattern_variables(&pattern, 0, &mut depths);
+            let mut variables = depths.into_iter().collect::<Vec<(String, usize)>>();
+            variables.sort();
+            let inner = Scope::extend(Some(&outer));
+            let next = self.bind(&inner, &identifier(&core("next")), false)?;
+            let matched = Scope::extend(Some(&inner));
+            let mut formals = Vec::new();
+            for (name, depth) in variables.iter() {
+                let fresh = self.bind(&matched, &identifier(name), false)?;
+                if let Value::Symbol(fresh) = &fresh {
+                    let variable = Rc::clone(&self.variables[fresh]);
+                    matched.bind(name, Denotation::Pattern(variable, *depth));
+                }
+                formals.push(fresh);
+            }
+            let mut body = self.expand(&output, &matched)?;
+            if let Some(fender) = fender {
+                body = from_list(vec![
+                    identifier("if"),
+                    self.expand(&fender, &matched)?,
+                    body,
+                    from_list(vec![next.clone()]),
+                ]);
+            }
+            let pattern = map_symbols(&pattern, &mut |symbol| {
+                aliases
+                    .get(symbol)
+                    .cloned()
+                    .unwrap_or_else(|| symbol.to_string())
+            });
+            let descriptor = list_with_tail(
+                vec![
+                    pattern,
+                    from_list(literals.iter().map(|l| identifier(&aliases[l])).collect()),
+                ],
+                from_list(variables.iter().map(|(name, _)| identifier(name)).collect()),
+            );
+            let dispatch = from_list(vec![
+                self.reference(&core("$syntax-dispatch"), &inner)?,
+                subject.clone(),
+                from_list(vec![identifier("quote"), descriptor]),
+                from_list(vec![identifier("lambda"), from_list(formals), body]),
+                next.clone(),
+            ]);
+            rest = from_list(vec![
+                from_list(vec![identifier("lambda"), from_list(vec![next]), dispatch]),
+                from_list(vec![identifier("lambda"), Value::Null, rest]),
+            ]);
+        }
+        Ok(from_list(vec![
+            from_list(vec![identifier("lambda"), from_list(vec![subject]), rest]),
+            input,
+        ]))
+    }
+
+    // A syntax template becomes a call of $syntax-template with the
+    // template, the pattern variables it uses with their depths, and their
+    // values. The identifiers it inserts are aliases of those here.
+    fn syntax(&mut self, template: &Value, scope: &Rc<Scope>) -> Result<Value, Error> {
+        let mut variables = Vec::new();
+        let template = map_symbols(template, &mut |symbol| match self.resolve(scope, symbol) {
+            Denotation::Pattern(_, depth) => {
+                if !variables.iter().any(|(name, _)| name == symbol) {
+                    variables.push((symbol.to_string(), depth));
+                }
+                symbol.to_string()
+            }
+            _ => self.insert(symbol, scope),
+        });
+        let depths = variables
+            .iter()
+            .map(|(name, depth)| {
+                cons(
+                    identifier(name),
+                    Value::Number(Number::Integer(*depth as i64)),
+                )
+            })
+            .collect();
+        let mut call = vec![
+            self.reference(&core("$syntax-template"), scope)?,
+            from_list(vec![identifier("quote"), cons(template, from_list(depths))]),
+        ];
+        for (name, _) in variables.iter() {
+            call.push(self.reference(name, scope)?);
+        }
+        Ok(from_list(call))
+    }
+
+    // The alias an identifier a syntax template inserts is written as, the
+    // same one for every template in a scope.
+    fn insert(&mut self, symbol: &str, scope: &Rc<Scope>) -> String {
+        self.inserted
+            .entry((Rc::as_ptr(scope) as usize, symbol.to_string()))
+            .or_insert_with(|| alias_of(symbol, scope))
+            .clone()
+    }
+
+    // Whether `symbol` is the special keyword `keyword` in `scope`.
+    fn is(&self, scope: &Rc<Scope>, symbol: &str, keyword: &str) -> bool {
+        matches!(self.resolve(scope, symbol), Denotation::Special(k) if k == keyword)
+    }
+
+    // The variables and initial values of let bindings, the values expanded.
+    fn bindings(
+        &mut self,
+        bindings: &Value,
+        scope: &Rc<Scope>,
+    ) -> Result<(Vec<Value>, Vec<Value>), Error> {
+        let mut variables = Vec::new();
+        let mut inits = Vec::new();
+        for binding in list(bindings)? {
+            match list(&binding)?.as_slice() {
+                [variable, init] => {
+                    variables.push(variable.clone());
+                    inits.push(self.expand(init, scope)?);
+                }
+                _ => return Err(malformed(&binding)),
+            }
+        }
+        Ok((variables, inits))
+    }
+
+    // Bind let variables in `scope` and pair them with their values again.
+    fn bound(
+        &mut self,
+        scope: &Rc<Scope>,
+        variables: Vec<Value>,
+        inits: Vec<Value>,
+    ) -> Result<Value, Error> {
+        let bound = variables
+            .iter()
+            .zip(inits)
+            .map(|(variable, init)| Ok(from_list(vec![self.bind(scope, variable, false)?, init])))
+            .collect::<Result<_, Error>>()?;
+        Ok(from_list(bound))
+    }
+
+    fn cond_clause(&mut self, clause: &Value, scope: &Rc<Scope>) -> Result<Value, Error> {
+        match list(clause)?.as_slice() {
+            [Value::Symbol(symbol), rest @ ..] if self.is(scope, symbol, "else") => {
+                Ok(list_with_tail(
+                    vec![identifier("else")],
+                    from_list(self.expand_all(rest, scope)?),
+                ))
+            }
+            [test, rest @ ..] => {
+                let test = self.expand(test, scope)?;
+                let rest = self.clause_rest(rest, scope)?;
+                Ok(list_with_tail(vec![test], rest))
+            }
+            _ => Err(malformed(clause)),
+        }
+    }
+
+    // The expressions of a cond or case clause after its test, which may
+    // start with `=>`.
+    fn clause_rest(&mut self, rest: &[Value], scope: &Rc<Scope>) -> Result<Value, Error> {
+        match rest {
+            [Value::Symbol(symbol), receiver] if self.is(scope, symbol, "=>") => {
+                Ok(from_list(vec![
+                    identifier("=>"),
+                    self.expand(receiver, scope)?,
+                ]))
+            }
+            _ => Ok(from_list(self.expand_all(rest, scope)?)),
+        }
+    }
+
+    fn quasiquote(
+        &mut self,
+        template: &Value,
+        depth: usize,
+        scope: &Rc<Scope>,
+    ) -> Result<Value, Error> {
+        match template {
+            Value::Pair(pair) => {
+                if let (Value::Symbol(symbol), Value::Pair(rest)) = (pair.car(), pair.cdr()) {
+                    if rest.cdr() == Value::Null {
+                        let operand = rest.car();
+                        match self.resolve(scope, &symbol) {
+                            Denotation::Special(keyword @ ("unquote" | "unquote-splicing")) => {
+                                let operand = match depth {
+                                    1 => self.expand(&operand, scope)?,
+                                    _ => self.quasiquote(&operand, depth - 1, scope)?,
+                                };
+                                return Ok(from_list(vec![identifier(keyword), operand]));
+                            }
+                            Denotation::Special("quasiquote") => {
+                                let operand = self.quasiquote(&operand, depth + 1, scope)?;
+                                return Ok(from_list(vec![identifier("quasiquote"), operand]));
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                Ok(cons(
+                    self.quasiquote(&pair.car(), depth, scope)?,
+                    self.quasiquote(&pair.cdr(), depth, scope)?,
+                ))
+            }
+            Value::Vector(vector) => {
+                let items = vector.borrow().clone();
+                let items = items
+                    .iter()
+                    .map(|item| self.quasiquote(item, depth, scope))
+                    .collect::<Result<_, _>>()?;
+                Ok(Value::vector(items))
+            }
+            other => Ok(strip(other)),
+        }
+    }
+
+    fn record_type(&mut self, form: &Value, scope: &Rc<Scope>, top: bool) -> Result<Value, Error> {
+        let items = list(form)?;
+        let (name, constructor, predicate, fields) = match items.as_slice() {
+            [_, name, constructor, predicate, fields @ ..] => {
+                (name, constructor, predicate, fields)
+            }
+            _ => return Err(malformed(form)),
+        };
+        let name = self.bind(scope, name, top)?;
+        let constructor = match list(constructor)?.as_slice() {
+            [constructor, fields @ ..] => list_with_tail(
+                vec![self.bind(scope, constructor, top)?],
+                from_list(fields.iter().map(strip).collect()),
+            ),
+            _ => return Err(malformed(constructor)),
+        };
+        let predicate = self.bind(scope, predicate, top)?;
+        let mut expanded = vec![
+            identifier("define-record-type"),
+            name,
+            constructor,
+            predicate,
+        ];
+        for field in fields {
+            match list(field)?.as_slice() {
+                [name, procedures @ ..] if (1..=2).contains(&procedures.len()) => {
+                    let mut spec = vec![strip(name)];
+                    for procedure in procedures {
+                        spec.push(self.bind(scope, procedure, top)?);
+                    }
+                    expanded.push(from_list(spec));
+                }
+                _ => return Err(malformed(field)),
+            }
+        }
+        Ok(from_list(expanded))
+    }
+
+    // A macro made from a transformer spec: syntax-rules, the keyword of
+    // another macro, or an expression for a transformer procedure.
+    fn transformer(
+        &mut self,
+        keyword: &str,
+        spec: &Value,
+        scope: &Rc<Scope>,
+    ) -> Result<Rc<Macro>, Error> {
+        if let Value::Symbol(symbol) = spec {
+            if let Denotation::Macro(m) = self.resolve(scope, symbol) {
+                return Ok(m);
+            }
+        }
+        let items = match split(spec) {
+            (items, Value::Null) => items,
+            _ => Vec::new(),
+        };
+        let mut rest = match items.split_first() {
+            Some((Value::Symbol(head), rest)) if self.is(scope, head, "syntax-rules") => rest,
+            _ => return self.procedural(keyword, spec, scope),
+        };
+        let ellipsis = match rest.split_first() {
+            Some((Value::Symbol(ellipsis), tail)) => {
+                rest = tail;
+                Some(ellipsis.clone())
+            }
+            _ => None,
+        };
+        let (literals, rules) = match rest.split_first() {
+            Some((literals, rules)) => (literals, rules),
+            None => return Err(malformed(spec)),
+        };
+        let mut m = Macro {
+            keyword: strip_symbol(keyword),
+            ellipsis,
+            literals: list(literals)?
+                .iter()
+                .map(|literal| identifier_of(literal).map(str::to_string))
+                .collect::<Result<_, _>>()?,
+            rules: Vec::new(),
+            procedure: None,
+            scope: Rc::clone(scope),
+        };
+        for rule in rules {
+            match list(rule)?.as_slice() {
+                [pattern, template] => {
+                    let mut variables = HashMap::new();
+                    m.pattern_variables(pattern, 0, &mut variables);
+                    m.rules.push(Rule {
+                        pattern: pattern.clone(),
+                        template: template.clone(),
+                        variables,
+                    });
+                }
+                _ => return Err(malformed(rule)),
+            }
+        }
+        Ok(Rc::new(m))
+    }
+
+    // A macro whose transformer is the procedure `spec` evaluates to.
+    fn procedural(
+        &mut self,
+        keyword: &str,
+        spec: &Value,
+        scope: &Rc<Scope>,
+    ) -> Result<Rc<Macro>, Error> {
+        let (convention, procedure) = match self.evaluate(spec, scope)? {
+            Value::Macro(m) => match &m.procedure {
+                Some((convention, procedure)) => (*convention, procedure.clone()),
+                None => return Ok(m),
+            },
+            procedure if is_procedure(&procedure) => (Convention::Syntax, procedure),
+            other => {
+                return Err(Error::BadSyntax(format!(
+                    "{}: expected a transformer, given {}",
+                    strip_symbol(keyword),
+                    other
+                )))
+            }
+        };
+        Ok(Rc::new(Macro {
+            keyword: strip_symbol(keyword),
+            ellipsis: None,
+            literals: Vec::new(),
+            rules: Vec::new(),
+            procedure: Some((convention, procedure)),
+            scope: Rc::clone(scope),
+        }))
+    }
+
+    // Evaluate an expression in the global environment, as the expression
+    // of a transformer procedure is when its keyword is defined.
+    fn evaluate(&mut self, expression: &Value, scope: &Rc<Scope>) -> Result<Value, Error> {
+        let expression = self.expand(expression, scope)?;
+        let expression = self.rename(expression);
+        keep(std::slice::from_ref(&expression));
+        let cdef = CDef::from_datum(&expression, self.span).map_err(|e| {
+            self.failed = self.failed.or(e.span);
+            Error::from(e)
+        })?;
+        eval::eval_cdef(&cdef, self.env)
+    }
+
+    // Rewrite a macro use with the first rule whose pattern it matches, or
+    // by calling the transformer procedure.
+    fn transcribe(
+        &mut self,
+        m: &Rc<Macro>,
+        form: &Value,
+        scope: &Rc<Scope>,
+    ) -> Result<Value, Error> {
+        if let Some((convention, procedure)) = &m.procedure {
+            let expansion = self.call(m, *convention, procedure, form, scope)?;
+            return Ok(located(form, expansion));
+        }
+        for rule in m.rules.iter() {
+            let mut matched = HashMap::new();
+            // The keyword position of a pattern is never matched.
+            let found = match (&rule.pattern, form) {
+                (Value::Pair(pattern), Value::Pair(form)) => {
+                    self.matches(m, &pattern.cdr(), &form.cdr(), scope, &mut matched)
+                }
+                (pattern, form) => self.matches(m, pattern, form, scope, &mut matched),
+            };
+            if found {
+                let bindings = matched
+                    .into_iter()
+                    .map(|(name, binding)| {
+                        let depth = rule.variables[&name];
+                        (name, (depth, binding))
+                    })
+                    .collect();
+                let expansion =
+                    m.instantiate(&rule.template, &bindings, &mut HashMap::new(), false)?;
+                return Ok(located(form, expansion));
+            }
+        }
+        Err(Error::BadSyntax(format!(
+            "no rule of {} matches {}",
+            m.keyword,
+            strip(form)
+        )))
+    }
+
+    fn call(
+        &mut self,
+        m: &Rc<Macro>,
+        convention: Convention,
+        procedure: &Value,
+        form: &Value,
+        scope: &Rc<Scope>,
+    ) -> Result<Value, Error> {
+        let mut transcription = Transcription {
+            m: Rc::clone(m),
+            env: Rc::clone(self.env),
+            scope: Rc::clone(scope),
+            renames: HashMap::new(),
+            injected: HashMap::new(),
+            hidden: HashMap::new(),
+        };
+        let args = match convention {
+            Convention::Explicit => vec![
+                form.clone(),
+                Value::Primitive(RENAME.clone()),
+                Value::Primitive(COMPARE.clone()),
+            ],
+            // The identifiers of the form are hidden, so that the ones the
+            // transformer leaves alone can be told from them afterwards.
+            Convention::Implicit => vec![
+                map_symbols(form, &mut |symbol| transcription.inject(symbol)),
+                Value::Primitive(INJECT.clone()),
+                Value::Primitive(COMPARE.clone()),
+            ],
+            Convention::Syntax => vec![form.clone()],
+        };
+        TRANSCRIPTIONS.with(|transcriptions| transcriptions.borrow_mut().push(transcription));
+        let result = eval::apply(procedure, args, self.env);
+        let mut transcription = TRANSCRIPTIONS
+            .with(|transcriptions| transcriptions.borrow_mut().pop())
+            .expect("the transcription pushed above");
+        let expansion = result?;
+        Ok(match convention {
+            Convention::Implicit => map_symbols(&expansion, &mut |symbol| match transcription
+                .injected
+                .get(symbol)
+            {
+                Some(injected) => injected.clone(),
+                None => m.rename(symbol, &mut transcription.renames),
+            }),
+            _ => expansion,
+        })
+    }
+
+    fn matches(
+        &self,
+        m: &Macro,
+        pattern: &Value,
+        form: &Value,
+        scope: &Rc<Scope>,
+        matched: &mut HashMap<String, Binding>,
+    ) -> bool {
+        match pattern {
+            Value::Symbol(symbol) if m.literals.contains(symbol) => match form {
+                Value::Symbol(identifier) => self
+                    .resolve(scope, identifier)
+                    .same(&self.resolve(&m.scope, symbol)),
+                _ => false,
+            },
+            Value::Symbol(symbol) if m.is_underscore(symbol) => true,
+            Value::Symbol(symbol) => {
+                matched.insert(symbol.clone(), Binding::One(form.clone()));
+                true
+            }
+            Value::Pair(_) => {
+                let (patterns, tail) = split(pattern);
+                let (items, rest) = split(form);
+                self.matches_sequence(m, &patterns, &tail, &items, &rest, scope, matched)
+            }
+            Value::Vector(pattern) => match form {
+                Value::Vector(form) => {
+                    let patterns = pattern.borrow().clone();
+                    let items = form.borrow().clone();
+                    self.matches_sequence(
+                        m,
+                        &patterns,
+                        &Value::Null,
+                        &items,
+                        &Value::Null,
+                        scope,
+                        matched,
+                    )
+                }
+                _ => false,
+            },
+            _ => pattern == form,
+        }
+    }
+
+    // Match the elements and tail of a list or vector pattern, one element
+    // of which may be followed by an ellipsis.
+    #[allow(clippy::too_many_arguments)]
+    fn matches_sequence(
+        &self,
+        m: &Macro,
+        patterns: &[Value],
+        tail: &Value,
+        items: &[Value],
+        rest: &Value,
+        scope: &Rc<Scope>,
+        matched: &mut HashMap<String, Binding>,
+    ) -> bool {
+        let ellipsis = match patterns.iter().position(|pattern| m.is_ellipsis(pattern)) {
+            Some(0) => return false,
+            Some(i) => i,
+            None => {
+                if items.len() < patterns.len() {
+                    return false;
+                }
+                let remaining = list_with_tail(items[patterns.len()..].to_vec(), rest.clone());
+                return patterns
+                    .iter()
+                    .zip(items)
+                    .all(|(pattern, item)| self.matches(m, pattern, item, scope, matched))
+                    && self.matches(m, tail, &remaining, scope, matched);
+            }
+        };
+        let (before, repeated, after) = (
+            &patterns[..ellipsis - 1],
+            &patterns[ellipsis - 1],
+            &patterns[ellipsis + 1..],
+        );
+        if items.len() < before.len() + after.len() {
+            return false;
+        }
+        let end = items.len() - after.len();
+        if !before
+            .iter()
+            .zip(items)
+            .all(|(pattern, item)| self.matches(m, pattern, item, scope, matched))
+        {
+            return false;
+        }
+        let mut repetitions = Vec::new();
+        for item in items[before.len()..end].iter() {
+            let mut inner = HashMap::new();
+            if !self.matches(m, repeated, item, scope, &mut inner) {
+                return false;
+            }
+            repetitions.push(inner);
+        }
+        let mut variables = HashMap::new();
+        m.pattern_variables(repeated, 0, &mut variables);
+        for name in variables.into_keys() {
+            let bindings = repetitions
+                .iter_mut()
+                .map(|inner| inner.remove(&name).unwrap_or(Binding::Many(Vec::new())))
+                .collect();
+            matched.insert(name, Binding::Many(bindings));
+        }
+        after
+            .iter()
+            .zip(&items[end..])
+            .all(|(pattern, item)| self.matches(m, pattern, item, scope, matched))
+            && self.matches(m, tail, rest, scope, matched)
+    }
+
+    // Write every variable under the name it ends up with: its own, or its
+    // fresh name if it was renamed. Quoted data is left alone.
+    fn rename(&self, form: Value) -> Value {
+        let mut visited = HashSet::new();
+        let mut stack = vec![form.clone()];
+        while let Some(value) = stack.pop() {
+            let pair = match value {
+                Value::Pair(pair) if visited.insert(gc::identity(&pair)) => pair,
+                _ => continue,
+            };
+            if matches!(pair.car(), Value::Symbol(symbol) if symbol == "quote") {
+                continue;
+            }
+            match self.renamed(&pair.car()) {
+                Some(name) => pair.set_car(name),
+                None => stack.push(pair.car()),
+            }
+            match self.renamed(&pair.cdr()) {
+                Some(name) => pair.set_cdr(name),
+                None => stack.push(pair.cdr()),
+            }
+        }
+        self.renamed(&form).unwrap_or(form)
+    }
+
+    fn renamed(&self, value: &Value) -> Option<Value> {
+        match value {
+            Value::Symbol(symbol) => self.variables.get(symbol).map(|variable| {
+                Value::Symbol(match variable.renamed.get() {
+                    true => variable.fresh.clone(),
+                    false => variable.name.clone(),
+                })
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl Macro {
+    // The matcher of syntax-case patterns and templates, whose identifiers
+    // are the aliases that say what they mean.
+    fn syntax(literals: Vec<String>) -> Macro {
+        Macro {
+            keyword: "syntax-case".to_string(),
+            ellipsis: None,
+            literals,
+            rules: Vec::new(),
+            procedure: None,
+            scope: Scope::extend(None),
+        }
+    }
+
+    fn is_ellipsis(&self, value: &Value) -> bool {
+        match value {
+            Value::Symbol(symbol) if !self.literals.contains(symbol) => match &self.ellipsis {
+                Some(ellipsis) => symbol == ellipsis,
+                None => strip_symbol(symbol) == "...",
+            },
+            _ => false,
+        }
+    }
+
+    fn is_underscore(&self, symbol: &str) -> bool {
+        !self.literals.iter().any(|literal| literal == symbol) && strip_symbol(symbol) == "_"
+    }
+
+    fn pattern_variables(
+        &self,
+        pattern: &Value,
+        depth: usize,
+        variables: &mut HashMap<String, usize>,
+    ) {
+        match pattern {
+            Value::Symbol(symbol)
+                if !self.literals.contains(symbol)
+                    && !self.is_underscore(symbol)
+                    && !self.is_ellipsis(pattern) =>
+            {
+                variables.insert(symbol.clone(), depth);
+            }
+            Value::Pair(_) | Value::Vector(_) => {
+                let (items, tail) = match pattern {
+                    Value::Vector(vector) => (vector.borrow().clone(), Value::Null),
+                    _ => split(pattern),
+                };
+                for (i, item) in items.iter().enumerate() {
+                    let repeated = items.get(i + 1).is_some_and(|next| self.is_ellipsis(next));
+                    self.pattern_variables(item, depth + usize::from(repeated), variables);
+                }
+                self.pattern_variables(&tail, depth, variables);
+            }
+            _ => (),
+        }
+    }
+
+    // Fill in a template. Within `(... template)` ellipses are ordinary
+    // identifiers.
+    fn instantiate(
+        &self,
+        template: &Value,
+        bindings: &Bindings,
+        renames: &mut HashMap<String, String>,
+        escaped: bool,
+    ) -> Result<Value, Error> {
+        match template {
+            Value::Symbol(symbol) => match bindings.get(symbol) {
+                Some((0, Binding::One(form))) => Ok(form.clone()),
+                Some(_) => Err(Error::BadSyntax(format!(
+                    "{}: pattern variable {} is used without its ellipsis",
+                    self.keyword,
+                    strip_symbol(symbol)
+                ))),
+                None => Ok(Value::Symbol(self.rename(symbol, renames))),
+            },
+            Value::Pair(_) => {
+                let (items, tail) = split(template);
+                if let ([ellipsis, template], false, Value::Null) =
+                    (items.as_slice(), escaped, &tail)
+                {
+                    if self.is_ellipsis(ellipsis) {
+                        return self.instantiate(template, bindings, renames, true);
+                    }
+                }
+                let items = self.instantiate_sequence(&items, bindings, renames, escaped)?;
+                let tail = self.instantiate(&tail, bindings, renames, escaped)?;
+                Ok(list_with_tail(items, tail))
+            }
+            Value::Vector(vector) => {
+                let items = vector.borrow().clone();
+                Ok(Value::vector(self.instantiate_sequence(
+                    &items, bindings, renames, escaped,
+                )?))
+            }
+            other => Ok(other.clone()),
+        }
+    }
+
+    fn instantiate_sequence(
+        &self,
+        items: &[Value],
+        bindings: &Bindings,
+        renames: &mut HashMap<String, String>,
+        escaped: bool,
+    ) -> Result<Vec<Value>, Error> {
+        let mut instantiated = Vec::new();
+        let mut i = 0;
+        while i < items.len() {
+            let mut depth = 0;
+            while !escaped
+                && items
+                    .get(i + 1 + depth)
+                    .is_some_and(|item| self.is_ellipsis(item))
+            {
+                depth += 1;
+            }
+            match depth {
+                0 => instantiated.push(self.instantiate(&items[i], bindings, renames, escaped)?),
+                _ => self.repeat(&items[i], depth, bindings, renames, &mut instantiated)?,
+            }
+            i += 1 + depth;
+        }
+        Ok(instantiated)
+    }
+
+    // Fill in a template followed by `depth` ellipses once for each form
+    // its pattern variables matched.
+    fn repeat(
+        &self,
+        template: &Value,
+        depth: usize,
+        bindings: &Bindings,
+        renames: &mut HashMap<String, String>,
+        instantiated: &mut Vec<Value>,
+    ) -> Result<(), Error> {
+        let mut occurrences = Vec::new();
+        self.occurrences(template, 0, false, &mut occurrences);
+        // The ellipsis repeats the variables bound under more ellipses than
+        // the template itself has.
+        let mut repeated: Vec<String> = Vec::new();
+        for (name, inner) in occurrences {
+            if let Some((bound, _)) = bindings.get(&name) {
+                if *bound >= inner + depth && !repeated.contains(&name) {
+                    repeated.push(name);
+                }
+            }
+        }
+        let lengths = repeated
+            .iter()
+            .map(|name| match &bindings[name].1 {
+                Binding::Many(forms) => forms.len(),
+                Binding::One(_) => 0,
+            })
+            .collect::<Vec<usize>>();
+        let length = match lengths.first() {
+            Some(length) if lengths.iter().all(|other| other == length) => *length,
+            Some(_) => {
+                return Err(Error::BadSyntax(format!(
+                    "{}: pattern variables repeated together matched different numbers of forms",
+                    self.keyword
+                )))
+            }
+            None => {
+                return Err(Error::BadSyntax(format!(
+                    "{}: no pattern variable to repeat in {}",
+                    self.keyword,
+                    strip(template)
+                )))
+            }
+        };
+        for i in 0..length {
+            let mut inner = bindings.clone();
+            for name in repeated.iter() {
+                if let (bound, Binding::Many(forms)) = &bindings[name] {
+                    inner.insert(name.clone(), (bound - 1, forms[i].clone()));
+                }
+            }
+            match depth {
+                1 => instantiated.push(self.instantiate(template, &inner, renames, false)?),
+                _ => self.repeat(template, depth - 1, &inner, renames, instantiated)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Every symbol of a template with the number of ellipses it is under.
+    fn occurrences(
+        &self,
+        template: &Value,
+        depth: usize,
+        escaped: bool,
+        found: &mut Vec<(String, usize)>,
+    ) {
+        let (items, tail) = match template {
+            Value::Symbol(symbol) => return found.push((symbol.clone(), depth)),
+            Value::Pair(_) => split(template),
+            Value::Vector(vector) => (vector.borrow().clone(), Value::Null),
+            _ => return,
+        };
+        if let ([ellipsis, template], false, Value::Null) = (items.as_slice(), escaped, &tail) {
+            if self.is_ellipsis(ellipsis) {
+                return self.occurrences(template, depth, true, found);
+            }
+        }
+        let mut i = 0;
+        while i < items.len() {
+            let mut repeats = 0;
+            while !escaped
+                && items
+                    .get(i + 1 + repeats)
+                    .is_some_and(|item| self.is_ellipsis(item))
+            {
+                repeats += 1;
+            }
+            self.occurrences(&items[i], depth + repeats, escaped, found);
+            i += 1 + repeats;
+        }
+        self.occurrences(&tail, depth, escaped, found);
+    }
+
+    // The alias a template symbol is inserted as, the same one for each
+    // occurrence in one expansion.
+    fn rename(&self, symbol: &str, renames: &mut HashMap<String, String>) -> String {
+        renames
+            .entry(symbol.to_string())
+            .or_insert_with(|| alias_of(symbol, &self.scope))
+            .clone()
+    }
+}
+
+// What `symbol` means in `scope`.
+fn resolve(env: &Environment, scope: &Rc<Scope>, symbol: &str) -> Denotation {
+    let mut next = Some(scope);
+    let mut root = scope;
+    while let Some(scope) = next {
+        if let Some(denotation) = scope.bindings.borrow().get(symbol) {
+            return denotation.clone();
+        }
+        root = scope;
+        next = scope.parent.as_ref();
+    }
+    // An identifier a macro of another environment inserts means what it
+    // means there. A variable of that environment is imported under a name
+    // of its own.
+    let home = root.env.as_ref().and_then(Weak::upgrade);
+    if let Some(home) = home.filter(|home| !std::ptr::eq(&**home, env)) {
+        return match resolve(&home, root, symbol) {
+            Denotation::Global(name) => Denotation::Global(import(env, &home, &name)),
+            denotation => denotation,
+        };
+    }
+    let global = env.get(&written(symbol));
+    if let Some(Value::Macro(m)) = global {
+        return Denotation::Macro(m);
+    }
+    match alias(symbol) {
+        // An alias defined at top level is a global variable of its own.
+        Some(_) if global.is_some() => Denotation::Global(symbol.to_string()),
+        Some(alias) => resolve(env, &alias.scope, &alias.symbol),
+        None if global.is_some() => Denotation::Global(symbol.to_string()),
+        None => match SPECIAL_FORMS.iter().find(|keyword| **keyword == symbol) {
+            Some(keyword) => Denotation::Special(keyword),
+            None => Denotation::Global(symbol.to_string()),
+        },
+    }
+}
+
+// Keep the bindings between a free alias and what it denotes from capturing
+// it, by renaming those that would be written under the same name.
+fn protect(scope: &Rc<Scope>, denotation: &Denotation) {
+    let name = match denotation {
+        Denotation::Variable(variable) | Denotation::Pattern(variable, _)
+            if !variable.renamed.get() =>
+        {
+            &variable.name
+        }
+        Denotation::Global(name) => name,
+        _ => return,
+    };
+    let mut next = Some(scope);
+    while let Some(scope) = next {
+        match scope.bindings.borrow().get(name) {
+            Some(binding) if binding.same(denotation) => return,
+            Some(Denotation::Variable(variable) | Denotation::Pattern(variable, _)) => {
+                variable.renamed.set(true)
+            }
+            _ => (),
+        }
+        next = scope.parent.as_ref();
+    }
+}
+
+// A new alias of `symbol` as it is in `scope`.
+fn alias_of(symbol: &str, scope: &Rc<Scope>) -> String {
+    let name = fresh(&strip_symbol(symbol));
+    let alias = Rc::new(Alias {
+        symbol: symbol.to_string(),
+        scope: Rc::clone(scope),
+    });
+    ALIASES.with(|aliases| aliases.borrow_mut().insert(name.clone(), alias));
+    EXPANSION.with(|expansion| {
+        let mut expansion = expansion.borrow_mut();
+        if expansion.depth > 0 {
+            expansion.aliases.push(name.clone());
+        }
+    });
+    name
+}
+
+// An identifier that means the builtin or syntax `name`, however the code
+// around it or the program binds `name`.
+fn core(name: &str) -> String {
+    alias_of(name, &Scope::root(&builtins::environment()))
+}
+
+// The name the variable `name` of `home` is imported into `env` under, one
+// no source text writes and the same for each reference.
+fn import(env: &Environment, home: &Rc<Environment>, name: &str) -> String {
+    let hidden = format!("{} {:x}", name, gc::identity(home));
+    env.import(&written(&hidden), home, &written(name));
+    hidden
+}
+
+fn alias(symbol: &str) -> Option<Rc<Alias>> {
+    ALIASES.with(|aliases| aliases.borrow().get(symbol).cloned())
+}
+
+// A name no source text writes without bars, made from `name`.
+fn fresh(name: &str) -> String {
+    let n = FRESH.with(|fresh| fresh.replace(fresh.get() + 1));
+    format!("{} {}", name, n)
+}
+
+// The identifier an alias was made from, through aliases of aliases.
+fn strip_symbol(symbol: &str) -> String {
+    let mut symbol = symbol.to_string();
+    while let Some(alias) = alias(&symbol) {
+        symbol.clone_from(&alias.symbol);
+    }
+    symbol
+}
+
+// A datum with its aliases replaced by their identifiers, as quote needs.
+// Data without aliases, which is all a program can write, is kept as it is,
+// shared structure and all.
+fn strip(datum: &Value) -> Value {
+    if !has_alias(datum) {
+        return datum.clone();
+    }
+    match datum {
+        Value::Symbol(symbol) => Value::Symbol(strip_symbol(symbol)),
+        Value::Pair(pair) => cons(strip(&pair.car()), strip(&pair.cdr())),
+        Value::Vector(vector) => Value::vector(vector.borrow().iter().map(strip).collect()),
+        other => other.clone(),
+    }
+}
+
+fn has_alias(datum: &Value) -> bool {
+    let mut visited = HashSet::new();
+    let mut stack = vec![datum.clone()];
+    while let Some(value) = stack.pop() {
+        match value {
+            Value::Symbol(symbol) if alias(&symbol).is_some() => return true,
+            Value::Pair(pair) if visited.insert(gc::identity(&pair)) => {
+                stack.push(pair.car());
+                stack.push(pair.cdr());
+            }
+            Value::Vector(vector) if visited.insert(gc::identity(&vector)) => {
+                stack.extend(vector.borrow().iter().cloned());
+            }
+            _ => (),
+        }
+    }
+    false
+}
+
+// How a symbol is written as an identifier, which is the name a global
+// variable is bound under.
+fn written(symbol: &str) -> String {
+    Value::Symbol(symbol.to_string()).to_string()
+}
+
+fn identifier(name: &str) -> Value {
+    Value::Symbol(name.to_string())
+}
+
+fn identifier_of(value: &Value) -> Result<&str, Error> {
+    match value {
+        Value::Symbol(symbol) => Ok(symbol),
+        other => Err(Error::BadSyntax(format!(
+            "expected an identifier, given {}",
+            strip(other)
+        ))),
+    }
+}
+
+fn malformed(form: &Value) -> Error {
+    Error::BadSyntax(format!("malformed form {}", strip(form)))
+}
+
+// The elements of a list and what ends it: `()` for a proper list.
+fn split(value: &Value) -> (Vec<Value>, Value) {
+    let mut items = Vec::new();
+    let mut tail = value.clone();
+    while let Value::Pair(pair) = tail {
+        items.push(pair.car());
+        tail = pair.cdr();
+    }
+    (items, tail)
+}
+
+fn list(value: &Value) -> Result<Vec<Value>, Error> {
+    match split(value) {
+        (items, Value::Null) => Ok(items),
+        _ => Err(Error::BadSyntax(format!(
+            "expected a list, given {}",
+            strip(value)
+        ))),
+    }
+}
+
+fn cons(car: Value, cdr: Value) -> Value {
+    Value::Pair(gc::alloc(Pair::new(car, cdr)))
+}
+
+// A form made from `from`, noted as written where `from` was unless it was
+// noted as written elsewhere.
+fn located(from: &Value, form: Value) -> Value {
+    ast::relocate(from, &form);
+    form
+}
+
+fn list_with_tail(items: Vec<Value>, tail: Value) -> Value {
+    items
+        .into_iter()
+        .rev()
+        .fold(tail, |cdr, car| cons(car, cdr))
+}
+
+fn from_list(items: Vec<Value>) -> Value {
+    list_with_tail(items, Value::Null)
+}
+
+// Copy a datum with every symbol replaced by what `f` makes of it, keeping
+// shared and cyclic structure.
+fn map_symbols(datum: &Value, f: &mut dyn FnMut(&str) -> String) -> Value {
+    map_symbols_in(datum, f, &mut HashMap::new())
+}
+
+fn map_symbols_in(
+    datum: &Value,
+    f: &mut dyn FnMut(&str) -> String,
+    copies: &mut HashMap<usize, Value>,
+) -> Value {
+    match datum {
+        Value::Symbol(symbol) => Value::Symbol(f(symbol)),
+        Value::Pair(pair) => {
+            if let Some(copy) = copies.get(&gc::identity(pair)) {
+                return copy.clone();
+            }
+            let copy = gc::alloc(Pair::new(Value::Null, Value::Null));
+            ast::relocate_pair(pair, &copy);
+            copies.insert(gc::identity(pair), Value::Pair(Rc::clone(&copy)));
+            copy.set_car(map_symbols_in(&pair.car(), f, copies));
+            copy.set_cdr(map_symbols_in(&pair.cdr(), f, copies));
+            Value::Pair(copy)
+        }
+        Value::Vector(vector) => {
+            if let Some(copy) = copies.get(&gc::identity(vector)) {
+                return copy.clone();
+            }
+            let copy = gc::alloc(RefCell::new(Vec::new()));
+            copies.insert(gc::identity(vector), Value::Vector(Rc::clone(&copy)));
+            let items = vector.borrow().clone();
+            *copy.borrow_mut() = items
+                .iter()
+                .map(|item| map_symbols_in(item, f, copies))
+                .collect();
+            Value::Vector(copy)
+        }
+        other => other.clone(),
+    }
+}
+
+fn is_procedure(value: &Value) -> bool {
+    matches!(
+        value,
+        Value::Procedure(_)
+            | Value::Primitive(_)
+            | Value::Control(_)
+            | Value::Continuation(_)
+            | Value::Parameter(_)
+            | Value::RecordProcedure(_)
+    )
+}
+
+// The procedures a transformer procedure is called with.
+const RENAME: Primitive = Primitive {
+    name: "rename",
+    arity: Arity::Exactly(1),
+    func: rename,
+};
+const INJECT: Primitive = Primitive {
+    name: "inject",
+    arity: Arity::Exactly(1),
+    func: inject,
+};
+const COMPARE: Primitive = Primitive {
+    name: "compare",
+    arity: Arity::Exactly(2),
+    func: compare,
+};
+
+/// The procedures of the low-level macro systems, which the builtins
+/// install into the global environment. `$syntax-dispatch` and
+/// `$syntax-template` are what syntax-case and syntax expand into.
+pub(crate) const PRIMITIVES: &[Primitive] = &[
+    Primitive {
+        name: "er-macro-transformer",
+        arity: Arity::Exactly(1),
+        func: er_macro_transformer,
+    },
+    Primitive {
+        name: "ir-macro-transformer",
+        arity: Arity::Exactly(1),
+        func: ir_macro_transformer,
+    },
+    Primitive {
+        name: "identifier?",
+        arity: Arity::Exactly(1),
+        func: is_identifier,
+    },
+    Primitive {
+        name: "syntax->datum",
+        arity: Arity::Exactly(1),
+        func: syntax_to_datum,
+    },
+    Primitive {
+        name: "datum->syntax",
+        arity: Arity::Exactly(2),
+        func: datum_to_syntax,
+    },
+    Primitive {
+        name: "free-identifier=?",
+        arity: Arity::Exactly(2),
+        func: free_identifier_equal,
+    },
+    Primitive {
+        name: "bound-identifier=?",
+        arity: Arity::Exactly(2),
+        func: bound_identifier_equal,
+    },
+    Primitive {
+        name: "generate-temporaries",
+        arity: Arity::Exactly(1),
+        func: generate_temporaries,
+    },
+    Primitive {
+        name: "syntax-violation",
+        arity: Arity::Between(3, 4),
+        func: syntax_violation,
+    },
+    Primitive {
+        name: "$syntax-template",
+        arity: Arity::AtLeast(1),
+        func: syntax_template,
+    },
+];
+
+pub(crate) const CONTROLS: &[Control] = &[Control {
+    name: "$syntax-dispatch",
+    arity: Arity::Exactly(4),
+    func: syntax_dispatch,
+}];
+
+// Run `f` on the call of a transformer procedure in progress.
+fn transcription<T>(procedure: &str, f: impl FnOnce(&mut Transcription) -> T) -> Result<T, Error> {
+    TRANSCRIPTIONS.with(
+        |transcriptions| match transcriptions.borrow_mut().last_mut() {
+            Some(transcription) => Ok(f(transcription)),
+            None => Err(Error::BadSyntax(format!(
+                "{}: called while no macro is being expanded",
+                procedure
+            ))),
+        },
+    )
+}
+
+fn symbol<'a>(procedure: &str, value: &'a Value) -> Result<&'a str, Error> {
+    match value {
+        Value::Symbol(symbol) => Ok(symbol),
+        other => Err(Error::WrongType {
+            procedure: procedure.to_string(),
+            expected: "identifier".to_string(),
+            given: other.to_string(),
+        }),
+    }
+}
+
+fn rename(args: &[Value]) -> Result<Value, Error> {
+    let symbol = symbol("rename", &args[0])?;
+    transcription("rename", |t| {
+        Value::Symbol(t.m.rename(symbol, &mut t.renames))
+    })
+}
+
+fn inject(args: &[Value]) -> Result<Value, Error> {
+    let symbol = symbol("inject", &args[0])?;
+    transcription("inject", |t| Value::Symbol(t.inject(symbol)))
+}
+
+fn compare(args: &[Value]) -> Result<Value, Error> {
+    match (&args[0], &args[1]) {
+        (Value::Symbol(a), Value::Symbol(b)) => transcription("compare", |t| {
+            Value::Boolean(t.denotation(a).same(&t.denotation(b)))
+        }),
+        (a, b) => Ok(Value::Boolean(a.eqv(b))),
+    }
+}
+
+fn transformer(procedure: &str, convention: Convention, value: &Value) -> Result<Value, Error> {
+    if !is_procedure(value) {
+        return Err(Error::WrongType {
+            procedure: procedure.to_string(),
+            expected: "procedure".to_string(),
+            given: value.to_string(),
+        });
+    }
+    // The keyword and scope are those of the define-syntax that binds it.
+    Ok(Value::Macro(Rc::new(Macro {
+        keyword: String::new(),
+        ellipsis: None,
+        literals: Vec::new(),
+        rules: Vec::new(),
+        procedure: Some((convention, value.clone())),
+        scope: Scope::extend(None),
+    })))
+}
+
+fn er_macro_transformer(args: &[Value]) -> Result<Value, Error> {
+    transformer("er-macro-transformer", Convention::Explicit, &args[0])
+}
+
+fn ir_macro_transformer(args: &[Value]) -> Result<Value, Error> {
+    transformer("ir-macro-transformer", Convention::Implicit, &args[0])
+}
+
+fn is_identifier(args: &[Value]) -> Result<Value, Error> {
+    Ok(Value::Boolean(matches!(args[0], Value::Symbol(_))))
+}
+
+fn syntax_to_datum(args: &[Value]) -> Result<Value, Error> {
+    Ok(strip(&args[0]))
+}
+
+// The identifiers of the datum are made to mean what they would where the
+// context identifier was inserted: as they are if the macro use wrote it,
+// and as aliases of the scope it was inserted in otherwise.
+fn datum_to_syntax(args: &[Value]) -> Result<Value, Error> {
+    let context = symbol("datum->syntax", &args[0])?;
+    Ok(match alias(context) {
+        Some(context) => {
+            let mut aliases = HashMap::new();
+            map_symbols(&args[1], &mut |symbol| {
+                aliases
+                    .entry(symbol.to_string())
+                    .or_insert_with(|| alias_of(symbol, &context.scope))
+                    .clone()
+            })
+        }
+        None => args[1].clone(),
+    })
+}
+
+fn free_identifier_equal(args: &[Value]) -> Result<Value, Error> {
+    let a = symbol("free-identifier=?", &args[0])?;
+    let b = symbol("free-identifier=?", &args[1])?;
+    let denotations = transcription("free-identifier=?", |t| {
+        (resolve(&t.env, &t.scope, a), resolve(&t.env, &t.scope, b))
+    });
+    Ok(Value::Boolean(match denotations {
+        Ok((a, b)) => a.same(&b),
+        // Outside a macro use identifiers are compared at top level.
+        Err(_) => strip_symbol(a) == strip_symbol(b),
+    }))
+}
+
+fn bound_identifier_equal(args: &[Value]) -> Result<Value, Error> {
+    let a = symbol("bound-identifier=?", &args[0])?;
+    let b = symbol("bound-identifier=?", &args[1])?;
+    Ok(Value::Boolean(a == b))
+}
+
+fn generate_temporaries(args: &[Value]) -> Result<Value, Error> {
+    match split(&args[0]) {
+        (items, Value::Null) => Ok(from_list(
+            items.iter().map(|_| identifier(&core("temp"))).collect(),
+        )),
+        _ => Err(Error::WrongType {
+            procedure: "generate-temporaries".to_string(),
+            expected: "list".to_string(),
+            given: args[0].to_string(),
+        }),
+    }
+}
+
+fn syntax_violation(args: &[Value]) -> Result<Value, Error> {
+    let message = match &args[1] {
+        Value::String(message) => message.borrow().clone(),
+        other => strip(other).to_string(),
+    };
+    let form = strip(args.get(3).unwrap_or(&args[2]));
+    Err(Error::BadSyntax(match &args[0] {
+        Value::Boolean(false) => format!("{} {}", message, form),
+        who => format!("{}: {} {}", strip(who), message, form),
+    }))
+}
+
+// Match the form against a clause of syntax-case, described as its pattern,
+// its literals and its pattern variables, and call the clause with what
+// the variables matched, or else the clauses after it.
+fn syntax_dispatch(args: &[Value]) -> Result<Tail, Error> {
+    let (pattern, literals, variables) = match split(&args[1]) {
+        (items, Value::Null) if items.len() >= 2 => {
+            (items[0].clone(), items[1].clone(), items[2..].to_vec())
+        }
+        _ => return Err(malformed(&args[1])),
+    };
+    let literals = list(&literals)?
+        .iter()
+        .map(|literal| identifier_of(literal).map(str::to_string))
+        .collect::<Result<_, _>>()?;
+    let matcher = Macro::syntax(literals);
+    let (env, scope) = transcription("$syntax-dispatch", |t| {
+        (Rc::clone(&t.env), Rc::clone(&t.scope))
+    })
+    .unwrap_or_else(|_| (gc::alloc(Environment::new()), Scope::extend(None)));
+    let expander = Expander {
+        env: &env,
+        span: Span::default(),
+        variables: HashMap::new(),
+        inserted: HashMap::new(),
+        failed: None,
+    };
+    let mut matched = HashMap::new();
+    if !expander.matches(&matcher, &pattern, &args[0], &scope, &mut matched) {
+        return Ok(Tail::Call(args[3].clone(), Vec::new()));
+    }
+    let values = variables
+        .iter()
+        .map(|variable| {
+            let binding = matched.remove(identifier_of(variable)?);
+            Ok(binding
+                .map(|binding| binding.syntax())
+                .unwrap_or(Value::Null))
+        })
+        .collect::<Result<_, Error>>()?;
+    Ok(Tail::Call(args[2].clone(), values))
+}
+
+// Fill in a syntax template, given with the depths of the pattern
+// variables it uses, with their values.
+fn syntax_template(args: &[Value]) -> Result<Value, Error> {
+    let (template, depths) = match &args[0] {
+        Value::Pair(pair) => (pair.car(), list(&pair.cdr())?),
+        other => return Err(malformed(other)),
+    };
+    let mut bindings = HashMap::new();
+    for (variable, value) in depths.iter().zip(&args[1..]) {
+        match variable {
+            Value::Pair(pair) => {
+                let depth = match pair.cdr() {
+                    Value::Number(Number::Integer(depth)) => depth as usize,
+                    other => return Err(malformed(&other)),
+                };
+                let name = identifier_of(&pair.car())?.to_string();
+                bindings.insert(name, (depth, Binding::of(value, depth)?));
+            }
+            other => return Err(malformed(other)),
+        }
+    }
+    let matcher = Macro::syntax(Vec::new());
+    match transcription("syntax", |t| {
+        matcher.instantiate(&template, &bindings, &mut t.renames, false)
+    }) {
+        Ok(instantiated) => instantiated,
+        // Outside a macro use, each template renames what it inserts.
+        Err(_) => matcher.instantiate(&template, &bindings, &mut HashMap::new(), false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r7rs::write::{Labels, Written};
+
+    // The forms a program expands into, with the numbers of fresh names
+    // left out.
+    fn expanded(input: &str) -> String {
+        let env = gc::alloc(Environment::new());
+        let mut forms = Vec::new();
+        for form in reader::read_all(input).unwrap() {
+            for form in expand_datum(form, &env, Span::default()).unwrap() {
+                forms.push(Written::new(&form, Labels::Cycles).to_string());
+            }
+        }
+        forms
+            .join(" ")
+            .split('|')
+            .enumerate()
+            .map(|(i, part)| match i % 2 {
+                1 => part
+                    .trim_end_matches(|c: char| c.is_ascii_digit())
+                    .trim_end(),
+                _ => part,
+            })
+            .collect::<Vec<&str>>()
+            .join("|")
+    }
+
+    #[test]
+    fn test_expand() {
+        let swap = "(define-syntax swap!
+                      (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))";
+        let tests = [
+            (
+                format!("{} (swap! tmp x)", swap),
+                "(let ((|tmp| tmp)) (set! tmp x) (set! x |tmp|))",
+            ),
+            (
+                format!("{} (lambda (tmp) (swap! tmp x))", swap),
+                "(lambda (tmp) (let ((|tmp| tmp)) (set! tmp x) (set! x |tmp|)))",
+            ),
+            // The binding of list around the use is renamed, so that the
+            // template's list is still the global one.
+            (
+                "(define-syntax l (syntax-rules () ((_ x) (list x))))
+                 (lambda (list) (l list))"
+                    .to_string(),
+                "(lambda (|list|) (list |list|))",
+            ),
+            (
+                "(define-syntax q (syntax-rules () ((_ x ...) '(x ... y #(z)))))
+                 (q 1 2)"
+                    .to_string(),
+                "(quote (1 2 y #(z)))",
+            ),
+            (
+                "(let-syntax ((one (syntax-rules () ((_) 1.0)))) (one))".to_string(),
+                "(let () 1.0)",
+            ),
+            // A variable named like a keyword keeps its fresh name.
+            (
+                "(lambda (if) (if 1))".to_string(),
+                "(lambda (|if|) (|if| 1))",
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(expanded(input), *output);
+        }
+    }
+
+    #[test]
+    fn test_expand_forgets_aliases() {
+        let env = gc::alloc(Environment::new());
+        builtins::install(&env);
+        let expand = |input: &str| {
+            for form in reader::read_all(input).unwrap() {
+                for cdef in expand_datum(form, &env, Span::default()).unwrap() {
+                    eval::eval_cdef(&CDef::from_datum(&cdef, Span::default()).unwrap(), &env)
+                        .unwrap();
+                }
+            }
+        };
+        let aliases = || ALIASES.with(|aliases| aliases.borrow().len());
+        expand(
+            "(define-syntax swap!
+               (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+             (define-syntax my-or
+               (lambda (x)
+                 (syntax-case x ()
+                   ((_) (syntax #f))
+                   ((_ e r ...) (syntax (let ((t e)) (if t t (my-or r ...))))))))
+             (define-syntax define-swap
+               (syntax-rules () ((_ name) (define-syntax name (syntax-rules () ((_ a b) (swap! a b)))))))
+             (define-swap exchange!)
+             (define x 1)
+             (define y 2)",
+        );
+        let uses = "(swap! x y)
+                    (exchange! x y)
+                    (my-or #f x)
+                    (letrec-syntax ((again (syntax-rules () ((_ a) (swap! a a))))) (again x))";
+        expand(uses);
+        let before = aliases();
+        for _ in 0..100 {
+            expand(uses);
+        }
+        assert_eq!(aliases(), before);
+        // The macros defined by macros still mean what they did.
+        expand("(exchange! x y)");
+        assert_eq!(env.get("x").unwrap().to_string(), "2");
+    }
+}
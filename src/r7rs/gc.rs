@@ -0,0 +1,247 @@
+//! The collector for cyclic garbage. Heap objects are shared through `Rc`,
+//! which frees everything but cycles, such as a closure stored in the frame
+//! it closes over. Every pair, vector, record, error object, parameter,
+//! promise, procedure and environment is allocated through `alloc`, which
+//! registers it with the heap of the current thread; `collect` then finds
+//! the registered objects that are only reachable from each other and
+//! breaks their cycles.
+//!
+//! The collector needs no list of roots. An object is a root if it has more
+//! strong references than the heap objects that point at it account for, so
+//! values held by Rust code, by the AST or by the host are never collected.
+
+use crate::r7rs::value::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+// A collection runs once this many objects have been allocated since the
+// last one, or as many as survived it if that is more.
+const COLLECT_AFTER: usize = 100_000;
+
+/// A heap object that can hold references to other heap objects.
+pub(crate) trait Trace {
+    /// Call `visit` with the identity of every heap object this one holds a
+    /// strong reference to, once per reference.
+    fn trace(&self, visit: &mut dyn FnMut(usize));
+    /// Drop the references this object holds by moving them into `trash`.
+    /// Only the references that can be replaced after the object is created
+    /// need clearing, as every cycle passes through one of them.
+    fn clear(&self, trash: &mut Vec<Value>);
+}
+
+/// Statistics about the heap of the current thread.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Heap objects that are alive now.
+    pub live: usize,
+    /// Heap objects allocated since the thread started.
+    pub allocated: usize,
+    /// Collections run so far.
+    pub collections: usize,
+    /// Heap objects freed by collections, as opposed to reference counting.
+    pub collected: usize,
+}
+
+struct Heap {
+    objects: Vec<Weak<dyn Trace>>,
+    // The length `objects` may grow to before dead entries are dropped.
+    prune_at: usize,
+    // Allocations since the last collection, and how many are due.
+    since_collection: usize,
+    collect_at: usize,
+    stats: HeapStats,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap {
+        objects: Vec::new(),
+        prune_at: 1024,
+        since_collection: 0,
+        collect_at: COLLECT_AFTER,
+        stats: HeapStats::default(),
+    });
+}
+
+/// Allocate a heap object and register it with the collector.
+pub(crate) fn alloc<T: Trace + 'static>(object: T) -> Rc<T> {
+    let object = Rc::new(object);
+    register(&object);
+    object
+}
+
+/// Register an object allocated with `Rc::new` with the collector, once it
+/// may become part of a cycle.
+pub(crate) fn register<T: Trace + 'static>(object: &Rc<T>) {
+    let weak: Weak<dyn Trace> = Rc::downgrade(object) as Weak<dyn Trace>;
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        if heap.objects.len() >= heap.prune_at {
+            heap.objects.retain(|object| object.strong_count() > 0);
+            heap.prune_at = (heap.objects.len() * 2).max(1024);
+        }
+        heap.objects.push(weak);
+        heap.since_collection += 1;
+        heap.stats.allocated += 1;
+    });
+}
+
+/// The identity of a heap object: the address it is shared at.
+pub(crate) fn identity<T: ?Sized>(object: &Rc<T>) -> usize {
+    Rc::as_ptr(object) as *const () as usize
+}
+
+/// Visit the heap object a value refers to, if any.
+pub(crate) fn trace_value(value: &Value, visit: &mut dyn FnMut(usize)) {
+    match value {
+        Value::Pair(pair) => visit(identity(pair)),
+        Value::Vector(vector) => visit(identity(vector)),
+        Value::Procedure(procedure) => visit(identity(procedure)),
+        Value::Continuation(continuation) => visit(identity(continuation)),
+        Value::Record(record) => visit(identity(record)),
+        Value::ErrorObject(object) => visit(identity(object)),
+        Value::Parameter(parameter) => visit(identity(parameter)),
+        Value::Promise(promise) => visit(identity(promise)),
+        _ => (),
+    }
+}
+
+/// Free the heap objects of the current thread that are only reachable from
+/// each other, such as closures bound in the frames they close over,
+/// returning how many were freed. The heap is shared by every Interpreter
+/// on the thread and by the values read or built on it from Rust, so this
+/// collects them all. Collections also run on their own as the heap grows.
+pub fn collect() -> usize {
+    let objects: Vec<Rc<dyn Trace>> = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.objects.retain(|object| object.strong_count() > 0);
+        heap.objects.iter().filter_map(Weak::upgrade).collect()
+    });
+    let index: HashMap<usize, usize> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, object)| (identity(object), i))
+        .collect();
+
+    // Count the references each object gets from other heap objects. Any
+    // others, less the one held by `objects`, come from outside the heap.
+    let mut internal = vec![0; objects.len()];
+    for object in objects.iter() {
+        object.trace(&mut |id| {
+            if let Some(&i) = index.get(&id) {
+                internal[i] += 1;
+            }
+        });
+    }
+    let mut marked = vec![false; objects.len()];
+    let mut stack: Vec<usize> = (0..objects.len())
+        .filter(|&i| Rc::strong_count(&objects[i]) - 1 > internal[i])
+        .collect();
+    while let Some(i) = stack.pop() {
+        if marked[i] {
+            continue;
+        }
+        marked[i] = true;
+        objects[i].trace(&mut |id| {
+            if let Some(&child) = index.get(&id) {
+                if !marked[child] {
+                    stack.push(child);
+                }
+            }
+        });
+    }
+
+    // Everything unmarked is only reachable from other unmarked objects.
+    // Clearing them all before any is dropped keeps the drops from running
+    // while the objects are being cleared.
+    let mut trash = Vec::new();
+    let mut freed = 0;
+    for (object, marked) in objects.iter().zip(marked.iter()) {
+        if !marked {
+            object.clear(&mut trash);
+            freed += 1;
+        }
+    }
+    drop(trash);
+    let live = objects.len() - freed;
+    drop(objects);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.since_collection = 0;
+        heap.collect_at = live.max(COLLECT_AFTER);
+        heap.stats.collections += 1;
+        heap.stats.collected += freed;
+    });
+    freed
+}
+
+/// Collect if enough has been allocated since the last collection. The
+/// evaluator calls this where no heap object is borrowed.
+pub(crate) fn collect_if_due() {
+    if HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.since_collection >= heap.collect_at
+    }) {
+        collect();
+    }
+}
+
+/// Statistics about the heap of the current thread, which is shared by
+/// every Interpreter on it.
+pub fn stats() -> HeapStats {
+    HEAP.with(|heap| {
+        let heap = heap.borrow();
+        HeapStats {
+            live: heap
+                .objects
+                .iter()
+                .filter(|object| object.strong_count() > 0)
+                .count(),
+            ..heap.stats
+        }
+    })
+}
+
+// Vectors are the one heap object whose type is not defined in this crate.
+impl Trace for RefCell<Vec<Value>> {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        for item in self.borrow().iter() {
+            trace_value(item, visit);
+        }
+    }
+    fn clear(&self, trash: &mut Vec<Value>) {
+        trash.append(&mut self.borrow_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r7rs::value::Pair;
+
+    #[test]
+    fn test_collect_cycle() {
+        collect();
+        let before = stats();
+        let pair = alloc(Pair::new(Value::Null, Value::Null));
+        let weak = Rc::downgrade(&pair);
+        pair.set_cdr(Value::Pair(Rc::clone(&pair)));
+        let vector = Value::vector(vec![Value::Pair(Rc::clone(&pair))]);
+        pair.set_car(vector.clone());
+
+        // The pair is held from Rust, so it survives.
+        assert_eq!(collect(), 0);
+        drop(pair);
+        assert_eq!(collect(), 0);
+        assert!(weak.upgrade().is_some());
+
+        drop(vector);
+        assert_eq!(collect(), 2);
+        assert!(weak.upgrade().is_none());
+        let after = stats();
+        assert_eq!(after.live, before.live);
+        assert_eq!(after.collected, before.collected + 2);
+        assert_eq!(after.allocated, before.allocated + 2);
+    }
+}
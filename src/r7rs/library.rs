@@ -0,0 +1,398 @@
+//! Libraries. A define-library form, whether a program writes it or it is
+//! found in a file, is evaluated in an environment of its own the first time
+//! it is imported. Importing binds what it exports in the importing
+//! environment to the same variables, so that importers see what the library
+//! later assigns. The standard libraries export the builtins.
+//!
+//! A library name that is neither standard nor defined by a program is
+//! looked up as a file: its parts are joined as path components and `.sld`
+//! is added, so `(example grid)` is `example/grid.sld` under each directory
+//! of the search path in turn.
+
+use crate::r7rs::ast::{
+    self, FeatureRequirement, Import, Library, LibraryDeclaration, LibraryName, Source, AST,
+};
+use crate::r7rs::builtins;
+use crate::r7rs::env::Environment;
+use crate::r7rs::error::Error;
+use crate::r7rs::eval::eval_cdef;
+use crate::r7rs::expand::expand;
+use crate::r7rs::gc;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// The standard libraries, by name, with the builtins they export. Syntax is
+// known everywhere, so the libraries that only export syntax export nothing.
+const STANDARD: &[(&[&str], &[&str])] = &[
+    (
+        &["scheme", "base"],
+        &[
+            "not",
+            "boolean?",
+            "char?",
+            "string?",
+            "symbol?",
+            "procedure?",
+            "null?",
+            "pair?",
+            "length",
+            "cons",
+            "car",
+            "cdr",
+            "list",
+            "append",
+            "string-length",
+            "string-append",
+            "char->integer",
+            "+",
+            "-",
+            "*",
+            "=",
+            "<",
+            ">",
+            "eq?",
+            "eqv?",
+            "equal?",
+            "set-car!",
+            "set-cdr!",
+            "vector?",
+            "vector",
+            "make-vector",
+            "vector-length",
+            "vector-ref",
+            "vector-set!",
+            "make-string",
+            "string-ref",
+            "string-set!",
+            "bytevector?",
+            "bytevector",
+            "make-bytevector",
+            "bytevector-length",
+            "bytevector-u8-ref",
+            "bytevector-u8-set!",
+            "values",
+            "error-object?",
+            "error-object-message",
+            "error-object-irritants",
+            "read-error?",
+            "file-error?",
+            "apply",
+            "call-with-current-continuation",
+            "call/cc",
+            "call-with-values",
+            "dynamic-wind",
+            "make-parameter",
+            "with-exception-handler",
+            "raise",
+            "raise-continuable",
+            "error",
+        ],
+    ),
+    (&["scheme", "case-lambda"], &[]),
+    (&["scheme", "lazy"], &["force", "make-promise", "promise?"]),
+    (
+        &["scheme", "write"],
+        &["write", "write-shared", "write-simple"],
+    ),
+    (
+        &["sevenfold", "syntax"],
+        &[
+            "er-macro-transformer",
+            "ir-macro-transformer",
+            "identifier?",
+            "syntax->datum",
+            "datum->syntax",
+            "free-identifier=?",
+            "bound-identifier=?",
+            "generate-temporaries",
+            "syntax-violation",
+        ],
+    ),
+];
+
+// The features cond-expand requirements may name.
+const FEATURES: &[&str] = &["r7rs", "ratios", "sevenfold", "else"];
+
+/// The libraries known to an interpreter: those its programs define, those
+/// it has loaded and the path it looks others up on.
+pub struct Libraries {
+    path: Vec<PathBuf>,
+    // Libraries defined but not loaded yet, with the text they were parsed
+    // from.
+    defined: HashMap<LibraryName, (Library, Rc<str>)>,
+    loaded: HashMap<LibraryName, Rc<Exports>>,
+    // The libraries being loaded, innermost last.
+    loading: Vec<LibraryName>,
+}
+
+// Names an import binds or a library exports, each with the variable of
+// the library environment it is bound to.
+type Names = Vec<(String, String)>;
+
+// A loaded library: its environment, and what it exports under the names
+// importers know them by.
+struct Exports {
+    env: Rc<Environment>,
+    names: Names,
+}
+
+impl Libraries {
+    pub fn new() -> Libraries {
+        Libraries {
+            path: vec![PathBuf::from(".")],
+            defined: HashMap::new(),
+            loaded: HashMap::new(),
+            loading: Vec::new(),
+        }
+    }
+
+    /// The directories library names are looked up in, in order.
+    pub fn path(&self) -> &[PathBuf] {
+        &self.path
+    }
+
+    pub fn set_path(&mut self, path: Vec<PathBuf>) {
+        self.path = path;
+    }
+
+    /// Define a library parsed from `text`. It is evaluated when it is first
+    /// imported, and again if it is defined again.
+    pub fn define(&mut self, library: Library, text: &Rc<str>) {
+        self.loaded.remove(&library.name);
+        self.defined
+            .insert(library.name.clone(), (library, Rc::clone(text)));
+    }
+
+    /// Bind what an import set names in `env`, loading its library first if
+    /// need be.
+    pub fn import(&mut self, set: &Import, env: &Environment) -> Result<(), Error> {
+        let (library, names) = self.names(set)?;
+        for (name, internal) in names.iter() {
+            env.import(name, &library.env, internal);
+        }
+        Ok(())
+    }
+
+    // The library an import set is of, and the names it binds with the
+    // variables of the library they are bound to.
+    fn names(&mut self, set: &Import) -> Result<(Rc<Exports>, Names), Error> {
+        Ok(match set {
+            Import::Library(name) => {
+                let library = self.load(name)?;
+                let names = library.names.clone();
+                (library, names)
+            }
+            Import::Only { set, identifiers } => {
+                let (library, mut names) = self.names(set)?;
+                require(identifiers, &names)?;
+                names.retain(|(name, _)| identifiers.contains(name));
+                (library, names)
+            }
+            Import::Except { set, identifiers } => {
+                let (library, mut names) = self.names(set)?;
+                require(identifiers, &names)?;
+                names.retain(|(name, _)| !identifiers.contains(name));
+                (library, names)
+            }
+            Import::Prefix { set, prefix } => {
+                let (library, mut names) = self.names(set)?;
+                for (name, _) in names.iter_mut() {
+                    name.insert_str(0, prefix);
+                }
+                (library, names)
+            }
+            Import::Rename { set, renames } => {
+                let (library, mut names) = self.names(set)?;
+                require(renames.iter().map(|(from, _)| from), &names)?;
+                for (name, _) in names.iter_mut() {
+                    if let Some((_, to)) = renames.iter().find(|(from, _)| from == name) {
+                        name.clone_from(to);
+                    }
+                }
+                (library, names)
+            }
+        })
+    }
+
+    fn load(&mut self, name: &LibraryName) -> Result<Rc<Exports>, Error> {
+        if let Some(library) = self.loaded.get(name) {
+            return Ok(Rc::clone(library));
+        }
+        if self.loading.contains(name) {
+            return Err(Error::BadSyntax(format!("library {} imports itself", name)));
+        }
+        let library = match standard(name) {
+            Some(names) => Exports {
+                env: builtins::environment(),
+                names: names
+                    .iter()
+                    .map(|name| (name.to_string(), name.to_string()))
+                    .collect(),
+            },
+            None => {
+                let (library, text) = match self.defined.remove(name) {
+                    Some(definition) => definition,
+                    None => self.find(name)?,
+                };
+                self.loading.push(library.name.clone());
+                let library = self.evaluate(&library, &text);
+                self.loading.pop();
+                library?
+            }
+        };
+        let library = Rc::new(library);
+        self.loaded.insert(name.clone(), Rc::clone(&library));
+        Ok(library)
+    }
+
+    // Evaluate the declarations of a library in a new environment.
+    fn evaluate(&mut self, library: &Library, text: &str) -> Result<Exports, Error> {
+        let env = gc::alloc(Environment::new());
+        let mut names = Vec::new();
+        let source = Source::new(library.span.file, text);
+        self.declare(&library.declarations, &source, &env, &mut names)?;
+        for (_, internal) in names.iter() {
+            if env.get(internal).is_none() {
+                return Err(Error::BadSyntax(format!(
+                    "library {} exports {}, which it does not define",
+                    library.name, internal
+                )));
+            }
+        }
+        Ok(Exports { env, names })
+    }
+
+    fn declare(
+        &mut self,
+        declarations: &[LibraryDeclaration],
+        source: &Source,
+        env: &Rc<Environment>,
+        names: &mut Names,
+    ) -> Result<(), Error> {
+        for declaration in declarations.iter() {
+            match declaration {
+                LibraryDeclaration::Export(specs) => names.extend(
+                    specs
+                        .iter()
+                        .map(|spec| (spec.external.clone(), spec.internal.clone())),
+                ),
+                LibraryDeclaration::Import(sets) => {
+                    for set in sets.iter() {
+                        self.import(set, env)?;
+                    }
+                }
+                LibraryDeclaration::Begin(forms) => {
+                    for span in forms.iter() {
+                        for form in expand(*span, source, env)?.iter() {
+                            eval_cdef(form, env)?;
+                        }
+                        gc::collect_if_due();
+                    }
+                }
+                LibraryDeclaration::IncludeLibraryDeclarations { files, span } => {
+                    let directory = ast::source_name(span.file)
+                        .and_then(|name| Path::new(&name).parent().map(Path::to_path_buf))
+                        .unwrap_or_default();
+                    for file in files.iter() {
+                        let path = directory.join(file);
+                        let name = path.display().to_string();
+                        let text = std::fs::read_to_string(&path)?;
+                        let source = Source::new(ast::register_source(&name), &text);
+                        let declarations = LibraryDeclaration::parse(&source)
+                            .map_err(|e| Error::Parse(e.with_source(&name)))?;
+                        self.declare(&declarations, &source, env, names)?;
+                    }
+                }
+                LibraryDeclaration::CondExpand(clauses) => {
+                    let clause = clauses
+                        .iter()
+                        .find(|(requirement, _)| self.satisfies(requirement));
+                    if let Some((_, declarations)) = clause {
+                        self.declare(declarations, source, env, names)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn satisfies(&self, requirement: &FeatureRequirement) -> bool {
+        match requirement {
+            FeatureRequirement::Feature(feature) => FEATURES.contains(&feature.as_str()),
+            FeatureRequirement::Library(name) => {
+                standard(name).is_some()
+                    || self.loaded.contains_key(name)
+                    || self.defined.contains_key(name)
+                    || self.file(name).is_some()
+            }
+            FeatureRequirement::And(requirements) => requirements
+                .iter()
+                .all(|requirement| self.satisfies(requirement)),
+            FeatureRequirement::Or(requirements) => requirements
+                .iter()
+                .any(|requirement| self.satisfies(requirement)),
+            FeatureRequirement::Not(requirement) => !self.satisfies(requirement),
+        }
+    }
+
+    // The file on the search path a library name is looked up as.
+    fn file(&self, name: &LibraryName) -> Option<PathBuf> {
+        let relative = format!("{}.sld", name.0.join("/"));
+        self.path
+            .iter()
+            .map(|directory| directory.join(&relative))
+            .find(|path| path.is_file())
+    }
+
+    // Parse the file a library name is looked up as. Every library the file
+    // defines is defined, and the one named is returned.
+    fn find(&mut self, name: &LibraryName) -> Result<(Library, Rc<str>), Error> {
+        let path = self
+            .file(name)
+            .ok_or_else(|| Error::UnknownLibrary(name.to_string()))?;
+        let source_name = path.display().to_string();
+        let text = std::fs::read_to_string(&path)?;
+        let text: Rc<str> = Rc::from(text);
+        let source = Source::new(ast::register_source(&source_name), &text);
+        let AST::Program { libraries, .. } =
+            AST::parse(&source).map_err(|e| Error::Parse(e.with_source(&source_name)))?;
+        for library in libraries {
+            self.define(library, &text);
+        }
+        self.defined.remove(name).ok_or_else(|| {
+            Error::BadSyntax(format!("{} does not define library {}", source_name, name))
+        })
+    }
+}
+
+impl Default for Libraries {
+    fn default() -> Libraries {
+        Libraries::new()
+    }
+}
+
+// The builtins a standard library exports.
+fn standard(name: &LibraryName) -> Option<&'static [&'static str]> {
+    STANDARD
+        .iter()
+        .find(|(parts, _)| name.0.iter().eq(parts.iter()))
+        .map(|(_, names)| *names)
+}
+
+// Check that an import set has every identifier that only, except or rename
+// names.
+fn require<'a>(
+    identifiers: impl IntoIterator<Item = &'a String>,
+    names: &[(String, String)],
+) -> Result<(), Error> {
+    for identifier in identifiers {
+        if names.iter().all(|(name, _)| name != identifier) {
+            return Err(Error::BadSyntax(format!(
+                "{} is not in the import set",
+                identifier
+            )));
+        }
+    }
+    Ok(())
+}
@@ -1,4 +1,14 @@
-mod ast;
+pub mod ast;
+pub mod builtins;
+pub mod compile;
 pub mod env;
+pub mod error;
+pub mod eval;
+pub mod expand;
+pub mod gc;
+pub mod library;
+pub mod number;
+pub mod reader;
 pub mod value;
 pub mod vars;
+pub mod write;
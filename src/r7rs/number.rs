@@ -0,0 +1,299 @@
+//! Conversion of numeric literals in any radix, with or without exactness
+//! prefixes, into Numbers.
+
+use crate::r7rs::value::Number;
+
+/// A real number while it is being read. Exact parts stay exact until the
+/// exactness prefix has been applied.
+#[derive(Clone, Copy)]
+enum Real {
+    Exact { numerator: i64, denominator: u64 },
+    Inexact(f64),
+}
+impl Real {
+    fn is_exact_zero(&self) -> bool {
+        matches!(self, Real::Exact { numerator: 0, .. })
+    }
+    fn to_f64(self) -> f64 {
+        match self {
+            Real::Exact {
+                numerator,
+                denominator,
+            } => numerator as f64 / denominator as f64,
+            Real::Inexact(r) => r,
+        }
+    }
+    fn into_number(self) -> Number {
+        match self {
+            Real::Exact {
+                numerator,
+                denominator: 1,
+            } => Number::Integer(numerator),
+            Real::Exact {
+                numerator,
+                denominator,
+            } => Number::Rational {
+                numerator,
+                denominator,
+            },
+            Real::Inexact(r) => Number::Real(r),
+        }
+    }
+}
+
+/// Parse the text of a `number` token, e.g. `42`, `#x-1F`, `#e1.5`, `1/3`,
+/// `+inf.0`, `1@0.5` or `-2.5+3i`. Complex numbers are always stored
+/// inexactly, so one with a nonzero imaginary part cannot be read with the
+/// `#e` prefix. On failure the error says what was expected instead, e.g.
+/// an exact number that fits in 64 bits.
+pub fn parse(text: &str) -> Result<Number, String> {
+    let mut radix = 10;
+    let mut exactness = None;
+    let mut body = text;
+    while let Some(rest) = body.strip_prefix('#') {
+        match rest.as_bytes().first().map(|b| b.to_ascii_lowercase()) {
+            Some(b'b') => radix = 2,
+            Some(b'o') => radix = 8,
+            Some(b'd') => radix = 10,
+            Some(b'x') => radix = 16,
+            Some(b'e') => exactness = Some(true),
+            Some(b'i') => exactness = Some(false),
+            _ => return Err("a number".to_string()),
+        }
+        body = &rest[1..];
+    }
+    let real = |part: &str| parse_real(part, radix, exactness);
+    let complex = |real: f64, imaginary: f64| match exactness {
+        Some(true) => Err("a number with an exact representation".to_string()),
+        _ => Ok(Number::Complex { real, imaginary }),
+    };
+    if let Some(body) = body.strip_suffix(|c| c == 'i' || c == 'I') {
+        let (real_part, imaginary_part) = body.split_at(imaginary_start(body, radix));
+        let real_part = match real_part {
+            "" => Real::Exact {
+                numerator: 0,
+                denominator: 1,
+            },
+            _ => real(real_part)?,
+        };
+        let imaginary_part = match imaginary_part {
+            "+" | "-" => real(&format!("{}1", imaginary_part))?,
+            _ => real(imaginary_part)?,
+        };
+        if imaginary_part.is_exact_zero() {
+            return Ok(real_part.into_number());
+        }
+        complex(real_part.to_f64(), imaginary_part.to_f64())
+    } else if let Some((magnitude, angle)) = body.split_once('@') {
+        let (magnitude, angle) = (real(magnitude)?, real(angle)?);
+        if angle.is_exact_zero() {
+            return Ok(magnitude.into_number());
+        }
+        let (magnitude, angle) = (magnitude.to_f64(), angle.to_f64());
+        complex(magnitude * angle.cos(), magnitude * angle.sin())
+    } else {
+        Ok(real(body)?.into_number())
+    }
+}
+
+// The imaginary part of `a+bi` starts at the last sign that is not the sign
+// of an exponent. A pure imaginary number has no real part.
+fn imaginary_start(body: &str, radix: u32) -> usize {
+    let bytes = body.as_bytes();
+    (1..bytes.len())
+        .rev()
+        .find(|&k| {
+            matches!(bytes[k], b'+' | b'-') && !(radix == 10 && matches!(bytes[k - 1], b'e' | b'E'))
+        })
+        .unwrap_or(0)
+}
+
+fn parse_real(text: &str, radix: u32, exactness: Option<bool>) -> Result<Real, String> {
+    let out_of_range = || "a number that fits in 64 bits".to_string();
+    let real = match text.to_ascii_lowercase().as_str() {
+        "+inf.0" => Real::Inexact(f64::INFINITY),
+        "-inf.0" => Real::Inexact(f64::NEG_INFINITY),
+        "+nan.0" | "-nan.0" => Real::Inexact(f64::NAN),
+        _ => {
+            let (negative, digits) = match text.as_bytes()[0] {
+                b'+' => (false, &text[1..]),
+                b'-' => (true, &text[1..]),
+                _ => (false, text),
+            };
+            if let Some((numerator, denominator)) = digits.split_once('/') {
+                let numerator =
+                    u64::from_str_radix(numerator, radix).map_err(|_| out_of_range())?;
+                let denominator =
+                    u64::from_str_radix(denominator, radix).map_err(|_| out_of_range())?;
+                if denominator == 0 {
+                    return Err("a nonzero denominator".to_string());
+                }
+                exact(negative, numerator, denominator).ok_or_else(out_of_range)?
+            } else if radix == 10 && digits.contains(['.', 'e', 'E']) {
+                if exactness == Some(true) {
+                    let (numerator, denominator) =
+                        exact_decimal(digits).ok_or_else(out_of_range)?;
+                    exact(negative, numerator, denominator).ok_or_else(out_of_range)?
+                } else {
+                    Real::Inexact(text.parse::<f64>().map_err(|_| out_of_range())?)
+                }
+            } else {
+                let magnitude = u64::from_str_radix(digits, radix).map_err(|_| out_of_range())?;
+                exact(negative, magnitude, 1).ok_or_else(out_of_range)?
+            }
+        }
+    };
+    match (exactness, real) {
+        (Some(false), real) => Ok(Real::Inexact(real.to_f64())),
+        (Some(true), Real::Inexact(_)) => Err("a number with an exact representation".to_string()),
+        (_, real) => Ok(real),
+    }
+}
+
+// An exact rational in lowest terms, or None if it does not fit.
+fn exact(negative: bool, numerator: u64, denominator: u64) -> Option<Real> {
+    let divisor = gcd(numerator, denominator);
+    // Negated before narrowing, as i64::MIN has no positive counterpart.
+    let magnitude = i128::from(numerator / divisor);
+    Some(Real::Exact {
+        numerator: i64::try_from(if negative { -magnitude } else { magnitude }).ok()?,
+        denominator: denominator / divisor,
+    })
+}
+
+// The exact value of an unsigned decimal such as `1.25e2` as a fraction.
+fn exact_decimal(digits: &str) -> Option<(u64, u64)> {
+    let (mantissa, exponent) = match digits.find(['e', 'E']) {
+        Some(k) => (&digits[..k], digits[k + 1..].parse::<i32>().ok()?),
+        None => (digits, 0),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let mut numerator: u64 = 0;
+    for digit in whole.bytes().chain(fraction.bytes()) {
+        numerator = numerator
+            .checked_mul(10)?
+            .checked_add(u64::from(digit - b'0'))?;
+    }
+    let exponent = exponent.checked_sub(i32::try_from(fraction.len()).ok()?)?;
+    if exponent >= 0 {
+        Some((
+            numerator.checked_mul(10u64.checked_pow(exponent as u32)?)?,
+            1,
+        ))
+    } else {
+        Some((numerator, 10u64.checked_pow(exponent.unsigned_abs())?))
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    match b {
+        0 => a.max(1),
+        _ => gcd(b, a % b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_exact() {
+        let tests = [
+            ("42", Number::Integer(42)),
+            ("-17", Number::Integer(-17)),
+            ("#b101", Number::Integer(5)),
+            ("#o-17", Number::Integer(-15)),
+            ("#xFF", Number::Integer(255)),
+            ("#X#e1f", Number::Integer(31)),
+            ("#d10", Number::Integer(10)),
+            (
+                "6/4",
+                Number::Rational {
+                    numerator: 3,
+                    denominator: 2,
+                },
+            ),
+            ("-4/2", Number::Integer(-2)),
+            (
+                "#e1.25",
+                Number::Rational {
+                    numerator: 5,
+                    denominator: 4,
+                },
+            ),
+            ("#e1e3", Number::Integer(1000)),
+            ("1+0i", Number::Integer(1)),
+            ("3@0", Number::Integer(3)),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(parse(input).unwrap(), *output, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_inexact() {
+        let tests = [
+            ("1.5", 1.5),
+            (".5", 0.5),
+            ("1.", 1.0),
+            ("-2e3", -2000.0),
+            ("#i3/4", 0.75),
+            ("#i#x10", 16.0),
+            ("+inf.0", f64::INFINITY),
+            ("-inf.0", f64::NEG_INFINITY),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(parse(input).unwrap(), Number::Real(*output), "{}", input);
+        }
+        assert!(matches!(parse("+nan.0").unwrap(), Number::Real(r) if r.is_nan()));
+    }
+
+    #[test]
+    fn test_parse_complex() {
+        let tests = [
+            ("1+2i", 1.0, 2.0),
+            ("-2.5-3i", -2.5, -3.0),
+            ("+i", 0.0, 1.0),
+            ("-i", 0.0, -1.0),
+            ("1e2-1e-1i", 100.0, -0.1),
+            ("#x1e+ai", 30.0, 10.0),
+            ("-inf.0i", 0.0, f64::NEG_INFINITY),
+            ("2@0.0", 2.0, 0.0),
+        ];
+        for (input, real, imaginary) in tests.iter() {
+            assert_eq!(
+                parse(input).unwrap(),
+                Number::Complex {
+                    real: *real,
+                    imaginary: *imaginary
+                },
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for input in [
+            "9223372036854775808",
+            "#x10000000000000000",
+            "1/0",
+            "#e+inf.0",
+            "#e1.2@3",
+            "#e1+2i",
+        ]
+        .iter()
+        {
+            assert!(parse(input).is_err(), "{}", input);
+        }
+        assert_eq!(
+            parse("-9223372036854775807").unwrap(),
+            Number::Integer(-9223372036854775807)
+        );
+        assert_eq!(
+            parse("-9223372036854775808").unwrap(),
+            Number::Integer(i64::MIN)
+        );
+    }
+}
@@ -0,0 +1,472 @@
+//! The reader turns the external representation of Scheme data into Values,
+//! the way `read` does. It is the basis of quotation and can also be used to
+//! load Scheme data from Rust, e.g. as a configuration format.
+
+use crate::parser::{child, unexpected, R7RSParser, Rule};
+use crate::r7rs::ast::{self, Source, Span};
+use crate::r7rs::error::{Error, ParseError};
+use crate::r7rs::gc;
+use crate::r7rs::number;
+use crate::r7rs::value::{Number, Pair, Value};
+use pest::Parser;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// Read a source text holding exactly one datum.
+pub fn read_datum(src: &str) -> Result<Value, Error> {
+    let source = Source::new(0, src);
+    let mut pairs = R7RSParser::parse(Rule::single_datum, src).map_err(ParseError::from)?;
+    let input = child(&mut pairs, pest::Span::new(src, 0, src.len()).unwrap())?;
+    let span = input.as_span();
+    let mut reader = Reader::new(&source, 0, false);
+    Ok(reader.datum(child(&mut input.into_inner(), span)?)?)
+}
+
+/// Read every datum in a source text.
+pub fn read_all(src: &str) -> Result<Vec<Value>, Error> {
+    let source = Source::new(0, src);
+    let mut pairs = R7RSParser::parse(Rule::data, src).map_err(ParseError::from)?;
+    let input = child(&mut pairs, pest::Span::new(src, 0, src.len()).unwrap())?;
+    input
+        .into_inner()
+        .filter(|pair| pair.as_rule() == Rule::datum)
+        .map(|pair| Ok(Reader::new(&source, 0, false).datum(pair)?))
+        .collect()
+}
+
+// The values of the datum labels defined so far in the datum being read.
+type Labels = HashMap<u64, Value>;
+
+/// Read the form of a command or definition written at `span` in `source`,
+/// noting where each of its lists and their elements were written.
+pub(crate) fn read_form(source: &Source, span: Span) -> Result<Value, Error> {
+    let src = &source.text()[span.start..span.end];
+    let mut pairs = R7RSParser::parse(Rule::single_datum, src).map_err(ParseError::from)?;
+    let input = child(&mut pairs, pest::Span::new(src, 0, src.len()).unwrap())?;
+    let whole = input.as_span();
+    let mut reader = Reader::new(source, span.start, true);
+    Ok(reader.datum(child(&mut input.into_inner(), whole)?)?)
+}
+
+// Reads data from pest pairs parsed from the text at `offset` in `source`,
+// keeping the labels of the datum being read. Identifiers and character
+// names are folded where the source folds case. When `locating`, it notes
+// where the lists it reads were written.
+struct Reader<'s> {
+    labels: Labels,
+    source: &'s Source<'s>,
+    offset: usize,
+    locating: bool,
+}
+impl<'s> Reader<'s> {
+    fn new(source: &'s Source<'s>, offset: usize, locating: bool) -> Reader<'s> {
+        Reader {
+            labels: Labels::new(),
+            source,
+            offset,
+            locating,
+        }
+    }
+
+    fn datum(&mut self, pair: pest::iterators::Pair<Rule>) -> Result<Value, ParseError> {
+        let span = pair.as_span();
+        let mut inner = pair.into_inner();
+        let first = child(&mut inner, span)?;
+        match first.as_rule() {
+            Rule::simple_datum => {
+                let folding = self.source.folds(self.offset + span.start());
+                simple_datum(child(&mut first.into_inner(), span)?, folding)
+            }
+            Rule::compound_datum => self.compound_datum(child(&mut first.into_inner(), span)?),
+            Rule::label => {
+                let label = first.as_str()[1..]
+                    .parse::<u64>()
+                    .map_err(|_| unexpected(&first, "a label that fits in 64 bits"))?;
+                match inner.next() {
+                    Some(pair) => {
+                        // References to the label from inside its own datum
+                        // are read as a placeholder, then patched to the
+                        // datum.
+                        let placeholder =
+                            Value::Pair(gc::alloc(Pair::new(Value::Null, Value::Null)));
+                        self.labels.insert(label, placeholder.clone());
+                        let value = self.datum(pair)?;
+                        if is_same(&value, &placeholder) {
+                            return Err(ParseError::new(span, "a datum other than its own label"));
+                        }
+                        replace(&value, &placeholder);
+                        self.labels.insert(label, value.clone());
+                        Ok(value)
+                    }
+                    None => self
+                        .labels
+                        .get(&label)
+                        .cloned()
+                        .ok_or_else(|| unexpected(&first, "a label defined earlier")),
+                }
+            }
+            _ => Err(unexpected(&first, "a datum")),
+        }
+    }
+
+    fn compound_datum(&mut self, pair: pest::iterators::Pair<Rule>) -> Result<Value, ParseError> {
+        match pair.as_rule() {
+            Rule::list => {
+                let mut items = Vec::new();
+                let mut tail = Value::Null;
+                let span = pair.as_span();
+                let mut inner = pair.into_inner();
+                while let Some(pair) = inner.next() {
+                    match pair.as_rule() {
+                        Rule::datum => items.push((pair.as_span(), self.datum(pair)?)),
+                        Rule::dot => tail = self.datum(child(&mut inner, span)?)?,
+                        _ => return Err(unexpected(&pair, "a datum")),
+                    }
+                }
+                Ok(self.list(span, items, tail))
+            }
+            Rule::vector => Ok(Value::Vector(gc::alloc(RefCell::new(
+                pair.into_inner()
+                    .map(|pair| self.datum(pair))
+                    .collect::<Result<_, _>>()?,
+            )))),
+            Rule::abbreviation => {
+                let span = pair.as_span();
+                let mut inner = pair.into_inner();
+                let prefix = child(&mut inner, span)?;
+                let keyword = match prefix.as_str() {
+                    "'" => "quote",
+                    "`" => "quasiquote",
+                    ",@" => "unquote-splicing",
+                    "," => "unquote",
+                    _ => return Err(unexpected(&prefix, "an abbreviation prefix")),
+                };
+                let operand = child(&mut inner, span)?;
+                let items = vec![
+                    (prefix.as_span(), Value::Symbol(keyword.to_string())),
+                    (operand.as_span(), self.datum(operand)?),
+                ];
+                Ok(self.list(span, items, Value::Null))
+            }
+            _ => Err(unexpected(&pair, "a datum")),
+        }
+    }
+
+    // The list of `items`, each read from where it was written, ending in
+    // `tail`.
+    fn list(&self, span: pest::Span, items: Vec<(pest::Span, Value)>, tail: Value) -> Value {
+        let locate = |span: pest::Span| {
+            self.locating.then(|| {
+                self.source
+                    .locate(self.offset + span.start(), self.offset + span.end())
+            })
+        };
+        let list = items.into_iter().rev().fold(tail, |cdr, (item, car)| {
+            let pair = gc::alloc(Pair::new(car, cdr));
+            if let Some(item) = locate(item) {
+                ast::locate(&pair, None, Some(item));
+            }
+            Value::Pair(pair)
+        });
+        if let (Some(span), Value::Pair(pair)) = (locate(span), &list) {
+            ast::locate(pair, Some(span), None);
+        }
+        list
+    }
+}
+
+// Replace every occurrence of `placeholder` reachable from `value` with
+// `value` itself, closing the cycles of a labelled datum.
+fn replace(value: &Value, placeholder: &Value) {
+    let is_placeholder = |item: &Value| is_same(item, placeholder);
+    let mut seen = HashSet::new();
+    let mut pending = vec![value.clone()];
+    while let Some(item) = pending.pop() {
+        match &item {
+            Value::Pair(pair) => {
+                if !seen.insert(Rc::as_ptr(pair) as usize) {
+                    continue;
+                }
+                for (field, set) in [
+                    (pair.car(), Pair::set_car as fn(&Pair, Value)),
+                    (pair.cdr(), Pair::set_cdr),
+                ] {
+                    if is_placeholder(&field) {
+                        set(pair, value.clone());
+                    } else {
+                        pending.push(field);
+                    }
+                }
+            }
+            Value::Vector(vector) => {
+                if !seen.insert(Rc::as_ptr(vector) as *const () as usize) {
+                    continue;
+                }
+                for element in vector.borrow_mut().iter_mut() {
+                    if is_placeholder(element) {
+                        *element = value.clone();
+                    } else {
+                        pending.push(element.clone());
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn is_same(a: &Value, b: &Value) -> bool {
+    matches!((a, b), (Value::Pair(a), Value::Pair(b)) if Rc::ptr_eq(a, b))
+}
+
+// Convert a `simple_datum` pair, folding the case of symbols and character
+// names when `folding`.
+fn simple_datum(pair: pest::iterators::Pair<Rule>, folding: bool) -> Result<Value, ParseError> {
+    match pair.as_rule() {
+        Rule::boolean => Ok(Value::Boolean(matches!(
+            pair.as_str().to_ascii_lowercase().as_str(),
+            "#t" | "#true"
+        ))),
+        Rule::number => Ok(Value::Number(number(&pair)?)),
+        Rule::character => Ok(Value::Character(character(pair, folding)?)),
+        Rule::string => Ok(Value::string(string(&pair)?)),
+        Rule::symbol => Ok(Value::Symbol(symbol(&pair, folding)?)),
+        Rule::bytevector => Ok(Value::bytevector(bytevector(pair)?)),
+        _ => Err(unexpected(&pair, "a datum")),
+    }
+}
+
+// Read the bytes of a `bytevector` pair. A byte may be written in any radix
+// but must be an exact integer between 0 and 255.
+fn bytevector(pair: pest::iterators::Pair<Rule>) -> Result<Vec<u8>, ParseError> {
+    pair.into_inner()
+        .map(|byte| match number::parse(byte.as_str()) {
+            Ok(Number::Integer(n)) if (0..=255).contains(&n) => Ok(n as u8),
+            _ => Err(unexpected(&byte, "a byte between 0 and 255")),
+        })
+        .collect()
+}
+
+// Convert a `number` pair into the Number it denotes.
+fn number(pair: &pest::iterators::Pair<Rule>) -> Result<Number, ParseError> {
+    number::parse(pair.as_str()).map_err(|expected| unexpected(pair, &expected))
+}
+
+// Decode a `character` pair: `#\a`, `#\newline` or `#\x41`. Names are
+// only matched whatever their case when `folding`.
+fn character(pair: pest::iterators::Pair<Rule>, folding: bool) -> Result<char, ParseError> {
+    let span = pair.as_span();
+    let character = child(&mut pair.into_inner(), span)?;
+    let invalid = || unexpected(&character, "a valid character");
+    match character.as_rule() {
+        Rule::any_character => character.as_str().chars().nth(2).ok_or_else(invalid),
+        Rule::named_character => match &*fold(&character.as_str()[2..], folding) {
+            "alarm" => Ok('\x07'),
+            "backspace" => Ok('\x08'),
+            "delete" => Ok('\x7F'),
+            "escape" => Ok('\x1B'),
+            "newline" => Ok('\x0A'),
+            "null" => Ok('\x00'),
+            "return" => Ok('\x0D'),
+            "space" => Ok('\x20'),
+            "tab" => Ok('\x09'),
+            _ => Err(invalid()),
+        },
+        Rule::hex_character => u32::from_str_radix(&character.as_str()[3..], 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| unexpected(&character, "a Unicode scalar value")),
+        _ => Err(invalid()),
+    }
+}
+
+/// The name of a symbol, without the vertical lines of `|a symbol|`. Only
+/// a symbol written without them is folded.
+fn symbol(pair: &pest::iterators::Pair<Rule>, folding: bool) -> Result<String, ParseError> {
+    let text = pair.as_str();
+    match text.strip_prefix('|') {
+        Some(inner) => unescape(&inner[..inner.len() - 1])
+            .ok_or_else(|| unexpected(pair, "escapes of Unicode scalar values")),
+        None => Ok(fold(text, folding).into_owned()),
+    }
+}
+
+// The Unicode case folding of a name, when `folding`.
+fn fold(name: &str, folding: bool) -> Cow<'_, str> {
+    match folding {
+        true => Cow::Owned(caseless::default_case_fold_str(name)),
+        false => Cow::Borrowed(name),
+    }
+}
+
+/// Decode a `string` pair into the contents of the string.
+pub(crate) fn string(pair: &pest::iterators::Pair<Rule>) -> Result<String, ParseError> {
+    let text = pair.as_str();
+    unescape(&text[1..text.len() - 1])
+        .ok_or_else(|| unexpected(pair, "escapes of Unicode scalar values"))
+}
+
+// Interpret the escapes allowed in strings and |symbols|: mnemonic escapes,
+// `\x41;` hex escapes, escaped delimiters and backslash-newline line
+// continuations, which drop the surrounding intraline whitespace. Returns
+// None for a hex escape that is not a Unicode scalar value.
+fn unescape(text: &str) -> Option<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('a') => result.push('\x07'),
+            Some('b') => result.push('\x08'),
+            Some('t') => result.push('\t'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take_while(|&c| c != ';').collect();
+                result.push(
+                    u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)?,
+                );
+            }
+            Some(c) if c == ' ' || c == '\t' || c == '\r' || c == '\n' => {
+                let mut c = c;
+                while c == ' ' || c == '\t' {
+                    c = chars.next()?;
+                }
+                if c == '\r' && chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+                while let Some(' ') | Some('\t') = chars.peek() {
+                    chars.next();
+                }
+            }
+            Some(c) => result.push(c),
+            None => result.push('\\'),
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_datum() {
+        let tests = [
+            ("#t", "#t"),
+            ("#\\space", "#\\space"),
+            ("#\\x41", "#\\A"),
+            ("\"foo\"", "\"foo\""),
+            ("\"a\\tb\\x41;\\\\\\\"\"", "\"a\\tbA\\\\\\\"\""),
+            ("|a\\x20;b\\|c|", "|a b\\|c|"),
+            ("foo", "foo"),
+            ("|foo bar|", "|foo bar|"),
+            ("42", "42"),
+            ("#x-1A", "-26"),
+            ("1.5e1", "15.0"),
+            ("1-2i", "1.0-2.0i"),
+            ("-inf.0", "-inf.0"),
+            ("()", "()"),
+            ("(a (b) . c)", "(a (b) . c)"),
+            ("#(a #t)", "#(a #t)"),
+            ("#u8(0 255)", "#u8(0 255)"),
+            ("#u8(#b101 #o17 #d20 #xFF #e1.0)", "#u8(5 15 20 255 1)"),
+            ("'a", "(quote a)"),
+            (",@a", "(unquote-splicing a)"),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(read_datum(input).unwrap().to_string(), *output);
+        }
+    }
+
+    #[test]
+    fn test_read_all() {
+        let data = read_all("(a . b) ; comment\n #;(skipped) #| nested |# c").unwrap();
+        let data: Vec<String> = data.iter().map(|d| d.to_string()).collect();
+        assert_eq!(data, vec!["(a . b)", "c"]);
+        assert!(read_all("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_labels() {
+        let tests = [
+            ("#0=(a b . #0#)", "#0=(a b . #0#)", "#0=(a b . #0#)"),
+            ("(#0=(x) #0# #1=y #1#)", "((x) (x) y y)", "(#0=(x) #0# y y)"),
+            (
+                "#0=#(1 #0# #1=(#0#))",
+                "#0=#(1 #0# (#0#))",
+                "#0=#(1 #0# (#0#))",
+            ),
+            (
+                "#0=(#1=(#0# . #1#))",
+                "#0=(#1=(#0# . #1#))",
+                "#0=(#1=(#0# . #1#))",
+            ),
+        ];
+        for (input, written, shared) in tests.iter() {
+            let value = read_datum(input).unwrap();
+            assert_eq!(value.to_string(), *written);
+            assert_eq!(value.write_shared().to_string(), *shared);
+        }
+        match read_datum("(#0=(x) #0#)").unwrap() {
+            Value::Pair(list) => match (list.car(), list.cdr()) {
+                (Value::Pair(first), Value::Pair(rest)) => match rest.car() {
+                    Value::Pair(second) => assert!(Rc::ptr_eq(&first, &second)),
+                    _ => panic!("expected a pair"),
+                },
+                _ => panic!("expected a list"),
+            },
+            _ => panic!("expected a list"),
+        }
+        assert!(matches!(read_datum("(#1#)"), Err(Error::Parse(_))));
+        assert!(matches!(read_datum("#0=#0#"), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn test_fold_case() {
+        let tests = [
+            ("(FOO #\\A #T)", vec!["(FOO #\\A #t)"]),
+            ("#!fold-case (FOO #\\A)", vec!["(foo #\\A)"]),
+            (
+                "A #!fold-case (B \"C\" |D| #\\NEWLINE #\\X41 ; E\n F) #!no-fold-case G",
+                vec!["A", "(b \"C\" D #\\newline #\\A f)", "G"],
+            ),
+            ("#!fold-case #|A #|B|# C|# D", vec!["d"]),
+            (
+                "#!fold-case \"\\\"A\" ΣΑΣ ſ \u{212A} Straße",
+                vec!["\"\\\"A\"", "σασ", "s", "k", "strasse"],
+            ),
+        ];
+        for (input, output) in tests.iter() {
+            let data = read_all(input).unwrap();
+            let data: Vec<String> = data.iter().map(|d| d.to_string()).collect();
+            assert_eq!(data, *output);
+        }
+        assert_eq!(
+            read_datum("#!fold-case (ABC #\\Space)")
+                .unwrap()
+                .to_string(),
+            "(abc #\\space)"
+        );
+    }
+
+    #[test]
+    fn test_read_errors() {
+        assert!(matches!(read_datum("a b"), Err(Error::Parse(_))));
+        assert!(matches!(read_datum("(a"), Err(Error::Parse(_))));
+        assert!(matches!(read_datum("#u8(256)"), Err(Error::Parse(_))));
+        assert!(matches!(read_datum("#u8(#x100)"), Err(Error::Parse(_))));
+        assert!(matches!(read_datum("#u8(-1)"), Err(Error::Parse(_))));
+        assert!(matches!(read_datum("#u8(1.5)"), Err(Error::Parse(_))));
+        assert!(matches!(read_datum("#\\xD800"), Err(Error::Parse(_))));
+        assert!(matches!(read_datum("#\\SPACE"), Err(Error::Parse(_))));
+        assert!(matches!(read_datum("\"\\xD800;\""), Err(Error::Parse(_))));
+    }
+}
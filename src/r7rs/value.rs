@@ -1,11 +1,26 @@
+use crate::r7rs::compile::Lambda;
 use crate::r7rs::env::Environment;
+use crate::r7rs::error::Error;
+use crate::r7rs::eval::{Continuation, Tail};
+use crate::r7rs::expand::Macro;
+use crate::r7rs::gc::{self, trace_value, Trace};
+use crate::r7rs::write::{Labels, Written};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::rc::Rc;
 
 /// The Value enum represents all possible values in the R7RS Scheme language.
 /// - boolean
 /// - character
 /// - null
 /// - pair
-/// - procedure
+/// - procedure (compound, primitive, control, continuation or generated for a
+///   record type)
+/// - record type and record
+/// - error object
+/// - parameter
+/// - promise
+/// - macro, bound to the keyword of a top-level define-syntax
 /// - symbol
 /// - bytevector
 /// - eof-object
@@ -13,60 +28,209 @@ use crate::r7rs::env::Environment;
 /// - port
 /// - string
 /// - vector
+///
+/// Values also holds the results of returning other than one value, which
+/// only appear as what an expression evaluates to.
+#[derive(Clone)]
 pub enum Value {
     Boolean(bool),
     Character(char),
     Null,
-    Pair(Box<Pair>),
-    Procedure(Box<Procedure>),
+    Pair(Rc<Pair>),
+    Procedure(Rc<Procedure>),
+    Primitive(Primitive),
+    Control(Control),
+    Continuation(Rc<Continuation>),
+    RecordProcedure(Rc<RecordProcedure>),
+    RecordType(Rc<RecordType>),
+    Record(Rc<Record>),
+    ErrorObject(Rc<ErrorObject>),
+    Parameter(Rc<Parameter>),
+    Promise(Rc<Promise>),
+    Macro(Rc<Macro>),
     Symbol(String),
-    Bytevector(Vec<u8>),
+    Bytevector(Rc<RefCell<Vec<u8>>>),
     EofObject,
     Number(Number),
     Port(Box<Port>),
-    String(String),
-    Vector(Vec<Value>),
+    String(Rc<RefCell<String>>),
+    Vector(Rc<RefCell<Vec<Value>>>),
+    Values(Rc<[Value]>),
+    Unspecified,
 }
+// Values display as write writes them, so data read back as the same data
+// and cyclic structure is labelled rather than followed forever.
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", Written::new(self, Labels::Cycles))
+    }
+}
+impl Value {
+    /// Write the value with datum labels for every pair and vector that is
+    /// reachable more than once, as write-shared does.
+    pub fn write_shared(&self) -> Written<'_> {
+        Written::new(self, Labels::Shared)
+    }
+
+    /// Write the value without datum labels, as write-simple does. This does
+    /// not terminate if the value is cyclic.
+    pub fn write_simple(&self) -> Written<'_> {
+        Written::new(self, Labels::None)
+    }
+
+    /// A new, mutable string.
+    pub fn string(s: impl Into<String>) -> Value {
+        Value::String(Rc::new(RefCell::new(s.into())))
+    }
+
+    /// A new, mutable bytevector.
+    pub fn bytevector(bytes: Vec<u8>) -> Value {
+        Value::Bytevector(Rc::new(RefCell::new(bytes)))
+    }
+
+    /// A new, mutable vector.
+    pub fn vector(items: Vec<Value>) -> Value {
+        Value::Vector(gc::alloc(RefCell::new(items)))
+    }
+
+    /// What returning `values` evaluates to: the value itself if there is
+    /// exactly one.
+    pub fn values(mut values: Vec<Value>) -> Value {
+        match values.len() {
+            1 => values.pop().unwrap(),
+            _ => Value::Values(Rc::from(values)),
+        }
+    }
+
+    /// The values a value stands for when it is returned.
+    pub fn into_values(self) -> Vec<Value> {
         match self {
-            Value::Boolean(b) => write!(
-                f,
-                "{}",
-                match b {
-                    true => "#t",
-                    false => "#f",
+            Value::Values(values) => values.to_vec(),
+            value => vec![value],
+        }
+    }
+
+    /// Whether two values are the same object, as eqv? decides. Objects
+    /// that can be mutated are the same only if they share storage; numbers
+    /// are the same if they are equal and equally exact.
+    pub fn eqv(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Pair(a), Value::Pair(b)) => Rc::ptr_eq(a, b),
+            (Value::Vector(a), Value::Vector(b)) => Rc::ptr_eq(a, b),
+            (Value::String(a), Value::String(b)) => Rc::ptr_eq(a, b),
+            (Value::Bytevector(a), Value::Bytevector(b)) => Rc::ptr_eq(a, b),
+            (Value::Number(Number::Real(a)), Value::Number(Number::Real(b))) => {
+                a.to_bits() == b.to_bits()
+            }
+            _ => self == other,
+        }
+    }
+
+    /// Whether two values are equal? : the same by eqv?, or pairs, vectors,
+    /// strings or bytevectors with equal contents. The comparison keeps its
+    /// own stack, so that long lists cannot overflow the Rust one, and takes
+    /// two objects it is already comparing to be equal, so that it
+    /// terminates on cyclic data.
+    pub fn equal(&self, other: &Value) -> bool {
+        let mut comparing = HashSet::new();
+        let mut stack = vec![(self.clone(), other.clone())];
+        while let Some((a, b)) = stack.pop() {
+            match (&a, &b) {
+                (Value::Pair(x), Value::Pair(y)) => {
+                    if Rc::ptr_eq(x, y) || !comparing.insert((gc::identity(x), gc::identity(y))) {
+                        continue;
+                    }
+                    stack.push((x.cdr(), y.cdr()));
+                    stack.push((x.car(), y.car()));
+                }
+                (Value::Vector(x), Value::Vector(y)) => {
+                    if Rc::ptr_eq(x, y) || !comparing.insert((gc::identity(x), gc::identity(y))) {
+                        continue;
+                    }
+                    let (x, y) = (x.borrow(), y.borrow());
+                    if x.len() != y.len() {
+                        return false;
+                    }
+                    stack.extend(x.iter().cloned().zip(y.iter().cloned()).rev());
+                }
+                (Value::String(_), Value::String(_))
+                | (Value::Bytevector(_), Value::Bytevector(_)) => {
+                    if a != b {
+                        return false;
+                    }
+                }
+                _ => {
+                    if !a.eqv(&b) {
+                        return false;
+                    }
                 }
-            ),
-            Value::Character(c) => write!(f, "#\\{}", c),
-            Value::Null => write!(f, "()"),
-            Value::Pair(p) => write!(f, "({} . {})", p.car, p.cdr),
-            Value::Procedure(p) => write!(f, "{}", p),
-            Value::Symbol(s) => write!(f, "{}", s),
-            Value::Bytevector(b) => write!(
-                f,
-                "#u8({})",
-                b.iter()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<String>>()
-                    .join(" ")
-            ),
-            Value::EofObject => write!(f, "#<eof>"),
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Port(_) => write!(f, "#<port>"),
-            Value::String(s) => write!(f, "\"{}\"", s),
-            Value::Vector(v) => write!(
-                f,
-                "#({})",
-                v.iter()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<String>>()
-                    .join(" ")
-            ),
+            }
+        }
+        true
+    }
+}
+
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+// Data compare by structure, for the host and tests. The comparison
+// recurses, so it is for small acyclic data; equal? uses Value::equal.
+// Procedures, records and ports are only equal to themselves.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Character(a), Value::Character(b)) => a == b,
+            (Value::Null, Value::Null) => true,
+            (Value::Pair(a), Value::Pair(b)) => {
+                Rc::ptr_eq(a, b) || (a.car() == b.car() && a.cdr() == b.cdr())
+            }
+            (Value::Procedure(a), Value::Procedure(b)) => Rc::ptr_eq(a, b),
+            (Value::Primitive(a), Value::Primitive(b)) => a.name == b.name,
+            (Value::Control(a), Value::Control(b)) => a.name == b.name,
+            (Value::Continuation(a), Value::Continuation(b)) => Rc::ptr_eq(a, b),
+            (Value::RecordProcedure(a), Value::RecordProcedure(b)) => Rc::ptr_eq(a, b),
+            (Value::RecordType(a), Value::RecordType(b)) => Rc::ptr_eq(a, b),
+            (Value::Record(a), Value::Record(b)) => Rc::ptr_eq(a, b),
+            (Value::ErrorObject(a), Value::ErrorObject(b)) => Rc::ptr_eq(a, b),
+            (Value::Parameter(a), Value::Parameter(b)) => Rc::ptr_eq(a, b),
+            (Value::Promise(a), Value::Promise(b)) => Rc::ptr_eq(a, b),
+            (Value::Macro(a), Value::Macro(b)) => Rc::ptr_eq(a, b),
+            (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            (Value::Bytevector(a), Value::Bytevector(b)) => Rc::ptr_eq(a, b) || a == b,
+            (Value::EofObject, Value::EofObject) => true,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Port(a), Value::Port(b)) => std::ptr::eq(&**a, &**b),
+            (Value::String(a), Value::String(b)) => Rc::ptr_eq(a, b) || a == b,
+            (Value::Vector(a), Value::Vector(b)) => Rc::ptr_eq(a, b) || a == b,
+            (Value::Values(a), Value::Values(b)) => a == b,
+            (Value::Unspecified, Value::Unspecified) => true,
+            _ => false,
         }
     }
 }
 
+/// Escape the contents of a string so that it reads back as the same string
+/// when written between double quotes.
+pub fn escape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            '\r' => result.push_str("\\r"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Number {
     Integer(i64),
     Real(f64),
@@ -77,25 +241,46 @@ impl std::fmt::Display for Number {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Number::Integer(i) => write!(f, "{}", i),
-            Number::Real(r) => write!(f, "{}", r),
+            Number::Real(r) => write_real(f, *r),
             Number::Rational {
                 numerator,
                 denominator,
             } => write!(f, "{}/{}", numerator, denominator),
             Number::Complex { real, imaginary } => {
-                if imaginary < &0.0 {
-                    write!(f, "{}{}i", real, imaginary)
-                } else {
-                    write!(f, "{}+{}i", real, imaginary)
+                write_real(f, *real)?;
+                if imaginary.is_finite() && imaginary.is_sign_positive() {
+                    write!(f, "+")?;
                 }
+                write_real(f, *imaginary)?;
+                write!(f, "i")
             }
         }
     }
 }
+// Infinities and NaN are written the way they are read. Other reals always
+// have a decimal point or an exponent, as `1.0`, `-0.0` and `1e21`, so that
+// they read back as inexact.
+fn write_real(f: &mut std::fmt::Formatter, r: f64) -> std::fmt::Result {
+    if r.is_nan() {
+        write!(f, "+nan.0")
+    } else if r.is_infinite() {
+        write!(f, "{}inf.0", if r > 0.0 { "+" } else { "-" })
+    } else {
+        write!(f, "{:?}", r)
+    }
+}
+/// A Procedure is a closure: a compiled lambda together with the
+/// environment it was created in.
+#[derive(Clone)]
 pub struct Procedure {
-    env: Environment,
-    params: Vec<String>,
-    body: Vec<Box<Value>>,
+    pub env: Rc<Environment>,
+    pub lambda: Rc<Lambda>,
+}
+impl Trace for Procedure {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        visit(gc::identity(&self.env));
+    }
+    fn clear(&self, _: &mut Vec<Value>) {}
 }
 /// The Display trait is used to print the contents of a Procedure struct.
 impl std::fmt::Display for Procedure {
@@ -103,11 +288,13 @@ impl std::fmt::Display for Procedure {
         let indent = "    ";
         write!(
             f,
-            "(lambda ({params})\n{indent}{body})",
-            params = self.params.join(" "),
+            "(lambda {formals}\n{indent}{body})",
+            formals = self.lambda.formals,
             indent = indent,
             body = self
+                .lambda
                 .body
+                .sequence
                 .iter()
                 .map(|v| format!("{val}\n{indent}", indent = indent, val = v).to_owned())
                 .collect::<String>()
@@ -120,8 +307,8 @@ impl std::fmt::Debug for Procedure {
         let indent = "    ";
         write!(
             f,
-            "(lambda ({params})\n{envs}\n{indent}{body})",
-            params = self.params.join(" "),
+            "(lambda {formals}\n{envs}\n{indent}{body})",
+            formals = self.lambda.formals,
             indent = indent,
             envs = self
                 .env
@@ -134,7 +321,9 @@ impl std::fmt::Debug for Procedure {
                 .collect::<Vec<String>>()
                 .join(format!("\n{indent}", indent = indent).as_str()),
             body = self
+                .lambda
                 .body
+                .sequence
                 .iter()
                 .map(|v| format!("{val}\n{indent}", indent = indent, val = v).to_owned())
                 .collect::<String>(),
@@ -142,16 +331,389 @@ impl std::fmt::Debug for Procedure {
     }
 }
 
-pub struct Pair {
-    car: Value,
-    cdr: Value,
+/// A Primitive is a procedure implemented in Rust. Its arguments are
+/// checked against `arity` before `func` is called.
+#[derive(Clone)]
+pub struct Primitive {
+    pub name: &'static str,
+    pub arity: Arity,
+    pub func: fn(&[Value]) -> Result<Value, Error>,
+}
+impl std::fmt::Display for Primitive {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "#<procedure {}>", self.name)
+    }
+}
+
+/// A Control is a primitive that calls other procedures, such as apply.
+/// Rather than make the call itself it returns it as a Tail for the
+/// evaluator to make, so that the call is a tail call.
+#[derive(Clone)]
+pub struct Control {
+    pub name: &'static str,
+    pub arity: Arity,
+    pub func: fn(&[Value]) -> Result<Tail, Error>,
+}
+impl std::fmt::Display for Control {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "#<procedure {}>", self.name)
+    }
+}
+
+/// The number of arguments a procedure accepts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+    Between(usize, usize),
+}
+impl Arity {
+    pub fn accepts(&self, n: usize) -> bool {
+        match *self {
+            Arity::Exactly(m) => n == m,
+            Arity::AtLeast(m) => n >= m,
+            Arity::Between(lo, hi) => lo <= n && n <= hi,
+        }
+    }
+}
+impl std::fmt::Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Arity::Exactly(n) => write!(f, "{}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+            Arity::Between(lo, hi) => write!(f, "between {} and {}", lo, hi),
+        }
+    }
+}
+
+/// A RecordType is the type descriptor created by define-record-type.
+pub struct RecordType {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+impl RecordType {
+    /// The position of the field named `name` among the fields.
+    pub fn field_index(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|field| field == name)
+    }
+}
+
+/// A Record is an instance of a record type. Records have identity, so they
+/// are shared and their field slots are mutable.
+pub struct Record {
+    pub record_type: Rc<RecordType>,
+    fields: RefCell<Vec<Value>>,
+}
+impl Record {
+    /// A record of `record_type` holding `fields`, one value per field in
+    /// the order the type declares them.
+    pub fn new(record_type: Rc<RecordType>, fields: Vec<Value>) -> Record {
+        debug_assert_eq!(fields.len(), record_type.fields.len());
+        Record {
+            record_type,
+            fields: RefCell::new(fields),
+        }
+    }
+
+    /// The value of the field named `name`, or None if the record type has
+    /// no such field.
+    pub fn field(&self, name: &str) -> Option<Value> {
+        let index = self.record_type.field_index(name)?;
+        Some(self.fields.borrow()[index].clone())
+    }
+
+    /// Replace the value of the field named `name`, returning the value it
+    /// held, or None if the record type has no such field.
+    pub fn set_field(&self, name: &str, value: Value) -> Option<Value> {
+        let index = self.record_type.field_index(name)?;
+        Some(std::mem::replace(
+            &mut self.fields.borrow_mut()[index],
+            value,
+        ))
+    }
+
+    /// Every field name with its value, in the order the type declares them.
+    pub fn field_values(&self) -> Vec<(String, Value)> {
+        self.record_type
+            .fields
+            .iter()
+            .cloned()
+            .zip(self.fields.borrow().iter().cloned())
+            .collect()
+    }
+}
+
+impl Trace for Record {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        for field in self.fields.borrow().iter() {
+            trace_value(field, visit);
+        }
+    }
+    fn clear(&self, trash: &mut Vec<Value>) {
+        trash.append(&mut self.fields.borrow_mut());
+    }
+}
+
+/// An ErrorObject is what `error` raises, and what errors detected by the
+/// implementation are raised as when a handler is installed to catch them.
+pub struct ErrorObject {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub irritants: RefCell<Vec<Value>>,
+    // The error the object was made from, if it was made from one, to be
+    // returned as itself if no handler handles the object.
+    pub(crate) cause: RefCell<Option<Error>>,
+}
+/// The kinds of error object R7RS lets a program tell apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    Error,
+    /// An error reading source text, recognised by read-error?.
+    Read,
+    /// An error opening a file, recognised by file-error?.
+    File,
+}
+impl ErrorObject {
+    pub fn new(message: impl Into<String>, irritants: Vec<Value>) -> ErrorObject {
+        ErrorObject {
+            kind: ErrorKind::Error,
+            message: message.into(),
+            irritants: RefCell::new(irritants),
+            cause: RefCell::new(None),
+        }
+    }
+}
+impl From<Error> for ErrorObject {
+    fn from(error: Error) -> ErrorObject {
+        ErrorObject {
+            kind: match error {
+                Error::Parse(_) => ErrorKind::Read,
+                Error::Io(_) => ErrorKind::File,
+                _ => ErrorKind::Error,
+            },
+            message: error.to_string(),
+            irritants: RefCell::new(Vec::new()),
+            cause: RefCell::new(Some(error)),
+        }
+    }
+}
+impl Trace for ErrorObject {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        for irritant in self.irritants.borrow().iter() {
+            trace_value(irritant, visit);
+        }
+    }
+    fn clear(&self, trash: &mut Vec<Value>) {
+        trash.append(&mut self.irritants.borrow_mut());
+    }
 }
-impl std::fmt::Display for Pair {
+// The message followed by the irritants, the way an uncaught error is
+// reported.
+impl std::fmt::Display for ErrorObject {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "({} . {})", self.car, self.cdr)
+        write!(f, "{}", self.message)?;
+        for irritant in self.irritants.borrow().iter() {
+            write!(f, " {}", irritant)?;
+        }
+        Ok(())
+    }
+}
+
+/// A Parameter is what make-parameter returns: a procedure that returns the
+/// parameter's value, which parameterize changes for the extent of its body.
+pub struct Parameter {
+    pub value: RefCell<Value>,
+    /// The procedure that converts the values the parameter is given.
+    pub converter: Option<Value>,
+}
+impl Trace for Parameter {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        trace_value(&self.value.borrow(), visit);
+        if let Some(converter) = &self.converter {
+            trace_value(converter, visit);
+        }
+    }
+    fn clear(&self, trash: &mut Vec<Value>) {
+        trash.push(self.value.replace(Value::Unspecified));
+    }
+}
+
+/// A Promise is what delay, delay-force and make-promise return. It holds a
+/// box with its value once it is forced, or the thunk that computes it until
+/// then. Forcing a promise made by delay-force takes on the state of the
+/// promise its thunk returns and shares its box with it, so that forcing a
+/// chain of them runs in constant space, as in the reference implementation
+/// of R7RS section 7.3.
+pub struct Promise {
+    state: RefCell<Rc<PromiseBox>>,
+}
+impl Promise {
+    pub fn new(done: bool, value: Value) -> Promise {
+        Promise {
+            state: RefCell::new(gc::alloc(PromiseBox {
+                done: Cell::new(done),
+                value: RefCell::new(value),
+            })),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state.borrow().done.get()
     }
+
+    /// The value of a promise that is done, or else its thunk.
+    pub fn value(&self) -> Value {
+        self.state.borrow().value.borrow().clone()
+    }
+
+    /// Take on the state of `other`, which the thunk of this promise
+    /// returned, and share this promise's box with it from now on.
+    pub(crate) fn update(&self, other: &Promise) {
+        let shared = Rc::clone(&self.state.borrow());
+        let (done, value) = (other.is_done(), other.value());
+        shared.done.set(done);
+        shared.value.replace(value);
+        other.state.replace(shared);
+    }
+}
+impl Trace for Promise {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        visit(gc::identity(&self.state.borrow()));
+    }
+    // Every cycle through a promise passes through the value of its box.
+    fn clear(&self, _: &mut Vec<Value>) {}
 }
 
+struct PromiseBox {
+    done: Cell<bool>,
+    value: RefCell<Value>,
+}
+impl Trace for PromiseBox {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        trace_value(&self.value.borrow(), visit);
+    }
+    fn clear(&self, trash: &mut Vec<Value>) {
+        trash.push(self.value.replace(Value::Unspecified));
+    }
+}
+
+/// A RecordProcedure is one of the procedures define-record-type generates
+/// for a record type.
+pub struct RecordProcedure {
+    pub name: String,
+    pub record_type: Rc<RecordType>,
+    pub kind: RecordProcedureKind,
+}
+/// The field indices are positions in `RecordType::fields`.
+pub enum RecordProcedureKind {
+    Constructor(Vec<usize>),
+    Predicate,
+    Accessor(usize),
+    Mutator(usize),
+}
+impl RecordProcedure {
+    pub fn arity(&self) -> Arity {
+        match &self.kind {
+            RecordProcedureKind::Constructor(fields) => Arity::Exactly(fields.len()),
+            RecordProcedureKind::Predicate | RecordProcedureKind::Accessor(_) => Arity::Exactly(1),
+            RecordProcedureKind::Mutator(_) => Arity::Exactly(2),
+        }
+    }
+    /// Call the procedure; the number of arguments must match `arity`.
+    pub fn call(&self, args: &[Value]) -> Result<Value, Error> {
+        match &self.kind {
+            RecordProcedureKind::Constructor(indices) => {
+                let mut fields = vec![Value::Unspecified; self.record_type.fields.len()];
+                for (index, arg) in indices.iter().zip(args.iter()) {
+                    fields[*index] = arg.clone();
+                }
+                Ok(Value::Record(gc::alloc(Record::new(
+                    Rc::clone(&self.record_type),
+                    fields,
+                ))))
+            }
+            RecordProcedureKind::Predicate => Ok(Value::Boolean(matches!(
+                &args[0],
+                Value::Record(r) if Rc::ptr_eq(&r.record_type, &self.record_type)
+            ))),
+            RecordProcedureKind::Accessor(index) => {
+                Ok(self.record(&args[0])?.fields.borrow()[*index].clone())
+            }
+            RecordProcedureKind::Mutator(index) => {
+                self.record(&args[0])?.fields.borrow_mut()[*index] = args[1].clone();
+                Ok(Value::Unspecified)
+            }
+        }
+    }
+
+    // The record an accessor or mutator is given, which must be of its type.
+    fn record<'v>(&self, value: &'v Value) -> Result<&'v Rc<Record>, Error> {
+        match value {
+            Value::Record(r) if Rc::ptr_eq(&r.record_type, &self.record_type) => Ok(r),
+            other => Err(Error::WrongType {
+                procedure: self.name.clone(),
+                expected: format!("a record of type {}", self.record_type.name),
+                given: other.to_string(),
+            }),
+        }
+    }
+}
+impl std::fmt::Display for RecordProcedure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "#<procedure {}>", self.name)
+    }
+}
+
+/// A pair is shared through `Rc`; its fields can be replaced in place, which
+/// is how shared and cyclic structure is built.
+pub struct Pair {
+    car: RefCell<Value>,
+    cdr: RefCell<Value>,
+}
+impl Pair {
+    pub fn new(car: Value, cdr: Value) -> Pair {
+        Pair {
+            car: RefCell::new(car),
+            cdr: RefCell::new(cdr),
+        }
+    }
+    pub fn car(&self) -> Value {
+        self.car.borrow().clone()
+    }
+    pub fn cdr(&self) -> Value {
+        self.cdr.borrow().clone()
+    }
+    pub fn set_car(&self, value: Value) {
+        *self.car.borrow_mut() = value;
+    }
+    pub fn set_cdr(&self, value: Value) {
+        *self.cdr.borrow_mut() = value;
+    }
+}
+impl Trace for Pair {
+    fn trace(&self, visit: &mut dyn FnMut(usize)) {
+        trace_value(&self.car.borrow(), visit);
+        trace_value(&self.cdr.borrow(), visit);
+    }
+    fn clear(&self, trash: &mut Vec<Value>) {
+        trash.push(self.car.replace(Value::Null));
+        trash.push(self.cdr.replace(Value::Null));
+    }
+}
+// Dropping a long list would otherwise recurse once per pair.
+impl Drop for Pair {
+    fn drop(&mut self) {
+        let mut tail = std::mem::replace(self.cdr.get_mut(), Value::Null);
+        while let Value::Pair(pair) = tail {
+            match Rc::try_unwrap(pair) {
+                Ok(mut pair) => tail = std::mem::replace(pair.cdr.get_mut(), Value::Null),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Port;
 impl std::fmt::Display for Port {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -162,6 +724,7 @@ impl std::fmt::Display for Port {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::r7rs::ast::{Body, ExpressionKind, Formals, Literal};
 
     #[test]
     fn test_display() {
@@ -170,31 +733,51 @@ mod tests {
         assert_eq!(Value::Character('a').to_string(), "#\\a");
         assert_eq!(Value::Null.to_string(), "()");
         assert_eq!(
-            Value::Pair(Box::new(Pair {
-                car: Value::Boolean(true),
-                cdr: Value::Boolean(false)
-            }))
+            Value::Pair(gc::alloc(Pair::new(
+                Value::Boolean(true),
+                Value::Boolean(false)
+            )))
             .to_string(),
             "(#t . #f)"
         );
         assert_eq!(
-            Value::Procedure(Box::new(Procedure {
-                env: Environment::new(),
-                params: vec!["a".to_string(), "b".to_string()],
-                body: vec![
-                    Box::new(Value::Boolean(true)),
-                    Box::new(Value::Boolean(false))
-                ]
+            Value::Procedure(gc::alloc(Procedure {
+                env: Rc::new(Environment::new()),
+                lambda: Rc::new(Lambda::new(
+                    Formals {
+                        required: vec!["a".to_string(), "b".to_string()],
+                        rest: None
+                    },
+                    Rc::new(Body {
+                        definitions: vec![],
+                        sequence: vec![
+                            ExpressionKind::Literal(Literal::Bool(true)).into(),
+                            ExpressionKind::Literal(Literal::Bool(false)).into()
+                        ]
+                    })
+                ))
             }))
             .to_string(),
             "(lambda (a b)\n    #t\n    #f\n    )"
         );
         assert_eq!(Value::Symbol("a".to_string()).to_string(), "a");
-        assert_eq!(Value::Bytevector(vec![1, 2, 3]).to_string(), "#u8(1 2 3)");
+        assert_eq!(Value::bytevector(vec![1, 2, 3]).to_string(), "#u8(1 2 3)");
         assert_eq!(Value::EofObject.to_string(), "#<eof>");
         assert_eq!(Value::Number(Number::Integer(1)).to_string(), "1");
-        assert_eq!(Value::Number(Number::Real(1.0)).to_string(), "1");
-        assert_eq!(Value::Number(Number::Real(1.23)).to_string(), "1.23");
+        let reals = [
+            (1.0, "1.0"),
+            (1.23, "1.23"),
+            (-0.0, "-0.0"),
+            (1e21, "1e21"),
+            (1e-7, "1e-7"),
+        ];
+        for (real, written) in reals.iter() {
+            assert_eq!(Value::Number(Number::Real(*real)).to_string(), *written);
+            assert_eq!(
+                crate::r7rs::reader::read_datum(written).unwrap(),
+                Value::Number(Number::Real(*real))
+            );
+        }
         assert_eq!(
             Value::Number(Number::Rational {
                 numerator: 1,
@@ -217,7 +800,7 @@ mod tests {
                 imaginary: 2.0
             })
             .to_string(),
-            "1+2i"
+            "1.0+2.0i"
         );
         assert_eq!(
             Value::Number(Number::Complex {
@@ -225,12 +808,16 @@ mod tests {
                 imaginary: -2.0
             })
             .to_string(),
-            "1-2i"
+            "1.0-2.0i"
         );
         assert_eq!(Value::Port(Box::new(Port)).to_string(), "#<port>");
-        assert_eq!(Value::String("a".to_string()).to_string(), "\"a\"");
+        assert_eq!(Value::string("a").to_string(), "\"a\"");
         assert_eq!(
-            Value::Vector(vec![Value::Boolean(true), Value::Boolean(false)]).to_string(),
+            Value::Vector(gc::alloc(RefCell::new(vec![
+                Value::Boolean(true),
+                Value::Boolean(false)
+            ])))
+            .to_string(),
             "#(#t #f)"
         );
     }
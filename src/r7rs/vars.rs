@@ -1,16 +1,74 @@
+use crate::r7rs::env::Environment;
+use crate::r7rs::error::Error;
 use crate::r7rs::value::Value;
+use std::rc::Rc;
 
-pub struct Variable {
-    name: String,
-    value: Value,
+/// A binding of an environment frame: a variable of the frame's own, or
+/// the variable of a library environment that an import shares with it.
+#[derive(Clone)]
+pub enum Variable {
+    Own {
+        name: String,
+        value: Value,
+    },
+    Imported {
+        name: String,
+        env: Rc<Environment>,
+        key: String,
+    },
+}
+impl Variable {
+    pub fn new(name: &str, value: Value) -> Variable {
+        Variable::Own {
+            name: name.to_string(),
+            value,
+        }
+    }
+    /// The variable `key` of `env`, bound as `name`.
+    pub fn imported(name: &str, env: &Rc<Environment>, key: &str) -> Variable {
+        Variable::Imported {
+            name: name.to_string(),
+            env: Rc::clone(env),
+            key: key.to_string(),
+        }
+    }
+    /// The value of the variable, which an imported variable lacks until
+    /// its library defines it.
+    pub fn value(&self) -> Option<Value> {
+        match self {
+            Variable::Own { value, .. } => Some(value.clone()),
+            Variable::Imported { env, key, .. } => env.get(key),
+        }
+    }
+    /// Assign the variable. Imported variables belong to their library
+    /// and cannot be assigned.
+    pub fn set(&mut self, value: Value) -> Result<(), Error> {
+        match self {
+            Variable::Own { value: old, .. } => {
+                *old = value;
+                Ok(())
+            }
+            Variable::Imported { name, .. } => Err(Error::BadSyntax(format!(
+                "{} is imported and cannot be assigned",
+                name
+            ))),
+        }
+    }
 }
 impl std::fmt::Display for Variable {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.value)
+        match self.value() {
+            Some(value) => write!(f, "{}", value),
+            None => write!(f, "#<unbound>"),
+        }
     }
 }
 impl std::fmt::Debug for Variable {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "(\"{}\" . {})", self.name, self.value)
+        match self {
+            Variable::Own { name, .. } | Variable::Imported { name, .. } => {
+                write!(f, "(\"{}\" . {})", name, self)
+            }
+        }
     }
 }
@@ -0,0 +1,361 @@
+//! The external representation of values, as written by write, write-shared
+//! and write-simple. Pairs and vectors are marked with datum labels (`#0=`
+//! and `#0#`) where the structure is shared, so that the output reads back
+//! as the same structure and cyclic data is written in finite space. Records
+//! are written with their fields, error objects with their irritants and
+//! parameters with their values, and all three are labelled the same way.
+
+use crate::parser::{R7RSParser, Rule};
+use crate::r7rs::value::{escape, Value};
+use pest::Parser;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// Which shared structure is marked with datum labels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Labels {
+    /// Structure that is part of a cycle, as write does.
+    Cycles,
+    /// Every pair, vector, record, error object or parameter reachable more
+    /// than once, as write-shared does.
+    Shared,
+    /// None; as with write-simple, cyclic data is written forever.
+    None,
+}
+
+/// A value and how to write it. Its Display impl writes the value.
+pub struct Written<'a> {
+    value: &'a Value,
+    labels: Labels,
+}
+impl<'a> Written<'a> {
+    pub fn new(value: &'a Value, labels: Labels) -> Written<'a> {
+        Written { value, labels }
+    }
+}
+impl fmt::Display for Written<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels = match self.labels {
+            Labels::None => HashMap::new(),
+            labels => find_labels(self.value, labels),
+        };
+        Writer { labels, next: 0 }.write(f, self.value)
+    }
+}
+
+// The identity of a value written with the values it holds: the address it
+// is shared at. These are pairs, vectors and records, and also error objects
+// and parameters, whose irritants and values are written with them.
+fn identity(value: &Value) -> Option<usize> {
+    match value {
+        Value::Pair(pair) => Some(Rc::as_ptr(pair) as *const () as usize),
+        Value::Vector(vector) => Some(Rc::as_ptr(vector) as *const () as usize),
+        Value::Record(record) => Some(Rc::as_ptr(record) as *const () as usize),
+        Value::ErrorObject(e) => Some(Rc::as_ptr(e) as *const () as usize),
+        Value::Parameter(p) => Some(Rc::as_ptr(p) as *const () as usize),
+        _ => None,
+    }
+}
+
+enum Step {
+    Enter(Value),
+    Leave(usize),
+}
+
+// Walk the pairs, vectors, records, error objects and parameters reachable from `value` depth first,
+// finding the ones that need a label. An object met again while it is still
+// being walked closes a cycle. The walk keeps its own stack so that long lists cannot
+// overflow the Rust one.
+fn find_labels(value: &Value, labels: Labels) -> HashMap<usize, Option<usize>> {
+    // Whether each object seen so far is still being walked.
+    let mut walking: HashMap<usize, bool> = HashMap::new();
+    let mut found = HashMap::new();
+    let mut steps = vec![Step::Enter(value.clone())];
+    while let Some(step) = steps.pop() {
+        let value = match step {
+            Step::Enter(value) => value,
+            Step::Leave(id) => {
+                walking.insert(id, false);
+                continue;
+            }
+        };
+        let id = match identity(&value) {
+            Some(id) => id,
+            None => continue,
+        };
+        if let Some(&open) = walking.get(&id) {
+            if open || labels == Labels::Shared {
+                found.insert(id, None);
+            }
+            continue;
+        }
+        walking.insert(id, true);
+        steps.push(Step::Leave(id));
+        match &value {
+            Value::Pair(pair) => {
+                steps.push(Step::Enter(pair.cdr()));
+                steps.push(Step::Enter(pair.car()));
+            }
+            Value::Vector(vector) => {
+                for item in vector.borrow().iter().rev() {
+                    if identity(item).is_some() {
+                        steps.push(Step::Enter(item.clone()));
+                    }
+                }
+            }
+            Value::Record(record) => {
+                for (_, field) in record.field_values().into_iter().rev() {
+                    if identity(&field).is_some() {
+                        steps.push(Step::Enter(field));
+                    }
+                }
+            }
+            Value::ErrorObject(e) => {
+                for irritant in e.irritants.borrow().iter().rev() {
+                    if identity(irritant).is_some() {
+                        steps.push(Step::Enter(irritant.clone()));
+                    }
+                }
+            }
+            Value::Parameter(p) => steps.push(Step::Enter(p.value.borrow().clone())),
+            _ => (),
+        }
+    }
+    found
+}
+
+struct Writer {
+    // The objects to label, with the number given to each once written.
+    labels: HashMap<usize, Option<usize>>,
+    next: usize,
+}
+impl Writer {
+    fn write(&mut self, f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+        if let Some(label) = identity(value).and_then(|id| self.labels.get_mut(&id)) {
+            match label {
+                Some(n) => return write!(f, "#{}#", n),
+                None => {
+                    *label = Some(self.next);
+                    write!(f, "#{}=", self.next)?;
+                    self.next += 1;
+                }
+            }
+        }
+        match value {
+            Value::Pair(pair) => {
+                write!(f, "(")?;
+                self.write(f, &pair.car())?;
+                let mut tail = pair.cdr();
+                loop {
+                    let next = match &tail {
+                        Value::Null => break,
+                        Value::Pair(pair) if !self.is_labelled(&tail) => {
+                            write!(f, " ")?;
+                            self.write(f, &pair.car())?;
+                            pair.cdr()
+                        }
+                        _ => {
+                            write!(f, " . ")?;
+                            self.write(f, &tail)?;
+                            break;
+                        }
+                    };
+                    tail = next;
+                }
+                write!(f, ")")
+            }
+            Value::Vector(vector) => {
+                write!(f, "#(")?;
+                for (i, item) in vector.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    self.write(f, item)?;
+                }
+                write!(f, ")")
+            }
+            // A record is written with its type name, less the angle
+            // brackets it is conventionally given, and its fields by name.
+            Value::Record(record) => {
+                let name = &record.record_type.name;
+                let name = name
+                    .strip_prefix('<')
+                    .and_then(|name| name.strip_suffix('>'))
+                    .unwrap_or(name);
+                write!(f, "#<")?;
+                write_symbol(f, name)?;
+                for (field, value) in record.field_values() {
+                    write!(f, " ")?;
+                    write_symbol(f, &field)?;
+                    write!(f, ": ")?;
+                    self.write(f, &value)?;
+                }
+                write!(f, ">")
+            }
+            Value::ErrorObject(e) => {
+                write!(f, "#<error \"{}\"", escape(&e.message))?;
+                for irritant in e.irritants.borrow().iter() {
+                    write!(f, " ")?;
+                    self.write(f, irritant)?;
+                }
+                write!(f, ">")
+            }
+            // A parameter is written with its current value.
+            Value::Parameter(p) => {
+                write!(f, "#<parameter ")?;
+                self.write(f, &p.value.borrow())?;
+                write!(f, ">")
+            }
+            other => write_atom(f, other),
+        }
+    }
+
+    fn is_labelled(&self, value: &Value) -> bool {
+        identity(value).is_some_and(|id| self.labels.contains_key(&id))
+    }
+}
+
+fn write_atom(f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+    match value {
+        Value::Boolean(b) => write!(f, "{}", if *b { "#t" } else { "#f" }),
+        Value::Character(c) => write_character(f, *c),
+        Value::Null => write!(f, "()"),
+        Value::Procedure(p) => write!(f, "{}", p),
+        Value::Primitive(p) => write!(f, "{}", p),
+        Value::Control(p) => write!(f, "{}", p),
+        Value::Continuation(_) => write!(f, "#<continuation>"),
+        Value::Promise(_) => write!(f, "#<promise>"),
+        Value::Macro(m) if m.keyword.is_empty() => write!(f, "#<syntax>"),
+        Value::Macro(m) => write!(f, "#<syntax {}>", m.keyword),
+        Value::RecordProcedure(p) => write!(f, "{}", p),
+        Value::RecordType(t) => write!(f, "#<record-type {}>", t.name),
+        Value::Symbol(s) => write_symbol(f, s),
+        Value::Bytevector(b) => write!(
+            f,
+            "#u8({})",
+            b.borrow()
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<String>>()
+                .join(" ")
+        ),
+        Value::EofObject => write!(f, "#<eof>"),
+        Value::Number(n) => write!(f, "{}", n),
+        Value::Port(_) => write!(f, "#<port>"),
+        Value::String(s) => write!(f, "\"{}\"", escape(&s.borrow())),
+        Value::Values(values) => write!(
+            f,
+            "{}",
+            values
+                .iter()
+                .map(|value| value.to_string())
+                .collect::<Vec<String>>()
+                .join(" ")
+        ),
+        Value::Unspecified => write!(f, "#<unspecified>"),
+        Value::Pair(_)
+        | Value::Vector(_)
+        | Value::Record(_)
+        | Value::ErrorObject(_)
+        | Value::Parameter(_) => {
+            unreachable!("compound values are written by Writer")
+        }
+    }
+}
+
+fn write_character(f: &mut fmt::Formatter, c: char) -> fmt::Result {
+    match c {
+        '\x07' => write!(f, "#\\alarm"),
+        '\x08' => write!(f, "#\\backspace"),
+        '\x7F' => write!(f, "#\\delete"),
+        '\x1B' => write!(f, "#\\escape"),
+        '\n' => write!(f, "#\\newline"),
+        '\0' => write!(f, "#\\null"),
+        '\r' => write!(f, "#\\return"),
+        ' ' => write!(f, "#\\space"),
+        '\t' => write!(f, "#\\tab"),
+        c if c.is_control() || c.is_whitespace() => write!(f, "#\\x{:x}", c as u32),
+        c => write!(f, "#\\{}", c),
+    }
+}
+
+// A symbol is written between vertical lines unless the reader would read
+// its name back as the same identifier.
+fn write_symbol(f: &mut fmt::Formatter, name: &str) -> fmt::Result {
+    let plain = !name.starts_with('|')
+        && R7RSParser::parse(Rule::identifier, name)
+            .map(|pairs| pairs.as_str().len() == name.len())
+            .unwrap_or(false);
+    if plain {
+        return write!(f, "{}", name);
+    }
+    write!(f, "|")?;
+    for c in name.chars() {
+        match c {
+            '|' => write!(f, "\\|")?,
+            '\\' => write!(f, "\\\\")?,
+            c if c.is_control() => write!(f, "\\x{:x};", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "|")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r7rs::gc;
+    use crate::r7rs::reader::read_datum;
+    use crate::r7rs::value::Pair;
+
+    #[test]
+    fn test_write() {
+        let tests = [
+            ("(a b . c)", "(a b . c)"),
+            ("(1 (2 #(3 \"x\")) ())", "(1 (2 #(3 \"x\")) ())"),
+            ("(#\\space #\\a #\\x7)", "(#\\space #\\a #\\alarm)"),
+            ("(|a b| |x\\|y| || |1| abc)", "(|a b| |x\\|y| || |1| abc)"),
+        ];
+        for (input, output) in tests.iter() {
+            assert_eq!(read_datum(input).unwrap().to_string(), *output);
+        }
+    }
+
+    #[test]
+    fn test_write_labels() {
+        let shared = Value::Pair(gc::alloc(Pair::new(Value::Null, Value::Null)));
+        let list = Value::Pair(gc::alloc(Pair::new(
+            shared.clone(),
+            Value::Pair(gc::alloc(Pair::new(shared, Value::Null))),
+        )));
+        assert_eq!(list.to_string(), "((()) (()))");
+        assert_eq!(list.write_shared().to_string(), "(#0=(()) #0#)");
+        assert_eq!(list.write_simple().to_string(), "((()) (()))");
+
+        let cycle = gc::alloc(Pair::new(Value::Symbol("a".to_string()), Value::Null));
+        cycle.set_cdr(Value::Pair(Rc::clone(&cycle)));
+        let cycle = Value::Pair(cycle);
+        assert_eq!(cycle.to_string(), "#0=(a . #0#)");
+        assert_eq!(cycle.write_shared().to_string(), "#0=(a . #0#)");
+        cycle_free(cycle);
+    }
+
+    #[test]
+    fn test_write_long_list() {
+        let list = (0..100_000).fold(Value::Null, |cdr, i| {
+            Value::Pair(gc::alloc(Pair::new(
+                Value::Number(crate::r7rs::value::Number::Integer(i)),
+                cdr,
+            )))
+        });
+        assert!(list.to_string().ends_with(" 1 0)"));
+    }
+
+    // Break a cycle built by a test so that it is dropped.
+    fn cycle_free(value: Value) {
+        if let Value::Pair(pair) = value {
+            pair.set_cdr(Value::Null);
+        }
+    }
+}